@@ -1,6 +1,8 @@
 /* basic STM32L412KBUx memory layout */
 MEMORY
 {
-  FLASH : ORIGIN = 0x08000000, LENGTH = 64K
-  RAM   : ORIGIN = 0x20000000, LENGTH = 40K
+  /* the last two 2K pages of flash are reserved for the user settings, see SETTINGS_ADDR in board.rs */
+  FLASH    : ORIGIN = 0x08000000, LENGTH = 60K
+  SETTINGS : ORIGIN = 0x0800F000, LENGTH = 4K
+  RAM      : ORIGIN = 0x20000000, LENGTH = 40K
 }
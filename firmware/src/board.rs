@@ -1,15 +1,27 @@
+use ribbon_core::{
+    board::{
+        adc_fs_to_normalized_fl, dac8162_words, AdcInputs, AdcPin, Dac8162Channel, DacOutputs,
+        FlashError, GateOutput, PanelSwitches, PeriodicTimers, SerialInput, SerialOutput,
+        SettingsFlash, Switch3wayState, FLASH_WORD_LEN, NUM_ADC_PINS, SETTINGS_NUM_PAGES,
+        SETTINGS_PAGE_LEN,
+    },
+    byte_ring::{ByteConsumer, ByteProducer, ByteRing},
+    dac_calibration::DacCalibration,
+    overruns::Task,
+};
+
 use stm32l4xx_hal::{
     adc::{SampleTime, Sequence, ADC},
     delay::Delay,
     device::SPI1,
     gpio::{Alternate, Input, Output, Pin, PullUp, PushPull, H8, L8},
     hal::spi::{Mode, Phase, Polarity},
-    pac::{ADC1, DMA1, TIM15, TIM2, TIM6, USART1},
+    pac::{Interrupt, ADC1, DMA1, FLASH, NVIC, TIM15, TIM2, TIM6, USART1},
     prelude::*,
     rcc::{ClockSecuritySystem, CrystalBypass},
     serial,
     spi::Spi,
-    timer::Timer,
+    timer::{Event, Timer},
 };
 
 // type aliases for complex types so clippy doesn't complain, pins are as required by the physical PCB layout
@@ -33,10 +45,16 @@ pub struct Board {
     // USART for MIDI
     _midi_tx: serial::Tx<USART1>,
     _midi_rx: serial::Rx<USART1>,
+    // bytes waiting to be sent via DMA, see `service_midi_tx`
+    midi_tx_ring: ByteProducer<'static, MIDI_TX_RING_LEN>,
+    // bytes received by the USART interrupt, see `service_midi_rx`
+    midi_rx_ring: ByteConsumer<'static, MIDI_RX_RING_LEN>,
 
     // SPI for DAC
     spi: SpiBus,
     nss: Pin<Output<PushPull>, H8, 'A', 15>, // manual chip select
+    // software correction of the DAC gain and offset
+    dac_calibration: DacCalibration,
 
     // general purpose delay
     delay: Delay,
@@ -102,10 +120,8 @@ impl Board {
 
         // configure DMA1 to transfer ADC readings to the buffer
         let mut dma1_ch1 = dma_channels.1;
-        unsafe {
-            dma1_ch1.set_peripheral_address(&dp.ADC1.dr as *const _ as u32, false);
-            dma1_ch1.set_memory_address(ADC_DMA_BUFF.as_ptr() as u32, true);
-        }
+        dma1_ch1.set_peripheral_address(&dp.ADC1.dr as *const _ as u32, false);
+        dma1_ch1.set_memory_address(core::ptr::addr_of!(ADC_DMA_BUFF) as u32, true);
         dma1_ch1.set_transfer_length(NUM_ADC_DMA_SIGNALS as u16);
         unsafe {
             (*DMA1::ptr()).ccr1.modify(|_, w| {
@@ -165,11 +181,15 @@ impl Board {
         // TIMx periodic timers
         //
         ////////////////////////////////////////////////////////////////////////
-        let _tim2 = Timer::tim2(dp.TIM2, TIM2_FREQ_HZ.Hz(), clocks, &mut rcc.apb1r1);
+        // each timer interrupts to run its task, the interrupts stay masked until `start_periodic_tasks` is called
+        let mut tim2 = Timer::tim2(dp.TIM2, TIM2_FREQ_HZ.Hz(), clocks, &mut rcc.apb1r1);
+        tim2.listen(Event::TimeOut);
 
-        let _tim6 = Timer::tim6(dp.TIM6, TIM6_FREQ_HZ.Hz(), clocks, &mut rcc.apb1r1);
+        let mut tim6 = Timer::tim6(dp.TIM6, TIM6_FREQ_HZ.Hz(), clocks, &mut rcc.apb1r1);
+        tim6.listen(Event::TimeOut);
 
-        let _tim15 = Timer::tim15(dp.TIM15, TIM15_FREQ_HZ.Hz(), clocks, &mut rcc.apb2);
+        let mut tim15 = Timer::tim15(dp.TIM15, TIM15_FREQ_HZ.Hz(), clocks, &mut rcc.apb2);
+        tim15.listen(Event::TimeOut);
 
         ////////////////////////////////////////////////////////////////////////
         //
@@ -177,13 +197,11 @@ impl Board {
         //
         ////////////////////////////////////////////////////////////////////////
 
-        // configure DMA1 to transmit bytes via the UART
+        // configure DMA1 to transmit bytes via the UART, straight from the ring, each transfer is started by
+        // `service_midi_tx` with the address and length of the bytes to send
         let mut dma1_ch4 = dma_channels.4;
         unsafe {
             dma1_ch4.set_peripheral_address(&dp.USART1.tdr as *const _ as u32, false);
-            dma1_ch4.set_memory_address(MIDI_USART_DMA_BUFF.as_ptr() as u32, true);
-        }
-        unsafe {
             (*DMA1::ptr()).ccr4.modify(|_, w| {
                 w.pl()
                     .high()
@@ -195,11 +213,30 @@ impl Board {
                     .enabled()
                     .dir()
                     .from_memory()
+                    .tcie()
+                    .set_bit()
             });
             // map DMA channel 4 to UART tx
             (*DMA1::ptr()).cselr.modify(|_, w| w.c4s().bits(0b0010));
         }
 
+        // nothing else uses the ring, the consumer is only used by the DMA interrupt once it is unmasked
+        let midi_tx_ring = unsafe {
+            let (producer, consumer) = (*core::ptr::addr_of_mut!(MIDI_TX_RING)).split();
+            MIDI_TX_CONSUMER = Some(consumer);
+            let mut nvic = cortex_m::Peripherals::steal().NVIC;
+            nvic.set_priority(Interrupt::DMA1_CH4, MIDI_TX_PRIORITY);
+            NVIC::unmask(Interrupt::DMA1_CH4);
+            producer
+        };
+
+        // likewise the producer is only used by the USART interrupt
+        let midi_rx_ring = unsafe {
+            let (producer, consumer) = (*core::ptr::addr_of_mut!(MIDI_RX_RING)).split();
+            MIDI_RX_PRODUCER = Some(producer);
+            consumer
+        };
+
         let tx_pin = gpioa
             .pa9
             .into_alternate(&mut gpioa.moder, &mut gpioa.otyper, &mut gpioa.afrh);
@@ -208,13 +245,20 @@ impl Board {
                 .pa10
                 .into_alternate(&mut gpioa.moder, &mut gpioa.otyper, &mut gpioa.afrh);
 
-        let usart = serial::Serial::usart1(
+        let mut usart = serial::Serial::usart1(
             dp.USART1,
             (tx_pin, rx_pin),
             serial::Config::default().baudrate(MIDI_BAUD_RATE_HZ.bps()),
             clocks,
             &mut rcc.apb2,
         );
+        // each received byte is handed to `service_midi_rx` by the USART interrupt
+        usart.listen(serial::Event::Rxne);
+        unsafe {
+            let mut nvic = cortex_m::Peripherals::steal().NVIC;
+            nvic.set_priority(Interrupt::USART1, MIDI_RX_PRIORITY);
+            NVIC::unmask(Interrupt::USART1);
+        }
         let (tx, rx) = usart.split();
 
         ////////////////////////////////////////////////////////////////////////
@@ -314,8 +358,11 @@ impl Board {
         Self {
             _midi_tx: tx,
             _midi_rx: rx,
+            midi_tx_ring,
+            midi_rx_ring,
             spi,
             nss,
+            dac_calibration: DacCalibration::new(),
             delay,
             mode_switch,
             midi_ch_switch,
@@ -323,35 +370,86 @@ impl Board {
         }
     }
 
-    /// `board.read_adc(p)` is the digitized analog value on pin `p` in the range `[0.0, +1.0]`
-    pub fn read_adc(&mut self, pin: AdcPin) -> f32 {
-        // the values are already stored in the buffer via DMA
-        unsafe { adc_fs_to_normalized_fl(ADC_DMA_BUFF[pin as usize]) }
+    /// `board.spi_write(words)` writes the words via SPI.
+    fn spi_write(&mut self, words: &[u8]) {
+        self.nss.set_low();
+        self.spi.write(words).unwrap();
+        self.nss.set_high();
     }
 
-    /// `board.dac8162_set_vout(v, c)` writes the voltage `v` to channel `c` of the onboard DAC.
-    ///
-    /// # Arguments
-    ///
-    /// * `v_out` - The analog voltage to write, clamped to `[0.0, DAC8162_MAX_VOLTS]`
+    /// `board.delay_ms(ms)` causes the board to busy-wait for `ms` milliseconds
+    pub fn delay_ms(&mut self, ms: u32) {
+        self.delay.delay_ms(ms);
+    }
+
+    /// `board.flash_operation(op)` is the result of running flash operation `op` with the flash controller unlocked
     ///
-    /// * `channel` - The enumerated DAC channel to write to
-    pub fn dac8162_set_vout(&mut self, v_out: f32, channel: Dac8162Channel) {
-        let v_out = v_out.max(0.0_f32).min(DAC8162_MAX_VOUT);
-
-        let val_u14 = (v_out * DAC8162_COUNTS_PER_VOLT) as u16;
-        // move the value out of DB0 and DB1
-        let val_u14 = val_u14 << 2;
-        // split it into bytes
-        let low_byte = (val_u14 & 0xFF) as u8;
-        let mid_byte = (val_u14 >> 8) as u8;
-        let high_byte = channel as u8 | 0b0001_1000; // write to channel and update output
-
-        self.spi_write(&[high_byte, mid_byte, low_byte]);
+    /// The CPU stalls while the flash is busy, so this blocks until the operation is done.
+    fn flash_operation(
+        &mut self,
+        op: impl FnOnce(&stm32l4xx_hal::pac::flash::RegisterBlock),
+    ) -> Result<(), FlashError> {
+        unsafe {
+            let flash = &*FLASH::ptr();
+
+            while flash.sr.read().bsy().bit_is_set() {
+                // wait for any ongoing operation to complete
+            }
+            // clear any errors left over from before
+            flash.sr.write(|w| w.bits(FLASH_SR_ERRORS));
+
+            // unlock the flash controller
+            if flash.cr.read().lock().bit_is_set() {
+                flash.keyr.write(|w| w.bits(FLASH_KEY_1));
+                flash.keyr.write(|w| w.bits(FLASH_KEY_2));
+            }
+
+            op(flash);
+
+            while flash.sr.read().bsy().bit_is_set() {
+                // wait for the operation to complete
+            }
+            let errors = flash.sr.read().bits() & FLASH_SR_ERRORS;
+
+            // lock the flash controller again
+            flash
+                .cr
+                .modify(|_, w| w.per().clear_bit().pg().clear_bit().lock().set_bit());
+
+            if errors == 0 {
+                Ok(())
+            } else {
+                Err(FlashError::Failed)
+            }
+        }
     }
+}
+
+impl AdcInputs for Board {
+    fn read_adc(&mut self, pin: AdcPin) -> f32 {
+        read_adc_dma(pin)
+    }
+}
+
+impl DacOutputs for Board {
+    fn dac8162_set_vout(&mut self, v_out: f32, channel: Dac8162Channel) {
+        let v_out = self.dac_calibration.apply(v_out, channel);
+        self.spi_write(&dac8162_words(v_out, channel));
+    }
+
+    fn set_dac_calibration(&mut self, calibration: DacCalibration) {
+        self.dac_calibration = calibration;
+    }
+}
 
-    /// `board.read_mode_switch()` is the enumerated state of the 3-way mode switch.
-    pub fn read_mode_switch(&self) -> Switch3wayState {
+impl GateOutput for Board {
+    fn set_gate(&mut self, val: bool) {
+        self.gate_pin.set_state(PinState::from(val));
+    }
+}
+
+impl PanelSwitches for Board {
+    fn read_mode_switch(&self) -> Switch3wayState {
         // The physical switch on the PCB is a SPDT on-off-on switch which grounds
         // either PB6, PB7, or neither pins depending on the position.
         match (self.mode_switch.0.is_low(), self.mode_switch.1.is_low()) {
@@ -362,8 +460,7 @@ impl Board {
         }
     }
 
-    /// `board.read_midi_mode_switch()` is the value of the rotary MIDI channel switch in `[0..15]`
-    pub fn read_midi_ch_switch(&self) -> u8 {
+    fn read_midi_ch_switch(&self) -> u8 {
         // the physical switch on the pcb is a Nidec SD-1011 coded rotary switch. This kind of switch represents a
         // binary number on 4 GPIO pins as you turn it
         [
@@ -375,92 +472,230 @@ impl Board {
         .iter()
         .fold(0_u8, |acc, x| (acc << 1_u8) + (*x as u8))
     }
+}
 
-    /// `board.serial_write_all(bs)` writes all bytes `bs` via the serial port
-    ///
-    /// # Requires
-    ///
-    /// * `bytes` is no greater than `MIDI_TX_BUFF_LEN` in length
-    pub fn serial_write_all(&mut self, bytes: &[u8]) {
-        if bytes.is_empty() {
-            return;
+impl SerialOutput for Board {
+    fn serial_tx_free(&self) -> usize {
+        self.midi_tx_ring.free_len()
+    }
+
+    fn serial_write_all(&mut self, bytes: &[u8]) {
+        if self.midi_tx_ring.write_all(bytes) {
+            // start sending if the DMA is idle, the interrupt preempts every task so it runs right away
+            NVIC::pend(Interrupt::DMA1_CH4);
         }
+    }
+}
 
-        // use DMA to send the bytes
-        unsafe {
-            MIDI_USART_DMA_BUFF[..bytes.len()].copy_from_slice(bytes);
+impl SerialInput for Board {
+    fn serial_read(&mut self, bytes: &mut [u8]) -> usize {
+        let received = self.midi_rx_ring.contiguous();
+        let len = received.len().min(bytes.len());
+        bytes[..len].copy_from_slice(&received[..len]);
+        self.midi_rx_ring.consume(len);
+        len
+    }
+}
 
-            while (*USART1::ptr()).isr.read().tc().bit_is_clear() {
-                // wait for any ongoing transfer to complete
-            }
+impl SettingsFlash for Board {
+    fn settings_read(&mut self, page: usize, offset: usize, bytes: &mut [u8]) {
+        // the flash is memory mapped, so it can be read like any other memory
+        let start = settings_page_addr(page) + offset;
+        bytes.iter_mut().enumerate().for_each(|(i, b)| unsafe {
+            *b = core::ptr::read_volatile((start + i) as *const u8);
+        });
+    }
 
-            // disable DMA
-            (*DMA1::ptr()).ccr4.modify(|_, w| w.en().disabled());
-            // set the length for the data transfer
-            (*DMA1::ptr())
-                .cndtr4
-                .write(|w| w.ndt().bits(bytes.len() as u16));
-            // clear the transfer complete flag
-            (*USART1::ptr()).icr.write(|w| w.tccf().set_bit());
-            // enable DMA to start the transfer
-            (*DMA1::ptr()).ccr4.modify(|_, w| w.en().enabled());
+    fn settings_erase(&mut self, page: usize) -> Result<(), FlashError> {
+        if SETTINGS_NUM_PAGES <= page {
+            return Err(FlashError::OutOfBounds);
         }
+        let page_num = SETTINGS_FIRST_PAGE_NUM + page as u8;
+
+        self.flash_operation(|flash| {
+            flash
+                .cr
+                .modify(|_, w| unsafe { w.per().set_bit().pnb().bits(page_num) });
+            flash.cr.modify(|_, w| w.start().set_bit());
+        })
     }
 
-    /// `board.spi_write(words)` writes the words via SPI.
-    fn spi_write(&mut self, words: &[u8]) {
-        self.nss.set_low();
-        self.spi.write(words).unwrap();
-        self.nss.set_high();
+    fn settings_write(
+        &mut self,
+        page: usize,
+        offset: usize,
+        bytes: &[u8],
+    ) -> Result<(), FlashError> {
+        if !offset.is_multiple_of(FLASH_WORD_LEN) || !bytes.len().is_multiple_of(FLASH_WORD_LEN) {
+            return Err(FlashError::Misaligned);
+        }
+        if SETTINGS_NUM_PAGES <= page || SETTINGS_PAGE_LEN < offset + bytes.len() {
+            return Err(FlashError::OutOfBounds);
+        }
+
+        // program one double word at a time, the low word must be written first
+        let start = settings_page_addr(page) + offset;
+        for (i, word) in bytes.chunks_exact(FLASH_WORD_LEN).enumerate() {
+            let addr = (start + i * FLASH_WORD_LEN) as *mut u32;
+            let lo = u32::from_le_bytes([word[0], word[1], word[2], word[3]]);
+            let hi = u32::from_le_bytes([word[4], word[5], word[6], word[7]]);
+
+            self.flash_operation(|flash| unsafe {
+                flash.cr.modify(|_, w| w.pg().set_bit());
+                core::ptr::write_volatile(addr, lo);
+                core::ptr::write_volatile(addr.add(1), hi);
+            })?;
+        }
+
+        Ok(())
     }
+}
 
-    /// `board.set_gate(val)` sets the state of the gate pin to `val`.
-    pub fn set_gate(&mut self, val: bool) {
-        self.gate_pin.set_state(PinState::from(val));
+impl PeriodicTimers for Board {
+    fn get_tim2_timeout(&self) -> bool {
+        take_timeout(Task::RibbonSampling)
     }
 
-    /// `board.delay_ms(ms)` causes the board to busy-wait for `ms` milliseconds
-    pub fn delay_ms(&mut self, ms: u32) {
-        self.delay.delay_ms(ms);
+    fn get_tim6_timeout(&self) -> bool {
+        take_timeout(Task::Ui)
     }
 
-    /// board.get_tim2_timeout()` is true iff timer TIM2 has timed out, self clearing.
-    pub fn get_tim2_timeout(&self) -> bool {
-        unsafe {
-            if (*TIM2::ptr()).sr.read().uif().bit() {
-                (*TIM2::ptr()).sr.modify(|_, w| w.uif().clear());
-                true
-            } else {
-                false
-            }
+    fn get_tim15_timeout(&self) -> bool {
+        take_timeout(Task::OutputUpdate)
+    }
+}
+
+/// `read_adc_dma(p)` is the latest reading of ADC pin `p` in [0.0, +1.0]
+///
+/// This doesn't need the board, so the ribbon sampling task can read the ADC while another task is using the board.
+pub fn read_adc_dma(pin: AdcPin) -> f32 {
+    // the values are already stored in the buffer via DMA
+    unsafe { adc_fs_to_normalized_fl(core::ptr::read_volatile(&ADC_DMA_BUFF[pin as usize])) }
+}
+
+/// `timeout_pending(t)` is true iff the timer of task `t` has timed out since it was last cleared
+pub fn timeout_pending(task: Task) -> bool {
+    unsafe {
+        match task {
+            Task::RibbonSampling => (*TIM2::ptr()).sr.read().uif().bit(),
+            Task::OutputUpdate => (*TIM15::ptr()).sr.read().uif().bit(),
+            Task::Ui => (*TIM6::ptr()).sr.read().uif().bit(),
         }
     }
+}
 
-    /// board.get_tim6_timeout()` is true iff timer TIM6 has timed out, self clearing.
-    pub fn get_tim6_timeout(&self) -> bool {
-        unsafe {
-            if (*TIM6::ptr()).sr.read().uif().bit() {
-                (*TIM6::ptr()).sr.modify(|_, w| w.uif().clear());
-                true
-            } else {
-                false
-            }
+/// `take_timeout(t)` is true iff the timer of task `t` has timed out, self clearing
+pub fn take_timeout(task: Task) -> bool {
+    if !timeout_pending(task) {
+        return false;
+    }
+    unsafe {
+        match task {
+            Task::RibbonSampling => (*TIM2::ptr()).sr.modify(|_, w| w.uif().clear()),
+            Task::OutputUpdate => (*TIM15::ptr()).sr.modify(|_, w| w.uif().clear()),
+            Task::Ui => (*TIM6::ptr()).sr.modify(|_, w| w.uif().clear()),
         }
     }
+    true
+}
 
-    /// board.get_tim15_timeout()` is true iff timer TIM6 has timed out, self clearing.
-    pub fn get_tim15_timeout(&self) -> bool {
-        unsafe {
-            if (*TIM15::ptr()).sr.read().uif().bit() {
-                (*TIM15::ptr()).sr.modify(|_, w| w.uif().clear());
-                true
-            } else {
-                false
+/// `service_midi_tx()` frees the bytes of the last DMA transfer once it is complete, and starts a transfer of the next
+/// bytes waiting in the MIDI ring if there are any
+///
+/// Called from the DMA transfer complete interrupt, which is also pended whenever bytes are written to the ring.
+pub fn service_midi_tx() {
+    unsafe {
+        let dma = &*DMA1::ptr();
+        // only the DMA interrupt uses the consumer and the number of bytes being sent
+        let consumer = (*core::ptr::addr_of_mut!(MIDI_TX_CONSUMER))
+            .as_mut()
+            .unwrap();
+
+        if 0 < MIDI_TX_IN_FLIGHT {
+            if dma.isr.read().tcif4().bit_is_clear() {
+                // still sending, this interrupt will run again when the transfer is complete
+                return;
             }
+            dma.ifcr.write(|w| w.ctcif4().set_bit());
+            consumer.consume(MIDI_TX_IN_FLIGHT);
+            MIDI_TX_IN_FLIGHT = 0;
+        }
+
+        let bytes = consumer.contiguous();
+        if bytes.is_empty() {
+            return;
+        }
+
+        // the channel must be disabled to set up the next transfer
+        dma.ccr4.modify(|_, w| w.en().disabled());
+        dma.cmar4.write(|w| w.ma().bits(bytes.as_ptr() as u32));
+        dma.cndtr4.write(|w| w.ndt().bits(bytes.len() as u16));
+        dma.ccr4.modify(|_, w| w.en().enabled());
+        MIDI_TX_IN_FLIGHT = bytes.len();
+    }
+}
+
+/// `service_midi_rx()` moves the byte received by the USART, if any, to the MIDI input ring
+///
+/// Called from the USART interrupt. A byte is lost if the ring is full or if the last one wasn't read in time, the MIDI
+/// parser picks up again at the next status byte.
+pub fn service_midi_rx() {
+    unsafe {
+        let usart = &*USART1::ptr();
+        // only the USART interrupt uses the producer
+        let producer = (*core::ptr::addr_of_mut!(MIDI_RX_PRODUCER))
+            .as_mut()
+            .unwrap();
+
+        let isr = usart.isr.read();
+        if isr.rxne().bit_is_set() {
+            // reading the byte clears the interrupt
+            let byte = usart.rdr.read().rdr().bits() as u8;
+            producer.write_all(&[byte]);
+        }
+        if isr.ore().bit_is_set() || isr.fe().bit_is_set() || isr.nf().bit_is_set() {
+            // the errors raise the interrupt too, until they are cleared
+            usart
+                .icr
+                .write(|w| w.orecf().set_bit().fecf().set_bit().ncf().set_bit());
         }
     }
 }
 
+/// `start_periodic_tasks()` sets the priority of each timer interrupt and unmasks them, so that the tasks start running
+///
+/// Call once everything the interrupt handlers use is ready.
+pub fn start_periodic_tasks() {
+    let tasks = [
+        (
+            Task::RibbonSampling,
+            Interrupt::TIM2,
+            RIBBON_SAMPLING_PRIORITY,
+        ),
+        (
+            Task::OutputUpdate,
+            Interrupt::TIM1_BRK_TIM15,
+            OUTPUT_UPDATE_PRIORITY,
+        ),
+        (Task::Ui, Interrupt::TIM6_DACUNDER, UI_PRIORITY),
+    ];
+
+    unsafe {
+        let mut nvic = cortex_m::Peripherals::steal().NVIC;
+        for (task, interrupt, priority) in tasks {
+            // the timers have been running since they were set up, that isn't a missed deadline
+            take_timeout(task);
+            nvic.set_priority(interrupt, priority);
+            NVIC::unmask(interrupt);
+        }
+    }
+}
+
+/// `settings_page_addr(p)` is the address of settings page `p`
+fn settings_page_addr(page: usize) -> usize {
+    SETTINGS_ADDR + page * SETTINGS_PAGE_LEN
+}
+
 ////////////////////////////////////////////////////////////////////////////////
 //
 // Public constants
@@ -470,86 +705,70 @@ impl Board {
 /// The frequenct of the main system clock
 pub const SYST_CLK_FREQ_MHZ: u32 = 80;
 
-/// The frequency for periodic timer TIM2
-pub const TIM2_FREQ_HZ: u32 = 1_000;
+/// The frequency for periodic timer TIM2, used to sample the ribbons
+pub const TIM2_FREQ_HZ: u32 = ribbon_core::RIBBON_SAMPLE_RATE_HZ;
 
-/// The frequency for periodic timer TIM6
-pub const TIM6_FREQ_HZ: u32 = 30;
+/// The frequency for periodic timer TIM6, used to read the panel controls
+pub const TIM6_FREQ_HZ: u32 = ribbon_core::UI_UPDATE_RATE_HZ;
 
-/// The frequency for periodic timer TIM15
-pub const TIM15_FREQ_HZ: u32 = 300;
+/// The frequency for periodic timer TIM15, used to update the outputs
+pub const TIM15_FREQ_HZ: u32 = ribbon_core::OUTPUT_UPDATE_RATE_HZ;
 
 /// The SPI clock frequency to use
 const SPI_CLK_FREQ_MHZ: u32 = 10;
 
-/// The maximum value that can be produced by the Analog to Digital Converters.
-pub const ADC_MAX: u16 = 0xFFF0;
-
-/// The maximum value that can be written to the onboard Digital to Analog Converter.
-pub const DAC8162_MAX_COUNT: u16 = (1 << 14) - 1;
-
-/// The maximum analog voltage that the DAC can produce after onboard amplification
-pub const DAC8162_MAX_VOUT: f32 = 5.0_f32;
-
 /// The baud rate required for MIDI communication
-pub const MIDI_BAUD_RATE_HZ: u32 = 31_250;
+pub const MIDI_BAUD_RATE_HZ: u32 = ribbon_core::midi_transmitter::MIDI_BAUD_RATE_HZ;
 
-////////////////////////////////////////////////////////////////////////////////
-//
-// Private constants and static variables
-//
-////////////////////////////////////////////////////////////////////////////////
+/// The address of the first of the flash pages reserved for settings, must match the `SETTINGS` region in `memory.x`
+pub const SETTINGS_ADDR: usize = 0x0800_F000;
 
-/// The number of DAC counts for 1 volt output
-const DAC8162_COUNTS_PER_VOLT: f32 = DAC8162_MAX_COUNT as f32 / DAC8162_MAX_VOUT;
+/// The interrupt priorities of the periodic tasks, lower numbers preempt higher ones
+///
+/// The STM32L412 implements the top 4 bits of each priority.
+pub const RIBBON_SAMPLING_PRIORITY: u8 = 1 << 4;
+pub const OUTPUT_UPDATE_PRIORITY: u8 = 2 << 4;
+pub const UI_PRIORITY: u8 = 3 << 4;
 
-/// ADC readings are stored in a static array via DMA
-const NUM_ADC_DMA_SIGNALS: usize = 3;
-static mut ADC_DMA_BUFF: [u16; NUM_ADC_DMA_SIGNALS] = [0; NUM_ADC_DMA_SIGNALS];
+/// The interrupt priority of the MIDI DMA transfer complete interrupt, which preempts all of the periodic tasks
+pub const MIDI_TX_PRIORITY: u8 = 0;
 
-const MIDI_TX_BUFF_LEN: usize = 16;
-static mut MIDI_USART_DMA_BUFF: [u8; MIDI_TX_BUFF_LEN] = [0; MIDI_TX_BUFF_LEN];
+/// The interrupt priority of the USART receive interrupt, which must read each byte before the next one arrives
+pub const MIDI_RX_PRIORITY: u8 = 0;
 
 ////////////////////////////////////////////////////////////////////////////////
 //
-// Private helper functions
+// Private constants and static variables
 //
 ////////////////////////////////////////////////////////////////////////////////
 
-/// `adc_fs_to_normalized_fl(v)` is the integer adc value normalized to [0.0, +1.0]
-///
-/// If the input value would overflow the output range it is clamped.
-fn adc_fs_to_normalized_fl(val: u16) -> f32 {
-    let val = val.min(ADC_MAX); // don't need to clamp negative values, it's already unsigned
+/// The number of the first of the flash pages reserved for settings
+const SETTINGS_FIRST_PAGE_NUM: u8 = ((SETTINGS_ADDR - 0x0800_0000) / SETTINGS_PAGE_LEN) as u8;
 
-    (val as f32) / (ADC_MAX as f32)
-}
+/// The keys which unlock the flash controller, written in order
+const FLASH_KEY_1: u32 = 0x4567_0123;
+const FLASH_KEY_2: u32 = 0xCDEF_89AB;
 
-////////////////////////////////////////////////////////////////////////////////
-//
-// Public enums
-//
-////////////////////////////////////////////////////////////////////////////////
-
-/// Pins which may be read by the ADC are represented here
-#[derive(Clone, Copy)]
-pub enum AdcPin {
-    PA0 = 0,
-    PA1 = 1,
-    PA2 = 2,
-}
+/// The error flags in the flash status register: OPTVERR, RDERR, FASTERR, MISERR, PGSERR, SIZERR, PGAERR, WRPERR,
+/// PROGERR, and OPERR
+const FLASH_SR_ERRORS: u32 = 0b1100_0011_1111_1010;
 
-/// Channels of the onboard DAC are represented here
-#[derive(Clone, Copy)]
-pub enum Dac8162Channel {
-    A = 0b000,
-    B = 0b001,
-}
+/// ADC readings are stored in a static array via DMA
+const NUM_ADC_DMA_SIGNALS: usize = NUM_ADC_PINS;
+static mut ADC_DMA_BUFF: [u16; NUM_ADC_DMA_SIGNALS] = [0; NUM_ADC_DMA_SIGNALS];
 
-/// Valid states of a 3-way switch are represented here
-#[derive(Clone, Copy)]
-pub enum Switch3wayState {
-    Up,
-    Middle,
-    Down,
-}
+/// MIDI bytes wait in a ring to be sent via DMA, room for the longest configuration answer, a tuning table of
+/// `MAX_CONFIG_SYSEX_LEN` bytes, which is about 170ms at the MIDI baud rate
+const MIDI_TX_RING_LEN: usize = 1024;
+static mut MIDI_TX_RING: ByteRing<MIDI_TX_RING_LEN> = ByteRing::new();
+static mut MIDI_TX_CONSUMER: Option<ByteConsumer<'static, MIDI_TX_RING_LEN>> = None;
+
+/// The number of bytes in the DMA transfer in progress, 0 if there is none
+static mut MIDI_TX_IN_FLIGHT: usize = 0;
+
+/// MIDI bytes received by the USART interrupt wait in a ring for the output update, which takes them at
+/// `OUTPUT_UPDATE_RATE_HZ`, every 3.3ms or so. At 31,250 baud about 10 bytes arrive in between, so there is room for
+/// several updates' worth
+const MIDI_RX_RING_LEN: usize = 64;
+static mut MIDI_RX_RING: ByteRing<MIDI_RX_RING_LEN> = ByteRing::new();
+static mut MIDI_RX_PRODUCER: Option<ByteProducer<'static, MIDI_RX_RING_LEN>> = None;
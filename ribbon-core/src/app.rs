@@ -0,0 +1,1102 @@
+//! # Application
+//!
+//! The top level behaviour of the ribbon controller, shared by the firmware and the host-side simulator.
+//!
+//! The application is paced by three periodic timers on the board:
+//!
+//! * TIM2 at `RIBBON_SAMPLE_RATE_HZ` polls the ribbons
+//!
+//! * TIM15 at `OUTPUT_UPDATE_RATE_HZ` updates the analog and MIDI outputs
+//!
+//! * TIM6 at `UI_UPDATE_RATE_HZ` reads the panel controls, and saves the settings to flash if they changed
+//!
+//! Each timer runs one task, in that order of priority, see `overruns::Task`. The firmware runs the tasks from the timer
+//! interrupts so that a slow UI update never delays the ribbon sampling, while the simulator polls the timers with
+//! `service`.
+//!
+//! The settings saved in flash are loaded when the application is initialized, see `settings`. If the panel switches are
+//! set for it at power up one of the calibration procedures runs before anything is played, see `dac_calibration`,
+//! `auto_calibration`, and `ribbon_calibration`.
+
+use crate::{
+    auto_calibration::AutoCalibration,
+    board::{
+        read_all_adc, AdcPin, BoardIo, Dac8162Channel, Switch3wayState, DAC8162_MAX_VOUT,
+        NUM_ADC_PINS,
+    },
+    dac_calibration::{CalibrationProcedure, ChannelCalibration, DacCalibration},
+    midi_merge::MidiMerge,
+    midi_transmitter::MidiTransmitter,
+    pitch_engine::PitchEngine,
+    ribbon_calibration::{RibbonCalibrationProcedure, WhichRibbon},
+    settings::{self, Settings},
+    sysex_config::{self, Command, Param, Reply, NUM_PARAMS},
+    ui::UiState,
+};
+
+/// The ribbon controller application is represented here
+pub struct App {
+    ui: UiState,
+
+    // turns the ribbon readings into CV, gate, and MIDI
+    pitch_engine: PitchEngine,
+
+    midi: MidiTransmitter,
+    // forwards the MIDI input to the MIDI output, and keeps the configuration requests
+    midi_in: MidiMerge,
+    // the next parameter to send while answering a dump request
+    dump_next: Option<usize>,
+
+    // the settings as they were last loaded or saved, to tell when they need saving again
+    saved_settings: Settings,
+
+    dac_calibration: DacCalibration,
+    // the calibration procedures, while they are running
+    dac_calibration_procedure: Option<CalibrationProcedure>,
+    auto_calibration: Option<AutoCalibration>,
+    ribbon_calibration_procedure: Option<RibbonCalibrationProcedure>,
+}
+
+impl App {
+    /// `App::new()` is a new application in its power-on state
+    pub fn new() -> Self {
+        Self {
+            ui: UiState::new(),
+            pitch_engine: PitchEngine::new(),
+            midi: MidiTransmitter::new(),
+            midi_in: MidiMerge::new(),
+            dump_next: None,
+            saved_settings: Settings::new(),
+            dac_calibration: DacCalibration::new(),
+            dac_calibration_procedure: None,
+            auto_calibration: None,
+            ribbon_calibration_procedure: None,
+        }
+    }
+
+    /// `app.init(b)` loads the saved settings and reads the initial state of the panel controls on board `b`, call once
+    /// before servicing the app
+    ///
+    /// The DAC calibration procedure is started instead of playing if the panel switches are set to
+    /// `DAC_CALIBRATION_MODE_SWITCH` and `CALIBRATION_MIDI_CH_SWITCH`. Likewise the 1 volt/octave auto-calibration is
+    /// started by `AUTO_CALIBRATION_MODE_SWITCH` and the ribbon calibration procedure by
+    /// `RIBBON_CALIBRATION_MODE_SWITCH`.
+    pub fn init<B: BoardIo>(&mut self, board: &mut B) {
+        // blank or corrupt flash means the defaults, which is what the pitch engine starts with anyway
+        if let Some(saved) = settings::load(board) {
+            saved.apply_to(&mut self.pitch_engine);
+            self.dac_calibration = saved.dac_calibration;
+        }
+        self.saved_settings = Settings::of(&self.pitch_engine, self.dac_calibration);
+
+        let calibrating = board.read_midi_ch_switch() == CALIBRATION_MIDI_CH_SWITCH;
+        match board.read_mode_switch() {
+            DAC_CALIBRATION_MODE_SWITCH if calibrating => {
+                // the procedure works with the raw DAC output
+                self.dac_calibration_procedure = Some(CalibrationProcedure::new());
+                board.set_dac_calibration(DacCalibration::new());
+            }
+            AUTO_CALIBRATION_MODE_SWITCH if calibrating => {
+                // the RIBBON CV output is measured raw
+                self.auto_calibration = Some(AutoCalibration::new());
+                board.set_dac_calibration(DacCalibration {
+                    a: ChannelCalibration::new(),
+                    ..self.dac_calibration
+                });
+            }
+            RIBBON_CALIBRATION_MODE_SWITCH if calibrating => {
+                self.ribbon_calibration_procedure = Some(RibbonCalibrationProcedure::new());
+                board.set_dac_calibration(self.dac_calibration);
+            }
+            _ => board.set_dac_calibration(self.dac_calibration),
+        }
+
+        self.ui.update(board);
+    }
+
+    /// `app.is_calibrating()` is true iff any of the calibration procedures is running
+    pub fn is_calibrating(&self) -> bool {
+        self.dac_calibration_procedure.is_some()
+            || self.auto_calibration.is_some()
+            || self.ribbon_calibration_procedure.is_some()
+    }
+
+    /// `app.service(b)` runs any periodic tasks which are due on board `b`, must be called continuously
+    ///
+    /// This is for boards which poll their timers, boards with timer interrupts can call the tasks from the interrupt
+    /// handlers instead, see `poll_ribbons`, `update_outputs`, and `update_ui`.
+    pub fn service<B: BoardIo>(&mut self, board: &mut B) {
+        // slow timer for updating UI, reading pots and such
+        if board.get_tim6_timeout() {
+            self.update_ui(board);
+        }
+
+        // fast timer for polling the ribbon
+        if board.get_tim2_timeout() {
+            self.poll_ribbons(&read_all_adc(board));
+        }
+
+        // timer to update analog and MIDI outputs
+        if board.get_tim15_timeout() {
+            self.update_outputs(board);
+        }
+    }
+
+    /// `app.poll_ribbons(s)` feeds the app the samples `s` of every ADC input, indexed by `AdcPin`
+    ///
+    /// Must be called for every sample taken at `RIBBON_SAMPLE_RATE_HZ`. The samples may be queued and handed over in a
+    /// batch just before `update_outputs`, the ribbons only need each sample in order.
+    pub fn poll_ribbons(&mut self, samples: &[f32; NUM_ADC_PINS]) {
+        let (main_sample, mod_sample) = (
+            samples[MAIN_RIBBON_PIN as usize],
+            samples[MOD_RIBBON_PIN as usize],
+        );
+        match (
+            &mut self.ribbon_calibration_procedure,
+            &mut self.auto_calibration,
+        ) {
+            (Some(procedure), _) => procedure.poll(main_sample, mod_sample),
+            (_, Some(auto)) => auto.poll(samples[LOOPBACK_PIN as usize]),
+            _ => self.pitch_engine.poll(main_sample, mod_sample),
+        }
+    }
+
+    /// `app.update_outputs(b)` updates the analog and MIDI outputs of board `b`, merging in anything received on its
+    /// MIDI input and answering the configuration requests, see `sysex_config`
+    ///
+    /// Must be called periodically at `OUTPUT_UPDATE_RATE_HZ`.
+    pub fn update_outputs<B: BoardIo>(&mut self, board: &mut B) {
+        // the MIDI chain keeps working while calibrating, though only what is forwarded is sent then
+        let device = board.read_midi_ch_switch();
+        self.midi_in.merge(board, &mut self.midi, device);
+        self.answer_requests(board, device);
+
+        if self.ribbon_calibration_procedure.is_some() {
+            self.calibrate_ribbons(board);
+            self.midi.send_queue(board);
+            return;
+        }
+        if self.auto_calibration.is_some() {
+            self.auto_calibrate(board);
+            self.midi.send_queue(board);
+            return;
+        }
+
+        // notes which can't be retuned with MTS while the MIDI output is busy are bent to pitch instead
+        self.pitch_engine.set_retune_room(self.midi.retune_room());
+        let output = self
+            .pitch_engine
+            .tick(self.ui.pitch_mode(), board.read_midi_ch_switch());
+
+        if self.dac_calibration_procedure.is_some() {
+            self.calibrate_dac(board, output.gate);
+            self.midi.send_queue(board);
+            return;
+        }
+
+        // set the analog outputs
+        board.dac8162_set_vout(output.ribbon_cv, Dac8162Channel::A);
+        board.dac8162_set_vout(output.mod_cv, Dac8162Channel::B);
+        board.set_gate(output.gate);
+
+        // queue the retunes ahead of the notes they retune, then send any MIDI messages, the queue might be empty but that
+        // is fine
+        output
+            .note_tunings
+            .into_iter()
+            .for_each(|t| self.midi.push_retune(t));
+        output.midi.into_iter().for_each(|msg| self.midi.push(msg));
+        self.midi.send_queue(board);
+    }
+
+    /// `app.update_ui(b)` reads the panel controls of board `b`, and saves the settings to its flash if they changed
+    ///
+    /// Must be called periodically at `UI_UPDATE_RATE_HZ`. Saving the settings can take tens of milliseconds when the
+    /// log moves to the other flash page, which erases two pages.
+    pub fn update_ui<B: BoardIo>(&mut self, board: &mut B) {
+        self.ui.update(board);
+        self.pitch_engine.set_glide_time(self.ui.glide_time());
+
+        // settings can be changed from the panel, e.g. the transpose, keep them for the next power cycle
+        let current = Settings::of(&self.pitch_engine, self.dac_calibration);
+        if current != self.saved_settings {
+            // if the flash fails there is nothing useful to do about it, the settings still work until power off
+            settings::save(board, &current).ok();
+            self.saved_settings = current;
+        }
+    }
+
+    /// `app.answer_requests(b, d)` carries out the configuration requests received by board `b` for device `d`, and
+    /// answers them
+    ///
+    /// Each answer waits until the MIDI output has caught up, so that it never holds up the notes or gets dropped, and a
+    /// dump is answered one parameter at a time. Requests are ignored while calibrating, the calibration procedures own
+    /// the settings until they are done. Changed settings are saved by the UI timer like any other change of settings.
+    fn answer_requests<B: BoardIo>(&mut self, board: &mut B, device: u8) {
+        while self.midi.is_idle() {
+            let current = Settings::of(&self.pitch_engine, self.dac_calibration);
+
+            let reply = if let Some(next) = self.dump_next {
+                self.dump_next = Some(next + 1).filter(|&n| n < NUM_PARAMS);
+                let param = Param::ALL[next];
+                Reply::Value(param, sysex_config::value_of(&current, param))
+            } else {
+                match self.midi_in.take_request() {
+                    None => return,
+                    Some(_) if self.is_calibrating() => continue,
+                    Some(Ok(Command::Get(param))) => {
+                        Reply::Value(param, sysex_config::value_of(&current, param))
+                    }
+                    Some(Ok(Command::Set(param, value))) => {
+                        match sysex_config::with_value(&current, param, &value) {
+                            Ok(changed) => {
+                                self.apply_settings(changed, board);
+                                // the pitch engine may have clamped the value
+                                let applied =
+                                    Settings::of(&self.pitch_engine, self.dac_calibration);
+                                Reply::Value(param, sysex_config::value_of(&applied, param))
+                            }
+                            Err(error) => Reply::Error(error),
+                        }
+                    }
+                    Some(Ok(Command::Dump)) => {
+                        self.dump_next = Some(0);
+                        continue;
+                    }
+                    Some(Ok(Command::Reset)) => {
+                        let defaults = Settings {
+                            dac_calibration: current.dac_calibration,
+                            ribbon_calibration: current.ribbon_calibration,
+                            ..Settings::new()
+                        };
+                        self.apply_settings(defaults, board);
+                        Reply::ResetDone
+                    }
+                    Some(Err(error)) => Reply::Error(error),
+                }
+            };
+            self.midi.send_sysex(&reply.sysex(device), board);
+        }
+    }
+
+    /// `app.apply_settings(s, b)` changes every setting to settings `s`, including the DAC calibration of board `b`
+    fn apply_settings<B: BoardIo>(&mut self, settings: Settings, board: &mut B) {
+        settings.apply_to(&mut self.pitch_engine);
+        self.dac_calibration = settings.dac_calibration;
+        board.set_dac_calibration(self.dac_calibration);
+    }
+
+    /// `app.calibrate_dac(b, g)` runs a step of the DAC calibration procedure on board `b` with main ribbon gate `g`
+    ///
+    /// Nothing is played while calibrating, the ribbon is only used to move on to the next step.
+    fn calibrate_dac<B: BoardIo>(&mut self, board: &mut B, gate: bool) {
+        let procedure = match &mut self.dac_calibration_procedure {
+            Some(procedure) => procedure,
+            None => return,
+        };
+        procedure.update(self.ui.glide_knob(), gate);
+
+        match procedure.output() {
+            Some((v_out, channel)) => {
+                let other_channel = match channel {
+                    Dac8162Channel::A => Dac8162Channel::B,
+                    Dac8162Channel::B => Dac8162Channel::A,
+                };
+                board.dac8162_set_vout(v_out, channel);
+                board.dac8162_set_vout(0.0_f32, other_channel);
+                board.set_gate(false);
+            }
+            // done, the new calibration is saved by the UI timer like any other change of settings
+            None => {
+                self.dac_calibration = procedure.result().unwrap_or(self.dac_calibration);
+                board.set_dac_calibration(self.dac_calibration);
+                self.dac_calibration_procedure = None;
+            }
+        }
+    }
+
+    /// `app.auto_calibrate(b)` runs a step of the 1 volt/octave auto-calibration on board `b`
+    ///
+    /// Nothing is played while calibrating, and the result is reported over MIDI at the end.
+    fn auto_calibrate<B: BoardIo>(&mut self, board: &mut B) {
+        let auto = match &mut self.auto_calibration {
+            Some(auto) => auto,
+            None => return,
+        };
+        auto.update();
+
+        if let Some(v_out) = auto.output() {
+            board.dac8162_set_vout(v_out, Dac8162Channel::A);
+            board.dac8162_set_vout(0.0_f32, Dac8162Channel::B);
+            board.set_gate(false);
+            return;
+        }
+
+        // done, the new calibration is saved by the UI timer like any other change of settings
+        if let Some(report) = auto.result() {
+            if report.accepted {
+                self.dac_calibration.a = report.calibration;
+            }
+            self.midi.send_sysex(&report.sysex(), board);
+        }
+        board.set_dac_calibration(self.dac_calibration);
+        self.auto_calibration = None;
+    }
+
+    /// `app.calibrate_ribbons(b)` runs a step of the ribbon calibration procedure on board `b`
+    ///
+    /// Nothing is played while calibrating, the output of the ribbon being calibrated shows where to tap it.
+    fn calibrate_ribbons<B: BoardIo>(&mut self, board: &mut B) {
+        let procedure = match &mut self.ribbon_calibration_procedure {
+            Some(procedure) => procedure,
+            None => return,
+        };
+        procedure.update();
+
+        match procedure.output() {
+            Some((ribbon, position)) => {
+                let v_out = position * DAC8162_MAX_VOUT;
+                let (ribbon_cv, mod_cv) = match ribbon {
+                    WhichRibbon::Main => (v_out, 0.0_f32),
+                    WhichRibbon::Mod => (0.0_f32, v_out),
+                };
+                board.dac8162_set_vout(ribbon_cv, Dac8162Channel::A);
+                board.dac8162_set_vout(mod_cv, Dac8162Channel::B);
+                board.set_gate(procedure.finger_is_pressing());
+            }
+            // done, the new maps are saved by the UI timer like any other change of settings
+            None => {
+                if let Some(calibration) = procedure.result() {
+                    self.pitch_engine.set_ribbon_calibration(calibration);
+                }
+                board.set_gate(false);
+                self.ribbon_calibration_procedure = None;
+            }
+        }
+    }
+}
+
+impl Default for App {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// The ADC pin connected to the main ribbon
+pub const MAIN_RIBBON_PIN: AdcPin = AdcPin::PA1;
+
+/// The ADC pin connected to the MOD ribbon
+pub const MOD_RIBBON_PIN: AdcPin = AdcPin::PA2;
+
+/// The position of the mode switch at power up which starts the DAC calibration procedure, `QUANTIZE`
+pub const DAC_CALIBRATION_MODE_SWITCH: Switch3wayState = Switch3wayState::Up;
+
+/// The position of the mode switch at power up which starts the 1 volt/octave auto-calibration, `ASSIST`
+pub const AUTO_CALIBRATION_MODE_SWITCH: Switch3wayState = Switch3wayState::Middle;
+
+/// The ADC pin which measures `RIBBON CV` during auto-calibration, the glide knob input, see `auto_calibration`
+pub const LOOPBACK_PIN: AdcPin = AdcPin::PA0;
+
+/// The position of the mode switch at power up which starts the ribbon calibration procedure, `SMOOTH`
+pub const RIBBON_CALIBRATION_MODE_SWITCH: Switch3wayState = Switch3wayState::Down;
+
+/// The position of the MIDI channel switch at power up which starts either calibration procedure, channel 16
+pub const CALIBRATION_MIDI_CH_SWITCH: u8 = 15;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        auto_calibration::LOOPBACK_DIVIDER,
+        board::{dac8162_words, ADC_VREF_VOLTS, DAC8162_MAX_COUNT},
+        dac_calibration::LOW_POINT_VOUT,
+        midi_generator::MAX_PITCH_BEND_RANGE,
+        midi_parser::{MidiParser, Received},
+        mock_board::MockBoard,
+        sysex_config::{ConfigError, Value},
+        transpose::Transpose,
+        tuning::{TuningTable, MAX_TUNING_DEGREES},
+        OUTPUT_UPDATE_RATE_HZ, RIBBON_SAMPLE_RATE_HZ,
+    };
+    use midi_convert::midi_types::MidiMessage;
+    use std::vec::Vec;
+
+    /// `configure(a, b, c)` is the configuration replies from app `a` on board `b` to command `c` sent to it, over the
+    /// next few output updates
+    fn configure(app: &mut App, board: &mut MockBoard, command: Command) -> Vec<Reply> {
+        board.clear_outputs();
+        board.serial_rx.extend(&command.sysex(board.midi_ch_switch));
+        for _ in 0..200 {
+            board.expire_tim15();
+            app.service(board);
+        }
+
+        let mut parser = MidiParser::new();
+        board
+            .serial_bytes
+            .iter()
+            .filter_map(|&b| match parser.parse(b) {
+                Some(Received::SysEx(sysex)) => {
+                    Reply::parse(sysex).map(|(_, reply)| reply.unwrap())
+                }
+                _ => None,
+            })
+            .collect()
+    }
+
+    fn value(bytes: &[u8]) -> Value {
+        Value::from_slice(bytes).unwrap()
+    }
+
+    #[test]
+    fn nothing_happens_until_a_timer_expires() {
+        let mut board = MockBoard::new();
+        let mut app = App::new();
+        app.init(&mut board);
+        app.service(&mut board);
+
+        assert!(board.dac_words.is_empty());
+        assert!(board.gate_writes.is_empty());
+        assert!(board.serial_bytes.is_empty());
+    }
+
+    #[test]
+    fn output_timer_writes_both_dac_channels_and_the_gate() {
+        let mut board = MockBoard::new();
+        let mut app = App::new();
+        app.init(&mut board);
+
+        board.expire_tim15();
+        app.service(&mut board);
+
+        assert_eq!(
+            board.dac_words,
+            [
+                dac8162_words(0.0, Dac8162Channel::A),
+                dac8162_words(0.0, Dac8162Channel::B)
+            ]
+        );
+        assert_eq!(board.gate_writes, [false]);
+    }
+
+    #[test]
+    fn pressing_the_main_ribbon_raises_the_gate_and_sends_a_note_on() {
+        let mut board = MockBoard::new();
+        let mut app = App::new();
+        board.midi_ch_switch = 4;
+        board.set_adc(MOD_RIBBON_PIN, 1.0);
+        app.init(&mut board);
+
+        board.set_adc(MAIN_RIBBON_PIN, 0.3);
+        for _ in 0..100 {
+            board.expire_tim2();
+            app.service(&mut board);
+        }
+        board.expire_tim15();
+        app.service(&mut board);
+
+        assert_eq!(board.gate_edges(), [true]);
+        let note_on = board
+            .midi_messages()
+            .into_iter()
+            .find(|m| matches!(m, MidiMessage::NoteOn(..)))
+            .unwrap();
+        assert!(matches!(
+            note_on,
+            MidiMessage::NoteOn(ch, _, vel) if u8::from(ch) == 4 && u8::from(vel) == 127
+        ));
+    }
+
+    #[test]
+    fn midi_input_is_merged_with_the_ribbon() {
+        let mut board = MockBoard::new();
+        let mut app = App::new();
+        board.midi_ch_switch = 4;
+        board.set_adc(MOD_RIBBON_PIN, 1.0);
+        app.init(&mut board);
+
+        board.set_adc(MAIN_RIBBON_PIN, 0.3);
+        for _ in 0..100 {
+            board.expire_tim2();
+            app.service(&mut board);
+        }
+        board
+            .serial_rx
+            .extend([0xF8, 0x99, 36, 100, 0xF0, 0x7D, 0x01, 0xF7]);
+        board.expire_tim15();
+        app.service(&mut board);
+
+        let messages = board.midi_messages();
+        assert_eq!(messages[0], MidiMessage::TimingClock);
+        assert!(messages.contains(&MidiMessage::NoteOn(9.into(), 36.into(), 100.into())));
+        assert!(messages
+            .iter()
+            .any(|m| matches!(m, MidiMessage::NoteOn(ch, ..) if u8::from(*ch) == 4)));
+        assert!(board
+            .serial_bytes
+            .windows(4)
+            .any(|w| w == [0xF0, 0x7D, 0x01, 0xF7]));
+    }
+
+    /// `ribbon_notes_without_retunes(b, ch)` is the notes played on channel `ch` in the bytes board `b` sent which were
+    /// not retuned with MTS since the note before, and the number of notes played
+    fn ribbon_notes_without_retunes(board: &MockBoard, channel: u8) -> (Vec<u8>, usize) {
+        let mut parser = MidiParser::new();
+        let mut retuned = Vec::new();
+        let mut not_retuned = Vec::new();
+        let mut num_notes = 0;
+        for &byte in &board.serial_bytes {
+            match parser.parse(byte) {
+                Some(Received::SysEx([0xF0, 0x7F, 0x7F, 0x08, 0x02, 0, 1, key, ..])) => {
+                    retuned.push(*key)
+                }
+                Some(Received::Message(MidiMessage::NoteOn(ch, note, _)))
+                    if u8::from(ch) == channel =>
+                {
+                    num_notes += 1;
+                    if !retuned.contains(&u8::from(note)) {
+                        not_retuned.push(u8::from(note));
+                    }
+                    retuned.clear();
+                }
+                _ => (),
+            }
+        }
+        (not_retuned, num_notes)
+    }
+
+    #[test]
+    fn every_note_is_retuned_before_it_plays_while_midi_is_merged() {
+        let mut board = MockBoard::new();
+        let mut app = App::new();
+        board.midi_ch_switch = 4;
+        board.set_adc(MOD_RIBBON_PIN, 1.0);
+        app.init(&mut board);
+        app.pitch_engine.set_mts(true);
+
+        // notes and SysEx messages come in by turns, at a steady 6 bytes per update which is well within the baud
+        // rate
+        let mut midi_in = Vec::new();
+        for _ in 0..10 {
+            (0..100).for_each(|note| midi_in.extend([0x99, note, 100, 0x89, note, 0]));
+            midi_in.extend([0xF0, 0x43, 0x10, 0x4C]);
+            midi_in.extend([0x01; 120 - 5]);
+            midi_in.push(0xF7);
+        }
+
+        // while sliding up and down the ribbon
+        for (update, bytes) in midi_in.chunks(6).enumerate() {
+            board.serial_rx.extend(bytes);
+            let sweep = (update % 400) as f32 / 200.0;
+            board.set_adc(MAIN_RIBBON_PIN, 0.2 + 0.3 * sweep.min(2.0 - sweep));
+            for _ in 0..(RIBBON_SAMPLE_RATE_HZ / OUTPUT_UPDATE_RATE_HZ) {
+                board.expire_tim2();
+                app.service(&mut board);
+            }
+            board.expire_tim15();
+            app.service(&mut board);
+        }
+
+        let (not_retuned, num_notes) = ribbon_notes_without_retunes(&board, 4);
+        assert!(50 < num_notes, "{}", num_notes);
+        assert_eq!(not_retuned, []);
+    }
+
+    /// `sounding_pitch(b, ch)` is the pitch in fractional MIDI notes of the last note played on channel `ch` in the bytes
+    /// board `b` sent, as tuned with MTS and bent with the default bend range
+    fn sounding_pitch(board: &MockBoard, channel: u8) -> f32 {
+        let mut parser = MidiParser::new();
+        let mut key_pitches: Vec<f32> = (0..128).map(|key| key as f32).collect();
+        let (mut note, mut bend) = (0, 0);
+        for &byte in &board.serial_bytes {
+            match parser.parse(byte) {
+                Some(Received::SysEx(
+                    [0xF0, 0x7F, 0x7F, 0x08, 0x02, 0, 1, key, semitone, msb, lsb, 0xF7],
+                )) => {
+                    key_pitches[*key as usize] =
+                        *semitone as f32 + ((*msb as u32) << 7 | *lsb as u32) as f32 / 16384.0;
+                }
+                Some(Received::Message(MidiMessage::NoteOn(ch, n, _)))
+                    if u8::from(ch) == channel =>
+                {
+                    note = u8::from(n)
+                }
+                Some(Received::Message(MidiMessage::PitchBendChange(ch, b)))
+                    if u8::from(ch) == channel =>
+                {
+                    bend = i16::from(b)
+                }
+                _ => (),
+            }
+        }
+        key_pitches[note as usize] + bend as f32 / 4096.0
+    }
+
+    #[test]
+    fn notes_which_cant_be_retuned_while_the_output_is_busy_are_bent_to_pitch() {
+        // slides across a few notes, then holds still
+        let play = |sysex_len: usize| {
+            let mut board = MockBoard::new();
+            let mut app = App::new();
+            board.midi_ch_switch = 4;
+            board.mode_switch = Switch3wayState::Down;
+            board.set_adc(MOD_RIBBON_PIN, 1.0);
+            app.init(&mut board);
+            app.pitch_engine.set_mts(true);
+            board.expire_tim6();
+            app.service(&mut board);
+
+            // a long SysEx message coming in holds up the notes until it is through
+            let mut midi_in = std::vec![0xF0, 0x43];
+            midi_in.resize(2 + sysex_len, 0x01);
+            midi_in.push(0xF7);
+            let mut midi_in = midi_in.chunks(6);
+
+            for update in 0..600 {
+                board.serial_rx.extend(midi_in.next().unwrap_or_default());
+                let position = 0.3 + 0.1 * (update.min(300) as f32 / 300.0);
+                board.set_adc(MAIN_RIBBON_PIN, position);
+                for _ in 0..(RIBBON_SAMPLE_RATE_HZ / OUTPUT_UPDATE_RATE_HZ) {
+                    board.expire_tim2();
+                    app.service(&mut board);
+                }
+                board.expire_tim15();
+                app.service(&mut board);
+            }
+            board
+        };
+
+        let idle = play(0);
+        let busy = play(1800);
+        assert_eq!(ribbon_notes_without_retunes(&idle, 4).0, []);
+        assert!(!ribbon_notes_without_retunes(&busy, 4).0.is_empty());
+        let (idle_pitch, busy_pitch) = (sounding_pitch(&idle, 4), sounding_pitch(&busy, 4));
+        assert!(
+            (idle_pitch - busy_pitch).abs() < 1E-3,
+            "{} {}",
+            idle_pitch,
+            busy_pitch
+        );
+    }
+
+    #[test]
+    fn settings_can_be_read_and_changed_over_sysex() {
+        let mut board = MockBoard::new();
+        let mut app = App::new();
+        board.midi_ch_switch = 6;
+        app.init(&mut board);
+
+        let range = Param::PitchBendRange;
+        assert_eq!(
+            configure(&mut app, &mut board, Command::Set(range, value(&[12]))),
+            [Reply::Value(range, value(&[12]))]
+        );
+        assert_eq!(
+            configure(&mut app, &mut board, Command::Get(range)),
+            [Reply::Value(range, value(&[12]))]
+        );
+        // the pitch engine clamps the range
+        assert_eq!(
+            configure(&mut app, &mut board, Command::Set(range, value(&[100]))),
+            [Reply::Value(range, value(&[MAX_PITCH_BEND_RANGE]))]
+        );
+        assert_eq!(
+            configure(&mut app, &mut board, Command::Set(Param::Mts, value(&[7]))),
+            [Reply::Error(ConfigError::BadValue)]
+        );
+
+        // the change is saved like a change from the panel
+        board.expire_tim6();
+        app.service(&mut board);
+        assert_eq!(
+            settings::load(&mut board).unwrap().pitch_bend_range,
+            MAX_PITCH_BEND_RANGE
+        );
+
+        // requests for other ribbon controllers are passed on and not answered
+        board.clear_outputs();
+        let for_another = Command::Get(range).sysex(7);
+        board.serial_rx.extend(&for_another);
+        board.expire_tim15();
+        app.service(&mut board);
+        assert_eq!(board.serial_bytes, &for_another[..]);
+    }
+
+    #[test]
+    fn dump_answers_every_param_and_reset_keeps_the_calibrations() {
+        let mut board = MockBoard::new();
+        let mut app = App::new();
+        app.init(&mut board);
+
+        let main_map = value(&[2, 0, 0, 0, 0, 0, 0, 0x80, 0x3F]);
+        configure(
+            &mut app,
+            &mut board,
+            Command::Set(Param::MainRibbonMap, main_map.clone()),
+        );
+        configure(&mut app, &mut board, Command::Set(Param::Span, value(&[3])));
+        let tuning = TuningTable::new(12.0, 60.0, &[0.0, 3.5, 7.0]).unwrap();
+        let table = value(&tuning.to_bytes());
+        configure(
+            &mut app,
+            &mut board,
+            Command::Set(Param::Tuning, table.clone()),
+        );
+
+        let dump = configure(&mut app, &mut board, Command::Dump);
+        assert_eq!(dump.len(), NUM_PARAMS);
+        assert!(dump
+            .iter()
+            .zip(Param::ALL)
+            .all(|(reply, param)| matches!(reply, Reply::Value(p, _) if *p == param)));
+        assert!(dump.contains(&Reply::Value(Param::Span, value(&[3]))));
+        assert_eq!(dump.last(), Some(&Reply::Value(Param::Tuning, table)));
+
+        assert_eq!(
+            configure(&mut app, &mut board, Command::Reset),
+            [Reply::ResetDone]
+        );
+        assert_eq!(
+            configure(&mut app, &mut board, Command::Get(Param::Span)),
+            [Reply::Value(
+                Param::Span,
+                sysex_config::value_of(&Settings::new(), Param::Span)
+            )]
+        );
+        assert_eq!(
+            configure(&mut app, &mut board, Command::Get(Param::MainRibbonMap)),
+            [Reply::Value(Param::MainRibbonMap, main_map)]
+        );
+        assert_eq!(app.pitch_engine.tuning(), None);
+    }
+
+    #[test]
+    fn batched_ribbon_samples_play_the_same_as_polled_ones() {
+        let run = |batched: bool| {
+            let mut board = MockBoard::new();
+            let mut app = App::new();
+            board.set_adc(MOD_RIBBON_PIN, 1.0);
+            app.init(&mut board);
+
+            for step in 0..300 {
+                board.set_adc(MAIN_RIBBON_PIN, 0.2 + step as f32 * 0.001);
+                if batched {
+                    // queued samples are handed over just before the outputs are updated, as by the firmware
+                    let samples = read_all_adc(&mut board);
+                    for _ in 0..3 {
+                        app.poll_ribbons(&samples);
+                    }
+                    app.update_outputs(&mut board);
+                } else {
+                    for _ in 0..3 {
+                        board.expire_tim2();
+                        app.service(&mut board);
+                    }
+                    board.expire_tim15();
+                    app.service(&mut board);
+                }
+            }
+            (board.dac_words, board.gate_writes, board.serial_bytes)
+        };
+
+        assert_eq!(run(true), run(false));
+    }
+
+    #[test]
+    fn saved_settings_are_loaded_at_init() {
+        let mut board = MockBoard::new();
+        let saved = Settings {
+            transpose: Transpose::new(3, 1),
+            ..Settings::new()
+        };
+        settings::save(&mut board, &saved).unwrap();
+
+        let mut app = App::new();
+        app.init(&mut board);
+        assert_eq!(Settings::of(&app.pitch_engine, app.dac_calibration), saved);
+    }
+
+    #[test]
+    fn changed_settings_are_saved_by_the_ui_timer() {
+        let mut board = MockBoard::new();
+        let mut app = App::new();
+        app.init(&mut board);
+        assert_eq!(settings::load(&mut board), None);
+
+        app.pitch_engine.set_transpose(Transpose::new(-2, 0));
+        board.expire_tim6();
+        app.service(&mut board);
+
+        assert_eq!(
+            settings::load(&mut board),
+            Some(Settings::of(&app.pitch_engine, DacCalibration::new()))
+        );
+    }
+
+    /// `tap_main_ribbon(b, a)` presses and releases the main ribbon of board `b` while servicing app `a`
+    fn tap_main_ribbon(board: &mut MockBoard, app: &mut App) {
+        tap_ribbon(board, app, MAIN_RIBBON_PIN, 0.3);
+    }
+
+    /// `tap_ribbon(b, a, p, v)` presses the ribbon on pin `p` of board `b` at raw value `v` and releases it while
+    /// servicing app `a`
+    fn tap_ribbon(board: &mut MockBoard, app: &mut App, pin: AdcPin, raw: f32) {
+        for (val, polls) in [(raw, 100), (1.0, 20)] {
+            board.set_adc(pin, val);
+            for _ in 0..polls {
+                board.expire_tim2();
+                app.service(board);
+            }
+            board.expire_tim15();
+            app.service(board);
+        }
+    }
+
+    #[test]
+    fn a_transpose_tapped_on_the_panel_survives_a_power_cycle() {
+        // the note played by a tap on the main ribbon, after powering up with the flash of board `b`
+        let note_after_power_up = |board: &mut MockBoard| {
+            let mut app = App::new();
+            board.set_adc(MOD_RIBBON_PIN, 1.0);
+            app.init(board);
+            board.clear_outputs();
+            tap_main_ribbon(board, &mut app);
+            board
+                .midi_messages()
+                .into_iter()
+                .find_map(|m| match m {
+                    MidiMessage::NoteOn(_, note, _) => Some(u8::from(note)),
+                    _ => None,
+                })
+                .unwrap()
+        };
+
+        let mut board = MockBoard::new();
+        let plain_note = note_after_power_up(&mut board);
+
+        // hold the MOD ribbon at its top and tap the top zone of the main ribbon, an octave up
+        let mut app = App::new();
+        app.init(&mut board);
+        board.set_adc(MOD_RIBBON_PIN, 0.5);
+        tap_ribbon(&mut board, &mut app, MAIN_RIBBON_PIN, 0.55);
+        assert_eq!(app.pitch_engine.transpose(), Transpose::new(0, 1));
+        board.expire_tim6();
+        app.service(&mut board);
+
+        assert_eq!(note_after_power_up(&mut board), plain_note + 12);
+    }
+
+    #[test]
+    fn a_saved_tuning_is_played_after_power_up() {
+        // every semitone a quarter tone sharp
+        let degrees: Vec<f32> = (0..12).map(|i| i as f32).collect();
+        let saved = Settings {
+            tuning: Some(TuningTable::new(12.0, 60.5, &degrees).unwrap()),
+            ..Settings::new()
+        };
+        let mut board = MockBoard::new();
+        settings::save(&mut board, &saved).unwrap();
+
+        let mut app = App::new();
+        board.mode_switch = Switch3wayState::Up;
+        board.set_adc(MOD_RIBBON_PIN, 1.0);
+        app.init(&mut board);
+        assert_eq!(app.pitch_engine.tuning(), saved.tuning.as_ref());
+
+        board.set_adc(MAIN_RIBBON_PIN, 0.3);
+        for _ in 0..100 {
+            board.expire_tim2();
+            app.service(&mut board);
+            board.expire_tim15();
+            app.service(&mut board);
+        }
+
+        let messages = board.midi_messages();
+        let note = messages
+            .iter()
+            .find_map(|m| match m {
+                MidiMessage::NoteOn(_, note, _) => Some(u8::from(*note)),
+                _ => None,
+            })
+            .unwrap();
+        let bend = messages
+            .iter()
+            .rev()
+            .find_map(|m| match m {
+                MidiMessage::PitchBendChange(_, bend) => Some(i16::from(*bend)),
+                _ => None,
+            })
+            .unwrap();
+        // the default bend range is two semitones
+        let pitch = note as f32 + bend as f32 / 4096.0;
+        assert!(
+            (pitch - (pitch as u32 as f32 + 0.5)).abs() < 1E-3,
+            "{}",
+            pitch
+        );
+    }
+
+    #[test]
+    fn a_tuning_table_can_be_sent_over_sysex() {
+        let mut board = MockBoard::new();
+        let mut app = App::new();
+        app.init(&mut board);
+
+        // the largest table there can be
+        let degrees: Vec<f32> = (0..MAX_TUNING_DEGREES)
+            .map(|i| i as f32 * 12.0 / MAX_TUNING_DEGREES as f32)
+            .collect();
+        let tuning = TuningTable::new(12.0, 60.0, &degrees).unwrap();
+        let table = value(&tuning.to_bytes());
+        assert_eq!(
+            configure(
+                &mut app,
+                &mut board,
+                Command::Set(Param::Tuning, table.clone())
+            ),
+            [Reply::Value(Param::Tuning, table)]
+        );
+        assert_eq!(app.pitch_engine.tuning(), Some(&tuning));
+
+        board.expire_tim6();
+        app.service(&mut board);
+        assert_eq!(settings::load(&mut board).unwrap().tuning, Some(tuning));
+
+        // and 0 goes back to the scale
+        assert_eq!(
+            configure(
+                &mut app,
+                &mut board,
+                Command::Set(Param::Tuning, value(&[0]))
+            ),
+            [Reply::Value(Param::Tuning, value(&[0]))]
+        );
+        assert_eq!(app.pitch_engine.tuning(), None);
+    }
+
+    #[test]
+    fn saved_dac_calibration_is_given_to_the_board() {
+        let mut board = MockBoard::new();
+        let saved = Settings {
+            dac_calibration: DacCalibration {
+                a: ChannelCalibration::from_points(1.1, 4.0),
+                b: ChannelCalibration::new(),
+            },
+            ..Settings::new()
+        };
+        settings::save(&mut board, &saved).unwrap();
+
+        let mut app = App::new();
+        app.init(&mut board);
+        assert!(!app.is_calibrating());
+        assert_eq!(board.dac_calibration, saved.dac_calibration);
+    }
+
+    #[test]
+    fn dac_calibration_procedure_runs_when_the_switches_are_set_at_power_up() {
+        let mut board = MockBoard::new();
+        board.mode_switch = DAC_CALIBRATION_MODE_SWITCH;
+        board.midi_ch_switch = CALIBRATION_MIDI_CH_SWITCH;
+        board.set_adc(MOD_RIBBON_PIN, 1.0);
+        board.set_adc(AdcPin::PA0, 0.5);
+        let mut app = App::new();
+        app.init(&mut board);
+        assert!(app.is_calibrating());
+
+        // the first step outputs the low point on RIBBON CV, with no correction
+        board.set_adc(MAIN_RIBBON_PIN, 1.0);
+        board.expire_tim15();
+        app.service(&mut board);
+        assert_eq!(
+            board.dac_words_for(Dac8162Channel::A),
+            [dac8162_words(LOW_POINT_VOUT, Dac8162Channel::A)]
+        );
+
+        // the MOD CV output needs a little more at the high point
+        for knob in [0.5, 0.5, 0.5, 0.6] {
+            board.set_adc(AdcPin::PA0, knob);
+            board.expire_tim6();
+            app.service(&mut board);
+            tap_main_ribbon(&mut board, &mut app);
+        }
+        assert!(!app.is_calibrating());
+        assert!(board.serial_bytes.is_empty());
+        assert_eq!(board.dac_calibration.a, ChannelCalibration::new());
+        assert!(1.0 < board.dac_calibration.b.gain);
+
+        board.expire_tim6();
+        app.service(&mut board);
+        assert_eq!(
+            settings::load(&mut board).unwrap().dac_calibration,
+            board.dac_calibration
+        );
+    }
+
+    #[test]
+    fn ribbon_calibration_procedure_runs_when_the_switches_are_set_at_power_up() {
+        let mut board = MockBoard::new();
+        board.mode_switch = RIBBON_CALIBRATION_MODE_SWITCH;
+        board.midi_ch_switch = CALIBRATION_MIDI_CH_SWITCH;
+        board.set_adc(MAIN_RIBBON_PIN, 1.0);
+        board.set_adc(MOD_RIBBON_PIN, 1.0);
+        let mut app = App::new();
+        app.init(&mut board);
+        assert!(app.is_calibrating());
+
+        // the first step is the bottom of the main ribbon
+        board.expire_tim15();
+        app.service(&mut board);
+        assert_eq!(
+            board.dac_words_for(Dac8162Channel::A),
+            [dac8162_words(0.0, Dac8162Channel::A)]
+        );
+
+        for raw in [0.02, 0.15, 0.3, 0.45, 0.6] {
+            tap_ribbon(&mut board, &mut app, MAIN_RIBBON_PIN, raw);
+        }
+        for raw in [0.02, 0.25, 0.45] {
+            tap_ribbon(&mut board, &mut app, MOD_RIBBON_PIN, raw);
+        }
+        assert!(!app.is_calibrating());
+        assert!(board.serial_bytes.is_empty());
+        let calibration = app.pitch_engine.ribbon_calibration();
+        assert_eq!(calibration.main_ribbon.readings().len(), 5);
+        assert_eq!(calibration.mod_ribbon.readings().len(), 3);
+
+        board.expire_tim6();
+        app.service(&mut board);
+        assert_eq!(
+            settings::load(&mut board).unwrap().ribbon_calibration,
+            calibration
+        );
+    }
+
+    #[test]
+    fn auto_calibration_corrects_ribbon_cv_and_reports_over_midi() {
+        let mut board = MockBoard::new();
+        board.mode_switch = AUTO_CALIBRATION_MODE_SWITCH;
+        board.midi_ch_switch = CALIBRATION_MIDI_CH_SWITCH;
+        let mut app = App::new();
+        app.init(&mut board);
+        assert!(app.is_calibrating());
+
+        // a loopback cable from an output which is 1% high
+        while app.is_calibrating() {
+            board.expire_tim15();
+            app.service(&mut board);
+            if let Some(&[_, mid, low]) = board.dac_words_for(Dac8162Channel::A).last() {
+                let counts = (((mid as u16) << 8) | low as u16) >> 2;
+                let v_out = counts as f32 * DAC8162_MAX_VOUT / DAC8162_MAX_COUNT as f32 * 1.01;
+                board.set_adc(LOOPBACK_PIN, v_out * LOOPBACK_DIVIDER / ADC_VREF_VOLTS);
+            }
+            for _ in 0..4 {
+                board.expire_tim2();
+                app.service(&mut board);
+            }
+        }
+
+        assert!((board.dac_calibration.a.gain * 1.01 - 1.0).abs() < 1E-3);
+        assert_eq!(board.serial_bytes[..4], [0xF0, 0x7D, 0x01, 1]);
+        assert_eq!(board.serial_bytes.last(), Some(&0xF7));
+    }
+}
@@ -0,0 +1,315 @@
+//! # 1 volt/octave auto-calibration
+//!
+//! The `RIBBON CV` output can calibrate itself by measuring its own output with the ADC, no multimeter needed.
+//!
+//! The glide knob input on `LOOPBACK_PIN` is repurposed for this. Disconnect the glide knob wiper and connect `RIBBON
+//! CV` to the input through a divider of two equal resistors, so that the 5 volt output fits in the range of the ADC.
+//! The result is only as good as the ADC reference and the divider, so use 0.1% resistors.
+//!
+//! Power up the ribbon controller with the `MODE` switch set to `ASSIST` and the `MIDI CH` switch set to 16 to start
+//! the calibration. The DAC is swept through `NUM_POINTS` voltages from `LOWEST_POINT_VOUT` to `HIGHEST_POINT_VOUT`, and
+//! each one is measured once the output has settled. A straight line is fitted through the measurements, which gives
+//! the gain and offset correction for the `RIBBON CV` output.
+//!
+//! The correction is only kept if it is plausible and the measurements are close to the line. Either way the result is
+//! reported over MIDI with a SysEx message, see `CalibrationReport`, and then the ribbon controller starts playing.
+
+use crate::{board::ADC_VREF_VOLTS, dac_calibration::ChannelCalibration};
+
+/// The automatic calibration of the `RIBBON CV` output is represented here, see the module docs
+pub struct AutoCalibration {
+    // the index of the voltage being measured
+    point: usize,
+    // the number of output updates left to wait before measuring the current voltage
+    settle_ticks: u32,
+    // the sum and number of loopback samples of the current voltage so far
+    sum: f32,
+    count: u32,
+    // the voltage measured at each point so far
+    measured: [f32; NUM_POINTS],
+}
+
+impl AutoCalibration {
+    /// `AutoCalibration::new()` is a new auto-calibration at the lowest voltage
+    pub fn new() -> Self {
+        Self {
+            point: 0,
+            settle_ticks: SETTLE_TICKS,
+            sum: 0.0_f32,
+            count: 0,
+            measured: [0.0_f32; NUM_POINTS],
+        }
+    }
+
+    /// `ac.poll(s)` feeds the loopback sample `s` in `[0.0, 1.0]` to the calibration
+    ///
+    /// Must be called periodically at `RIBBON_SAMPLE_RATE_HZ`.
+    pub fn poll(&mut self, loopback_sample: f32) {
+        if self.settle_ticks == 0 && self.count < SAMPLES_PER_POINT {
+            self.sum += loopback_sample;
+            self.count += 1;
+        }
+    }
+
+    /// `ac.update()` moves on to the next voltage once the current one has been measured
+    ///
+    /// Must be called periodically at `OUTPUT_UPDATE_RATE_HZ`.
+    pub fn update(&mut self) {
+        if NUM_POINTS <= self.point {
+            return;
+        }
+        if 0 < self.settle_ticks {
+            self.settle_ticks -= 1;
+            return;
+        }
+        if self.count == SAMPLES_PER_POINT {
+            let average = self.sum / self.count as f32;
+            self.measured[self.point] = average * ADC_VREF_VOLTS / LOOPBACK_DIVIDER;
+
+            self.point += 1;
+            self.settle_ticks = SETTLE_TICKS;
+            self.sum = 0.0_f32;
+            self.count = 0;
+        }
+    }
+
+    /// `ac.output()` is the uncorrected voltage to write to `RIBBON CV`, or `None` if the calibration is done
+    pub fn output(&self) -> Option<f32> {
+        (self.point < NUM_POINTS).then(|| point_vout(self.point))
+    }
+
+    /// `ac.result()` is the outcome of the calibration, or `None` if it is not done yet
+    pub fn result(&self) -> Option<CalibrationReport> {
+        if self.point < NUM_POINTS {
+            return None;
+        }
+
+        let requested: [f32; NUM_POINTS] = core::array::from_fn(point_vout);
+        let (slope, intercept) = fit_line(&requested, &self.measured);
+
+        // the output is `slope * v + intercept` for `v` volts written, so write `(v - intercept) / slope` to get `v`
+        let calibration = ChannelCalibration {
+            gain: 1.0_f32 / slope,
+            offset: -intercept / slope,
+        };
+        let max_error_cents = requested
+            .iter()
+            .zip(&self.measured)
+            .map(|(&r, &m)| (m - (slope * r + intercept)).abs() * CENTS_PER_VOLT)
+            .fold(0.0_f32, f32::max);
+
+        Some(CalibrationReport {
+            accepted: calibration.is_plausible() && max_error_cents <= MAX_ERROR_CENTS,
+            calibration,
+            max_error_cents,
+        })
+    }
+}
+
+impl Default for AutoCalibration {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// The outcome of an auto-calibration is represented here
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct CalibrationReport {
+    /// True iff the correction is good enough to be used
+    pub accepted: bool,
+    /// The correction for the `RIBBON CV` output
+    pub calibration: ChannelCalibration,
+    /// The furthest any measurement was from the fitted line, in cents
+    pub max_error_cents: f32,
+}
+
+impl CalibrationReport {
+    /// `cr.sysex()` is calibration report `cr` as a SysEx message
+    ///
+    /// ```text
+    /// F0 7D 01 <accepted> <gain> <offset> <error> F7
+    /// ```
+    ///
+    /// The manufacturer ID 7D is for non-commercial use. Accepted is 1 if the correction is used and 0 if not. The other
+    /// fields are three 7 bit bytes each, most significant first, holding a 21 bit number with `REPORT_FIELD_ZERO` added
+    /// so that it is never negative:
+    ///
+    /// * the gain error in parts per million, i.e. `(gain - 1) * 1,000,000`
+    ///
+    /// * the offset in microvolts
+    ///
+    /// * the furthest measurement from the fitted line in 1/100 of a cent
+    pub fn sysex(&self) -> [u8; CALIBRATION_REPORT_LEN] {
+        let gain_ppm = report_field((self.calibration.gain - 1.0_f32) * 1E6);
+        let offset_uv = report_field(self.calibration.offset * 1E6);
+        let error = report_field(self.max_error_cents * 100.0_f32);
+
+        [
+            SYSEX_START,
+            NON_COMMERCIAL_ID,
+            CALIBRATION_REPORT_ID,
+            self.accepted as u8,
+            gain_ppm[0],
+            gain_ppm[1],
+            gain_ppm[2],
+            offset_uv[0],
+            offset_uv[1],
+            offset_uv[2],
+            error[0],
+            error[1],
+            error[2],
+            SYSEX_END,
+        ]
+    }
+}
+
+/// `point_vout(i)` is the voltage written for calibration point `i`
+fn point_vout(point: usize) -> f32 {
+    LOWEST_POINT_VOUT
+        + (HIGHEST_POINT_VOUT - LOWEST_POINT_VOUT) * point as f32 / (NUM_POINTS - 1) as f32
+}
+
+/// `fit_line(xs, ys)` is the slope and intercept of the least squares line through the points `(xs[i], ys[i])`
+fn fit_line(xs: &[f32], ys: &[f32]) -> (f32, f32) {
+    let n = xs.len() as f32;
+    let mean_x = xs.iter().sum::<f32>() / n;
+    let mean_y = ys.iter().sum::<f32>() / n;
+
+    let (covariance, variance) =
+        xs.iter()
+            .zip(ys)
+            .fold((0.0_f32, 0.0_f32), |(cov, var), (&x, &y)| {
+                (
+                    cov + (x - mean_x) * (y - mean_y),
+                    var + (x - mean_x) * (x - mean_x),
+                )
+            });
+
+    let slope = covariance / variance;
+    (slope, mean_y - slope * mean_x)
+}
+
+/// `report_field(v)` is value `v` rounded and offset by `REPORT_FIELD_ZERO` as three 7 bit bytes, most significant first
+fn report_field(val: f32) -> [u8; 3] {
+    let max = (REPORT_FIELD_ZERO * 2 - 1) as f32;
+    let field = (val + REPORT_FIELD_ZERO as f32 + 0.5_f32).clamp(0.0_f32, max) as u32;
+    [
+        (field >> 14) as u8 & 0x7F,
+        (field >> 7) as u8 & 0x7F,
+        field as u8 & 0x7F,
+    ]
+}
+
+/// The lowest voltage measured
+pub const LOWEST_POINT_VOUT: f32 = 0.5_f32;
+
+/// The highest voltage measured
+pub const HIGHEST_POINT_VOUT: f32 = 4.5_f32;
+
+/// The number of voltages measured
+pub const NUM_POINTS: usize = 9;
+
+/// The fraction of the `RIBBON CV` voltage which reaches the loopback input through the divider
+pub const LOOPBACK_DIVIDER: f32 = 0.5_f32;
+
+/// The furthest a measurement may be from the fitted line for the correction to be used, in cents
+pub const MAX_ERROR_CENTS: f32 = 5.0_f32;
+
+/// The number of bytes in a calibration report message
+pub const CALIBRATION_REPORT_LEN: usize = 14;
+
+/// The number added to each field of a calibration report so that it is never negative
+pub const REPORT_FIELD_ZERO: u32 = 1 << 20;
+
+// about 100ms for the output and the loopback input to settle
+const SETTLE_TICKS: u32 = 30;
+
+// the loopback input is averaged over this many samples, about 64ms
+const SAMPLES_PER_POINT: u32 = 64;
+
+const CENTS_PER_VOLT: f32 = 1_200.0_f32;
+
+const SYSEX_START: u8 = 0xF0;
+const SYSEX_END: u8 = 0xF7;
+const NON_COMMERCIAL_ID: u8 = 0x7D;
+const CALIBRATION_REPORT_ID: u8 = 0x01;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// `run(ac, f)` runs auto-calibration `ac` to the end, with the loopback input reading `f(v)` when `v` volts are
+    /// written
+    fn run(calibration: &mut AutoCalibration, loopback: impl Fn(f32) -> f32) {
+        while let Some(v_out) = calibration.output() {
+            let sample = loopback(v_out) * LOOPBACK_DIVIDER / ADC_VREF_VOLTS;
+            for _ in 0..4 {
+                calibration.poll(sample);
+            }
+            calibration.update();
+        }
+    }
+
+    #[test]
+    fn a_perfect_output_needs_no_correction() {
+        let mut auto = AutoCalibration::new();
+        assert_eq!(auto.result(), None);
+        run(&mut auto, |v| v);
+
+        let report = auto.result().unwrap();
+        assert!(report.accepted);
+        assert!((report.calibration.gain - 1.0).abs() < 1E-4);
+        assert!(report.calibration.offset.abs() < 1E-4);
+        assert!(report.max_error_cents < 0.1);
+    }
+
+    #[test]
+    fn gain_and_offset_errors_are_corrected() {
+        let mut auto = AutoCalibration::new();
+        let output = |v: f32| v * 1.02 + 0.01;
+        run(&mut auto, output);
+
+        let report = auto.result().unwrap();
+        assert!(report.accepted);
+        for v in [1.0, 2.0, 3.0] {
+            assert!((output(report.calibration.apply(v)) - v).abs() < 1E-4);
+        }
+    }
+
+    #[test]
+    fn a_missing_loopback_cable_is_rejected() {
+        let mut auto = AutoCalibration::new();
+        run(&mut auto, |_| 0.0);
+        assert!(!auto.result().unwrap().accepted);
+    }
+
+    #[test]
+    fn a_crooked_output_is_rejected() {
+        let mut auto = AutoCalibration::new();
+        run(&mut auto, |v| v + 0.02 * (v * 3.0).sin());
+
+        let report = auto.result().unwrap();
+        assert!(MAX_ERROR_CENTS < report.max_error_cents);
+        assert!(!report.accepted);
+    }
+
+    #[test]
+    fn report_fields_are_offset_seven_bit_numbers() {
+        let report = CalibrationReport {
+            accepted: true,
+            calibration: ChannelCalibration {
+                gain: 1.0,
+                offset: -0.000_001,
+            },
+            max_error_cents: 0.5,
+        };
+        let sysex = report.sysex();
+
+        assert_eq!(sysex[..4], [0xF0, 0x7D, 0x01, 1]);
+        assert_eq!(sysex[4..7], [0x40, 0x00, 0x00]);
+        assert_eq!(sysex[7..10], [0x3F, 0x7F, 0x7F]);
+        assert_eq!(sysex[10..13], [0x40, 0x00, 50]);
+        assert_eq!(sysex[13], 0xF7);
+        assert!(sysex[1..13].iter().all(|&b| b < 0x80));
+    }
+}
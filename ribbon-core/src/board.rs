@@ -0,0 +1,256 @@
+//! # Board capabilities
+//!
+//! The capabilities of the physical board are represented here as traits, so that the logic which uses them does not
+//! need to know which board it is running on. The firmware implements these traits for the real STM32L412 board, and
+//! `MockBoard` implements them for host-side tests.
+
+use crate::dac_calibration::DacCalibration;
+
+/// Analog inputs which may be read by the ADC
+pub trait AdcInputs {
+    /// `board.read_adc(p)` is the digitized analog value on pin `p` in the range `[0.0, +1.0]`
+    fn read_adc(&mut self, pin: AdcPin) -> f32;
+}
+
+/// The onboard DAC8162 digital to analog converter
+pub trait DacOutputs {
+    /// `board.dac8162_set_vout(v, c)` writes the voltage `v` to channel `c` of the onboard DAC.
+    ///
+    /// # Arguments
+    ///
+    /// * `v_out` - The analog voltage to write, clamped to `[0.0, DAC8162_MAX_VOUT]`
+    ///
+    /// * `channel` - The enumerated DAC channel to write to
+    ///
+    /// The voltage is corrected by the DAC calibration before it is written.
+    fn dac8162_set_vout(&mut self, v_out: f32, channel: Dac8162Channel);
+
+    /// `board.set_dac_calibration(c)` sets the correction applied to every voltage written to the DAC to calibration `c`
+    fn set_dac_calibration(&mut self, calibration: DacCalibration);
+}
+
+/// The digital gate output
+pub trait GateOutput {
+    /// `board.set_gate(val)` sets the state of the gate output to `val`.
+    fn set_gate(&mut self, val: bool);
+}
+
+/// The panel mounted switches
+pub trait PanelSwitches {
+    /// `board.read_mode_switch()` is the enumerated state of the 3-way mode switch.
+    fn read_mode_switch(&self) -> Switch3wayState;
+
+    /// `board.read_midi_ch_switch()` is the value of the rotary MIDI channel switch in `[0..15]`
+    fn read_midi_ch_switch(&self) -> u8;
+}
+
+/// The serial port used for MIDI
+///
+/// Writing never waits for the serial port, the bytes are queued and sent in the background.
+pub trait SerialOutput {
+    /// `board.serial_tx_free()` is the number of bytes which can be queued to be sent right now
+    fn serial_tx_free(&self) -> usize;
+
+    /// `board.serial_write_all(bs)` queues all bytes `bs` to be sent via the serial port
+    ///
+    /// Requires that there are no more bytes than `serial_tx_free()`, otherwise none of them are sent.
+    fn serial_write_all(&mut self, bytes: &[u8]);
+}
+
+/// The serial port used for MIDI input
+///
+/// Bytes are received in the background, and wait until they are read.
+pub trait SerialInput {
+    /// `board.serial_read(bs)` moves the oldest bytes received into `bs`, and is the number of bytes moved
+    fn serial_read(&mut self, bytes: &mut [u8]) -> usize;
+}
+
+/// The periodic timers which pace the main loop
+pub trait PeriodicTimers {
+    /// `board.get_tim2_timeout()` is true iff the ribbon sampling timer has timed out, self clearing.
+    fn get_tim2_timeout(&self) -> bool;
+
+    /// `board.get_tim6_timeout()` is true iff the UI timer has timed out, self clearing.
+    fn get_tim6_timeout(&self) -> bool;
+
+    /// `board.get_tim15_timeout()` is true iff the output update timer has timed out, self clearing.
+    fn get_tim15_timeout(&self) -> bool;
+}
+
+/// The pages of internal flash memory reserved for storing settings, `SETTINGS_NUM_PAGES` of them
+///
+/// Flash is erased a whole page at a time, which sets every byte to `FLASH_ERASED`, and programmed a word of
+/// `FLASH_WORD_LEN` bytes at a time. A word may only be programmed once between erases.
+pub trait SettingsFlash {
+    /// `board.settings_read(p, o, bs)` fills `bs` with the bytes of settings page `p` starting at offset `o`
+    fn settings_read(&mut self, page: usize, offset: usize, bytes: &mut [u8]);
+
+    /// `board.settings_erase(p)` erases the whole of settings page `p`
+    fn settings_erase(&mut self, page: usize) -> Result<(), FlashError>;
+
+    /// `board.settings_write(p, o, bs)` programs bytes `bs` into settings page `p` starting at offset `o`
+    ///
+    /// Both `o` and the length of `bs` must be multiples of `FLASH_WORD_LEN`, and the bytes must have been erased.
+    fn settings_write(
+        &mut self,
+        page: usize,
+        offset: usize,
+        bytes: &[u8],
+    ) -> Result<(), FlashError>;
+}
+
+/// The ways writing to flash can fail are represented here
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum FlashError {
+    /// There is no such settings page, or the bytes don't fit in it
+    OutOfBounds,
+    /// The offset or length is not a whole number of flash words
+    Misaligned,
+    /// The bytes were already programmed since the last erase
+    NotErased,
+    /// The flash controller reported an error
+    Failed,
+}
+
+/// Every capability of the board, implemented for anything which implements all of the individual capabilities
+pub trait BoardIo:
+    AdcInputs
+    + DacOutputs
+    + GateOutput
+    + PanelSwitches
+    + SerialOutput
+    + SerialInput
+    + PeriodicTimers
+    + SettingsFlash
+{
+}
+
+impl<B> BoardIo for B where
+    B: AdcInputs
+        + DacOutputs
+        + GateOutput
+        + PanelSwitches
+        + SerialOutput
+        + SerialInput
+        + PeriodicTimers
+        + SettingsFlash
+{
+}
+
+/// `dac8162_words(v, c)` is the 3 byte SPI command which writes voltage `v` to channel `c` of the DAC8162.
+///
+/// # Arguments
+///
+/// * `v_out` - The analog voltage to write, clamped to `[0.0, DAC8162_MAX_VOUT]`
+///
+/// * `channel` - The enumerated DAC channel to write to
+pub fn dac8162_words(v_out: f32, channel: Dac8162Channel) -> [u8; 3] {
+    let v_out = v_out.clamp(0.0_f32, DAC8162_MAX_VOUT);
+
+    let val_u14 = (v_out * DAC8162_COUNTS_PER_VOLT) as u16;
+    // move the value out of DB0 and DB1
+    let val_u14 = val_u14 << 2;
+    // split it into bytes
+    let low_byte = (val_u14 & 0xFF) as u8;
+    let mid_byte = (val_u14 >> 8) as u8;
+    let high_byte = channel as u8 | 0b0001_1000; // write to channel and update output
+
+    [high_byte, mid_byte, low_byte]
+}
+
+/// `adc_fs_to_normalized_fl(v)` is the integer adc value normalized to [0.0, +1.0]
+///
+/// If the input value would overflow the output range it is clamped.
+pub fn adc_fs_to_normalized_fl(val: u16) -> f32 {
+    let val = val.min(ADC_MAX); // don't need to clamp negative values, it's already unsigned
+
+    (val as f32) / (ADC_MAX as f32)
+}
+
+/// `read_all_adc(b)` is the reading of every ADC input of board `b` in [0.0, +1.0], indexed by `AdcPin`
+pub fn read_all_adc<B: AdcInputs>(board: &mut B) -> [f32; NUM_ADC_PINS] {
+    [AdcPin::PA0, AdcPin::PA1, AdcPin::PA2].map(|pin| board.read_adc(pin))
+}
+
+/// The maximum value that can be produced by the Analog to Digital Converters.
+pub const ADC_MAX: u16 = 0xFFF0;
+
+/// The voltage at the top of the range of the Analog to Digital Converters.
+pub const ADC_VREF_VOLTS: f32 = 3.3_f32;
+
+/// The maximum value that can be written to the onboard Digital to Analog Converter.
+pub const DAC8162_MAX_COUNT: u16 = (1 << 14) - 1;
+
+/// The maximum analog voltage that the DAC can produce after onboard amplification
+pub const DAC8162_MAX_VOUT: f32 = 5.0_f32;
+
+/// The number of DAC counts for 1 volt output
+const DAC8162_COUNTS_PER_VOLT: f32 = DAC8162_MAX_COUNT as f32 / DAC8162_MAX_VOUT;
+
+/// The size of a settings page in bytes, one page of the STM32L412 flash
+pub const SETTINGS_PAGE_LEN: usize = 2048;
+
+/// The number of flash pages reserved for settings, so one can be erased while the other still holds the settings
+pub const SETTINGS_NUM_PAGES: usize = 2;
+
+/// The number of bytes programmed into flash at once, a double word on the STM32L412
+pub const FLASH_WORD_LEN: usize = 8;
+
+/// The value of every byte of flash after it is erased
+pub const FLASH_ERASED: u8 = 0xFF;
+
+/// Pins which may be read by the ADC are represented here
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum AdcPin {
+    PA0 = 0,
+    PA1 = 1,
+    PA2 = 2,
+}
+
+/// The number of pins which may be read by the ADC
+pub const NUM_ADC_PINS: usize = 3;
+
+/// Channels of the onboard DAC are represented here
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Dac8162Channel {
+    A = 0b000,
+    B = 0b001,
+}
+
+/// Valid states of a 3-way switch are represented here
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Switch3wayState {
+    Up,
+    Middle,
+    Down,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn zero_volts_is_all_zero_counts() {
+        assert_eq!(dac8162_words(0.0, Dac8162Channel::A), [0b0001_1000, 0, 0]);
+    }
+
+    #[test]
+    fn max_volts_is_full_scale_shifted_out_of_the_low_bits() {
+        assert_eq!(
+            dac8162_words(DAC8162_MAX_VOUT, Dac8162Channel::B),
+            [0b0001_1001, 0xFF, 0xFC]
+        );
+    }
+
+    #[test]
+    fn out_of_range_volts_are_clamped() {
+        assert_eq!(
+            dac8162_words(-1.0, Dac8162Channel::A),
+            dac8162_words(0.0, Dac8162Channel::A)
+        );
+        assert_eq!(
+            dac8162_words(100.0, Dac8162Channel::A),
+            dac8162_words(DAC8162_MAX_VOUT, Dac8162Channel::A)
+        );
+    }
+}
@@ -0,0 +1,226 @@
+//! # Byte ring
+//!
+//! A lock-free ring buffer of bytes, for handing bytes from one execution context to another without either of them
+//! waiting, e.g. from the task which renders MIDI messages to the interrupt which feeds them to the serial port's DMA.
+//!
+//! The ring is split into a `ByteProducer` which writes bytes and a `ByteConsumer` which reads them. Each half may be
+//! used from a different context, but there must only ever be one of each.
+//!
+//! The consumer reads the queued bytes in place, as a slice of the ring's own storage, so a DMA transfer can be started
+//! straight from the ring without copying the bytes anywhere else first.
+
+use core::{
+    cell::UnsafeCell,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+/// A ring of at most `N` bytes is represented here, `N` must be a power of 2
+pub struct ByteRing<const N: usize> {
+    buffer: UnsafeCell<[u8; N]>,
+    // the total number of bytes ever written and read, wrapping, the difference is the number of bytes in the ring
+    written: AtomicUsize,
+    read: AtomicUsize,
+}
+
+// the producer only writes the free part of the buffer and the consumer only reads the queued part, and the counters
+// which divide the two parts are atomic
+unsafe impl<const N: usize> Sync for ByteRing<N> {}
+
+impl<const N: usize> ByteRing<N> {
+    /// `ByteRing::new()` is a new empty ring
+    pub const fn new() -> Self {
+        // the counters wrap around, which only keeps the index in the buffer right if `N` divides the wrap
+        assert!(N.is_power_of_two());
+        Self {
+            buffer: UnsafeCell::new([0; N]),
+            written: AtomicUsize::new(0),
+            read: AtomicUsize::new(0),
+        }
+    }
+
+    /// `br.split()` is the producer and the consumer halves of ring `br`
+    pub fn split(&mut self) -> (ByteProducer<'_, N>, ByteConsumer<'_, N>) {
+        (ByteProducer { ring: self }, ByteConsumer { ring: self })
+    }
+
+    /// `br.len()` is the number of bytes in ring `br`
+    fn len(&self) -> usize {
+        let written = self.written.load(Ordering::Acquire);
+        let read = self.read.load(Ordering::Acquire);
+        written.wrapping_sub(read)
+    }
+}
+
+impl<const N: usize> Default for ByteRing<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// The writing half of a byte ring is represented here
+pub struct ByteProducer<'a, const N: usize> {
+    ring: &'a ByteRing<N>,
+}
+
+impl<const N: usize> ByteProducer<'_, N> {
+    /// `bp.free_len()` is the number of bytes which can be written to the ring right now
+    pub fn free_len(&self) -> usize {
+        N - self.ring.len()
+    }
+
+    /// `bp.write_all(bs)` writes all of the bytes `bs` to the ring and is true, or writes nothing and is false if there
+    /// is not room for all of them
+    pub fn write_all(&mut self, bytes: &[u8]) -> bool {
+        if self.free_len() < bytes.len() {
+            return false;
+        }
+
+        let written = self.ring.written.load(Ordering::Relaxed);
+        let buffer = self.ring.buffer.get() as *mut u8;
+        for (i, &byte) in bytes.iter().enumerate() {
+            let index = written.wrapping_add(i) % N;
+            // the index is in the free part of the buffer, which the consumer doesn't touch
+            unsafe { buffer.add(index).write(byte) };
+        }
+        // publish the bytes only once they are in the buffer
+        self.ring
+            .written
+            .store(written.wrapping_add(bytes.len()), Ordering::Release);
+        true
+    }
+}
+
+/// The reading half of a byte ring is represented here
+pub struct ByteConsumer<'a, const N: usize> {
+    ring: &'a ByteRing<N>,
+}
+
+impl<const N: usize> ByteConsumer<'_, N> {
+    /// `bc.len()` is the number of bytes waiting to be read from the ring
+    pub fn len(&self) -> usize {
+        self.ring.len()
+    }
+
+    /// `bc.is_empty()` is true iff there are no bytes waiting to be read from the ring
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// `bc.contiguous()` is the oldest bytes waiting to be read, up to the end of the ring's storage
+    ///
+    /// The bytes stay in the ring until they are consumed, so this is the same slice each time until `consume` is
+    /// called. The rest of the bytes, if any, follow from the start of the ring's storage.
+    pub fn contiguous(&self) -> &[u8] {
+        let read = self.ring.read.load(Ordering::Relaxed);
+        let start = read % N;
+        let len = self.len().min(N - start);
+        let buffer = self.ring.buffer.get() as *const u8;
+        // the slice is in the queued part of the buffer, which the producer doesn't touch until it is consumed
+        unsafe { core::slice::from_raw_parts(buffer.add(start), len) }
+    }
+
+    /// `bc.consume(n)` removes the oldest `n` bytes from the ring, or all of them if there are fewer than `n`
+    pub fn consume(&mut self, num_bytes: usize) {
+        let read = self.ring.read.load(Ordering::Relaxed);
+        let num_bytes = num_bytes.min(self.len());
+        // release the bytes only once they are done with
+        self.ring
+            .read
+            .store(read.wrapping_add(num_bytes), Ordering::Release);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::vec::Vec;
+
+    /// `read_all(bc)` is all of the bytes waiting in the ring of consumer `bc`, which are consumed
+    fn read_all<const N: usize>(consumer: &mut ByteConsumer<N>) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        while !consumer.is_empty() {
+            let chunk = consumer.contiguous();
+            bytes.extend_from_slice(chunk);
+            let len = chunk.len();
+            consumer.consume(len);
+        }
+        bytes
+    }
+
+    #[test]
+    fn bytes_come_out_in_order() {
+        let mut ring = ByteRing::<8>::new();
+        let (mut producer, mut consumer) = ring.split();
+
+        assert!(producer.write_all(&[1, 2, 3]));
+        assert!(producer.write_all(&[4]));
+        assert_eq!(consumer.len(), 4);
+        assert_eq!(producer.free_len(), 4);
+        assert_eq!(read_all(&mut consumer), [1, 2, 3, 4]);
+        assert_eq!(producer.free_len(), 8);
+    }
+
+    #[test]
+    fn writes_which_do_not_fit_write_nothing() {
+        let mut ring = ByteRing::<4>::new();
+        let (mut producer, mut consumer) = ring.split();
+
+        assert!(producer.write_all(&[1, 2, 3]));
+        assert!(!producer.write_all(&[4, 5]));
+        assert!(producer.write_all(&[4]));
+        assert!(!producer.write_all(&[5]));
+        assert_eq!(read_all(&mut consumer), [1, 2, 3, 4]);
+    }
+
+    #[test]
+    fn queued_bytes_wrap_around_the_end_of_the_storage() {
+        let mut ring = ByteRing::<4>::new();
+        let (mut producer, mut consumer) = ring.split();
+
+        producer.write_all(&[1, 2, 3]);
+        consumer.consume(2);
+        producer.write_all(&[4, 5, 6]);
+
+        // the contiguous part stops at the end of the storage
+        assert_eq!(consumer.contiguous(), [3, 4]);
+        consumer.consume(1);
+        assert_eq!(consumer.contiguous(), [4]);
+        assert_eq!(read_all(&mut consumer), [4, 5, 6]);
+    }
+
+    #[test]
+    fn consuming_more_than_is_queued_empties_the_ring() {
+        let mut ring = ByteRing::<4>::new();
+        let (mut producer, mut consumer) = ring.split();
+
+        producer.write_all(&[1, 2]);
+        consumer.consume(10);
+        assert!(consumer.is_empty());
+        assert_eq!(producer.free_len(), 4);
+    }
+
+    #[test]
+    fn producer_and_consumer_can_run_at_the_same_time() {
+        let mut ring = ByteRing::<16>::new();
+        let (mut producer, mut consumer) = ring.split();
+        let sent: Vec<u8> = (0..10_000_u32).map(|i| (i % 251) as u8).collect();
+
+        let received = std::thread::scope(|s| {
+            s.spawn(|| {
+                for chunk in sent.chunks(3) {
+                    while !producer.write_all(chunk) {
+                        std::thread::yield_now();
+                    }
+                }
+            });
+
+            let mut received = Vec::new();
+            while received.len() < sent.len() {
+                received.extend(read_all(&mut consumer));
+            }
+            received
+        });
+
+        assert_eq!(received, sent);
+    }
+}
@@ -0,0 +1,273 @@
+//! # DAC calibration
+//!
+//! The gain of the DAC and the output amplifiers is never exactly right, and the outputs are a few millivolts off at 0
+//! volts. Each DAC channel is corrected in software with a gain and an offset, applied by the board every time a
+//! voltage is written to the DAC.
+//!
+//! The corrections are found with a multimeter and a guided calibration procedure. Power up the ribbon controller with
+//! the `MODE` switch set to `QUANTIZE` and the `MIDI CH` switch set to 16 to start the procedure. Then for each step:
+//!
+//! * measure the output named in the table below
+//!
+//! * turn the glide knob until the meter reads the target voltage, the knob trims the output by up to `TRIM_RANGE`
+//!   volts either way
+//!
+//! * tap the main ribbon to move on to the next step
+//!
+//! ```text
+//! | step | output     | target          |
+//! | 1    | RIBBON CV  | LOW_POINT_VOUT  |
+//! | 2    | RIBBON CV  | HIGH_POINT_VOUT |
+//! | 3    | MOD CV     | LOW_POINT_VOUT  |
+//! | 4    | MOD CV     | HIGH_POINT_VOUT |
+//! ```
+//!
+//! After the last step the corrections are saved with the other settings and the ribbon controller starts playing.
+
+use crate::board::Dac8162Channel;
+
+/// The correction for a single DAC channel is represented here
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ChannelCalibration {
+    /// The uncorrected volts written for each volt requested
+    pub gain: f32,
+    /// The uncorrected volts written when 0 volts is requested
+    pub offset: f32,
+}
+
+impl ChannelCalibration {
+    /// `ChannelCalibration::new()` is a correction which changes nothing
+    pub const fn new() -> Self {
+        Self {
+            gain: 1.0_f32,
+            offset: 0.0_f32,
+        }
+    }
+
+    /// `ChannelCalibration::from_points(l, h)` is the correction for a channel which outputs `LOW_POINT_VOUT` when `l`
+    /// volts are written and `HIGH_POINT_VOUT` when `h` volts are written
+    pub fn from_points(low: f32, high: f32) -> Self {
+        let gain = (high - low) / (HIGH_POINT_VOUT - LOW_POINT_VOUT);
+        Self {
+            gain,
+            offset: low - gain * LOW_POINT_VOUT,
+        }
+    }
+
+    /// `cc.apply(v)` is the uncorrected voltage to write to the DAC for it to output `v` volts
+    pub fn apply(&self, v_out: f32) -> f32 {
+        v_out * self.gain + self.offset
+    }
+
+    /// `cc.is_plausible()` is true iff correction `cc` is close enough to no correction to be believed
+    ///
+    /// Anything further off than this means a mistake during calibration or a broken board.
+    pub fn is_plausible(&self) -> bool {
+        (1.0_f32 - self.gain).abs() <= MAX_GAIN_ERROR && self.offset.abs() <= MAX_OFFSET_ERROR
+    }
+}
+
+impl Default for ChannelCalibration {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// The corrections for both DAC channels are represented here
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct DacCalibration {
+    /// The correction for channel A, the `RIBBON CV` output
+    pub a: ChannelCalibration,
+    /// The correction for channel B, the `MOD CV` output
+    pub b: ChannelCalibration,
+}
+
+impl DacCalibration {
+    /// `DacCalibration::new()` is a calibration which corrects nothing
+    pub const fn new() -> Self {
+        Self {
+            a: ChannelCalibration::new(),
+            b: ChannelCalibration::new(),
+        }
+    }
+
+    /// `dc.apply(v, c)` is the uncorrected voltage to write to DAC channel `c` for it to output `v` volts
+    pub fn apply(&self, v_out: f32, channel: Dac8162Channel) -> f32 {
+        match channel {
+            Dac8162Channel::A => self.a.apply(v_out),
+            Dac8162Channel::B => self.b.apply(v_out),
+        }
+    }
+}
+
+/// The guided calibration procedure is represented here, see the module docs
+pub struct CalibrationProcedure {
+    // the index of the current step in `STEPS`
+    step: usize,
+    // the uncorrected voltage that hit the target in each step so far
+    found: [f32; NUM_STEPS],
+    // the uncorrected voltage for the current step, as trimmed by the knob
+    v_out: f32,
+    gate_was_high: bool,
+}
+
+impl CalibrationProcedure {
+    /// `CalibrationProcedure::new()` is a new calibration procedure at the first step
+    pub fn new() -> Self {
+        Self {
+            step: 0,
+            found: [0.0_f32; NUM_STEPS],
+            v_out: STEPS[0].1,
+            gate_was_high: false,
+        }
+    }
+
+    /// `cp.update(k, g)` trims the current step with knob position `k` in `[0.0, 1.0]`, and moves on to the next step
+    /// when gate `g` falls at the end of a tap on the main ribbon
+    pub fn update(&mut self, knob: f32, gate: bool) {
+        let tapped = self.gate_was_high && !gate;
+        self.gate_was_high = gate;
+
+        if STEPS.len() <= self.step {
+            return;
+        }
+        if tapped {
+            self.found[self.step] = self.v_out;
+            self.step += 1;
+        }
+
+        // the knob centered is no trim at all
+        let trim = (knob - 0.5_f32) * 2.0_f32 * TRIM_RANGE;
+        if let Some(&(_, target)) = STEPS.get(self.step) {
+            self.v_out = target + trim;
+        }
+    }
+
+    /// `cp.output()` is the uncorrected voltage to write and the DAC channel to write it to for the current step, or
+    /// `None` if the procedure is done
+    pub fn output(&self) -> Option<(f32, Dac8162Channel)> {
+        STEPS
+            .get(self.step)
+            .map(|&(channel, _)| (self.v_out, channel))
+    }
+
+    /// `cp.result()` is the calibration found by the procedure, or `None` if it is not done yet
+    pub fn result(&self) -> Option<DacCalibration> {
+        if self.step < NUM_STEPS {
+            return None;
+        }
+        Some(DacCalibration {
+            a: ChannelCalibration::from_points(self.found[0], self.found[1]),
+            b: ChannelCalibration::from_points(self.found[2], self.found[3]),
+        })
+    }
+}
+
+impl Default for CalibrationProcedure {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// The lower of the two voltages each channel is calibrated at
+pub const LOW_POINT_VOUT: f32 = 1.0_f32;
+
+/// The higher of the two voltages each channel is calibrated at
+pub const HIGH_POINT_VOUT: f32 = 4.0_f32;
+
+/// The most the glide knob can trim the output by in either direction during calibration, in volts
+pub const TRIM_RANGE: f32 = 0.25_f32;
+
+/// The largest believable gain error, as a fraction of the ideal gain
+const MAX_GAIN_ERROR: f32 = 0.2_f32;
+
+/// The largest believable offset error in volts
+const MAX_OFFSET_ERROR: f32 = 0.5_f32;
+
+const NUM_STEPS: usize = 4;
+
+// the channel and target voltage of each step, in order
+const STEPS: [(Dac8162Channel, f32); NUM_STEPS] = [
+    (Dac8162Channel::A, LOW_POINT_VOUT),
+    (Dac8162Channel::A, HIGH_POINT_VOUT),
+    (Dac8162Channel::B, LOW_POINT_VOUT),
+    (Dac8162Channel::B, HIGH_POINT_VOUT),
+];
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// `tap(cp, k)` taps the main ribbon with the knob at `k` during calibration procedure `cp`
+    fn tap(procedure: &mut CalibrationProcedure, knob: f32) {
+        procedure.update(knob, true);
+        procedure.update(knob, false);
+    }
+
+    #[test]
+    fn new_calibration_changes_nothing() {
+        let cal = DacCalibration::new();
+        assert_eq!(cal.apply(2.5, Dac8162Channel::A), 2.5);
+        assert_eq!(cal.apply(2.5, Dac8162Channel::B), 2.5);
+    }
+
+    #[test]
+    fn correction_hits_both_points() {
+        let cal = ChannelCalibration::from_points(1.02, 4.05);
+        assert!((cal.apply(LOW_POINT_VOUT) - 1.02).abs() < 1E-5);
+        assert!((cal.apply(HIGH_POINT_VOUT) - 4.05).abs() < 1E-5);
+        assert!(cal.is_plausible());
+    }
+
+    #[test]
+    fn wild_corrections_are_not_plausible() {
+        assert!(!ChannelCalibration::from_points(1.0, 1.1).is_plausible());
+        assert!(!ChannelCalibration::from_points(2.0, 5.0).is_plausible());
+    }
+
+    #[test]
+    fn procedure_steps_through_both_channels() {
+        let mut procedure = CalibrationProcedure::new();
+        procedure.update(0.5, false);
+        assert_eq!(
+            procedure.output(),
+            Some((LOW_POINT_VOUT, Dac8162Channel::A))
+        );
+
+        // the knob fully clockwise trims all the way up
+        procedure.update(1.0, false);
+        assert_eq!(
+            procedure.output(),
+            Some((LOW_POINT_VOUT + TRIM_RANGE, Dac8162Channel::A))
+        );
+
+        tap(&mut procedure, 0.5);
+        assert_eq!(
+            procedure.output(),
+            Some((HIGH_POINT_VOUT, Dac8162Channel::A))
+        );
+        tap(&mut procedure, 0.5);
+        assert_eq!(procedure.output().unwrap().1, Dac8162Channel::B);
+        assert_eq!(procedure.result(), None);
+
+        tap(&mut procedure, 0.5);
+        tap(&mut procedure, 0.6);
+        assert_eq!(procedure.output(), None);
+
+        let cal = procedure.result().unwrap();
+        assert_eq!(cal.a, ChannelCalibration::new());
+        assert!(1.0 < cal.b.gain);
+    }
+
+    #[test]
+    fn holding_the_ribbon_does_not_move_on() {
+        let mut procedure = CalibrationProcedure::new();
+        for _ in 0..10 {
+            procedure.update(0.5, true);
+        }
+        assert_eq!(
+            procedure.output(),
+            Some((LOW_POINT_VOUT, Dac8162Channel::A))
+        );
+    }
+}
@@ -0,0 +1,44 @@
+//! # Ribbon core
+//!
+//! Hardware independent logic for the ribbon controller.
+//!
+//! Nothing in this crate touches the microcontroller peripherals, so everything here can be built and tested on a
+//! regular host computer as well as being used by the firmware.
+
+#![no_std]
+
+#[cfg(any(test, feature = "mock"))]
+extern crate std;
+
+pub mod app;
+pub mod auto_calibration;
+pub mod board;
+pub mod byte_ring;
+pub mod dac_calibration;
+pub mod midi_generator;
+pub mod midi_merge;
+pub mod midi_parser;
+pub mod midi_transmitter;
+#[cfg(any(test, feature = "mock"))]
+pub mod mock_board;
+pub mod mts;
+pub mod overruns;
+pub mod pitch_engine;
+pub mod ribbon_calibration;
+pub mod rpn;
+pub mod scale;
+pub mod settings;
+pub mod sysex_config;
+pub mod transpose;
+pub mod tuning;
+pub mod ui;
+pub mod velocity;
+
+/// The rate at which the ribbons are sampled
+pub const RIBBON_SAMPLE_RATE_HZ: u32 = 1_000;
+
+/// The rate at which the analog and MIDI outputs are updated
+pub const OUTPUT_UPDATE_RATE_HZ: u32 = 300;
+
+/// The rate at which the panel controls are read
+pub const UI_UPDATE_RATE_HZ: u32 = 30;
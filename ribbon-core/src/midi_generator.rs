@@ -0,0 +1,1135 @@
+//! # MIDI generator
+//!
+//! Turns the pitch of the main ribbon and the position of the MOD ribbon into MIDI messages.
+//!
+//! Three output modes are available:
+//!
+//! * Standard: everything is sent on the single channel set by the `MIDI CH` switch, notes change as the finger slides
+//!   from one semitone to the next and pitch bend covers the space in between. How one note hands over to the next is
+//!   set by the `TransitionPolicy`.
+//!
+//! * MPE: the ribbon acts as an MPE lower zone with channel 1 as the manager channel. Each press plays a note on the next
+//!   member channel, slides are sent as per-note pitch bend, and the MOD ribbon is sent as timbre (CC74) on the member
+//!   channel of the note.
+//!
+//! * Pitch bend only: a centre note is picked when the user presses the ribbon and held for the whole gesture, slides
+//!   are sent as pitch bend relative to it. A new centre note is only played if the finger slides beyond the pitch bend
+//!   range. This works best with a wide pitch bend range, and suits instruments with retriggering envelopes.
+//!
+//! In any mode the generator can also retune each note with the MIDI Tuning Standard just before it is played, so that
+//! receivers which support MTS play the exact pitch of the ribbon without relying on their pitch bend range. Pitch bend
+//! is then only used for slides away from the pitch the note was started at. If the MIDI output has no room for the
+//! retune, see `set_retune_room`, the note is bent from the tuning its key was given last instead, which is equal
+//! temperament until it is retuned.
+
+use crate::{
+    mts::NoteTuning,
+    rpn::{self, RPN_MPE_CONFIGURATION, RPN_PITCH_BEND_SENSITIVITY},
+    scale::Scale,
+};
+
+use heapless::Vec;
+use midi_convert::midi_types::MidiMessage;
+use synth_utils::quantizer::{self, Quantizer};
+
+/// The MIDI output modes are represented here
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum MidiMode {
+    /// All messages on the channel set by the `MIDI CH` switch
+    Standard,
+    /// MPE lower zone, per-note messages on rotating member channels
+    Mpe,
+    /// One note per press on the channel set by the `MIDI CH` switch, slides are sent as pitch bend
+    PitchBendOnly,
+}
+
+/// The ways of moving from one note to the next when sliding in standard mode are represented here
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TransitionPolicy {
+    /// The new note starts before the old note stops, non-retriggering instruments slide smoothly
+    OverlappingLegato,
+    /// The old note stops before the new note starts, instruments retrigger their envelopes for each new note
+    NoteOffFirst,
+    /// The note played at first-press is held until release and bent to follow the finger, clamped to the bend range
+    SingleNote,
+}
+
+/// The state of the ribbons as seen by the MIDI generator for a single update is represented here
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct RibbonGesture {
+    /// The pitch of the main ribbon in volts, 1volt/octave, before glide
+    pub pitch: f32,
+    /// True iff the user pressed the main ribbon since the last update
+    pub finger_just_pressed: bool,
+    /// True iff the user is pressing the main ribbon
+    pub finger_is_pressing: bool,
+    /// True iff the user released the main ribbon since the last update
+    pub finger_just_released: bool,
+    /// The velocity of the press, in `[1..127]`, used for every note played during the gesture
+    pub velocity: u8,
+    /// The velocity of the release, in `[1..127]`, used for the note-off sent when the user lets go of the ribbon
+    pub release_velocity: u8,
+    /// The MOD ribbon value in `[0.0, 1.0]`
+    pub mod_value: f32,
+}
+
+/// The MIDI messages produced by a single update
+pub type MidiMessages = Vec<MidiMessage, MAX_MIDI_MESSAGES_PER_TICK>;
+
+/// The MTS note tunings produced by a single update, to be sent before the MIDI messages of the same update
+pub type NoteTunings = Vec<NoteTuning, MAX_NOTE_TUNINGS_PER_TICK>;
+
+/// Receiver settings which have been announced, so they can be announced again if they change
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Announcement {
+    // the channel and pitch bend range in standard mode
+    Standard(u8, u8),
+    Mpe,
+}
+
+/// The MIDI generator is represented here
+pub struct MidiGenerator {
+    // quantizer for converting the ribbon pitch to MIDI note and pitch bend
+    midi_quantizer: Quantizer,
+
+    mode: MidiMode,
+    // the mode used for the last update, so that a note is not left hanging if the mode changes
+    active_mode: MidiMode,
+
+    // how to get from one note to the next when sliding in standard mode
+    transition_policy: TransitionPolicy,
+
+    // keep track of conversions so we don't write more MIDI data than needed if nothing changed
+    last_pitch_bend: f32,
+    last_mod_wheel: u8,
+
+    // the pitch bend range of the receiver in semitones, used in standard mode
+    pitch_bend_range: u8,
+    // the receiver settings last announced, if any
+    announced: Option<Announcement>,
+
+    // the channel and note of the note currently sounding, if any
+    sounding: Option<(u8, u8)>,
+    // the MPE member channel used for the most recent note
+    last_member_channel: u8,
+
+    // true iff notes are retuned with MTS before they are played
+    mts_enabled: bool,
+    // the MTS tunings for the notes started by the latest update
+    note_tunings: NoteTunings,
+    // the number of MTS tunings the MIDI output has room for in an update
+    retune_room: usize,
+    // how far each key was last retuned from its equal tempered pitch in semitones
+    key_detunes: [f32; NUM_MIDI_KEYS],
+    // how far the sounding note was retuned from its equal tempered pitch in semitones, zero without MTS
+    sounding_detune: f32,
+
+    // the number of semitones every note is shifted by
+    transpose: i8,
+}
+
+impl MidiGenerator {
+    /// `MidiGenerator::new()` is a new MIDI generator in standard mode with the default pitch bend range
+    pub fn new() -> Self {
+        Self {
+            midi_quantizer: Quantizer::new(),
+            mode: MidiMode::Standard,
+            active_mode: MidiMode::Standard,
+            transition_policy: TransitionPolicy::OverlappingLegato,
+            last_pitch_bend: 0.0_f32,
+            last_mod_wheel: 0,
+            pitch_bend_range: DEFAULT_PITCH_BEND_RANGE,
+            announced: None,
+            sounding: None,
+            last_member_channel: MPE_NUM_MEMBER_CHANNELS,
+            mts_enabled: false,
+            note_tunings: Vec::new(),
+            retune_room: MAX_NOTE_TUNINGS_PER_TICK,
+            key_detunes: [0.0_f32; NUM_MIDI_KEYS],
+            sounding_detune: 0.0_f32,
+            transpose: 0,
+        }
+    }
+
+    /// `mg.set_mode(m)` sets the MIDI output mode to `m`.
+    ///
+    /// Any note sounding in the old mode is released and the new mode is announced to the receiver with the next
+    /// update.
+    pub fn set_mode(&mut self, mode: MidiMode) {
+        self.mode = mode;
+    }
+
+    /// `mg.mode()` is the MIDI output mode
+    pub fn mode(&self) -> MidiMode {
+        self.mode
+    }
+
+    /// `mg.set_transition_policy(p)` sets how notes change when sliding in standard mode to policy `p`
+    pub fn set_transition_policy(&mut self, policy: TransitionPolicy) {
+        self.transition_policy = policy;
+    }
+
+    /// `mg.transition_policy()` is how notes change when sliding in standard mode
+    pub fn transition_policy(&self) -> TransitionPolicy {
+        self.transition_policy
+    }
+
+    /// `mg.set_scale(s, r)` restricts the notes played to scale `s` with root `r`, pitch bend covers the rest
+    pub fn set_scale(&mut self, scale: Scale, root: u8) {
+        scale.apply_to(&mut self.midi_quantizer, root);
+    }
+
+    /// `mg.set_pitch_bend_range(r)` sets the standard mode pitch bend range of the receiver to `r` semitones.
+    ///
+    /// The range is clamped to `[MIN_PITCH_BEND_RANGE, MAX_PITCH_BEND_RANGE]`. The new range is announced to the
+    /// receiver with the next update.
+    pub fn set_pitch_bend_range(&mut self, semitones: u8) {
+        self.pitch_bend_range = semitones.clamp(MIN_PITCH_BEND_RANGE, MAX_PITCH_BEND_RANGE);
+    }
+
+    /// `mg.pitch_bend_range()` is the standard mode pitch bend range of the receiver in semitones
+    pub fn pitch_bend_range(&self) -> u8 {
+        self.pitch_bend_range
+    }
+
+    /// `mg.set_transpose(t)` shifts every note played by `t` semitones, the lowest and highest MIDI notes are the limit
+    pub fn set_transpose(&mut self, semitones: i8) {
+        self.transpose = semitones;
+    }
+
+    /// `mg.transpose()` is the number of semitones every note is shifted by
+    pub fn transpose(&self) -> i8 {
+        self.transpose
+    }
+
+    /// `mg.set_mts(e)` enables retuning each note with an MTS single note tuning change before it is played iff `e`
+    pub fn set_mts(&mut self, enabled: bool) {
+        self.mts_enabled = enabled;
+    }
+
+    /// `mg.mts()` is true iff notes are retuned with MTS before they are played
+    pub fn mts(&self) -> bool {
+        self.mts_enabled
+    }
+
+    /// `mg.set_retune_room(n)` lets each update retune up to `n` notes with MTS, it must be set to the number of
+    /// retunes the MIDI output has room for before each update
+    ///
+    /// The notes started once the room is used up are not retuned, they are bent from the tuning their key was given
+    /// last.
+    pub fn set_retune_room(&mut self, room: usize) {
+        self.retune_room = room;
+    }
+
+    /// `mg.take_note_tunings()` is the MTS note tunings from the latest update, which must be sent before the MIDI
+    /// messages of that update. Always empty unless MTS is enabled.
+    pub fn take_note_tunings(&mut self) -> NoteTunings {
+        core::mem::take(&mut self.note_tunings)
+    }
+
+    /// `mg.generate(g, ch)` is the MIDI messages for ribbon gesture `g`.
+    ///
+    /// Must be called periodically at `OUTPUT_UPDATE_RATE_HZ`.
+    ///
+    /// # Arguments
+    ///
+    /// * `gesture` - the state of the ribbons for this update
+    ///
+    /// * `midi_channel` - the MIDI channel to send messages on, in `[0..15]`, ignored in MPE mode
+    pub fn generate(&mut self, gesture: &RibbonGesture, midi_channel: u8) -> MidiMessages {
+        let mut midi = Vec::new();
+        self.note_tunings.clear();
+
+        // switching modes, don't leave a note hanging from the old one
+        if self.mode != self.active_mode {
+            if let Some((ch, note)) = self.sounding.take() {
+                midi.push(MidiMessage::NoteOff(ch.into(), note.into(), 0.into()))
+                    .ok();
+            }
+            self.active_mode = self.mode;
+        }
+
+        let announcement = match self.mode {
+            MidiMode::Standard | MidiMode::PitchBendOnly => {
+                Announcement::Standard(midi_channel, self.pitch_bend_range)
+            }
+            MidiMode::Mpe => Announcement::Mpe,
+        };
+        if self.announced != Some(announcement) {
+            self.announce(announcement, &mut midi);
+        }
+
+        match self.mode {
+            MidiMode::Standard => self.generate_standard(gesture, midi_channel, &mut midi),
+            MidiMode::Mpe => self.generate_mpe(gesture, &mut midi),
+            MidiMode::PitchBendOnly => self.generate_bend_only(gesture, midi_channel, &mut midi),
+        }
+
+        midi
+    }
+
+    /// `mg.announce(a, m)` pushes the messages which set up the receiver for announcement `a` onto `m`
+    fn announce(&mut self, announcement: Announcement, midi: &mut MidiMessages) {
+        // leaving MPE, a configuration with no member channels turns the receiver's zone off again
+        if self.announced == Some(Announcement::Mpe) && announcement != Announcement::Mpe {
+            rpn::rpn_messages(MPE_MANAGER_CHANNEL, RPN_MPE_CONFIGURATION, 0, 0)
+                .iter()
+                .for_each(|&m| {
+                    midi.push(m).ok();
+                });
+        }
+
+        let messages = match announcement {
+            Announcement::Standard(channel, range) => {
+                rpn::rpn_messages(channel, RPN_PITCH_BEND_SENSITIVITY, range, 0)
+            }
+            // the configuration message also sets the bend range of the member channels to 48 semitones
+            Announcement::Mpe => rpn::rpn_messages(
+                MPE_MANAGER_CHANNEL,
+                RPN_MPE_CONFIGURATION,
+                MPE_NUM_MEMBER_CHANNELS,
+                0,
+            ),
+        };
+        messages.iter().for_each(|&m| {
+            midi.push(m).ok();
+        });
+        self.announced = Some(announcement);
+    }
+
+    /// `mg.generate_standard(g, ch, m)` pushes the standard mode messages for gesture `g` on channel `ch` onto `m`
+    fn generate_standard(
+        &mut self,
+        gesture: &RibbonGesture,
+        midi_channel: u8,
+        midi: &mut MidiMessages,
+    ) {
+        // the extra quarter step helps keep things in-tune
+        let midi_conversion = self
+            .midi_quantizer
+            .convert(gesture.pitch + quantizer::HALF_SEMITONE_WIDTH);
+        let this_midi_note = self.transposed(midi_conversion.note_num + LOWEST_MIDI_NOTE);
+
+        // Each round there may be zero or more MIDI messages sent:
+        //
+        // * a note-on message if the user just pressed the ribbon or if they slid into a new note
+        // * a note-off message if the user just released the ribbon or if they slid into a new note
+        // * a pitch bend message if the value has changed since last time
+        //
+        // A note is also started if the user is pressing the ribbon but nothing is sounding, which happens if the mode
+        // was changed in the middle of a gesture
+        let note_on = |note: u8| {
+            MidiMessage::NoteOn(midi_channel.into(), note.into(), gesture.velocity.into())
+        };
+        let pitch = self.midi_pitch(gesture.pitch);
+        // notes are stopped on the channel they were started on, in case the channel was changed while they sounded
+        let note_off =
+            |(ch, note): (u8, u8)| MidiMessage::NoteOff(ch.into(), note.into(), 0.into());
+        if gesture.finger_just_released {
+            if let Some((ch, note)) = self.sounding.take() {
+                midi.push(MidiMessage::NoteOff(
+                    ch.into(),
+                    note.into(),
+                    gesture.release_velocity.into(),
+                ))
+                .ok();
+            }
+        } else if gesture.finger_just_pressed
+            || (gesture.finger_is_pressing && self.sounding.is_none())
+        {
+            self.tune_note(this_midi_note, pitch);
+            midi.push(note_on(this_midi_note)).ok();
+            self.sounding = Some((midi_channel, this_midi_note));
+        } else if let Some(old @ (_, old_note)) = self.sounding {
+            if this_midi_note != old_note {
+                match self.transition_policy {
+                    TransitionPolicy::OverlappingLegato => {
+                        self.tune_note(this_midi_note, pitch);
+                        midi.push(note_on(this_midi_note)).ok();
+                        midi.push(note_off(old)).ok();
+                        self.sounding = Some((midi_channel, this_midi_note));
+                    }
+                    TransitionPolicy::NoteOffFirst => {
+                        midi.push(note_off(old)).ok();
+                        self.tune_note(this_midi_note, pitch);
+                        midi.push(note_on(this_midi_note)).ok();
+                        self.sounding = Some((midi_channel, this_midi_note));
+                    }
+                    // the held note is bent to follow the finger instead
+                    TransitionPolicy::SingleNote => (),
+                }
+            }
+        }
+
+        // pitch bend is relative to the note which is sounding, which is usually the nearest note to the finger
+        let bent_note = self.sounding.map_or(this_midi_note, |(_, note)| note);
+        let bend_semitones = midi_conversion.fraction / quantizer::SEMITONE_WIDTH
+            + (this_midi_note as f32 - bent_note as f32)
+            - self.detune();
+        // full-scale pitch bend spans the bend range of the receiver
+        let this_pitch_bend =
+            (bend_semitones / self.pitch_bend_range as f32).clamp(-1.0_f32, 1.0_f32);
+
+        self.push_pitch_bend(this_pitch_bend, midi_channel, midi);
+        self.push_mod_wheel(gesture, midi_channel, midi);
+    }
+
+    /// `mg.generate_bend_only(g, ch, m)` pushes the pitch bend only mode messages for gesture `g` on channel `ch` onto
+    /// `m`
+    fn generate_bend_only(
+        &mut self,
+        gesture: &RibbonGesture,
+        midi_channel: u8,
+        midi: &mut MidiMessages,
+    ) {
+        let pitch = self.midi_pitch(gesture.pitch);
+        let range = self.pitch_bend_range as f32;
+
+        if gesture.finger_just_released {
+            if let Some((ch, note)) = self.sounding.take() {
+                midi.push(MidiMessage::NoteOff(
+                    ch.into(),
+                    note.into(),
+                    gesture.release_velocity.into(),
+                ))
+                .ok();
+            }
+        } else if gesture.finger_is_pressing {
+            // A new centre note is needed when the user presses the ribbon, or if they slide so far that the centre
+            // note can't reach the finger with pitch bend. The old note is stopped first, and the bend is set before
+            // the new note starts so that it starts at the right pitch.
+            let needs_new_note = match self.sounding {
+                None => true,
+                Some((_, note)) => range < (pitch - note as f32 - self.detune()).abs(),
+            };
+            if gesture.finger_just_pressed || needs_new_note {
+                if let Some((ch, note)) = self.sounding.take() {
+                    midi.push(MidiMessage::NoteOff(ch.into(), note.into(), 0.into()))
+                        .ok();
+                }
+                let note = self.nearest_note(gesture.pitch);
+                self.tune_note(note, pitch);
+                self.push_pitch_bend(
+                    (pitch - note as f32 - self.sounding_detune) / range,
+                    midi_channel,
+                    midi,
+                );
+                midi.push(MidiMessage::NoteOn(
+                    midi_channel.into(),
+                    note.into(),
+                    gesture.velocity.into(),
+                ))
+                .ok();
+                self.sounding = Some((midi_channel, note));
+            } else if let Some((ch, note)) = self.sounding {
+                self.push_pitch_bend((pitch - note as f32 - self.detune()) / range, ch, midi);
+            }
+        }
+
+        self.push_mod_wheel(gesture, midi_channel, midi);
+    }
+
+    /// `mg.generate_mpe(g, m)` pushes the MPE mode messages for gesture `g` onto `m`
+    fn generate_mpe(&mut self, gesture: &RibbonGesture, midi: &mut MidiMessages) {
+        let pitch = self.midi_pitch(gesture.pitch);
+        let this_timbre = (gesture.mod_value * 127.0_f32) as u8;
+
+        if gesture.finger_just_released {
+            if let Some((ch, note)) = self.sounding.take() {
+                midi.push(MidiMessage::NoteOff(
+                    ch.into(),
+                    note.into(),
+                    gesture.release_velocity.into(),
+                ))
+                .ok();
+            }
+        }
+        if !gesture.finger_is_pressing {
+            return;
+        }
+
+        // A new note is played when the user presses the ribbon, or if they slide so far that the note can't reach
+        // the finger with pitch bend. Notes are only ever started with the in-tune part of the pitch so that the
+        // fractional part goes into the per-note pitch bend.
+        let needs_new_note = match self.sounding {
+            None => true,
+            Some((_, note)) => MPE_PITCH_BEND_RANGE < (pitch - note as f32 - self.detune()).abs(),
+        };
+        if gesture.finger_just_pressed || needs_new_note {
+            let old_note = self.sounding;
+            let ch = self.next_member_channel();
+            let note = self.nearest_note(gesture.pitch);
+            self.tune_note(note, pitch);
+            let bend = mpe_pitch_bend(pitch - self.sounding_detune, note);
+
+            // set up the expression of the member channel before the note starts
+            midi.push(MidiMessage::PitchBendChange(ch.into(), bend.into()))
+                .ok();
+            midi.push(MidiMessage::ControlChange(
+                ch.into(),
+                MIDI_CC_TIMBRE.into(),
+                this_timbre.into(),
+            ))
+            .ok();
+            midi.push(MidiMessage::NoteOn(
+                ch.into(),
+                note.into(),
+                gesture.velocity.into(),
+            ))
+            .ok();
+            if let Some((old_ch, old_note)) = old_note {
+                midi.push(MidiMessage::NoteOff(
+                    old_ch.into(),
+                    old_note.into(),
+                    0.into(),
+                ))
+                .ok();
+            }
+
+            self.sounding = Some((ch, note));
+            self.last_pitch_bend = bend;
+            self.last_mod_wheel = this_timbre;
+        } else if let Some((ch, note)) = self.sounding {
+            let bend = mpe_pitch_bend(pitch - self.detune(), note);
+            if bend != self.last_pitch_bend {
+                midi.push(MidiMessage::PitchBendChange(ch.into(), bend.into()))
+                    .ok();
+                self.last_pitch_bend = bend;
+            }
+            if this_timbre != self.last_mod_wheel {
+                midi.push(MidiMessage::ControlChange(
+                    ch.into(),
+                    MIDI_CC_TIMBRE.into(),
+                    this_timbre.into(),
+                ))
+                .ok();
+                self.last_mod_wheel = this_timbre;
+            }
+        }
+    }
+
+    /// `mg.tune_note(n, p)` retunes note `n` to fractional MIDI pitch `p` with MTS before it is played, if enabled and
+    /// there is room for the retune.
+    ///
+    /// Must be called just before the note-on for each new note.
+    fn tune_note(&mut self, note: u8, pitch: f32) {
+        if !self.mts_enabled {
+            self.sounding_detune = 0.0_f32;
+            return;
+        }
+        // without room for the retune the key keeps its last tuning, and pitch bend makes up the difference
+        if self.note_tunings.len() < self.retune_room
+            && self.note_tunings.push(NoteTuning::new(note, pitch)).is_ok()
+        {
+            self.key_detunes[note as usize] = pitch - note as f32;
+        }
+        self.sounding_detune = self.key_detunes[note as usize];
+    }
+
+    /// `mg.detune()` is how far the sounding note was retuned with MTS in semitones, zero if nothing is sounding
+    fn detune(&self) -> f32 {
+        self.sounding.map_or(0.0_f32, |_| self.sounding_detune)
+    }
+
+    /// `mg.nearest_note(p)` is the MIDI note nearest to pitch `p` in volts, transposed
+    fn nearest_note(&mut self, pitch: f32) -> u8 {
+        // the extra quarter step helps keep things in-tune
+        let note = self
+            .midi_quantizer
+            .convert(pitch + quantizer::HALF_SEMITONE_WIDTH)
+            .note_num
+            + LOWEST_MIDI_NOTE;
+        self.transposed(note)
+    }
+
+    /// `mg.transposed(n)` is MIDI note `n` shifted by the transpose, limited to the range of MIDI notes
+    fn transposed(&self, note: u8) -> u8 {
+        (note as i16 + self.transpose as i16).clamp(0, MAX_MIDI_NOTE as i16) as u8
+    }
+
+    /// `mg.midi_pitch(p)` is pitch `p` in volts as the fractional MIDI note number it is played as, transposed
+    fn midi_pitch(&self, pitch: f32) -> f32 {
+        volts_to_midi_pitch(pitch) + self.transpose as f32
+    }
+
+    /// `mg.push_pitch_bend(b, ch, m)` pushes pitch bend `b` on channel `ch` onto `m` if it changed since last time
+    fn push_pitch_bend(&mut self, bend: f32, midi_channel: u8, midi: &mut MidiMessages) {
+        let bend = bend.clamp(-1.0_f32, 1.0_f32);
+        if self.last_pitch_bend != bend {
+            midi.push(MidiMessage::PitchBendChange(
+                midi_channel.into(),
+                bend.into(),
+            ))
+            .ok();
+            self.last_pitch_bend = bend;
+        }
+    }
+
+    /// `mg.push_mod_wheel(g, ch, m)` pushes the mod wheel for gesture `g` on channel `ch` onto `m` if it changed since
+    /// last time
+    fn push_mod_wheel(
+        &mut self,
+        gesture: &RibbonGesture,
+        midi_channel: u8,
+        midi: &mut MidiMessages,
+    ) {
+        let this_mod_wheel = (gesture.mod_value * 127.0_f32) as u8;
+        if this_mod_wheel != self.last_mod_wheel {
+            midi.push(MidiMessage::ControlChange(
+                midi_channel.into(),
+                MIDI_CC_MOD_WHEEL.into(),
+                this_mod_wheel.into(),
+            ))
+            .ok();
+            self.last_mod_wheel = this_mod_wheel;
+        }
+    }
+
+    /// `mg.next_member_channel()` is the MPE member channel to play the next note on, rotating through the zone
+    fn next_member_channel(&mut self) -> u8 {
+        self.last_member_channel = self.last_member_channel % MPE_NUM_MEMBER_CHANNELS + 1;
+        self.last_member_channel
+    }
+}
+
+impl Default for MidiGenerator {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// `volts_to_midi_pitch(v)` is pitch `v` in volts converted to the fractional MIDI note number it is played as
+pub fn volts_to_midi_pitch(volts: f32) -> f32 {
+    // the extra quarter step helps keep things in-tune
+    (volts + quantizer::HALF_SEMITONE_WIDTH) / quantizer::SEMITONE_WIDTH + LOWEST_MIDI_NOTE as f32
+}
+
+/// `midi_pitch_to_volts(p)` is the pitch in volts which is played as fractional MIDI note number `p`
+pub fn midi_pitch_to_volts(midi_pitch: f32) -> f32 {
+    (midi_pitch - LOWEST_MIDI_NOTE as f32) * quantizer::SEMITONE_WIDTH
+        - quantizer::HALF_SEMITONE_WIDTH
+}
+
+/// `mpe_pitch_bend(p, n)` is the per-note pitch bend which bends note `n` to pitch `p` in semitones
+fn mpe_pitch_bend(pitch: f32, note: u8) -> f32 {
+    ((pitch - note as f32) / MPE_PITCH_BEND_RANGE).clamp(-1.0_f32, 1.0_f32)
+}
+
+/// The maximum number of MIDI messages that may be produced by a single update
+pub const MAX_MIDI_MESSAGES_PER_TICK: usize = 16;
+
+/// The maximum number of MTS note tunings that may be produced by a single update
+pub const MAX_NOTE_TUNINGS_PER_TICK: usize = 2;
+
+/// The smallest pitch bend range that may be set, in semitones
+pub const MIN_PITCH_BEND_RANGE: u8 = 1;
+
+/// The largest pitch bend range that may be set, in semitones
+pub const MAX_PITCH_BEND_RANGE: u8 = 48;
+
+/// The pitch bend range used until another is set, this is the usual default of most instruments
+pub const DEFAULT_PITCH_BEND_RANGE: u8 = 2;
+
+/// The manager channel of the MPE lower zone, MIDI channel 1
+pub const MPE_MANAGER_CHANNEL: u8 = 0;
+
+/// The number of member channels in the MPE lower zone, MIDI channels 2 through 16
+pub const MPE_NUM_MEMBER_CHANNELS: u8 = 15;
+
+/// The pitch bend range of the MPE member channels in semitones, set by the MPE configuration message
+pub const MPE_PITCH_BEND_RANGE: f32 = 48.0_f32;
+
+/// The MIDI note played at the bottom of the ribbon, where the `RIBBON CV` output is zero volts, before any transpose
+pub const LOWEST_MIDI_NOTE: u8 = 5;
+
+const MAX_MIDI_NOTE: u8 = 127;
+const NUM_MIDI_KEYS: usize = MAX_MIDI_NOTE as usize + 1;
+
+const MIDI_CC_MOD_WHEEL: u8 = 0x01;
+const MIDI_CC_TIMBRE: u8 = 0x4A;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pressing(pitch: f32) -> RibbonGesture {
+        RibbonGesture {
+            pitch,
+            finger_just_pressed: false,
+            finger_is_pressing: true,
+            finger_just_released: false,
+            velocity: 127,
+            release_velocity: 64,
+            mod_value: 0.0,
+        }
+    }
+
+    fn just_pressed(pitch: f32) -> RibbonGesture {
+        RibbonGesture {
+            finger_just_pressed: true,
+            ..pressing(pitch)
+        }
+    }
+
+    fn just_released(pitch: f32) -> RibbonGesture {
+        RibbonGesture {
+            finger_is_pressing: false,
+            finger_just_released: true,
+            ..pressing(pitch)
+        }
+    }
+
+    fn mpe_generator() -> MidiGenerator {
+        let mut gen = MidiGenerator::new();
+        gen.set_mode(MidiMode::Mpe);
+        gen
+    }
+
+    fn note_ons(midi: &MidiMessages) -> std::vec::Vec<(u8, u8)> {
+        midi.iter()
+            .filter_map(|m| match m {
+                MidiMessage::NoteOn(ch, note, _) => Some((u8::from(*ch), u8::from(*note))),
+                _ => None,
+            })
+            .collect()
+    }
+
+    fn note_offs(midi: &MidiMessages) -> std::vec::Vec<(u8, u8)> {
+        midi.iter()
+            .filter_map(|m| match m {
+                MidiMessage::NoteOff(ch, note, _) => Some((u8::from(*ch), u8::from(*note))),
+                _ => None,
+            })
+            .collect()
+    }
+
+    fn pitch_bends(midi: &MidiMessages) -> std::vec::Vec<(u8, i16)> {
+        midi.iter()
+            .filter_map(|m| match m {
+                MidiMessage::PitchBendChange(ch, bend) => Some((u8::from(*ch), i16::from(*bend))),
+                _ => None,
+            })
+            .collect()
+    }
+
+    // the pitch in volts which is exactly in tune with the note `semitones` above the lowest note
+    fn semitones(semitones: f32) -> f32 {
+        semitones * quantizer::SEMITONE_WIDTH - quantizer::HALF_SEMITONE_WIDTH
+    }
+
+    fn slide_up_a_whole_step(policy: TransitionPolicy) -> (MidiMessages, MidiMessages) {
+        let mut gen = MidiGenerator::new();
+        gen.set_transition_policy(policy);
+        gen.set_pitch_bend_range(4);
+        gen.generate(&RibbonGesture::default(), 0);
+        gen.generate(&just_pressed(semitones(3.0)), 0);
+        let slide = gen.generate(&pressing(semitones(5.0)), 0);
+        let release = gen.generate(&just_released(semitones(5.0)), 0);
+        (slide, release)
+    }
+
+    #[test]
+    fn overlapping_legato_starts_the_new_note_before_stopping_the_old_one() {
+        let (slide, release) = slide_up_a_whole_step(TransitionPolicy::OverlappingLegato);
+        assert_eq!(
+            slide[..2],
+            [
+                MidiMessage::NoteOn(0.into(), 10.into(), 127.into()),
+                MidiMessage::NoteOff(0.into(), 8.into(), 0.into())
+            ]
+        );
+        assert_eq!(note_offs(&release), [(0, 10)]);
+    }
+
+    #[test]
+    fn note_off_first_stops_the_old_note_before_starting_the_new_one() {
+        let (slide, release) = slide_up_a_whole_step(TransitionPolicy::NoteOffFirst);
+        assert_eq!(
+            slide[..2],
+            [
+                MidiMessage::NoteOff(0.into(), 8.into(), 0.into()),
+                MidiMessage::NoteOn(0.into(), 10.into(), 127.into())
+            ]
+        );
+        assert_eq!(note_offs(&release), [(0, 10)]);
+    }
+
+    #[test]
+    fn single_note_holds_the_first_note_and_bends_it() {
+        let (slide, release) = slide_up_a_whole_step(TransitionPolicy::SingleNote);
+        assert!(note_ons(&slide).is_empty());
+        assert!(note_offs(&slide).is_empty());
+        // a whole step is half of the bend range
+        let bend = pitch_bends(&slide);
+        assert!((bend[0].1 as f32 - 8192.0 / 2.0).abs() <= 2.0);
+        assert_eq!(note_offs(&release), [(0, 8)]);
+    }
+
+    #[test]
+    fn single_note_bend_is_clamped_to_the_bend_range() {
+        let mut gen = MidiGenerator::new();
+        gen.set_transition_policy(TransitionPolicy::SingleNote);
+        gen.generate(&RibbonGesture::default(), 0);
+        gen.generate(&just_pressed(semitones(3.0)), 0);
+        let slide = gen.generate(&pressing(semitones(20.0)), 0);
+        assert!(note_ons(&slide).is_empty());
+        assert_eq!(pitch_bends(&slide), [(0, 8191)]);
+    }
+
+    #[test]
+    fn release_sends_one_note_off_even_if_the_finger_moved() {
+        for policy in [
+            TransitionPolicy::OverlappingLegato,
+            TransitionPolicy::NoteOffFirst,
+            TransitionPolicy::SingleNote,
+        ] {
+            let mut gen = MidiGenerator::new();
+            gen.set_transition_policy(policy);
+            gen.generate(&RibbonGesture::default(), 0);
+            gen.generate(&just_pressed(semitones(3.0)), 0);
+            let release = gen.generate(&just_released(semitones(10.0)), 0);
+            assert_eq!(note_offs(&release), [(0, 8)]);
+            assert!(note_ons(&release).is_empty());
+        }
+    }
+
+    #[test]
+    fn release_velocity_is_sent_with_the_release_note_off_in_every_mode() {
+        for mode in [MidiMode::Standard, MidiMode::Mpe, MidiMode::PitchBendOnly] {
+            let mut gen = MidiGenerator::new();
+            gen.set_mode(mode);
+            gen.generate(&RibbonGesture::default(), 0);
+            gen.generate(&just_pressed(semitones(3.0)), 0);
+            let release = gen.generate(
+                &RibbonGesture {
+                    release_velocity: 99,
+                    ..just_released(semitones(3.0))
+                },
+                0,
+            );
+            assert!(matches!(
+                release[..],
+                [MidiMessage::NoteOff(_, _, vel)] if u8::from(vel) == 99
+            ));
+        }
+    }
+
+    #[test]
+    fn note_off_is_sent_on_the_channel_the_note_was_started_on() {
+        let mut gen = MidiGenerator::new();
+        gen.generate(&RibbonGesture::default(), 2);
+        gen.generate(&just_pressed(semitones(3.0)), 2);
+        let release = gen.generate(&just_released(semitones(3.0)), 6);
+        assert_eq!(note_offs(&release), [(2, 8)]);
+    }
+
+    fn bend_only_generator(range: u8) -> MidiGenerator {
+        let mut gen = MidiGenerator::new();
+        gen.set_mode(MidiMode::PitchBendOnly);
+        gen.set_pitch_bend_range(range);
+        gen.generate(&RibbonGesture::default(), 0);
+        gen
+    }
+
+    #[test]
+    fn bend_only_holds_the_centre_note_and_bends_it() {
+        let mut gen = bend_only_generator(12);
+        assert_eq!(
+            note_ons(&gen.generate(&just_pressed(semitones(3.0)), 0)),
+            [(0, 8)]
+        );
+
+        for slide_to in [4.0, 6.0, 9.0, 15.0, 0.0] {
+            let midi = gen.generate(&pressing(semitones(slide_to)), 0);
+            assert!(note_ons(&midi).is_empty());
+            assert!(note_offs(&midi).is_empty());
+            let bend = pitch_bends(&midi)[0].1 as f32;
+            assert!((bend - (slide_to - 3.0) / 12.0 * 8192.0).abs() <= 2.0);
+        }
+
+        assert_eq!(
+            note_offs(&gen.generate(&just_released(semitones(0.0)), 0)),
+            [(0, 8)]
+        );
+    }
+
+    #[test]
+    fn bend_only_first_press_bend_is_sent_before_the_note() {
+        let mut gen = bend_only_generator(12);
+        gen.generate(&just_pressed(semitones(3.0)), 0);
+        gen.generate(&pressing(semitones(9.0)), 0);
+        gen.generate(&just_released(semitones(9.0)), 0);
+
+        // the bend left over from the last gesture is reset before the next note starts
+        match gen.generate(&just_pressed(semitones(20.0)), 0)[..] {
+            [MidiMessage::PitchBendChange(_, bend), MidiMessage::NoteOn(_, note, _)] => {
+                assert_eq!(i16::from(bend), 0);
+                assert_eq!(u8::from(note), 25);
+            }
+            _ => panic!("expected pitch bend then note-on"),
+        }
+    }
+
+    #[test]
+    fn bend_only_plays_a_new_centre_note_beyond_the_bend_range() {
+        let mut gen = bend_only_generator(2);
+        gen.generate(&just_pressed(semitones(3.0)), 0);
+
+        match gen.generate(&pressing(semitones(6.25)), 0)[..] {
+            [MidiMessage::NoteOff(_, old, _), MidiMessage::PitchBendChange(_, bend), MidiMessage::NoteOn(_, new, _)] =>
+            {
+                assert_eq!(u8::from(old), 8);
+                assert_eq!(u8::from(new), 11);
+                assert!((i16::from(bend) as f32 - 0.25 / 2.0 * 8192.0).abs() <= 2.0);
+            }
+            _ => panic!("expected note-off, pitch bend, then note-on"),
+        }
+    }
+
+    #[test]
+    fn bend_only_announces_the_bend_range() {
+        let mut gen = MidiGenerator::new();
+        gen.set_mode(MidiMode::PitchBendOnly);
+        gen.set_pitch_bend_range(24);
+        assert_eq!(
+            gen.generate(&RibbonGesture::default(), 3)[..rpn::NUM_MESSAGES_PER_RPN],
+            rpn::rpn_messages(3, RPN_PITCH_BEND_SENSITIVITY, 24, 0)
+        );
+    }
+
+    #[test]
+    fn mpe_configuration_is_sent_on_the_manager_channel() {
+        let mut gen = mpe_generator();
+        let midi = gen.generate(&RibbonGesture::default(), 7);
+        assert_eq!(
+            midi[..rpn::NUM_MESSAGES_PER_RPN],
+            rpn::rpn_messages(0, RPN_MPE_CONFIGURATION, MPE_NUM_MEMBER_CHANNELS, 0)
+        );
+        assert!(gen.generate(&RibbonGesture::default(), 7).is_empty());
+    }
+
+    #[test]
+    fn leaving_mpe_turns_the_zone_off() {
+        let mut gen = mpe_generator();
+        gen.generate(&RibbonGesture::default(), 0);
+
+        gen.set_mode(MidiMode::Standard);
+        gen.set_pitch_bend_range(12);
+        let midi = gen.generate(&RibbonGesture::default(), 5);
+        let (zone_off, rest) = midi.split_at(rpn::NUM_MESSAGES_PER_RPN);
+        assert_eq!(zone_off, rpn::rpn_messages(0, RPN_MPE_CONFIGURATION, 0, 0));
+        assert_eq!(
+            rest[..rpn::NUM_MESSAGES_PER_RPN],
+            rpn::rpn_messages(5, RPN_PITCH_BEND_SENSITIVITY, 12, 0)
+        );
+
+        // only once, changing channel in standard mode is not leaving MPE
+        let midi = gen.generate(&RibbonGesture::default(), 6);
+        assert_eq!(
+            midi[..rpn::NUM_MESSAGES_PER_RPN],
+            rpn::rpn_messages(6, RPN_PITCH_BEND_SENSITIVITY, 12, 0)
+        );
+    }
+
+    #[test]
+    fn mpe_notes_rotate_over_the_member_channels() {
+        let mut gen = mpe_generator();
+        gen.generate(&RibbonGesture::default(), 0);
+
+        let mut channels = std::vec::Vec::new();
+        for _ in 0..MPE_NUM_MEMBER_CHANNELS + 1 {
+            channels.push(note_ons(&gen.generate(&just_pressed(semitones(3.0)), 0))[0].0);
+            gen.generate(&just_released(semitones(3.0)), 0);
+        }
+
+        assert_eq!(channels[0], 1);
+        assert_eq!(channels[14], 15);
+        assert_eq!(channels[15], 1);
+    }
+
+    #[test]
+    fn mpe_note_off_is_sent_on_the_channel_of_the_note() {
+        let mut gen = mpe_generator();
+        gen.generate(&RibbonGesture::default(), 0);
+        let on = note_ons(&gen.generate(&just_pressed(semitones(3.0)), 0));
+        let off = note_offs(&gen.generate(&just_released(semitones(10.0)), 0));
+        assert_eq!(on, off);
+    }
+
+    #[test]
+    fn mpe_slides_are_per_note_pitch_bend() {
+        let mut gen = mpe_generator();
+        gen.generate(&RibbonGesture::default(), 0);
+        let on = note_ons(&gen.generate(&just_pressed(semitones(3.0)), 0));
+
+        // slide up an octave, no new notes are played
+        let midi = gen.generate(&pressing(semitones(15.0)), 0);
+        assert!(note_ons(&midi).is_empty());
+        let bend = pitch_bends(&midi);
+        assert_eq!(bend.len(), 1);
+        assert_eq!(bend[0].0, on[0].0);
+        // 12 of 48 semitones is a quarter of the bend range
+        assert!((bend[0].1 as f32 - 8192.0 / 4.0).abs() <= 2.0);
+    }
+
+    #[test]
+    fn mpe_expression_is_set_before_the_note_starts() {
+        let mut gen = mpe_generator();
+        gen.generate(&RibbonGesture::default(), 0);
+        let midi = gen.generate(
+            &RibbonGesture {
+                mod_value: 0.5,
+                ..just_pressed(semitones(3.0))
+            },
+            0,
+        );
+
+        match midi[..] {
+            [MidiMessage::PitchBendChange(..), MidiMessage::ControlChange(ch, cc, val), MidiMessage::NoteOn(note_ch, ..)] =>
+            {
+                assert_eq!(ch, note_ch);
+                assert_eq!(u8::from(cc), MIDI_CC_TIMBRE);
+                assert_eq!(u8::from(val), 63);
+            }
+            _ => panic!("expected pitch bend, timbre, then note-on"),
+        }
+    }
+
+    #[test]
+    fn mpe_mod_ribbon_is_timbre_on_the_member_channel() {
+        let mut gen = mpe_generator();
+        gen.generate(&RibbonGesture::default(), 0);
+        let on = note_ons(&gen.generate(&just_pressed(semitones(3.0)), 0));
+        let midi = gen.generate(
+            &RibbonGesture {
+                mod_value: 1.0,
+                ..pressing(semitones(3.0))
+            },
+            0,
+        );
+        assert_eq!(
+            midi[..],
+            [MidiMessage::ControlChange(
+                on[0].0.into(),
+                MIDI_CC_TIMBRE.into(),
+                127.into()
+            )]
+        );
+    }
+
+    #[test]
+    fn mpe_slide_beyond_the_bend_range_moves_to_a_new_member_channel() {
+        let mut gen = mpe_generator();
+        gen.generate(&RibbonGesture::default(), 0);
+        let first = note_ons(&gen.generate(&just_pressed(semitones(0.0)), 0))[0];
+
+        let midi = gen.generate(&pressing(semitones(50.0)), 0);
+        let second = note_ons(&midi)[0];
+        assert_ne!(first.0, second.0);
+        assert_eq!(note_offs(&midi), [first]);
+    }
+
+    fn mts_generator(mode: MidiMode) -> MidiGenerator {
+        let mut gen = MidiGenerator::new();
+        gen.set_mode(mode);
+        gen.set_mts(true);
+        gen.generate(&RibbonGesture::default(), 0);
+        gen
+    }
+
+    #[test]
+    fn mts_tunes_each_note_to_the_exact_pitch_before_it_plays() {
+        for mode in [MidiMode::Standard, MidiMode::Mpe, MidiMode::PitchBendOnly] {
+            let mut gen = mts_generator(mode);
+            let midi = gen.generate(&just_pressed(semitones(3.3)), 0);
+            let tunings = gen.take_note_tunings();
+
+            let note = note_ons(&midi)[0].1;
+            assert_eq!(tunings.len(), 1);
+            assert_eq!(tunings[0].key, note);
+            assert!((tunings[0].pitch - 8.3).abs() < 1E-4);
+            // the note is in tune without any bend
+            assert!(pitch_bends(&midi).iter().all(|&(_, bend)| bend == 0));
+        }
+    }
+
+    #[test]
+    fn mts_notes_are_bent_to_pitch_without_room_for_the_retune() {
+        for (mode, range) in [
+            (MidiMode::Standard, DEFAULT_PITCH_BEND_RANGE as f32),
+            (MidiMode::Mpe, MPE_PITCH_BEND_RANGE),
+            (MidiMode::PitchBendOnly, DEFAULT_PITCH_BEND_RANGE as f32),
+        ] {
+            let mut gen = mts_generator(mode);
+            gen.set_retune_room(0);
+            let midi = gen.generate(&just_pressed(semitones(3.3)), 0);
+            assert!(gen.take_note_tunings().is_empty());
+
+            // the key is still in equal temperament
+            let note = note_ons(&midi)[0].1;
+            let bend = pitch_bends(&midi).last().unwrap().1;
+            let pitch = note as f32 + bend as f32 / 8192.0 * range;
+            assert!((pitch - 8.3).abs() < 1E-2, "{:?} {}", mode, pitch);
+        }
+    }
+
+    #[test]
+    fn mts_notes_without_room_are_bent_from_the_last_tuning_of_their_key() {
+        let mut gen = mts_generator(MidiMode::Standard);
+        gen.generate(&just_pressed(semitones(3.3)), 0);
+        assert_eq!(gen.take_note_tunings().len(), 1);
+        gen.generate(&just_released(semitones(3.3)), 0);
+
+        gen.set_retune_room(0);
+        let midi = gen.generate(&just_pressed(semitones(3.4)), 0);
+        assert!(gen.take_note_tunings().is_empty());
+        // the key is still tuned to the first press, a tenth of a semitone is a twentieth of the default bend range
+        let bend = pitch_bends(&midi)[0].1 as f32;
+        assert!((bend - 0.1 / 2.0 * 8192.0).abs() <= 2.0, "{}", bend);
+    }
+
+    #[test]
+    fn mts_slides_are_bent_from_the_tuned_pitch() {
+        let mut gen = mts_generator(MidiMode::Standard);
+        gen.generate(&just_pressed(semitones(3.3)), 0);
+        let midi = gen.generate(&pressing(semitones(3.5)), 0);
+
+        assert!(gen.take_note_tunings().is_empty());
+        // a fifth of a semitone is a tenth of the default bend range
+        let bend = pitch_bends(&midi)[0].1 as f32;
+        assert!((bend - 0.2 / 2.0 * 8192.0).abs() <= 2.0);
+    }
+
+    #[test]
+    fn mts_retunes_the_new_note_when_sliding_into_it() {
+        let mut gen = mts_generator(MidiMode::Standard);
+        gen.generate(&just_pressed(semitones(3.0)), 0);
+        gen.take_note_tunings();
+        let midi = gen.generate(&pressing(semitones(5.0)), 0);
+
+        let tunings = gen.take_note_tunings();
+        assert_eq!(tunings.len(), 1);
+        assert_eq!(tunings[0].key, note_ons(&midi)[0].1);
+    }
+
+    #[test]
+    fn no_tunings_without_mts() {
+        let mut gen = MidiGenerator::new();
+        gen.generate(&RibbonGesture::default(), 0);
+        gen.generate(&just_pressed(semitones(3.3)), 0);
+        assert!(gen.take_note_tunings().is_empty());
+    }
+
+    #[test]
+    fn switching_modes_releases_the_sounding_note() {
+        let mut gen = MidiGenerator::new();
+        gen.generate(&RibbonGesture::default(), 4);
+        let on = note_ons(&gen.generate(&just_pressed(semitones(3.0)), 4));
+
+        gen.set_mode(MidiMode::Mpe);
+        let midi = gen.generate(&pressing(semitones(3.0)), 4);
+        assert_eq!(note_offs(&midi), on);
+        // the MPE note picks up where the standard note left off
+        assert_eq!(note_ons(&midi)[0].1, on[0].1);
+    }
+}
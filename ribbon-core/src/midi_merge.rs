@@ -0,0 +1,301 @@
+//! # MIDI merge
+//!
+//! Forwards the MIDI received on the MIDI input to the MIDI output, merged with the ribbon's own messages, so that the
+//! controller can sit anywhere in a chain of MIDI devices.
+//!
+//! Incoming channel and system common messages join the ribbon's messages in the MIDI transmitter's queue, so they share
+//! its bandwidth budget and running status. Realtime messages such as the timing clock skip the queue and are sent
+//! straight away to keep the timing of the chain tight. SysEx messages are streamed through as they arrive, so they can
+//! be any length, see `MidiTransmitter::forward_sysex`.
+//!
+//! Configuration requests for this ribbon controller are kept to be answered instead of being forwarded, see
+//! `sysex_config`. Requests for every ribbon controller in the chain are kept and forwarded. The first few bytes of each
+//! SysEx message are held back until they show whether it is a request to keep.
+
+use crate::{
+    board::{SerialInput, SerialOutput},
+    midi_parser::{MidiParser, Received},
+    midi_transmitter::MidiTransmitter,
+    sysex_config::{self, Command, ConfigError, REQUEST_HEADER_LEN},
+};
+
+use heapless::{Deque, Vec};
+use midi_convert::midi_types::MidiMessage;
+
+/// What is done with the SysEx message being received is represented here
+enum Route {
+    /// Not known yet, the bytes so far are held back
+    Undecided(Vec<u8, REQUEST_HEADER_LEN>),
+    /// Streamed through to the MIDI output
+    Forward,
+    /// A request for this ribbon controller, not forwarded
+    Keep,
+}
+
+/// The MIDI merge engine is represented here
+pub struct MidiMerge {
+    parser: MidiParser,
+    // what is done with the SysEx message being received, if one is
+    route: Option<Route>,
+
+    // configuration requests waiting to be answered, oldest first
+    requests: Deque<Result<Command, ConfigError>, MAX_WAITING_REQUESTS>,
+}
+
+impl MidiMerge {
+    /// `MidiMerge::new()` is a new merge engine which hasn't received anything yet
+    pub fn new() -> Self {
+        Self {
+            parser: MidiParser::new(),
+            route: None,
+            requests: Deque::new(),
+        }
+    }
+
+    /// `mm.merge(b, mt, d)` forwards everything received on the MIDI input of board `b` to the MIDI transmitter `mt`,
+    /// apart from the configuration requests for device `d`
+    ///
+    /// Must be called once per output update, before the transmitter's queue is sent. The requests are kept until they
+    /// are taken with `take_request`, if too many are waiting the newest are dropped.
+    pub fn merge<B: SerialInput + SerialOutput>(
+        &mut self,
+        board: &mut B,
+        midi: &mut MidiTransmitter,
+        device: u8,
+    ) {
+        let mut chunk = [0; CHUNK_LEN];
+        loop {
+            let len = board.serial_read(&mut chunk);
+            if len == 0 {
+                return;
+            }
+
+            for &byte in &chunk[..len] {
+                self.route_sysex(byte, midi, device);
+
+                match self.parser.parse(byte) {
+                    Some(Received::Message(msg)) if is_realtime(msg) => {
+                        midi.send_realtime(msg, board)
+                    }
+                    Some(Received::Message(msg)) => midi.push(msg),
+                    // the message itself has been forwarded already, or not, as it arrived
+                    Some(Received::SysEx(sysex)) => match Command::parse(sysex) {
+                        Some((target, request)) if sysex_config::addresses(target, device) => {
+                            self.requests.push_back(request).ok();
+                        }
+                        _ => (),
+                    },
+                    None => (),
+                }
+            }
+        }
+    }
+
+    /// `mm.route_sysex(b, mt, d)` forwards received byte `b` to the MIDI transmitter `mt` if it is part of a SysEx message
+    /// which is not a request for device `d`
+    fn route_sysex(&mut self, byte: u8, midi: &mut MidiTransmitter, device: u8) {
+        match byte {
+            SYSEX_START => {
+                self.abandon_sysex(midi);
+                self.route = Some(Route::Undecided(
+                    Vec::from_slice(&[byte]).unwrap_or_default(),
+                ));
+            }
+            // realtime messages don't interrupt anything
+            REALTIME_FIRST..=u8::MAX => (),
+            SYSEX_END => match self.route.take() {
+                // too short to be a request
+                Some(Route::Undecided(held)) => {
+                    midi.forward_sysex(&held);
+                    midi.forward_sysex(&[byte]);
+                }
+                Some(Route::Forward) => midi.forward_sysex(&[byte]),
+                Some(Route::Keep) | None => (),
+            },
+            // any other status byte abandons an unfinished SysEx message
+            STATUS_FIRST..=u8::MAX => self.abandon_sysex(midi),
+            _ => match &mut self.route {
+                Some(Route::Undecided(held)) => {
+                    held.push(byte).ok();
+                    if held.is_full() {
+                        if sysex_config::request_target(held) == Some(device) {
+                            self.route = Some(Route::Keep);
+                        } else {
+                            midi.forward_sysex(held);
+                            self.route = Some(Route::Forward);
+                        }
+                    }
+                }
+                Some(Route::Forward) => midi.forward_sysex(&[byte]),
+                Some(Route::Keep) | None => (),
+            },
+        }
+    }
+
+    /// `mm.abandon_sysex(mt)` gives up on the SysEx message being received, finishing it if some of it has been
+    /// forwarded to the MIDI transmitter `mt` already
+    fn abandon_sysex(&mut self, midi: &mut MidiTransmitter) {
+        if let Some(Route::Forward) = self.route.take() {
+            midi.forward_sysex(&[SYSEX_END]);
+        }
+    }
+
+    /// `mm.take_request()` is the oldest configuration request waiting to be answered, or the error to answer it with if
+    /// it can't be carried out, `None` if there are none
+    pub fn take_request(&mut self) -> Option<Result<Command, ConfigError>> {
+        self.requests.pop_front()
+    }
+}
+
+impl Default for MidiMerge {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// `is_realtime(m)` is true iff message `m` is a one byte system realtime message
+fn is_realtime(msg: MidiMessage) -> bool {
+    matches!(
+        msg,
+        MidiMessage::TimingClock
+            | MidiMessage::Start
+            | MidiMessage::Continue
+            | MidiMessage::Stop
+            | MidiMessage::ActiveSensing
+            | MidiMessage::Reset
+    )
+}
+
+const STATUS_FIRST: u8 = 0x80;
+const SYSEX_START: u8 = 0xF0;
+const SYSEX_END: u8 = 0xF7;
+const REALTIME_FIRST: u8 = 0xF8;
+
+/// The number of received bytes read from the board at a time
+const CHUNK_LEN: usize = 16;
+
+/// The most configuration requests which can wait to be answered
+const MAX_WAITING_REQUESTS: usize = 4;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{mock_board::MockBoard, sysex_config::Param};
+
+    const DEVICE: u8 = 3;
+
+    #[test]
+    fn channel_messages_are_forwarded_through_the_queue() {
+        let mut board = MockBoard::new();
+        let mut merge = MidiMerge::new();
+        let mut midi = MidiTransmitter::new();
+
+        board.serial_rx.extend([0x92, 60, 100, 64, 90]);
+        merge.merge(&mut board, &mut midi, DEVICE);
+        assert!(board.serial_bytes.is_empty());
+
+        midi.push(MidiMessage::NoteOn(0.into(), 48.into(), 127.into()));
+        midi.send_queue(&mut board);
+
+        assert_eq!(
+            board.midi_messages(),
+            [
+                MidiMessage::NoteOn(2.into(), 60.into(), 100.into()),
+                MidiMessage::NoteOn(2.into(), 64.into(), 90.into()),
+                MidiMessage::NoteOn(0.into(), 48.into(), 127.into()),
+            ]
+        );
+        assert!(board.serial_rx.is_empty());
+    }
+
+    #[test]
+    fn realtime_messages_skip_the_queue() {
+        let mut board = MockBoard::new();
+        let mut merge = MidiMerge::new();
+        let mut midi = MidiTransmitter::new();
+
+        midi.push(MidiMessage::NoteOn(0.into(), 48.into(), 127.into()));
+        board.serial_rx.extend([0xFA, 0xF8]);
+        merge.merge(&mut board, &mut midi, DEVICE);
+
+        assert_eq!(board.serial_bytes, [0xFA, 0xF8]);
+
+        midi.send_queue(&mut board);
+        assert_eq!(board.serial_bytes, [0xFA, 0xF8, 0x90, 48, 127]);
+    }
+
+    #[test]
+    fn sysex_is_streamed_through_while_the_queue_is_backed_up() {
+        let mut board = MockBoard::new();
+        let mut merge = MidiMerge::new();
+        let mut midi = MidiTransmitter::new();
+
+        // longer than the parser can hold, arriving a little at a time
+        let sysex: std::vec::Vec<u8> = core::iter::once(0xF0)
+            .chain((0..300).map(|i| (i % 128) as u8))
+            .chain(core::iter::once(0xF7))
+            .collect();
+        for (i, part) in sysex.chunks(8).enumerate() {
+            // more of the ribbon's own messages than the budget can carry
+            for ch in 0..8 {
+                midi.push(MidiMessage::PitchBendChange(ch.into(), (i as u16).into()));
+            }
+            board.serial_rx.extend(part);
+            merge.merge(&mut board, &mut midi, DEVICE);
+            midi.send_queue(&mut board);
+        }
+        for _ in 0..20 {
+            midi.send_queue(&mut board);
+        }
+
+        assert!(board
+            .serial_bytes
+            .windows(sysex.len())
+            .any(|sent| sent == &sysex[..]));
+        assert_eq!(midi.num_dropped(), 0);
+    }
+
+    #[test]
+    fn unfinished_sysex_is_finished_when_another_message_starts() {
+        let mut board = MockBoard::new();
+        let mut merge = MidiMerge::new();
+        let mut midi = MidiTransmitter::new();
+
+        board
+            .serial_rx
+            .extend([0xF0, 0x7E, 0x01, 0x02, 0x03, 0x04, 0x91, 60, 100]);
+        merge.merge(&mut board, &mut midi, DEVICE);
+        midi.send_queue(&mut board);
+
+        assert_eq!(
+            board.serial_bytes,
+            [0xF0, 0x7E, 0x01, 0x02, 0x03, 0x04, 0xF7, 0x91, 60, 100]
+        );
+    }
+
+    #[test]
+    fn configuration_requests_are_kept_to_be_answered() {
+        let mut board = MockBoard::new();
+        let mut merge = MidiMerge::new();
+        let mut midi = MidiTransmitter::new();
+
+        let for_us = Command::Get(Param::Span).sysex(DEVICE);
+        let for_another = Command::Get(Param::Span).sysex(DEVICE + 1);
+        let for_all = Command::Dump.sysex(sysex_config::ALL_DEVICES);
+        board.serial_rx.extend(&for_us);
+        board.serial_rx.extend(&for_another);
+        board.serial_rx.extend(&for_all);
+        merge.merge(&mut board, &mut midi, DEVICE);
+
+        assert_eq!(merge.take_request(), Some(Ok(Command::Get(Param::Span))));
+        assert_eq!(merge.take_request(), Some(Ok(Command::Dump)));
+        assert_eq!(merge.take_request(), None);
+
+        // only the requests for other ribbon controllers go on down the chain
+        for _ in 0..10 {
+            midi.send_queue(&mut board);
+        }
+        let forwarded: std::vec::Vec<u8> = for_another.iter().chain(&for_all).copied().collect();
+        assert_eq!(board.serial_bytes, forwarded);
+    }
+}
@@ -0,0 +1,204 @@
+//! # MIDI input parser
+//!
+//! Turns the bytes received on the MIDI input into messages, one byte at a time as they arrive.
+//!
+//! Channel messages may use running status. Realtime messages such as the timing clock are one byte long and may turn
+//! up anywhere, even in the middle of another message, without disturbing it. SysEx messages are collected whole, from
+//! the `F0` to the `F7`, and any other status byte ends an unfinished SysEx message, which is then dropped.
+
+use crate::sysex_config::MAX_CONFIG_SYSEX_LEN;
+
+use heapless::Vec;
+use midi_convert::{midi_types::MidiMessage, MidiByteStreamParser};
+
+/// The things which can be received on the MIDI input are represented here
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Received<'a> {
+    /// A channel, system common, or realtime message
+    Message(MidiMessage),
+    /// A whole SysEx message, including the `F0` at the start and the `F7` at the end
+    SysEx(&'a [u8]),
+}
+
+/// The MIDI input parser is represented here
+pub struct MidiParser {
+    // channel and system common messages with running status, and realtime messages
+    messages: MidiByteStreamParser,
+
+    // the SysEx message received so far, if one is being received
+    sysex: Vec<u8, MAX_SYSEX_LEN>,
+    in_sysex: bool,
+    // true iff the SysEx message being received is too long to keep
+    sysex_overflowed: bool,
+
+    // the number of SysEx messages dropped because they were too long, wrapping around
+    num_dropped_sysex: u32,
+}
+
+impl MidiParser {
+    /// `MidiParser::new()` is a new parser which is not part way through any message
+    pub fn new() -> Self {
+        Self {
+            messages: MidiByteStreamParser::new(),
+            sysex: Vec::new(),
+            in_sysex: false,
+            sysex_overflowed: false,
+            num_dropped_sysex: 0,
+        }
+    }
+
+    /// `mp.parse(b)` is the message completed by received byte `b`, or `None` if it doesn't complete one
+    pub fn parse(&mut self, byte: u8) -> Option<Received<'_>> {
+        match byte {
+            SYSEX_START => {
+                self.sysex.clear();
+                self.sysex.push(byte).ok();
+                self.in_sysex = true;
+                self.sysex_overflowed = false;
+                // SysEx cancels running status
+                self.messages.parse(byte);
+                None
+            }
+            SYSEX_END if self.in_sysex => {
+                self.in_sysex = false;
+                self.messages.parse(byte);
+                if self.sysex_overflowed || self.sysex.push(byte).is_err() {
+                    self.num_dropped_sysex = self.num_dropped_sysex.wrapping_add(1);
+                    return None;
+                }
+                Some(Received::SysEx(&self.sysex))
+            }
+            // realtime messages don't interrupt anything
+            REALTIME_FIRST..=u8::MAX => self.messages.parse(byte).map(Received::Message),
+            // any other status byte starts a new message, abandoning an unfinished SysEx message
+            STATUS_FIRST..=u8::MAX => {
+                self.in_sysex = false;
+                self.messages.parse(byte).map(Received::Message)
+            }
+            _ if self.in_sysex => {
+                if self.sysex.push(byte).is_err() {
+                    self.sysex_overflowed = true;
+                }
+                None
+            }
+            _ => self.messages.parse(byte).map(Received::Message),
+        }
+    }
+
+    /// `mp.num_dropped_sysex()` is the number of SysEx messages which were dropped because they were longer than
+    /// `MAX_SYSEX_LEN`, wrapping around if it gets that far
+    pub fn num_dropped_sysex(&self) -> u32 {
+        self.num_dropped_sysex
+    }
+}
+
+impl Default for MidiParser {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// The longest SysEx message which can be received, including the `F0` and `F7`, long enough for any configuration
+/// request
+pub const MAX_SYSEX_LEN: usize = MAX_CONFIG_SYSEX_LEN;
+
+const STATUS_FIRST: u8 = 0x80;
+const SYSEX_START: u8 = 0xF0;
+const SYSEX_END: u8 = 0xF7;
+const REALTIME_FIRST: u8 = 0xF8;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::vec::Vec;
+
+    /// `Owned` is an owned copy of a received message, so that a test can keep several of them
+    #[derive(Debug, PartialEq)]
+    enum Owned {
+        Message(MidiMessage),
+        SysEx(Vec<u8>),
+    }
+
+    /// `parse_all(bs)` is every message received in the bytes `bs`, in order
+    fn parse_all(bytes: &[u8]) -> Vec<Owned> {
+        let mut parser = MidiParser::new();
+        bytes
+            .iter()
+            .filter_map(|&b| {
+                parser.parse(b).map(|r| match r {
+                    Received::Message(m) => Owned::Message(m),
+                    Received::SysEx(s) => Owned::SysEx(s.to_vec()),
+                })
+            })
+            .collect()
+    }
+
+    fn note_on(note: u8, vel: u8) -> Owned {
+        Owned::Message(MidiMessage::NoteOn(1.into(), note.into(), vel.into()))
+    }
+
+    #[test]
+    fn running_status_is_followed() {
+        assert_eq!(
+            parse_all(&[0x91, 60, 100, 64, 90, 67, 80]),
+            [note_on(60, 100), note_on(64, 90), note_on(67, 80)]
+        );
+    }
+
+    #[test]
+    fn realtime_bytes_can_come_in_the_middle_of_a_message() {
+        assert_eq!(
+            parse_all(&[0x91, 0xF8, 60, 0xFA, 100, 64, 0xF8, 90]),
+            [
+                Owned::Message(MidiMessage::TimingClock),
+                Owned::Message(MidiMessage::Start),
+                note_on(60, 100),
+                Owned::Message(MidiMessage::TimingClock),
+                note_on(64, 90),
+            ]
+        );
+    }
+
+    #[test]
+    fn sysex_is_collected_whole() {
+        assert_eq!(
+            parse_all(&[0x91, 60, 100, 0xF0, 0x7D, 0xF8, 0x01, 0x02, 0xF7]),
+            [
+                note_on(60, 100),
+                Owned::Message(MidiMessage::TimingClock),
+                Owned::SysEx(std::vec![0xF0, 0x7D, 0x01, 0x02, 0xF7]),
+            ]
+        );
+    }
+
+    #[test]
+    fn running_status_does_not_survive_sysex() {
+        assert_eq!(
+            parse_all(&[0x91, 60, 100, 0xF0, 0x7D, 0xF7, 64, 90]),
+            [note_on(60, 100), Owned::SysEx(std::vec![0xF0, 0x7D, 0xF7])]
+        );
+    }
+
+    #[test]
+    fn unfinished_sysex_is_dropped_when_another_message_starts() {
+        assert_eq!(
+            parse_all(&[0xF0, 0x7D, 0x01, 0x91, 60, 100, 0xF7]),
+            [note_on(60, 100)]
+        );
+    }
+
+    #[test]
+    fn sysex_which_is_too_long_is_dropped() {
+        let mut parser = MidiParser::new();
+        let mut bytes = std::vec![0xF0];
+        bytes.extend(core::iter::repeat_n(0x01, MAX_SYSEX_LEN));
+        bytes.push(0xF7);
+
+        assert!(bytes.iter().all(|&b| parser.parse(b).is_none()));
+        assert_eq!(parser.num_dropped_sysex(), 1);
+
+        // the next one is fine
+        assert!(parser.parse(0xF0).is_none());
+        assert_eq!(parser.parse(0xF7), Some(Received::SysEx(&[0xF0, 0xF7])));
+    }
+}
@@ -0,0 +1,886 @@
+use crate::{
+    board::SerialOutput,
+    midi_generator::{MAX_MIDI_MESSAGES_PER_TICK, MAX_NOTE_TUNINGS_PER_TICK},
+    mts::{NoteTuning, MTS_NOTE_TUNING_LEN},
+    OUTPUT_UPDATE_RATE_HZ,
+};
+
+use heapless::{Deque, Vec};
+use midi_convert::{midi_types::MidiMessage, MidiRenderSlice};
+
+// room for a whole update's worth of messages to wait behind the last update's
+const MAX_NUM_MESSAGES_IN_QUEUE: usize = 2 * MAX_MIDI_MESSAGES_PER_TICK;
+
+// and for the retunes of those updates, which have room of their own so that other messages never crowd them out
+const MAX_NUM_RETUNES_IN_QUEUE: usize = 2 * MAX_NOTE_TUNINGS_PER_TICK;
+
+// MIDI messages may have a variable length, but the ones we care about are no more than 3 bytes long
+const MAX_BYTES_PER_MSG: usize = 3;
+
+/// The most bytes that may be written to the serial port by a single call to `send_queue`
+pub const BYTE_BUFF_LEN: usize =
+    MAX_NUM_MESSAGES_IN_QUEUE * MAX_BYTES_PER_MSG + MAX_NUM_RETUNES_IN_QUEUE * MTS_NOTE_TUNING_LEN;
+
+/// The baud rate of the MIDI serial port
+pub const MIDI_BAUD_RATE_HZ: u32 = 31_250;
+
+/// The number of bytes the MIDI serial port can send in one second, each byte is 10 bits long with the start and stop
+/// bits
+pub const MIDI_BYTES_PER_SECOND: u32 = MIDI_BAUD_RATE_HZ / 10;
+
+/// The number of bytes the MIDI serial port can send in one output update, rounded down
+pub const MIDI_BYTES_PER_UPDATE: i32 = (MIDI_BYTES_PER_SECOND / OUTPUT_UPDATE_RATE_HZ) as i32;
+
+/// The most bytes that may be sent at once after the MIDI output has been quiet for a while
+pub const MAX_MIDI_BURST_LEN: i32 = 32;
+
+/// A very basic MIDI transmitter is represented here.
+///
+/// Messages wait in a queue until the serial port has room for them, so a slow serial port never holds anything up.
+/// When the serial port can't keep up:
+///
+/// * a pitch bend or control change replaces the queued one for the same channel and controller, only the latest value
+///   matters, unless a note on the same channel is queued in between
+///
+/// * a note-off is never dropped, the oldest other queued message is dropped to make room for it if the queue is full
+///
+/// * any other message is dropped if the queue is full
+///
+/// The RPN controllers are never replaced, each message of an RPN matters.
+///
+/// MTS note tunings are queued just ahead of the note-on they retune, so the note can't start before its retune, see
+/// `push_retune`. Once queued they are never dropped or replaced.
+///
+/// Each output update may only send `MIDI_BYTES_PER_UPDATE` bytes, which is what the MIDI baud rate can carry, plus any
+/// left over from quiet updates up to `MAX_MIDI_BURST_LEN`. The fractions of a byte left over by rounding down add up to
+/// an extra byte every few updates, so over time the budget is exactly the baud rate. So when there is more to send than
+/// fits, the messages wait here where they can still be replaced by later values, rather than in the serial port where
+/// they can't.
+///
+/// SysEx messages forwarded from the MIDI input are streamed through a buffer of their own as they arrive, see
+/// `forward_sysex`, so they can be any length. Each one is sent after the messages which were queued before it arrived,
+/// or if another forwarded SysEx message is still waiting, after that one. Once one has started nothing else but realtime
+/// messages can be sent until it is finished, so the messages queued since wait for it.
+///
+/// The bytes are sent with running status, the status byte of a channel message is left out if it is the same as the
+/// one before it. The status byte is sent again after a SysEx message, and after any update with nothing to send so that
+/// a receiver which has just been plugged in soon catches on.
+pub struct MidiTransmitter {
+    msg_queue: Vec<Queued, { MAX_NUM_MESSAGES_IN_QUEUE + MAX_NUM_RETUNES_IN_QUEUE }>,
+    byte_buffer: [u8; BYTE_BUFF_LEN],
+
+    // the status byte of the last channel message sent, which the receiver will assume until it gets another one
+    running_status: Option<u8>,
+    // the number of bytes which may be sent before the budget runs out, negative if a SysEx message overdrew it
+    budget: i32,
+    // the fraction of a byte added to the budget by the updates so far which has not been added yet, in
+    // 1 / OUTPUT_UPDATE_RATE_HZ bytes
+    budget_fraction: u32,
+
+    // forwarded SysEx bytes waiting to be sent
+    thru: Deque<u8, SYSEX_THRU_LEN>,
+    // true iff a forwarded SysEx message has been started on the serial port but not finished
+    thru_started: bool,
+    // true iff the rest of the forwarded SysEx message coming in is dropped, because there was no room for it
+    thru_overflowed: bool,
+    // the number of queued messages which arrived before the next forwarded SysEx message, and are sent before it
+    num_ahead_of_thru: usize,
+
+    // the number of messages dropped, and replaced by a later message, wrapping around
+    num_dropped: u32,
+    num_coalesced: u32,
+}
+
+impl MidiTransmitter {
+    /// `MidiTransmitter::new()` is a new midi transmitter
+    ///
+    /// # Arguments:
+    ///
+    /// * `channel` - The MIDI channel to use
+    pub fn new() -> Self {
+        Self {
+            msg_queue: Vec::new(),
+            byte_buffer: [0; BYTE_BUFF_LEN],
+            running_status: None,
+            budget: MAX_MIDI_BURST_LEN,
+            budget_fraction: 0,
+            thru: Deque::new(),
+            thru_started: false,
+            thru_overflowed: false,
+            num_ahead_of_thru: 0,
+            num_dropped: 0,
+            num_coalesced: 0,
+        }
+    }
+
+    /// `mt.push(m)` pushes the MIDI message `m` onto the message queue
+    pub fn push(&mut self, msg: MidiMessage) {
+        if self.coalesce(msg) {
+            self.num_coalesced = self.num_coalesced.wrapping_add(1);
+            return;
+        }
+
+        if self.num_queued_messages() == MAX_NUM_MESSAGES_IN_QUEUE {
+            self.num_dropped = self.num_dropped.wrapping_add(1);
+            let droppable = |q: &Queued| matches!(q, Queued::Message(m) if !is_note_off(m));
+            match (msg, self.msg_queue.iter().position(droppable)) {
+                (MidiMessage::NoteOff(..), Some(oldest)) => {
+                    self.msg_queue.remove(oldest);
+                    if oldest < self.num_ahead_of_thru {
+                        self.num_ahead_of_thru -= 1;
+                    }
+                }
+                // nothing can make room, the queue is full of note-offs
+                _ => return,
+            }
+        }
+        self.msg_queue.push(Queued::Message(msg)).ok();
+    }
+
+    /// `mt.push_retune(t)` pushes the MTS note tuning `t` onto the message queue, just ahead of the note-on it retunes
+    ///
+    /// The retune is dropped if there is no room left for retunes, see `retune_room`.
+    pub fn push_retune(&mut self, tuning: NoteTuning) {
+        if self.retune_room() == 0 {
+            self.num_dropped = self.num_dropped.wrapping_add(1);
+            return;
+        }
+        self.msg_queue.push(Queued::Retune(tuning)).ok();
+    }
+
+    /// `mt.retune_room()` is the number of MTS note tunings which may be pushed before they are dropped
+    ///
+    /// The room is only used up by the queued retunes, whatever else is queued.
+    pub fn retune_room(&self) -> usize {
+        MAX_NUM_RETUNES_IN_QUEUE - (self.msg_queue.len() - self.num_queued_messages())
+    }
+
+    /// `mt.num_queued_messages()` is the number of queued MIDI messages, not counting the retunes
+    fn num_queued_messages(&self) -> usize {
+        self.msg_queue
+            .iter()
+            .filter(|q| matches!(q, Queued::Message(_)))
+            .count()
+    }
+
+    /// `mt.send_sysex(bs, s)` sends the SysEx message `bs` via the serial port `s` right away, if nothing is waiting to
+    /// be sent ahead of it
+    ///
+    /// The queued messages are sent first if they can be. The message is dropped if any of them are still waiting after
+    /// that, a forwarded SysEx message is still being sent, or the serial port doesn't have room for it, so it is best
+    /// sent once the transmitter `is_idle`. It may overdraw the budget, holding up later messages instead.
+    pub fn send_sysex<S: SerialOutput>(&mut self, sysex: &[u8], serial: &mut S) {
+        self.send_waiting(serial);
+
+        if self.msg_queue.is_empty() && !self.thru_started && sysex.len() <= serial.serial_tx_free()
+        {
+            serial.serial_write_all(sysex);
+            self.budget -= sysex.len() as i32;
+            // SysEx cancels running status
+            self.running_status = None;
+        } else {
+            self.num_dropped = self.num_dropped.wrapping_add(1);
+        }
+    }
+
+    /// `mt.forward_sysex(bs)` adds the bytes `bs` of a SysEx message forwarded from the MIDI input to the ones waiting to be
+    /// sent, they are sent with the queued messages
+    ///
+    /// The bytes may be any part of a message, as they arrive, but every message must be finished with an `F7`. If the
+    /// message comes in faster than it can be sent out, with the ribbon's own messages, the rest of it is dropped and it is
+    /// finished early, counted as one dropped message.
+    pub fn forward_sysex(&mut self, bytes: &[u8]) {
+        for &byte in bytes {
+            match byte {
+                SYSEX_START => {
+                    self.thru_overflowed = false;
+                    if self.thru.is_empty() && !self.thru_started {
+                        self.num_ahead_of_thru = self.msg_queue.len();
+                    }
+                }
+                // there is always room left for the end of the message
+                SYSEX_END => {
+                    self.thru.push_back(byte).ok();
+                    continue;
+                }
+                _ => (),
+            }
+            if self.thru_overflowed {
+                continue;
+            }
+            if SYSEX_THRU_LEN - 1 <= self.thru.len() {
+                self.thru_overflowed = true;
+                self.num_dropped = self.num_dropped.wrapping_add(1);
+                continue;
+            }
+            self.thru.push_back(byte).ok();
+        }
+    }
+
+    /// `mt.send_realtime(m, s)` sends the realtime message `m` such as the timing clock via the serial port `s` right
+    /// away, ahead of any queued messages
+    ///
+    /// Realtime messages may be sent between any other messages without disturbing them, and any delay would make them
+    /// less useful. The message is dropped if the serial port is full, and may overdraw the budget.
+    pub fn send_realtime<S: SerialOutput>(&mut self, msg: MidiMessage, serial: &mut S) {
+        let mut rendered = [0; MAX_BYTES_PER_MSG];
+        let len = msg.render_slice(&mut rendered);
+
+        if len <= serial.serial_tx_free() {
+            serial.serial_write_all(&rendered[..len]);
+            self.budget -= len as i32;
+        } else {
+            self.num_dropped = self.num_dropped.wrapping_add(1);
+        }
+    }
+
+    /// `mt.send_queue(s)` sends as many of the queued MIDI messages as the budget and the serial port `s` have room for,
+    /// in order
+    ///
+    /// Must be called once per output update. The rest stay queued to be sent next time.
+    pub fn send_queue<S: SerialOutput>(&mut self, serial: &mut S) {
+        self.budget_fraction += MIDI_BYTES_PER_SECOND % OUTPUT_UPDATE_RATE_HZ;
+        let extra_byte = (self.budget_fraction / OUTPUT_UPDATE_RATE_HZ) as i32;
+        self.budget_fraction %= OUTPUT_UPDATE_RATE_HZ;
+        self.budget = (self.budget + MIDI_BYTES_PER_UPDATE + extra_byte).min(MAX_MIDI_BURST_LEN);
+
+        if self.msg_queue.is_empty() && self.thru.is_empty() {
+            self.running_status = None;
+        }
+        self.send_waiting(serial);
+    }
+
+    /// `mt.send_waiting(s)` sends as many of the queued MIDI messages and forwarded SysEx bytes as the budget and the
+    /// serial port `s` have room for, in order
+    fn send_waiting<S: SerialOutput>(&mut self, serial: &mut S) {
+        loop {
+            // nothing can go in the middle of a forwarded SysEx message
+            let sent =
+                if self.thru_started || (!self.thru.is_empty() && self.num_ahead_of_thru == 0) {
+                    self.send_thru(serial)
+                } else {
+                    self.send_queued(serial)
+                };
+            if !sent || self.thru_started {
+                return;
+            }
+        }
+    }
+
+    /// `mt.send_thru(s)` sends as much of the next forwarded SysEx message as the budget and the serial port `s` have room
+    /// for, and is true iff anything was sent
+    fn send_thru<S: SerialOutput>(&mut self, serial: &mut S) -> bool {
+        let room = serial
+            .serial_tx_free()
+            .min(BYTE_BUFF_LEN)
+            .min(self.budget.max(0) as usize);
+
+        let mut len = 0;
+        while len < room {
+            match self.thru.pop_front() {
+                Some(byte) => {
+                    self.byte_buffer[len] = byte;
+                    len += 1;
+                    self.thru_started = byte != SYSEX_END;
+                    // the messages queued behind this one may go next
+                    if !self.thru_started {
+                        break;
+                    }
+                }
+                None => break,
+            }
+        }
+
+        if 0 < len {
+            serial.serial_write_all(&self.byte_buffer[..len]);
+            self.budget -= len as i32;
+            // SysEx cancels running status
+            self.running_status = None;
+        }
+        0 < len
+    }
+
+    /// `mt.send_queued(s)` sends as many of the queued MIDI messages as the budget and the serial port `s` have room for,
+    /// in order up to the next forwarded SysEx message, and is true iff anything was sent
+    fn send_queued<S: SerialOutput>(&mut self, serial: &mut S) -> bool {
+        let max_num_sent = if self.thru.is_empty() {
+            self.msg_queue.len()
+        } else {
+            self.num_ahead_of_thru
+        };
+        let room = serial
+            .serial_tx_free()
+            .min(BYTE_BUFF_LEN)
+            .min(self.budget.max(0) as usize);
+
+        let mut i = 0;
+        let mut num_sent = 0;
+        let mut running_status = self.running_status;
+        for queued in self.msg_queue.iter().take(max_num_sent) {
+            // rendering needs room for the longest message, whatever the length of this one
+            let mut rendered = [0; MTS_NOTE_TUNING_LEN];
+            let len = queued.render(&mut rendered);
+            let status = rendered[0];
+
+            let bytes = if running_status == Some(status) {
+                &rendered[1..len]
+            } else {
+                &rendered[..len]
+            };
+            if room < i + bytes.len() {
+                break;
+            }
+            self.byte_buffer[i..(i + bytes.len())].copy_from_slice(bytes);
+            i += bytes.len();
+            num_sent += 1;
+
+            running_status = match status {
+                CHANNEL_STATUS_FIRST..=CHANNEL_STATUS_LAST => Some(status),
+                // system common messages cancel running status, realtime messages leave it alone
+                SYSTEM_COMMON_FIRST..=SYSTEM_COMMON_LAST => None,
+                _ => running_status,
+            };
+        }
+
+        if 0 < i {
+            serial.serial_write_all(&self.byte_buffer[..i]);
+            self.budget -= i as i32;
+            self.running_status = running_status;
+        }
+        for _ in 0..num_sent {
+            self.msg_queue.remove(0);
+        }
+        self.num_ahead_of_thru = self.num_ahead_of_thru.saturating_sub(num_sent);
+        0 < num_sent
+    }
+
+    /// `mt.is_idle()` is true iff nothing is waiting to be sent and the budget is not overdrawn, so that a SysEx message
+    /// sent now goes out without holding anything up
+    pub fn is_idle(&self) -> bool {
+        self.msg_queue.is_empty() && self.thru.is_empty() && !self.thru_started && 0 <= self.budget
+    }
+
+    /// `mt.num_dropped()` is the number of messages which have been dropped because the serial port couldn't keep up,
+    /// wrapping around if it gets that far
+    pub fn num_dropped(&self) -> u32 {
+        self.num_dropped
+    }
+
+    /// `mt.num_coalesced()` is the number of pitch bends and control changes which were replaced by a later value before
+    /// they were sent, wrapping around if it gets that far
+    pub fn num_coalesced(&self) -> u32 {
+        self.num_coalesced
+    }
+
+    /// `mt.coalesce(m)` is true iff message `m` replaced a queued message which it makes stale
+    fn coalesce(&mut self, msg: MidiMessage) -> bool {
+        let key = match CoalescingKey::of(&msg) {
+            Some(key) => key,
+            None => return false,
+        };
+
+        for queued in self.msg_queue.iter_mut().rev() {
+            let queued = match queued {
+                Queued::Message(queued) => queued,
+                Queued::Retune(_) => continue,
+            };
+            if CoalescingKey::of(queued) == Some(key) {
+                *queued = msg;
+                return true;
+            }
+            // a note on the same channel may need the queued value to start at the right pitch or timbre
+            match queued {
+                MidiMessage::NoteOn(ch, ..) | MidiMessage::NoteOff(ch, ..)
+                    if u8::from(*ch) == key.channel() =>
+                {
+                    return false
+                }
+                _ => (),
+            }
+        }
+        false
+    }
+}
+
+impl Default for MidiTransmitter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// The things waiting in the message queue are represented here
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Queued {
+    Message(MidiMessage),
+    Retune(NoteTuning),
+}
+
+impl Queued {
+    /// `q.render(bs)` renders queued `q` into the start of `bs`, and is the number of bytes rendered
+    fn render(&self, bytes: &mut [u8; MTS_NOTE_TUNING_LEN]) -> usize {
+        match self {
+            Self::Message(msg) => msg.render_slice(bytes),
+            Self::Retune(tuning) => {
+                bytes.copy_from_slice(&tuning.sysex());
+                MTS_NOTE_TUNING_LEN
+            }
+        }
+    }
+}
+
+/// The messages which only matter until a later value for the same thing is sent are represented here
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum CoalescingKey {
+    PitchBend { channel: u8 },
+    Control { channel: u8, control: u8 },
+}
+
+impl CoalescingKey {
+    /// `CoalescingKey::of(m)` is the key of message `m`, or `None` if it must never be replaced
+    fn of(msg: &MidiMessage) -> Option<Self> {
+        match *msg {
+            MidiMessage::PitchBendChange(ch, _) => Some(Self::PitchBend { channel: ch.into() }),
+            MidiMessage::ControlChange(ch, control, _) => {
+                let control = u8::from(control);
+                (!RPN_CONTROLS.contains(&control)).then(|| Self::Control {
+                    channel: ch.into(),
+                    control,
+                })
+            }
+            _ => None,
+        }
+    }
+
+    /// `ck.channel()` is the MIDI channel of key `ck`
+    fn channel(&self) -> u8 {
+        match *self {
+            Self::PitchBend { channel } | Self::Control { channel, .. } => channel,
+        }
+    }
+}
+
+/// `is_note_off(m)` is true iff message `m` is a note-off
+fn is_note_off(msg: &MidiMessage) -> bool {
+    matches!(msg, MidiMessage::NoteOff(..))
+}
+
+// data entry MSB and LSB, data increment and decrement, NRPN and RPN LSB and MSB
+const RPN_CONTROLS: [u8; 8] = [6, 38, 96, 97, 98, 99, 100, 101];
+
+/// The most forwarded SysEx bytes which can wait to be sent, enough for the MIDI input to run well ahead of the output
+const SYSEX_THRU_LEN: usize = 256;
+
+const SYSEX_START: u8 = 0xF0;
+const SYSEX_END: u8 = 0xF7;
+
+// the ranges of status bytes which start channel messages and system common messages
+const CHANNEL_STATUS_FIRST: u8 = 0x80;
+const CHANNEL_STATUS_LAST: u8 = 0xEF;
+const SYSTEM_COMMON_FIRST: u8 = 0xF0;
+const SYSTEM_COMMON_LAST: u8 = 0xF7;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock_board::MockBoard;
+
+    fn bend(ch: u8, val: u16) -> MidiMessage {
+        MidiMessage::PitchBendChange(ch.into(), val.into())
+    }
+
+    fn note_off(note: u8) -> MidiMessage {
+        MidiMessage::NoteOff(0.into(), note.into(), 0.into())
+    }
+
+    #[test]
+    fn queued_messages_are_rendered_in_order() {
+        let mut board = MockBoard::new();
+        let mut midi = MidiTransmitter::new();
+
+        midi.push(MidiMessage::NoteOn(2.into(), 60.into(), 127.into()));
+        midi.push(MidiMessage::ControlChange(2.into(), 1.into(), 64.into()));
+        midi.send_queue(&mut board);
+
+        assert_eq!(board.serial_bytes, [0x92, 60, 127, 0xB2, 1, 64]);
+    }
+
+    #[test]
+    fn queue_is_emptied_after_sending() {
+        let mut board = MockBoard::new();
+        let mut midi = MidiTransmitter::new();
+
+        midi.push(MidiMessage::NoteOff(0.into(), 60.into(), 0.into()));
+        midi.send_queue(&mut board);
+        midi.send_queue(&mut board);
+
+        assert_eq!(board.serial_bytes, [0x80, 60, 0]);
+    }
+
+    #[test]
+    fn messages_wait_for_room_in_the_serial_port() {
+        let mut board = MockBoard::new();
+        let mut midi = MidiTransmitter::new();
+
+        midi.push(note_off(60));
+        midi.push(note_off(61));
+        board.serial_tx_free = 4;
+        midi.send_queue(&mut board);
+        assert_eq!(board.serial_bytes, [0x80, 60, 0]);
+
+        // with running status the second note-off only needs 2 bytes
+        board.serial_tx_free = 2;
+        midi.send_queue(&mut board);
+        assert_eq!(board.serial_bytes, [0x80, 60, 0, 61, 0]);
+        assert_eq!(midi.num_dropped(), 0);
+    }
+
+    #[test]
+    fn waiting_bends_and_controls_are_replaced_by_later_values() {
+        let mut board = MockBoard::new();
+        let mut midi = MidiTransmitter::new();
+
+        midi.push(bend(0, 0x1000));
+        midi.push(bend(1, 0x1000));
+        midi.push(MidiMessage::ControlChange(0.into(), 1.into(), 10.into()));
+        midi.push(bend(0, 0x2000));
+        midi.push(MidiMessage::ControlChange(0.into(), 1.into(), 20.into()));
+        midi.send_queue(&mut board);
+
+        assert_eq!(
+            board.serial_bytes,
+            [0xE0, 0x00, 0x40, 0xE1, 0x00, 0x20, 0xB0, 1, 20]
+        );
+        assert_eq!(midi.num_coalesced(), 2);
+    }
+
+    #[test]
+    fn bends_are_not_replaced_across_a_note_on_the_same_channel() {
+        let mut board = MockBoard::new();
+        let mut midi = MidiTransmitter::new();
+
+        midi.push(bend(0, 0x1000));
+        midi.push(MidiMessage::NoteOn(0.into(), 60.into(), 100.into()));
+        midi.push(bend(0, 0x2000));
+        midi.send_queue(&mut board);
+
+        assert_eq!(board.serial_bytes.len(), 9);
+        assert_eq!(midi.num_coalesced(), 0);
+    }
+
+    #[test]
+    fn rpn_messages_are_never_replaced() {
+        let mut board = MockBoard::new();
+        let mut midi = MidiTransmitter::new();
+
+        let rpn = crate::rpn::rpn_messages(0, crate::rpn::RPN_PITCH_BEND_SENSITIVITY, 48, 0);
+        rpn.iter().for_each(|&m| midi.push(m));
+        midi.send_queue(&mut board);
+
+        assert_eq!(board.midi_messages(), rpn);
+        assert_eq!(midi.num_coalesced(), 0);
+    }
+
+    #[test]
+    fn note_offs_make_room_in_a_full_queue() {
+        let mut board = MockBoard::new();
+        let mut midi = MidiTransmitter::new();
+        let program = |ch: usize| MidiMessage::ProgramChange((ch as u8 % 16).into(), 0.into());
+
+        board.serial_tx_free = 0;
+        midi.push(MidiMessage::NoteOn(0.into(), 60.into(), 100.into()));
+        for ch in 1..MAX_NUM_MESSAGES_IN_QUEUE {
+            midi.push(program(ch));
+        }
+        midi.push(program(0));
+        assert_eq!(midi.num_dropped(), 1);
+
+        // the note-on is the oldest message which may be dropped
+        midi.push(note_off(60));
+        assert_eq!(midi.num_dropped(), 2);
+
+        board.serial_tx_free = usize::MAX;
+        for _ in 0..10 {
+            midi.send_queue(&mut board);
+        }
+        let sent = board.midi_messages();
+        assert_eq!(sent.len(), MAX_NUM_MESSAGES_IN_QUEUE);
+        assert_eq!(sent[0], program(1));
+        assert_eq!(sent.last(), Some(&note_off(60)));
+        assert!(!sent.iter().any(|m| matches!(m, MidiMessage::NoteOn(..))));
+    }
+
+    #[test]
+    fn sysex_waits_behind_queued_messages_or_is_dropped() {
+        let mut board = MockBoard::new();
+        let mut midi = MidiTransmitter::new();
+        let sysex = [0xF0, 0x7D, 0x01, 0xF7];
+
+        midi.push(note_off(60));
+        board.serial_tx_free = 6;
+        midi.send_sysex(&sysex, &mut board);
+        assert_eq!(board.serial_bytes, [0x80, 60, 0]);
+        assert_eq!(midi.num_dropped(), 1);
+
+        board.serial_tx_free = 4;
+        midi.send_sysex(&sysex, &mut board);
+        assert_eq!(board.serial_bytes[3..], sysex);
+    }
+
+    #[test]
+    fn retunes_go_ahead_of_their_note_and_are_never_dropped_once_queued() {
+        let mut board = MockBoard::new();
+        let mut midi = MidiTransmitter::new();
+        let retune = NoteTuning::new(60, 60.5);
+        let note_on = MidiMessage::NoteOn(0.into(), 60.into(), 100.into());
+
+        board.serial_tx_free = 0;
+        midi.forward_sysex(&[0xF0, 0x7D]);
+        midi.push_retune(retune);
+        midi.push(note_on);
+        // note-offs make room by dropping the note-on, but not its retune
+        for note in 0..MAX_NUM_MESSAGES_IN_QUEUE as u8 {
+            midi.push(note_off(note));
+        }
+        assert_eq!(midi.retune_room(), MAX_NUM_RETUNES_IN_QUEUE - 1);
+
+        board.serial_tx_free = usize::MAX;
+        midi.forward_sysex(&[0xF7]);
+        for _ in 0..100 {
+            midi.send_queue(&mut board);
+        }
+        let sent = &board.serial_bytes;
+        assert_eq!(sent[..3], [0xF0, 0x7D, 0xF7]);
+        assert_eq!(sent[3..3 + MTS_NOTE_TUNING_LEN], retune.sysex());
+        assert!(!board
+            .midi_messages()
+            .iter()
+            .any(|m| matches!(m, MidiMessage::NoteOn(..))));
+        assert_eq!(midi.num_dropped(), 1);
+    }
+
+    #[test]
+    fn retunes_are_dropped_without_room_whatever_else_is_queued() {
+        let mut board = MockBoard::new();
+        let mut midi = MidiTransmitter::new();
+
+        board.serial_tx_free = 0;
+        for note in 0..MAX_NUM_MESSAGES_IN_QUEUE as u8 {
+            midi.push(note_off(note));
+        }
+        for key in 0..MAX_NUM_RETUNES_IN_QUEUE as u8 {
+            assert_eq!(midi.retune_room(), MAX_NUM_RETUNES_IN_QUEUE - key as usize);
+            midi.push_retune(NoteTuning::new(key, key as f32));
+        }
+        assert_eq!(midi.retune_room(), 0);
+        assert_eq!(midi.num_dropped(), 0);
+
+        midi.push_retune(NoteTuning::new(100, 100.0));
+        assert_eq!(midi.num_dropped(), 1);
+
+        board.serial_tx_free = usize::MAX;
+        for _ in 0..100 {
+            midi.send_queue(&mut board);
+        }
+        assert_eq!(midi.retune_room(), MAX_NUM_RETUNES_IN_QUEUE);
+    }
+
+    #[test]
+    fn repeated_status_bytes_are_left_out() {
+        let mut board = MockBoard::new();
+        let mut midi = MidiTransmitter::new();
+
+        midi.push(MidiMessage::NoteOn(0.into(), 60.into(), 100.into()));
+        midi.push(MidiMessage::NoteOn(0.into(), 64.into(), 100.into()));
+        midi.push(bend(0, 0x2000));
+        midi.push(MidiMessage::NoteOn(0.into(), 67.into(), 100.into()));
+        midi.send_queue(&mut board);
+        midi.push(MidiMessage::NoteOn(0.into(), 72.into(), 100.into()));
+        midi.send_queue(&mut board);
+
+        assert_eq!(
+            board.serial_bytes,
+            [0x90, 60, 100, 64, 100, 0xE0, 0x00, 0x40, 0x90, 67, 100, 72, 100]
+        );
+    }
+
+    #[test]
+    fn sysex_and_quiet_updates_send_the_status_again() {
+        let mut board = MockBoard::new();
+        let mut midi = MidiTransmitter::new();
+
+        midi.push(note_off(60));
+        midi.send_queue(&mut board);
+        midi.send_sysex(&[0xF0, 0x7D, 0xF7], &mut board);
+        midi.push(note_off(61));
+        midi.send_queue(&mut board);
+        midi.send_queue(&mut board);
+        midi.push(note_off(62));
+        midi.send_queue(&mut board);
+
+        assert_eq!(
+            board.serial_bytes,
+            [0x80, 60, 0, 0xF0, 0x7D, 0xF7, 0x80, 61, 0, 0x80, 62, 0]
+        );
+    }
+
+    #[test]
+    fn each_update_stays_within_the_budget() {
+        let mut board = MockBoard::new();
+        let mut midi = MidiTransmitter::new();
+        let num_updates = 300;
+
+        let mut sent_per_update = std::vec::Vec::new();
+        for i in 0..num_updates {
+            // much more than the MIDI baud rate can carry
+            for ch in 0..8 {
+                midi.push(bend(ch, i));
+            }
+            let before = board.serial_bytes.len();
+            midi.send_queue(&mut board);
+            sent_per_update.push(board.serial_bytes.len() - before);
+        }
+
+        assert!(sent_per_update
+            .iter()
+            .all(|&n| n <= MAX_MIDI_BURST_LEN as usize));
+        let total: usize = sent_per_update.iter().sum();
+        let baud_rate_bytes = MIDI_BYTES_PER_SECOND * num_updates as u32 / OUTPUT_UPDATE_RATE_HZ;
+        assert!(total <= MAX_MIDI_BURST_LEN as usize + baud_rate_bytes as usize);
+        assert_eq!(midi.num_dropped(), 0);
+    }
+
+    #[test]
+    fn forwarded_sysex_keeps_up_with_the_baud_rate() {
+        let mut board = MockBoard::new();
+        let mut midi = MidiTransmitter::new();
+
+        // a long message arriving as fast as the MIDI input can carry it, for 10 seconds
+        let num_updates = 10 * OUTPUT_UPDATE_RATE_HZ;
+        let len = (MIDI_BYTES_PER_SECOND * num_updates / OUTPUT_UPDATE_RATE_HZ) as usize;
+        let sysex: std::vec::Vec<u8> = core::iter::once(0xF0)
+            .chain((0..len - 2).map(|i| (i % 128) as u8))
+            .chain(core::iter::once(0xF7))
+            .collect();
+
+        let mut arrived = 0;
+        for update in 1..=num_updates as usize {
+            let due = len * update / num_updates as usize;
+            midi.forward_sysex(&sysex[arrived..due]);
+            arrived = due;
+            // the ribbon is being played at the same time
+            midi.push(bend(0, update as u16));
+            midi.send_queue(&mut board);
+        }
+        for _ in 0..10 {
+            midi.send_queue(&mut board);
+        }
+
+        assert_eq!(midi.num_dropped(), 0);
+        assert_eq!(board.serial_bytes[..len], sysex[..]);
+        // the ribbon's latest pitch bend comes out once the message is done
+        assert_eq!(
+            board.midi_messages().last(),
+            Some(&bend(0, num_updates as u16))
+        );
+    }
+
+    #[test]
+    fn forwarded_sysex_which_falls_behind_is_finished_early() {
+        let mut board = MockBoard::new();
+        let mut midi = MidiTransmitter::new();
+
+        board.serial_tx_free = 0;
+        midi.forward_sysex(&[0xF0]);
+        midi.forward_sysex(&[0x01; 2 * SYSEX_THRU_LEN]);
+        midi.forward_sysex(&[0xF7]);
+        assert_eq!(midi.num_dropped(), 1);
+
+        board.serial_tx_free = usize::MAX;
+        for _ in 0..100 {
+            midi.send_queue(&mut board);
+        }
+        assert_eq!(board.serial_bytes.len(), SYSEX_THRU_LEN);
+        assert_eq!(board.serial_bytes.last(), Some(&0xF7));
+
+        // nothing is held up afterwards
+        midi.push(note_off(60));
+        midi.send_queue(&mut board);
+        assert_eq!(board.serial_bytes[SYSEX_THRU_LEN..], [0x80, 60, 0]);
+    }
+
+    #[test]
+    fn rendered_bytes_decode_to_the_messages_pushed() {
+        let mut board = MockBoard::new();
+        let mut midi = MidiTransmitter::new();
+
+        // a mix of messages which is never more than the budget, so nothing is held back or replaced
+        let mut pushed = std::vec::Vec::new();
+        for i in 0..200_u16 {
+            let ch = (i % 3) as u8;
+            let note = 40 + (i % 30) as u8;
+            let update: std::vec::Vec<MidiMessage> = match i % 4 {
+                0 => std::vec![
+                    MidiMessage::NoteOn(ch.into(), note.into(), 100.into()),
+                    MidiMessage::NoteOn(ch.into(), (note + 12).into(), 100.into()),
+                    bend(ch, i * 40),
+                ],
+                1 => std::vec![MidiMessage::ControlChange(
+                    ch.into(),
+                    1.into(),
+                    ((i % 128) as u8).into()
+                )],
+                2 => std::vec![
+                    MidiMessage::ProgramChange(ch.into(), 5.into()),
+                    MidiMessage::ChannelPressure(ch.into(), 9.into()),
+                ],
+                _ => std::vec![
+                    MidiMessage::NoteOff(ch.into(), note.into(), 64.into()),
+                    MidiMessage::NoteOff(ch.into(), (note + 12).into(), 64.into()),
+                ],
+            };
+            for msg in update {
+                midi.push(msg);
+                pushed.push(msg);
+            }
+            midi.send_queue(&mut board);
+        }
+
+        assert_eq!(board.midi_messages(), pushed);
+        // and running status saved some bytes
+        let full_len: usize = pushed.iter().map(|m| m.len()).sum();
+        assert!(board.serial_bytes.len() < full_len);
+    }
+
+    #[test]
+    fn held_back_bends_decode_to_the_latest_values() {
+        let mut board = MockBoard::new();
+        let mut midi = MidiTransmitter::new();
+
+        for i in 0..100_u16 {
+            for ch in 0..6 {
+                midi.push(bend(ch, i * 100 + ch as u16));
+            }
+            midi.send_queue(&mut board);
+        }
+        // let everything drain
+        for _ in 0..10 {
+            midi.send_queue(&mut board);
+        }
+
+        let sent = board.midi_messages();
+        assert!(sent.len() < 600);
+        assert!(0 < midi.num_coalesced());
+        for ch in 0..6 {
+            let values: std::vec::Vec<u16> = sent
+                .iter()
+                .filter_map(|m| match m {
+                    MidiMessage::PitchBendChange(c, v) if u8::from(*c) == ch => Some(u16::from(*v)),
+                    _ => None,
+                })
+                .collect();
+            // each channel's bends are in order and end with the latest value
+            assert!(values.windows(2).all(|w| w[0] < w[1]));
+            assert_eq!(values.last(), Some(&(99 * 100 + ch as u16)));
+        }
+    }
+}
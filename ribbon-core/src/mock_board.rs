@@ -0,0 +1,273 @@
+//! # Mock board
+//!
+//! A stand-in for the physical board which can be used in host-side tests.
+//!
+//! The inputs (ADC readings, switch positions, received serial bytes, and timer timeouts) are set directly by the test,
+//! and every output the code under test produces (DAC commands, gate changes, and serial bytes) is recorded so that the
+//! test can make assertions about it. The settings flash pages are kept in RAM.
+
+use crate::{
+    board::{
+        dac8162_words, AdcInputs, AdcPin, Dac8162Channel, DacOutputs, FlashError, GateOutput,
+        PanelSwitches, PeriodicTimers, SerialInput, SerialOutput, SettingsFlash, Switch3wayState,
+        NUM_ADC_PINS,
+    },
+    dac_calibration::DacCalibration,
+    settings::RamFlash,
+};
+
+use core::cell::Cell;
+use midi_convert::{midi_types::MidiMessage, MidiByteStreamParser};
+use std::{collections::VecDeque, vec::Vec};
+
+/// A recording board for host-side tests is represented here
+pub struct MockBoard {
+    /// The values returned by `read_adc`, indexed by pin
+    pub adc: [f32; NUM_ADC_PINS],
+    /// The value returned by `read_mode_switch`
+    pub mode_switch: Switch3wayState,
+    /// The value returned by `read_midi_ch_switch`
+    pub midi_ch_switch: u8,
+
+    /// The correction applied to every voltage written to the DAC
+    pub dac_calibration: DacCalibration,
+
+    /// Every SPI command written to the DAC, in order
+    pub dac_words: Vec<[u8; 3]>,
+    /// Every value written to the gate output, in order
+    pub gate_writes: Vec<bool>,
+    /// Every byte written to the serial port, in order
+    pub serial_bytes: Vec<u8>,
+    /// The value returned by `serial_tx_free`, less the bytes written since it was set
+    pub serial_tx_free: usize,
+    /// Bytes received by the serial port which have not been read yet, oldest first
+    pub serial_rx: VecDeque<u8>,
+
+    /// The settings flash pages
+    pub flash: RamFlash,
+
+    tim2_timeout: Cell<bool>,
+    tim6_timeout: Cell<bool>,
+    tim15_timeout: Cell<bool>,
+}
+
+impl MockBoard {
+    /// `MockBoard::new()` is a new mock board with grounded ADC inputs, the mode switch down, MIDI channel 0, and no
+    /// recorded outputs
+    pub fn new() -> Self {
+        Self {
+            adc: [0.0_f32; NUM_ADC_PINS],
+            mode_switch: Switch3wayState::Down,
+            midi_ch_switch: 0,
+            dac_calibration: DacCalibration::new(),
+            dac_words: Vec::new(),
+            gate_writes: Vec::new(),
+            serial_bytes: Vec::new(),
+            serial_tx_free: usize::MAX,
+            serial_rx: VecDeque::new(),
+            flash: RamFlash::new(),
+            tim2_timeout: Cell::new(false),
+            tim6_timeout: Cell::new(false),
+            tim15_timeout: Cell::new(false),
+        }
+    }
+
+    /// `mb.set_adc(p, v)` sets the value that will be read from ADC pin `p` to `v`
+    pub fn set_adc(&mut self, pin: AdcPin, val: f32) {
+        self.adc[pin as usize] = val;
+    }
+
+    /// `mb.expire_tim2()` makes the next call to `get_tim2_timeout` return true
+    pub fn expire_tim2(&mut self) {
+        self.tim2_timeout.set(true);
+    }
+
+    /// `mb.expire_tim6()` makes the next call to `get_tim6_timeout` return true
+    pub fn expire_tim6(&mut self) {
+        self.tim6_timeout.set(true);
+    }
+
+    /// `mb.expire_tim15()` makes the next call to `get_tim15_timeout` return true
+    pub fn expire_tim15(&mut self) {
+        self.tim15_timeout.set(true);
+    }
+
+    /// `mb.dac_words_for(c)` is every SPI command written to DAC channel `c`, in order
+    pub fn dac_words_for(&self, channel: Dac8162Channel) -> Vec<[u8; 3]> {
+        self.dac_words
+            .iter()
+            .filter(|w| w[0] & 0b0000_0111 == channel as u8)
+            .copied()
+            .collect()
+    }
+
+    /// `mb.gate_edges()` is the gate writes with repeated values removed, i.e. only the changes of state
+    pub fn gate_edges(&self) -> Vec<bool> {
+        let mut edges = Vec::new();
+        let mut last = false;
+        for &g in &self.gate_writes {
+            if g != last {
+                edges.push(g);
+                last = g;
+            }
+        }
+        edges
+    }
+
+    /// `mb.midi_messages()` is every MIDI message in the bytes written to the serial port, in order, SysEx is skipped
+    pub fn midi_messages(&self) -> Vec<MidiMessage> {
+        let mut parser = MidiByteStreamParser::new();
+        self.serial_bytes
+            .iter()
+            .filter_map(|&b| parser.parse(b))
+            .collect()
+    }
+
+    /// `mb.clear_outputs()` forgets all of the recorded outputs
+    pub fn clear_outputs(&mut self) {
+        self.dac_words.clear();
+        self.gate_writes.clear();
+        self.serial_bytes.clear();
+    }
+}
+
+impl Default for MockBoard {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl AdcInputs for MockBoard {
+    fn read_adc(&mut self, pin: AdcPin) -> f32 {
+        self.adc[pin as usize]
+    }
+}
+
+impl DacOutputs for MockBoard {
+    fn dac8162_set_vout(&mut self, v_out: f32, channel: Dac8162Channel) {
+        let v_out = self.dac_calibration.apply(v_out, channel);
+        self.dac_words.push(dac8162_words(v_out, channel));
+    }
+
+    fn set_dac_calibration(&mut self, calibration: DacCalibration) {
+        self.dac_calibration = calibration;
+    }
+}
+
+impl GateOutput for MockBoard {
+    fn set_gate(&mut self, val: bool) {
+        self.gate_writes.push(val);
+    }
+}
+
+impl PanelSwitches for MockBoard {
+    fn read_mode_switch(&self) -> Switch3wayState {
+        self.mode_switch
+    }
+
+    fn read_midi_ch_switch(&self) -> u8 {
+        self.midi_ch_switch
+    }
+}
+
+impl SerialOutput for MockBoard {
+    fn serial_tx_free(&self) -> usize {
+        self.serial_tx_free
+    }
+
+    fn serial_write_all(&mut self, bytes: &[u8]) {
+        assert!(bytes.len() <= self.serial_tx_free, "serial port overflow");
+        self.serial_tx_free -= bytes.len();
+        self.serial_bytes.extend_from_slice(bytes);
+    }
+}
+
+impl SerialInput for MockBoard {
+    fn serial_read(&mut self, bytes: &mut [u8]) -> usize {
+        let len = bytes.len().min(self.serial_rx.len());
+        bytes
+            .iter_mut()
+            .zip(self.serial_rx.drain(..len))
+            .for_each(|(b, rx)| *b = rx);
+        len
+    }
+}
+
+impl PeriodicTimers for MockBoard {
+    fn get_tim2_timeout(&self) -> bool {
+        self.tim2_timeout.replace(false)
+    }
+
+    fn get_tim6_timeout(&self) -> bool {
+        self.tim6_timeout.replace(false)
+    }
+
+    fn get_tim15_timeout(&self) -> bool {
+        self.tim15_timeout.replace(false)
+    }
+}
+
+impl SettingsFlash for MockBoard {
+    fn settings_read(&mut self, page: usize, offset: usize, bytes: &mut [u8]) {
+        self.flash.settings_read(page, offset, bytes);
+    }
+
+    fn settings_erase(&mut self, page: usize) -> Result<(), FlashError> {
+        self.flash.settings_erase(page)
+    }
+
+    fn settings_write(
+        &mut self,
+        page: usize,
+        offset: usize,
+        bytes: &[u8],
+    ) -> Result<(), FlashError> {
+        self.flash.settings_write(page, offset, bytes)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn timeouts_are_self_clearing() {
+        let mut board = MockBoard::new();
+        assert!(!board.get_tim2_timeout());
+
+        board.expire_tim2();
+        assert!(!board.get_tim6_timeout());
+        assert!(board.get_tim2_timeout());
+        assert!(!board.get_tim2_timeout());
+    }
+
+    #[test]
+    fn dac_words_are_recorded_per_channel() {
+        let mut board = MockBoard::new();
+        board.dac8162_set_vout(1.0, Dac8162Channel::A);
+        board.dac8162_set_vout(2.0, Dac8162Channel::B);
+        board.dac8162_set_vout(3.0, Dac8162Channel::A);
+
+        assert_eq!(
+            board.dac_words_for(Dac8162Channel::A),
+            [
+                dac8162_words(1.0, Dac8162Channel::A),
+                dac8162_words(3.0, Dac8162Channel::A)
+            ]
+        );
+        assert_eq!(
+            board.dac_words_for(Dac8162Channel::B),
+            [dac8162_words(2.0, Dac8162Channel::B)]
+        );
+    }
+
+    #[test]
+    fn gate_edges_ignore_repeated_writes() {
+        let mut board = MockBoard::new();
+        [false, true, true, true, false, false, true]
+            .iter()
+            .for_each(|&g| board.set_gate(g));
+
+        assert_eq!(board.gate_edges(), [true, false, true]);
+    }
+}
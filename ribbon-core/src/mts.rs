@@ -0,0 +1,105 @@
+//! # MIDI Tuning Standard
+//!
+//! Receivers which support the MIDI Tuning Standard (MTS) can retune any of their 128 keys to an arbitrary pitch with
+//! a real-time single note tuning change SysEx message. The new tuning takes effect immediately, so retuning a key just
+//! before it is played makes it sound at the exact pitch without relying on pitch bend or the bend range of the
+//! receiver.
+//!
+//! ```text
+//! F0 7F <device> 08 02 <program> <count> [<key> <semitone> <fraction MSB> <fraction LSB>]... F7
+//! ```
+//!
+//! Only one key is retuned per message, and the message is sent to every device and tuning program 0.
+
+/// A tuning change for a single key is represented here
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct NoteTuning {
+    /// The MIDI key to retune, in `[0..127]`
+    pub key: u8,
+    /// The pitch to tune the key to in fractional MIDI note numbers
+    pub pitch: f32,
+}
+
+impl NoteTuning {
+    /// `NoteTuning::new(k, p)` is a tuning change which tunes key `k` to pitch `p` in fractional MIDI note numbers
+    pub fn new(key: u8, pitch: f32) -> Self {
+        Self { key, pitch }
+    }
+
+    /// `t.sysex()` is tuning change `t` as a real-time single note tuning change SysEx message
+    ///
+    /// The pitch is clamped to the range MTS can express, and rounded to the nearest 1/16384 of a semitone.
+    pub fn sysex(&self) -> [u8; MTS_NOTE_TUNING_LEN] {
+        let steps =
+            (self.pitch.clamp(0.0_f32, MAX_MTS_PITCH) * FRACTION_STEPS as f32 + 0.5_f32) as u32;
+        let steps = steps.min(MAX_MTS_STEPS);
+        let semitone = (steps / FRACTION_STEPS) as u8;
+        let fraction = (steps % FRACTION_STEPS) as u16;
+
+        [
+            SYSEX_START,
+            UNIVERSAL_REALTIME,
+            ALL_DEVICES,
+            MTS_SUB_ID,
+            NOTE_CHANGE_SUB_ID,
+            TUNING_PROGRAM,
+            1,
+            self.key & 0x7F,
+            semitone,
+            (fraction >> 7) as u8,
+            (fraction & 0x7F) as u8,
+            SYSEX_END,
+        ]
+    }
+}
+
+/// The number of bytes in a single note tuning change message
+pub const MTS_NOTE_TUNING_LEN: usize = 12;
+
+const SYSEX_START: u8 = 0xF0;
+const SYSEX_END: u8 = 0xF7;
+const UNIVERSAL_REALTIME: u8 = 0x7F;
+const ALL_DEVICES: u8 = 0x7F;
+const MTS_SUB_ID: u8 = 0x08;
+const NOTE_CHANGE_SUB_ID: u8 = 0x02;
+const TUNING_PROGRAM: u8 = 0;
+
+// the fraction of a semitone is 14 bits
+const FRACTION_STEPS: u32 = 1 << 14;
+
+// semitone 127 with all fraction bits set means "no change", so the highest pitch is one step below that
+const MAX_MTS_STEPS: u32 = 128 * FRACTION_STEPS - 2;
+const MAX_MTS_PITCH: f32 = 128.0_f32;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn in_tune_pitch_has_no_fraction() {
+        assert_eq!(
+            NoteTuning::new(69, 69.0).sysex(),
+            [0xF0, 0x7F, 0x7F, 0x08, 0x02, 0, 1, 69, 69, 0, 0, 0xF7]
+        );
+    }
+
+    #[test]
+    fn fraction_is_fourteen_bits_msb_first() {
+        // a quarter tone above middle C is half of the 14 bit fraction
+        let msg = NoteTuning::new(60, 60.5).sysex();
+        assert_eq!(msg[7..11], [60, 60, 0x40, 0x00]);
+
+        // a key may be tuned to a pitch other than its own
+        let msg = NoteTuning::new(61, 60.25).sysex();
+        assert_eq!(msg[7..11], [61, 60, 0x20, 0x00]);
+    }
+
+    #[test]
+    fn pitch_is_clamped_to_the_mts_range() {
+        assert_eq!(NoteTuning::new(0, -3.0).sysex()[8..11], [0, 0, 0]);
+        assert_eq!(
+            NoteTuning::new(127, 200.0).sysex()[8..11],
+            [127, 0x7F, 0x7E]
+        );
+    }
+}
@@ -0,0 +1,87 @@
+//! # Overruns
+//!
+//! Each periodic task of the application has a deadline, it must be done before its timer next times out. A task which
+//! is still running when that happens has overrun, and the next period starts late.
+//!
+//! The firmware counts the overruns of each task here. The counts are atomic so that they can be recorded from interrupt
+//! handlers of any priority and read from anywhere else.
+
+use core::sync::atomic::{AtomicU32, Ordering};
+
+/// The periodic tasks of the application are represented here, from the highest priority to the lowest
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Task {
+    /// Samples the ribbons at `RIBBON_SAMPLE_RATE_HZ`
+    RibbonSampling = 0,
+    /// Updates the analog and MIDI outputs at `OUTPUT_UPDATE_RATE_HZ`
+    OutputUpdate = 1,
+    /// Reads the panel controls and saves the settings at `UI_UPDATE_RATE_HZ`
+    Ui = 2,
+}
+
+/// The number of periodic tasks
+pub const NUM_TASKS: usize = 3;
+
+/// The overrun count of each task is represented here
+pub struct Overruns {
+    counts: [AtomicU32; NUM_TASKS],
+}
+
+impl Overruns {
+    /// `Overruns::new()` is a new set of counts with no overruns
+    pub const fn new() -> Self {
+        Self {
+            counts: [AtomicU32::new(0), AtomicU32::new(0), AtomicU32::new(0)],
+        }
+    }
+
+    /// `o.record(t)` counts a missed deadline of task `t`
+    pub fn record(&self, task: Task) {
+        self.counts[task as usize].fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// `o.count(t)` is the number of deadlines task `t` has missed, wrapping around if it gets that far
+    pub fn count(&self, task: Task) -> u32 {
+        self.counts[task as usize].load(Ordering::Relaxed)
+    }
+
+    /// `o.total()` is the number of deadlines missed by all of the tasks together
+    pub fn total(&self) -> u32 {
+        self.counts
+            .iter()
+            .fold(0, |acc, c| acc.wrapping_add(c.load(Ordering::Relaxed)))
+    }
+}
+
+impl Default for Overruns {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn each_task_is_counted_separately() {
+        let overruns = Overruns::new();
+        assert_eq!(overruns.total(), 0);
+
+        overruns.record(Task::OutputUpdate);
+        overruns.record(Task::OutputUpdate);
+        overruns.record(Task::Ui);
+
+        assert_eq!(overruns.count(Task::RibbonSampling), 0);
+        assert_eq!(overruns.count(Task::OutputUpdate), 2);
+        assert_eq!(overruns.count(Task::Ui), 1);
+        assert_eq!(overruns.total(), 3);
+    }
+
+    #[test]
+    fn counts_can_be_recorded_through_a_shared_static() {
+        static OVERRUNS: Overruns = Overruns::new();
+        OVERRUNS.record(Task::RibbonSampling);
+        assert_eq!(OVERRUNS.count(Task::RibbonSampling), 1);
+    }
+}
@@ -0,0 +1,1020 @@
+//! # Pitch engine
+//!
+//! The pitch engine turns readings from the main and MOD ribbons into the analog control voltages, the gate signal,
+//! and the MIDI messages produced by the ribbon controller.
+//!
+//! # Inputs
+//!
+//! * Raw ribbon samples, polled at `RIBBON_SAMPLE_RATE_HZ`
+//!
+//! * The pitch mode and MIDI channel, supplied each time the outputs are updated
+//!
+//! A tap on the main ribbon while the MOD ribbon is held at its top changes the transpose instead of playing a note, see
+//! `transpose`.
+//!
+//! # Outputs
+//!
+//! * The `RIBBON CV` and `MOD CV` voltages, the `GATE` state, and zero or more MIDI messages
+
+use crate::{
+    board::DAC8162_MAX_VOUT,
+    midi_generator::{
+        midi_pitch_to_volts, volts_to_midi_pitch, MidiGenerator, MidiMessages, MidiMode,
+        NoteTunings, RibbonGesture, TransitionPolicy,
+    },
+    ribbon_calibration::RibbonCalibration,
+    scale::{Scale, MAX_ROOT},
+    transpose::{Transpose, TRANSPOSE_EDIT_MOD_THRESHOLD},
+    tuning::TuningTable,
+    velocity::{VelocityCurve, VelocityDetector, MAX_VELOCITY},
+    OUTPUT_UPDATE_RATE_HZ, RIBBON_SAMPLE_RATE_HZ,
+};
+
+use synth_utils::{
+    glide_processor::GlideProcessor,
+    quantizer::{self, Quantizer},
+    ribbon_controller::{self, RibbonController},
+};
+
+/// There are three modes for the ribbon pitch information
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum PitchMode {
+    HardQuantize,
+    Assist,
+    Smooth,
+}
+
+/// The number of semitones covered by the main ribbon from end to end are represented here
+///
+/// A wide span has more range, a narrow span spreads the notes out so they are easier to hit.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum RibbonSpan {
+    /// One octave
+    Semitones12,
+    /// Two octaves
+    Semitones24,
+    /// About 2 1/2 octaves, the lowest note is F and the highest note is C
+    #[default]
+    Semitones32,
+    /// Four octaves
+    Semitones48,
+    /// Five octaves, the full range of the `RIBBON CV` output
+    Semitones60,
+}
+
+impl RibbonSpan {
+    /// `s.semitones()` is the number of semitones covered by span `s`
+    pub fn semitones(&self) -> u8 {
+        match self {
+            RibbonSpan::Semitones12 => 12,
+            RibbonSpan::Semitones24 => 24,
+            RibbonSpan::Semitones32 => 32,
+            RibbonSpan::Semitones48 => 48,
+            RibbonSpan::Semitones60 => 60,
+        }
+    }
+
+    /// `s.max_vout()` is the 1volt/octave voltage at the top of the main ribbon with span `s`
+    pub fn max_vout(&self) -> f32 {
+        self.semitones() as f32 * quantizer::SEMITONE_WIDTH
+    }
+}
+
+/// The pitch engine which converts ribbon readings into CV, gate, and MIDI is represented here
+pub struct PitchEngine {
+    // main ribbon for playing notes
+    main_ribbon: Ribbon,
+    // smaller aux ribbon which acts like a mod-wheel
+    mod_ribbon: Ribbon,
+    // maps the ribbon readings to finger positions
+    ribbon_calibration: RibbonCalibration,
+
+    // the number of semitones from one end of the main ribbon to the other
+    span: RibbonSpan,
+
+    // quantizer for converting the raw ribbon reading to 1v/oct analog steps
+    ribbon_quantizer: Quantizer,
+    // the scale both quantizers snap to
+    scale: Scale,
+    scale_root: u8,
+    // a microtonal tuning which replaces the scale, if any
+    tuning: Option<TuningTable>,
+
+    glide: GlideProcessor,
+
+    // used in ASSIST pitch mode
+    offset_when_finger_pressed_down: f32,
+
+    // works out how hard the finger landed on the main ribbon
+    velocity_detector: VelocityDetector,
+    // the velocities of the most recent press and release
+    velocity: u8,
+    release_velocity: u8,
+    finger_was_pressing: bool,
+
+    // shifts both the CV and MIDI outputs
+    transpose: Transpose,
+    // true while the main ribbon is pressed to change the transpose rather than to play
+    transpose_tap: bool,
+    // the ribbon pitch before the latest transpose tap, held while tapping
+    held_pitch: f32,
+
+    midi_generator: MidiGenerator,
+}
+
+/// The outputs calculated by the pitch engine for a single update are represented here
+pub struct EngineOutput {
+    /// The 1volt/octave `RIBBON CV` voltage, with glide applied
+    pub ribbon_cv: f32,
+    /// The `MOD CV` voltage in `[0.0, MOD_CV_MAX_VOUT]`
+    pub mod_cv: f32,
+    /// The state of the `GATE` output, true iff the user is pressing the main ribbon
+    pub gate: bool,
+    /// The MTS note tunings to send before `midi`, empty unless MTS is enabled
+    pub note_tunings: NoteTunings,
+    /// The MIDI messages to send, may be empty if nothing changed
+    pub midi: MidiMessages,
+}
+
+impl PitchEngine {
+    /// `PitchEngine::new()` is a new pitch engine with the ribbons released and no glide
+    pub fn new() -> Self {
+        Self {
+            main_ribbon: new_main_ribbon(),
+            mod_ribbon: new_mod_ribbon(),
+            ribbon_calibration: RibbonCalibration::new(),
+            span: RibbonSpan::default(),
+            ribbon_quantizer: Quantizer::new(),
+            scale: Scale::Chromatic,
+            scale_root: 0,
+            tuning: None,
+            glide: GlideProcessor::new(OUTPUT_UPDATE_RATE_HZ as f32),
+            offset_when_finger_pressed_down: 0.0_f32,
+            velocity_detector: VelocityDetector::new(),
+            velocity: MAX_VELOCITY,
+            release_velocity: MAX_VELOCITY,
+            finger_was_pressing: false,
+            transpose: Transpose::default(),
+            transpose_tap: false,
+            held_pitch: 0.0_f32,
+            midi_generator: MidiGenerator::new(),
+        }
+    }
+
+    /// `pe.poll(m, r)` feeds the raw main ribbon sample `m` and MOD ribbon sample `r` to the engine.
+    ///
+    /// Must be called periodically at `RIBBON_SAMPLE_RATE_HZ`.
+    ///
+    /// # Arguments
+    ///
+    /// * `main_ribbon_sample` - the raw main ribbon reading, in `[0.0, 1.0]`
+    ///
+    /// * `mod_ribbon_sample` - the raw MOD ribbon reading, in `[0.0, 1.0]`
+    pub fn poll(&mut self, main_ribbon_sample: f32, mod_ribbon_sample: f32) {
+        self.main_ribbon.poll(main_ribbon_sample);
+        self.velocity_detector.poll(main_ribbon_sample);
+        self.mod_ribbon.poll(mod_ribbon_sample);
+
+        // catch the velocities as soon as the press or release registers, while the landing or lifting of the finger
+        // is still in the sample history
+        let finger_is_pressing = self.main_ribbon.finger_is_pressing();
+        if finger_is_pressing && !self.finger_was_pressing {
+            self.velocity = self.velocity_detector.velocity();
+        } else if !finger_is_pressing && self.finger_was_pressing {
+            self.release_velocity = self.velocity_detector.release_velocity();
+        }
+        self.finger_was_pressing = finger_is_pressing;
+    }
+
+    /// `pe.set_glide_time(t)` sets the portamento time applied to the `RIBBON CV` output to `t`
+    pub fn set_glide_time(&mut self, t: f32) {
+        self.glide.set_time(t);
+    }
+
+    /// `pe.set_ribbon_calibration(c)` sets the maps from ribbon readings to finger positions to calibration `c`
+    pub fn set_ribbon_calibration(&mut self, calibration: RibbonCalibration) {
+        self.ribbon_calibration = calibration;
+    }
+
+    /// `pe.ribbon_calibration()` is the maps from ribbon readings to finger positions
+    pub fn ribbon_calibration(&self) -> RibbonCalibration {
+        self.ribbon_calibration
+    }
+
+    /// `pe.set_span(s)` sets the number of semitones covered by the main ribbon to span `s`
+    pub fn set_span(&mut self, span: RibbonSpan) {
+        self.span = span;
+    }
+
+    /// `pe.span()` is the number of semitones covered by the main ribbon
+    pub fn span(&self) -> RibbonSpan {
+        self.span
+    }
+
+    /// `pe.set_scale(s, r)` sets the scale used by every pitch mode to scale `s` with root `r`
+    ///
+    /// Quantized notes snap to the scale for both the `RIBBON CV` and MIDI outputs, and the MIDI output only plays
+    /// notes in the scale using pitch bend to reach the pitches in between.
+    ///
+    /// # Arguments
+    ///
+    /// * `scale` - the scale to use
+    ///
+    /// * `root` - the root note of the scale as a pitch class, 0 is C, clamped to `[0..MAX_ROOT]`
+    pub fn set_scale(&mut self, scale: Scale, root: u8) {
+        self.scale = scale;
+        self.scale_root = root.min(MAX_ROOT);
+        scale.apply_to(&mut self.ribbon_quantizer, self.scale_root);
+        if self.tuning.is_none() {
+            self.midi_generator.set_scale(scale, self.scale_root);
+        }
+    }
+
+    /// `pe.scale()` is the scale and its root
+    pub fn scale(&self) -> (Scale, u8) {
+        (self.scale, self.scale_root)
+    }
+
+    /// `pe.set_tuning(t)` sets the microtonal tuning to `t`, or goes back to the scale if `t` is `None`
+    ///
+    /// While a tuning is set the `QUANTIZE` and `ASSIST` modes snap to the pitches of the tuning instead of the scale,
+    /// and the MIDI output plays each pitch as the nearest note plus pitch bend.
+    pub fn set_tuning(&mut self, tuning: Option<TuningTable>) {
+        let scale = match tuning {
+            // the MIDI output needs every note available to get close to the tuned pitches
+            Some(_) => Scale::Chromatic,
+            None => self.scale,
+        };
+        self.midi_generator.set_scale(scale, self.scale_root);
+        self.tuning = tuning;
+    }
+
+    /// `pe.tuning()` is the microtonal tuning, if any
+    pub fn tuning(&self) -> Option<&TuningTable> {
+        self.tuning.as_ref()
+    }
+
+    /// `pe.set_pitch_bend_range(r)` sets the pitch bend range of the receiving instrument to `r` semitones.
+    ///
+    /// The range is clamped to `[MIN_PITCH_BEND_RANGE, MAX_PITCH_BEND_RANGE]`. The new range is announced to the
+    /// receiver with the next engine update.
+    pub fn set_pitch_bend_range(&mut self, semitones: u8) {
+        self.midi_generator.set_pitch_bend_range(semitones);
+    }
+
+    /// `pe.pitch_bend_range()` is the pitch bend range of the receiving instrument in semitones
+    pub fn pitch_bend_range(&self) -> u8 {
+        self.midi_generator.pitch_bend_range()
+    }
+
+    /// `pe.set_transition_policy(p)` sets how MIDI notes change when sliding to policy `p`
+    pub fn set_transition_policy(&mut self, policy: TransitionPolicy) {
+        self.midi_generator.set_transition_policy(policy);
+    }
+
+    /// `pe.transition_policy()` is how MIDI notes change when sliding
+    pub fn transition_policy(&self) -> TransitionPolicy {
+        self.midi_generator.transition_policy()
+    }
+
+    /// `pe.set_velocity_curve(c)` sets the curve which converts the landing speed of the finger to note velocity to `c`
+    pub fn set_velocity_curve(&mut self, curve: VelocityCurve) {
+        self.velocity_detector.set_curve(curve);
+    }
+
+    /// `pe.velocity_curve()` is the curve which converts the landing speed of the finger to note velocity
+    pub fn velocity_curve(&self) -> VelocityCurve {
+        self.velocity_detector.curve()
+    }
+
+    /// `pe.set_midi_mode(m)` sets the MIDI output mode to `m`
+    pub fn set_midi_mode(&mut self, mode: MidiMode) {
+        self.midi_generator.set_mode(mode);
+    }
+
+    /// `pe.midi_mode()` is the MIDI output mode
+    pub fn midi_mode(&self) -> MidiMode {
+        self.midi_generator.mode()
+    }
+
+    /// `pe.set_transpose(t)` shifts the `RIBBON CV` and MIDI outputs by transpose `t`
+    ///
+    /// The scale or tuning moves along with everything else. The `RIBBON CV` output is limited to the range of the DAC
+    /// and the MIDI output to the range of MIDI notes.
+    pub fn set_transpose(&mut self, transpose: Transpose) {
+        self.transpose = transpose;
+        self.midi_generator
+            .set_transpose(transpose.total_semitones());
+    }
+
+    /// `pe.transpose()` is the transpose applied to the `RIBBON CV` and MIDI outputs
+    pub fn transpose(&self) -> Transpose {
+        self.transpose
+    }
+
+    /// `pe.set_mts(e)` enables retuning each MIDI note to the exact ribbon pitch with MTS before it is played iff `e`
+    pub fn set_mts(&mut self, enabled: bool) {
+        self.midi_generator.set_mts(enabled);
+    }
+
+    /// `pe.mts()` is true iff MIDI notes are retuned with MTS before they are played
+    pub fn mts(&self) -> bool {
+        self.midi_generator.mts()
+    }
+
+    /// `pe.set_retune_room(n)` lets each update retune up to `n` MIDI notes with MTS, see
+    /// `MidiGenerator::set_retune_room`
+    pub fn set_retune_room(&mut self, room: usize) {
+        self.midi_generator.set_retune_room(room);
+    }
+
+    /// `pe.tick(pm, ch)` is the engine output for pitch mode `pm` and MIDI channel `ch`.
+    ///
+    /// Must be called periodically at `OUTPUT_UPDATE_RATE_HZ`.
+    ///
+    /// # Arguments
+    ///
+    /// * `pitch_mode` - the enumerated pitch mode to use for the main ribbon
+    ///
+    /// * `midi_channel` - the MIDI channel to send messages on, in `[0..15]`
+    pub fn tick(&mut self, pitch_mode: PitchMode, midi_channel: u8) -> EngineOutput {
+        let main_position = self
+            .ribbon_calibration
+            .main_ribbon
+            .position(self.main_ribbon.value());
+        let mod_position = self
+            .ribbon_calibration
+            .mod_ribbon
+            .position(self.mod_ribbon.value());
+
+        // expand the ribbon signal to 1volt/octave range
+        let mut one_v_per_oct_ribbon = main_position * self.span.max_vout();
+
+        let (stairstep, fraction) = self.quantize(one_v_per_oct_ribbon);
+
+        let mut finger_just_pressed = self.main_ribbon.finger_just_pressed();
+        let mut finger_just_released = self.main_ribbon.finger_just_released();
+        let mut finger_is_pressing = self.main_ribbon.finger_is_pressing();
+
+        // a tap while the MOD ribbon is held at the top changes the transpose, and plays nothing until it is released
+        let mod_ribbon_at_top =
+            self.mod_ribbon.finger_is_pressing() && TRANSPOSE_EDIT_MOD_THRESHOLD < mod_position;
+        if finger_just_pressed && mod_ribbon_at_top {
+            self.set_transpose(self.transpose.tapped(main_position));
+            self.transpose_tap = true;
+        }
+        let tapping_transpose = self.transpose_tap;
+        if tapping_transpose {
+            self.transpose_tap = !finger_just_released;
+            finger_just_pressed = false;
+            finger_is_pressing = false;
+            finger_just_released = false;
+        }
+
+        // the main ribbon can be one of three modes
+        match pitch_mode {
+            // hard-quantize and smooth modes are simple to calculate
+            PitchMode::HardQuantize => {
+                one_v_per_oct_ribbon = stairstep;
+            }
+            PitchMode::Smooth => {
+                let fudge_factor = quantizer::HALF_SEMITONE_WIDTH;
+                one_v_per_oct_ribbon -= fudge_factor;
+            }
+            // assist mode has more going on
+            PitchMode::Assist => {
+                if finger_just_pressed {
+                    // When the user first presses down after having lifted their finger record the offset between the
+                    // finger position and the center of the note. We'll use this offset to make sure that it plays
+                    // a nice in-tune note at first-press.
+                    self.offset_when_finger_pressed_down = fraction;
+
+                    // use the stairstep for the first press for a nice in-tune note
+                    one_v_per_oct_ribbon = stairstep;
+                } else {
+                    // The user is continuing to press the ribbon and maybe sliding around, use the smooth val but
+                    // remove the offset
+                    one_v_per_oct_ribbon -= self.offset_when_finger_pressed_down;
+                }
+            }
+        };
+
+        // the pitch stays where it was while tapping so the CV doesn't jump to the tap
+        if tapping_transpose {
+            one_v_per_oct_ribbon = self.held_pitch;
+        } else {
+            self.held_pitch = one_v_per_oct_ribbon;
+        }
+
+        let gesture = RibbonGesture {
+            pitch: one_v_per_oct_ribbon,
+            finger_just_pressed,
+            finger_is_pressing,
+            finger_just_released,
+            velocity: self.velocity,
+            release_velocity: self.release_velocity,
+            mod_value: mod_position,
+        };
+
+        let midi = self.midi_generator.generate(&gesture, midi_channel);
+
+        EngineOutput {
+            ribbon_cv: self.glide.process(
+                (one_v_per_oct_ribbon + self.transpose.volts()).clamp(0.0_f32, DAC8162_MAX_VOUT),
+            ),
+            mod_cv: mod_position * MOD_CV_MAX_VOUT,
+            gate: finger_is_pressing,
+            note_tunings: self.midi_generator.take_note_tunings(),
+            midi,
+        }
+    }
+
+    /// `pe.quantize(v)` is the 1volt/octave ribbon voltage `v` split into the nearest in-tune voltage and the remainder
+    fn quantize(&mut self, v: f32) -> (f32, f32) {
+        match &self.tuning {
+            // work in MIDI pitches so that the MIDI output plays the tuned pitches exactly
+            Some(tuning) => {
+                let stairstep = midi_pitch_to_volts(tuning.nearest(volts_to_midi_pitch(v)));
+                (stairstep, v - stairstep)
+            }
+            None => {
+                let conversion = self.ribbon_quantizer.convert(v);
+                (conversion.stairstep, conversion.fraction)
+            }
+        }
+    }
+}
+
+impl Default for PitchEngine {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// The full-scale voltage of the `MOD CV` output
+pub const MOD_CV_MAX_VOUT: f32 = 5.0_f32;
+
+/// The ribbon controller used for both ribbons
+pub(crate) type Ribbon = RibbonController<RIBBON_BUFF_CAPACITY>;
+
+/// `new_main_ribbon()` is a new controller for the main ribbon
+///
+/// The resistances only need to be close, the ribbon calibration takes care of the differences between units.
+pub(crate) fn new_main_ribbon() -> Ribbon {
+    RibbonController::new(
+        RIBBON_SAMPLE_RATE_HZ as f32,
+        19_876.0_f32, // end-to-end resistance of the softpot as measured on the prototype
+        10_000.0_f32, // resistance of the series resistor going to vref
+        1E6,          // pullup resistor from the wiper to the positive voltage refererence
+    )
+}
+
+/// `new_mod_ribbon()` is a new controller for the MOD ribbon, see `new_main_ribbon`
+pub(crate) fn new_mod_ribbon() -> Ribbon {
+    RibbonController::new(
+        RIBBON_SAMPLE_RATE_HZ as f32,
+        10_271.0_f32, // end-to-end resistance of the softpot as measured on the prototype
+        10_000.0_f32, // resistance of the series resistor going to vref
+        1E6,          // pullup resistor from the wiper to the positive voltage refererence
+    )
+}
+
+const RIBBON_BUFF_CAPACITY: usize =
+    ribbon_controller::sample_rate_to_capacity(RIBBON_SAMPLE_RATE_HZ);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::midi_generator::{
+        DEFAULT_PITCH_BEND_RANGE, LOWEST_MIDI_NOTE, MAX_PITCH_BEND_RANGE, MIN_PITCH_BEND_RANGE,
+    };
+    use crate::ribbon_calibration::RibbonMap;
+    use midi_convert::midi_types::MidiMessage;
+
+    // enough polls to fill the ribbon buffers and register a press
+    const POLLS_TO_REGISTER_PRESS: usize = RIBBON_BUFF_CAPACITY * 2;
+    // enough ticks for the glide filter to settle
+    const TICKS_TO_SETTLE: usize = 50;
+
+    // a raw ADC reading well above the finger-press boundary of either ribbon
+    const RELEASED: f32 = 1.0;
+
+    fn press(engine: &mut PitchEngine, main_sample: f32, mod_sample: f32) {
+        for _ in 0..POLLS_TO_REGISTER_PRESS {
+            engine.poll(main_sample, mod_sample);
+        }
+    }
+
+    fn release(engine: &mut PitchEngine) {
+        engine.poll(RELEASED, RELEASED);
+    }
+
+    fn settle(engine: &mut PitchEngine, mode: PitchMode) -> EngineOutput {
+        let mut out = engine.tick(mode, 0);
+        for _ in 0..TICKS_TO_SETTLE {
+            out = engine.tick(mode, 0);
+        }
+        out
+    }
+
+    fn is_on_semitone(v: f32) -> bool {
+        let steps = v / quantizer::SEMITONE_WIDTH;
+        (steps - (steps + 0.5) as u32 as f32).abs() < 1E-3
+    }
+
+    fn is_almost(a: f32, b: f32) -> bool {
+        (a - b).abs() < 1E-4
+    }
+
+    fn notes(out: &EngineOutput) -> std::vec::Vec<MidiMessage> {
+        out.midi
+            .iter()
+            .filter(|m| matches!(m, MidiMessage::NoteOn(..) | MidiMessage::NoteOff(..)))
+            .copied()
+            .collect()
+    }
+
+    fn pitch_bend(out: &EngineOutput) -> Option<i16> {
+        out.midi.iter().find_map(|m| match m {
+            MidiMessage::PitchBendChange(_, bend) => Some(i16::from(*bend)),
+            _ => None,
+        })
+    }
+
+    fn announced_bend_range(out: &EngineOutput) -> Option<(u8, u8)> {
+        match out.midi[..] {
+            [MidiMessage::ControlChange(ch, rpn_msb, msb), MidiMessage::ControlChange(_, rpn_lsb, lsb), MidiMessage::ControlChange(_, _, range), ..]
+                if u8::from(rpn_msb) == 101
+                    && u8::from(rpn_lsb) == 100
+                    && u8::from(msb) == 0
+                    && u8::from(lsb) == 0 =>
+            {
+                Some((u8::from(ch), u8::from(range)))
+            }
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn gate_follows_main_ribbon() {
+        let mut engine = PitchEngine::new();
+        assert!(!engine.tick(PitchMode::Smooth, 0).gate);
+
+        press(&mut engine, 0.3, RELEASED);
+        assert!(engine.tick(PitchMode::Smooth, 0).gate);
+
+        release(&mut engine);
+        assert!(!engine.tick(PitchMode::Smooth, 0).gate);
+    }
+
+    #[test]
+    fn hard_quantize_cv_is_on_a_semitone() {
+        let mut engine = PitchEngine::new();
+        press(&mut engine, 0.31, RELEASED);
+        assert!(is_on_semitone(
+            settle(&mut engine, PitchMode::HardQuantize).ribbon_cv
+        ));
+    }
+
+    #[test]
+    fn smooth_cv_tracks_the_finger_between_semitones() {
+        let mut engine = PitchEngine::new();
+        press(&mut engine, 0.31, RELEASED);
+        let quantized = settle(&mut engine, PitchMode::HardQuantize).ribbon_cv;
+        let smooth = settle(&mut engine, PitchMode::Smooth).ribbon_cv;
+
+        assert!(!is_on_semitone(smooth));
+        // smooth mode is shifted down half a step so the centre of each note bucket is in tune
+        assert!((smooth - quantized).abs() < quantizer::SEMITONE_WIDTH);
+    }
+
+    #[test]
+    fn assist_first_press_is_in_tune_then_slides_smoothly() {
+        let mut engine = PitchEngine::new();
+        press(&mut engine, 0.31, RELEASED);
+        let first_press = engine.tick(PitchMode::Assist, 0);
+        assert!(first_press.gate);
+        let in_tune = settle(&mut engine, PitchMode::Assist).ribbon_cv;
+        assert!(is_on_semitone(in_tune));
+
+        // sliding a little bit moves the pitch smoothly away from the in-tune note
+        press(&mut engine, 0.312, RELEASED);
+        let slid = settle(&mut engine, PitchMode::Assist).ribbon_cv;
+        assert!(in_tune < slid);
+        assert!(!is_on_semitone(slid));
+    }
+
+    #[test]
+    fn switching_from_smooth_to_hard_quantize_mid_gesture_snaps_to_a_semitone() {
+        let mut engine = PitchEngine::new();
+        press(&mut engine, 0.31, RELEASED);
+        assert!(!is_on_semitone(
+            settle(&mut engine, PitchMode::Smooth).ribbon_cv
+        ));
+        assert!(is_on_semitone(
+            settle(&mut engine, PitchMode::HardQuantize).ribbon_cv
+        ));
+    }
+
+    #[test]
+    fn switching_from_assist_to_smooth_mid_gesture_drops_the_offset() {
+        let mut engine = PitchEngine::new();
+        press(&mut engine, 0.31, RELEASED);
+        let assist = settle(&mut engine, PitchMode::Assist).ribbon_cv;
+        let smooth = settle(&mut engine, PitchMode::Smooth).ribbon_cv;
+        let assist_again = settle(&mut engine, PitchMode::Assist).ribbon_cv;
+
+        assert!(!is_almost(assist, smooth));
+        // the offset recorded at first-press is kept for the rest of the gesture
+        assert!(is_almost(assist, assist_again));
+    }
+
+    #[test]
+    fn switching_to_assist_mid_gesture_keeps_the_offset_from_the_last_assist_press() {
+        let mut engine = PitchEngine::new();
+        press(&mut engine, 0.31, RELEASED);
+        let first_assist = settle(&mut engine, PitchMode::Assist).ribbon_cv;
+        release(&mut engine);
+        engine.tick(PitchMode::Assist, 0);
+
+        // press the same spot again while in hard quantize mode, then switch to assist
+        press(&mut engine, 0.31, RELEASED);
+        settle(&mut engine, PitchMode::HardQuantize);
+        assert!(is_almost(
+            settle(&mut engine, PitchMode::Assist).ribbon_cv,
+            first_assist
+        ));
+    }
+
+    #[test]
+    fn press_sends_note_on_and_release_sends_note_off() {
+        let mut engine = PitchEngine::new();
+        press(&mut engine, 0.31, RELEASED);
+        let out = engine.tick(PitchMode::HardQuantize, 3);
+        let note = match notes(&out)[0] {
+            MidiMessage::NoteOn(ch, note, vel) => {
+                assert_eq!(u8::from(ch), 3);
+                assert_eq!(u8::from(vel), 127);
+                note
+            }
+            _ => panic!("expected a note-on first"),
+        };
+
+        // the finger snaps straight off the ribbon, a full velocity release
+        release(&mut engine);
+        let out = engine.tick(PitchMode::HardQuantize, 3);
+        assert_eq!(
+            notes(&out),
+            [MidiMessage::NoteOff(3.into(), note, 127.into())]
+        );
+    }
+
+    #[test]
+    fn gentle_landing_plays_a_quieter_note() {
+        let velocity = |engine: &mut PitchEngine| match notes(&engine.tick(PitchMode::Smooth, 0))[0]
+        {
+            MidiMessage::NoteOn(_, _, vel) => u8::from(vel),
+            _ => panic!("expected a note-on"),
+        };
+
+        let mut firm = PitchEngine::new();
+        press(&mut firm, 0.31, RELEASED);
+
+        // drag the signal down slowly from released to the finger position
+        let mut gentle = PitchEngine::new();
+        for i in 0..40 {
+            gentle.poll(RELEASED - i as f32 * 0.02, RELEASED);
+        }
+        press(&mut gentle, 0.31, RELEASED);
+
+        assert!(velocity(&mut gentle) < velocity(&mut firm));
+    }
+
+    #[test]
+    fn scale_applies_to_cv_and_midi_in_every_mode() {
+        let in_scale = |cv: f32| {
+            let note = (cv / quantizer::SEMITONE_WIDTH + 0.5) as u8 + LOWEST_MIDI_NOTE;
+            Scale::MinorPentatonic.contains(9, note)
+        };
+
+        for mode in [
+            PitchMode::HardQuantize,
+            PitchMode::Assist,
+            PitchMode::Smooth,
+        ] {
+            // slide across the whole ribbon, every note played must be in A minor pentatonic
+            let mut engine = PitchEngine::new();
+            engine.set_scale(Scale::MinorPentatonic, 9);
+            for i in 0..60 {
+                press(&mut engine, 0.01 * i as f32, RELEASED);
+                let out = engine.tick(mode, 0);
+                for note in notes(&out) {
+                    if let MidiMessage::NoteOn(_, note, _) = note {
+                        assert!(Scale::MinorPentatonic.contains(9, note.into()));
+                    }
+                }
+                if mode == PitchMode::HardQuantize {
+                    assert!(in_scale(settle(&mut engine, mode).ribbon_cv));
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn assist_first_press_snaps_to_the_scale() {
+        let mut engine = PitchEngine::new();
+        engine.set_scale(Scale::WholeTone, 0);
+        press(&mut engine, 0.31, RELEASED);
+        engine.tick(PitchMode::Assist, 0);
+        let cv = settle(&mut engine, PitchMode::Assist).ribbon_cv;
+
+        assert!(is_on_semitone(cv));
+        let note = (cv / quantizer::SEMITONE_WIDTH + 0.5) as u8 + LOWEST_MIDI_NOTE;
+        assert!(Scale::WholeTone.contains(0, note));
+    }
+
+    #[test]
+    fn tuned_pitches_are_played_exactly_as_note_plus_bend() {
+        // quarter tones, every pitch is a note or half way between two notes
+        let degrees: std::vec::Vec<f32> = (0..24).map(|i| i as f32 * 0.5).collect();
+        let mut engine = PitchEngine::new();
+        engine.set_tuning(Some(TuningTable::new(12.0, 60.0, &degrees).unwrap()));
+
+        for i in 0..50 {
+            press(&mut engine, 0.01 * i as f32, RELEASED);
+            let out = settle(&mut engine, PitchMode::HardQuantize);
+            let pitch = volts_to_midi_pitch(out.ribbon_cv);
+            assert!(is_almost(pitch * 2.0, (pitch * 2.0 + 0.5) as u32 as f32));
+        }
+
+        // the note plus the bend always lands on a quarter tone
+        let mut note = 0;
+        for i in 0..50 {
+            press(&mut engine, 0.01 * i as f32, RELEASED);
+            let out = engine.tick(PitchMode::HardQuantize, 0);
+            for msg in out.midi.iter() {
+                match msg {
+                    MidiMessage::NoteOn(_, n, _) => note = u8::from(*n),
+                    MidiMessage::PitchBendChange(_, bend) => {
+                        // the default bend range is two semitones
+                        let pitch = note as f32 + i16::from(*bend) as f32 / 4096.0;
+                        assert!((pitch * 2.0 - (pitch * 2.0 + 0.5) as u32 as f32).abs() < 1E-3);
+                    }
+                    _ => (),
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn clearing_the_tuning_goes_back_to_the_scale() {
+        let mut engine = PitchEngine::new();
+        engine.set_scale(Scale::Major, 0);
+        engine.set_tuning(Some(TuningTable::new(12.0, 60.0, &[0.0, 5.5]).unwrap()));
+        engine.set_tuning(None);
+
+        press(&mut engine, 0.31, RELEASED);
+        let cv = settle(&mut engine, PitchMode::HardQuantize).ribbon_cv;
+        assert!(is_on_semitone(cv));
+        let note = (cv / quantizer::SEMITONE_WIDTH + 0.5) as u8 + LOWEST_MIDI_NOTE;
+        assert!(Scale::Major.contains(0, note));
+    }
+
+    // a raw MOD ribbon reading near the top end of the ribbon
+    const MOD_AT_TOP: f32 = 0.5;
+
+    fn note_on_number(out: &EngineOutput) -> u8 {
+        match notes(out)[0] {
+            MidiMessage::NoteOn(_, note, _) => u8::from(note),
+            _ => panic!("expected a note-on"),
+        }
+    }
+
+    #[test]
+    fn transpose_shifts_cv_and_midi() {
+        let mut plain = PitchEngine::new();
+        let mut shifted = PitchEngine::new();
+        shifted.set_transpose(Transpose::new(-3, 1));
+
+        press(&mut plain, 0.31, RELEASED);
+        press(&mut shifted, 0.31, RELEASED);
+        let plain_note = note_on_number(&plain.tick(PitchMode::HardQuantize, 0));
+        let shifted_note = note_on_number(&shifted.tick(PitchMode::HardQuantize, 0));
+        assert_eq!(shifted_note, plain_note + 9);
+
+        let plain_cv = settle(&mut plain, PitchMode::HardQuantize).ribbon_cv;
+        let shifted_cv = settle(&mut shifted, PitchMode::HardQuantize).ribbon_cv;
+        assert!(is_almost(
+            shifted_cv - plain_cv,
+            9.0 * quantizer::SEMITONE_WIDTH
+        ));
+    }
+
+    #[test]
+    fn transposed_cv_is_clamped_to_the_dac_range() {
+        let mut engine = PitchEngine::new();
+        engine.set_transpose(Transpose::new(0, 4));
+        press(&mut engine, 0.55, RELEASED);
+        assert_eq!(
+            settle(&mut engine, PitchMode::Smooth).ribbon_cv,
+            DAC8162_MAX_VOUT
+        );
+
+        engine.set_transpose(Transpose::new(-48, -4));
+        assert_eq!(settle(&mut engine, PitchMode::Smooth).ribbon_cv, 0.0);
+    }
+
+    #[test]
+    fn tapping_while_holding_the_mod_ribbon_top_changes_the_transpose() {
+        let mut engine = PitchEngine::new();
+        engine.tick(PitchMode::Smooth, 0);
+
+        // octave up from the top zone of the main ribbon, then a semitone down from the second zone
+        for (tap, expected) in [(0.55, Transpose::new(0, 1)), (0.2, Transpose::new(-1, 1))] {
+            press(&mut engine, tap, MOD_AT_TOP);
+            let out = engine.tick(PitchMode::Smooth, 0);
+            assert!(!out.gate);
+            assert!(notes(&out).is_empty());
+            assert_eq!(engine.transpose(), expected);
+
+            release(&mut engine);
+            assert!(notes(&engine.tick(PitchMode::Smooth, 0)).is_empty());
+        }
+
+        // the next press plays as normal
+        press(&mut engine, 0.31, RELEASED);
+        assert!(engine.tick(PitchMode::Smooth, 0).gate);
+    }
+
+    #[test]
+    fn span_sets_the_range_of_the_main_ribbon() {
+        let unshifted_cv = |span: RibbonSpan| {
+            let mut engine = PitchEngine::new();
+            engine.set_span(span);
+            press(&mut engine, 0.31, RELEASED);
+            settle(&mut engine, PitchMode::Smooth).ribbon_cv + quantizer::HALF_SEMITONE_WIDTH
+        };
+
+        let one_octave = unshifted_cv(RibbonSpan::Semitones12);
+        assert!(is_almost(
+            unshifted_cv(RibbonSpan::Semitones24),
+            one_octave * 2.0
+        ));
+        assert!(is_almost(
+            unshifted_cv(RibbonSpan::Semitones60),
+            one_octave * 5.0
+        ));
+        assert!(is_almost(
+            RibbonSpan::Semitones60.max_vout(),
+            DAC8162_MAX_VOUT
+        ));
+    }
+
+    #[test]
+    fn scale_root_is_clamped() {
+        let mut engine = PitchEngine::new();
+        engine.set_scale(Scale::Major, 40);
+        assert_eq!(engine.scale(), (Scale::Major, MAX_ROOT));
+    }
+
+    #[test]
+    fn no_midi_when_nothing_changes() {
+        let mut engine = PitchEngine::new();
+        press(&mut engine, 0.31, RELEASED);
+        settle(&mut engine, PitchMode::HardQuantize);
+        assert!(engine.tick(PitchMode::HardQuantize, 0).midi.is_empty());
+    }
+
+    #[test]
+    fn sliding_into_a_new_note_sends_note_on_then_note_off() {
+        let mut engine = PitchEngine::new();
+        press(&mut engine, 0.31, RELEASED);
+        settle(&mut engine, PitchMode::HardQuantize);
+
+        press(&mut engine, 0.4, RELEASED);
+        let out = engine.tick(PitchMode::HardQuantize, 0);
+        match notes(&out)[..] {
+            [MidiMessage::NoteOn(_, new_note, _), MidiMessage::NoteOff(_, old_note, _)] => {
+                assert!(u8::from(old_note) < u8::from(new_note))
+            }
+            _ => panic!("expected a note-on followed by a note-off"),
+        }
+    }
+
+    #[test]
+    fn hard_quantize_sends_no_pitch_bend_while_sliding_within_a_note() {
+        let mut engine = PitchEngine::new();
+        press(&mut engine, 0.31, RELEASED);
+        settle(&mut engine, PitchMode::HardQuantize);
+
+        press(&mut engine, 0.3105, RELEASED);
+        let out = engine.tick(PitchMode::HardQuantize, 0);
+        assert!(!out
+            .midi
+            .iter()
+            .any(|m| matches!(m, MidiMessage::PitchBendChange(..))));
+    }
+
+    #[test]
+    fn smooth_sends_pitch_bend_while_sliding_within_a_note() {
+        let mut engine = PitchEngine::new();
+        press(&mut engine, 0.31, RELEASED);
+        settle(&mut engine, PitchMode::Smooth);
+
+        press(&mut engine, 0.3105, RELEASED);
+        let out = engine.tick(PitchMode::Smooth, 0);
+        assert!(out
+            .midi
+            .iter()
+            .any(|m| matches!(m, MidiMessage::PitchBendChange(..))));
+    }
+
+    #[test]
+    fn mod_ribbon_drives_mod_cv_and_mod_wheel() {
+        let mut engine = PitchEngine::new();
+        press(&mut engine, RELEASED, 0.2);
+        let out = engine.tick(PitchMode::Smooth, 5);
+
+        assert!(0.0 < out.mod_cv && out.mod_cv < MOD_CV_MAX_VOUT);
+        assert!(out.midi.iter().any(|m| matches!(
+            m,
+            MidiMessage::ControlChange(ch, cc, _) if u8::from(*ch) == 5 && u8::from(*cc) == 1
+        )));
+    }
+
+    #[test]
+    fn bend_range_is_announced_at_power_up() {
+        let mut engine = PitchEngine::new();
+        let out = engine.tick(PitchMode::Smooth, 9);
+        assert_eq!(
+            announced_bend_range(&out),
+            Some((9, DEFAULT_PITCH_BEND_RANGE))
+        );
+        assert_eq!(
+            announced_bend_range(&engine.tick(PitchMode::Smooth, 9)),
+            None
+        );
+    }
+
+    #[test]
+    fn bend_range_is_announced_again_when_the_channel_changes() {
+        let mut engine = PitchEngine::new();
+        engine.tick(PitchMode::Smooth, 0);
+        assert_eq!(
+            announced_bend_range(&engine.tick(PitchMode::Smooth, 1)),
+            Some((1, DEFAULT_PITCH_BEND_RANGE))
+        );
+    }
+
+    #[test]
+    fn bend_range_is_announced_again_when_the_range_changes() {
+        let mut engine = PitchEngine::new();
+        engine.tick(PitchMode::Smooth, 0);
+        engine.set_pitch_bend_range(12);
+        assert_eq!(
+            announced_bend_range(&engine.tick(PitchMode::Smooth, 0)),
+            Some((0, 12))
+        );
+    }
+
+    #[test]
+    fn bend_range_is_clamped() {
+        let mut engine = PitchEngine::new();
+        engine.set_pitch_bend_range(0);
+        assert_eq!(engine.pitch_bend_range(), MIN_PITCH_BEND_RANGE);
+        engine.set_pitch_bend_range(200);
+        assert_eq!(engine.pitch_bend_range(), MAX_PITCH_BEND_RANGE);
+    }
+
+    #[test]
+    fn wider_bend_range_means_smaller_bend_values() {
+        let mut narrow = PitchEngine::new();
+        let mut wide = PitchEngine::new();
+        wide.set_pitch_bend_range(DEFAULT_PITCH_BEND_RANGE * 4);
+
+        press(&mut narrow, 0.3, RELEASED);
+        press(&mut wide, 0.3, RELEASED);
+        let narrow_bend = pitch_bend(&narrow.tick(PitchMode::Smooth, 0)).unwrap() as f32;
+        let wide_bend = pitch_bend(&wide.tick(PitchMode::Smooth, 0)).unwrap() as f32;
+
+        assert!((narrow_bend / 4.0 - wide_bend).abs() <= 1.0);
+    }
+
+    #[test]
+    fn ribbon_calibration_maps_the_reading_to_the_position() {
+        let mut engine = PitchEngine::new();
+        press(&mut engine, RELEASED, 0.3);
+        let uncalibrated = engine.tick(PitchMode::Smooth, 0).mod_cv / MOD_CV_MAX_VOUT;
+
+        // a map which puts the current reading right in the middle of the ribbon
+        let map = RibbonMap::from_readings(&[0.0, uncalibrated, 1.0]).unwrap();
+        engine.set_ribbon_calibration(RibbonCalibration {
+            mod_ribbon: map,
+            ..RibbonCalibration::new()
+        });
+        assert!(is_almost(
+            engine.tick(PitchMode::Smooth, 0).mod_cv,
+            MOD_CV_MAX_VOUT / 2.0
+        ));
+    }
+}
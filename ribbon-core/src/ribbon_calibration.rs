@@ -0,0 +1,305 @@
+//! # Ribbon calibration
+//!
+//! No two softpots are quite the same. The end-to-end resistance varies from one to the next, and the readings are
+//! not perfectly linear along the length of the ribbon. Each ribbon has a piecewise-linear map from the reading of its
+//! ribbon controller to the position of the finger, found by touching the ribbon at known positions.
+//!
+//! Power up the ribbon controller with the `MODE` switch set to `SMOOTH` and the `MIDI CH` switch set to 16 to start
+//! the calibration procedure. Then tap each ribbon at the positions in the table below, in order:
+//!
+//! ```text
+//! | step | ribbon | position     |
+//! | 1    | main   | bottom       |
+//! | 2    | main   | 1/4 of a way |
+//! | 3    | main   | middle       |
+//! | 4    | main   | 3/4 of a way |
+//! | 5    | main   | top          |
+//! | 6    | MOD    | bottom       |
+//! | 7    | MOD    | middle       |
+//! | 8    | MOD    | top          |
+//! ```
+//!
+//! While calibrating, the output of the ribbon being calibrated shows the position to tap, 0 volts for the bottom up to
+//! 5 volts for the top, and the `GATE` output follows the finger. After the last step the maps are saved with the other
+//! settings and the ribbon controller starts playing. If the taps were out of order the old maps are kept.
+
+use crate::pitch_engine::{new_main_ribbon, new_mod_ribbon, Ribbon};
+
+/// A piecewise-linear map from ribbon controller reading to finger position is represented here
+///
+/// The map is a list of the readings at evenly spaced positions from the bottom to the top of the ribbon.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct RibbonMap {
+    readings: [f32; MAX_MAP_POINTS],
+    len: usize,
+}
+
+impl RibbonMap {
+    /// `RibbonMap::new()` is a map which changes nothing, the position is the reading
+    pub const fn new() -> Self {
+        let mut readings = [0.0_f32; MAX_MAP_POINTS];
+        readings[1] = 1.0_f32;
+        Self { readings, len: 2 }
+    }
+
+    /// `RibbonMap::from_readings(rs)` is the map through readings `rs` taken at evenly spaced positions from the bottom
+    /// to the top, or `None` if there are too few or too many readings or they don't strictly increase
+    pub fn from_readings(readings: &[f32]) -> Option<Self> {
+        let len = readings.len();
+        let increasing = readings
+            .windows(2)
+            .all(|w| w[0].is_finite() && w[1].is_finite() && w[0] < w[1]);
+        if !(2..=MAX_MAP_POINTS).contains(&len) || !increasing {
+            return None;
+        }
+
+        let mut map = Self {
+            readings: [0.0_f32; MAX_MAP_POINTS],
+            len,
+        };
+        map.readings[..len].copy_from_slice(readings);
+        Some(map)
+    }
+
+    /// `rm.readings()` is the readings of map `rm`, at evenly spaced positions from the bottom to the top
+    pub fn readings(&self) -> &[f32] {
+        &self.readings[..self.len]
+    }
+
+    /// `rm.position(r)` is the finger position in `[0.0, 1.0]` for ribbon controller reading `r`
+    ///
+    /// Readings past either end of the map continue the nearest segment, and the position is clamped.
+    pub fn position(&self, reading: f32) -> f32 {
+        let readings = self.readings();
+        let num_segments = readings.len() - 1;
+
+        // the segment the reading falls in, the first or last segment for readings off either end
+        let segment = readings[1..num_segments]
+            .iter()
+            .take_while(|&&r| r <= reading)
+            .count();
+        let (lo, hi) = (readings[segment], readings[segment + 1]);
+
+        let position = (segment as f32 + (reading - lo) / (hi - lo)) / num_segments as f32;
+        position.clamp(0.0_f32, 1.0_f32)
+    }
+}
+
+impl Default for RibbonMap {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// The maps for both ribbons are represented here
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct RibbonCalibration {
+    pub main_ribbon: RibbonMap,
+    pub mod_ribbon: RibbonMap,
+}
+
+impl RibbonCalibration {
+    /// `RibbonCalibration::new()` is a calibration which changes nothing
+    pub const fn new() -> Self {
+        Self {
+            main_ribbon: RibbonMap::new(),
+            mod_ribbon: RibbonMap::new(),
+        }
+    }
+}
+
+/// The two ribbons are represented here
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum WhichRibbon {
+    Main,
+    Mod,
+}
+
+/// The ribbon calibration procedure is represented here, see the module docs
+pub struct RibbonCalibrationProcedure {
+    main_ribbon: Ribbon,
+    mod_ribbon: Ribbon,
+
+    // the index of the current step in `STEPS`
+    step: usize,
+    // the reading at the end of the tap in each step so far
+    found: [f32; NUM_STEPS],
+}
+
+impl RibbonCalibrationProcedure {
+    /// `RibbonCalibrationProcedure::new()` is a new ribbon calibration procedure at the first step
+    pub fn new() -> Self {
+        Self {
+            main_ribbon: new_main_ribbon(),
+            mod_ribbon: new_mod_ribbon(),
+            step: 0,
+            found: [0.0_f32; NUM_STEPS],
+        }
+    }
+
+    /// `rcp.poll(m, r)` feeds the raw main ribbon sample `m` and MOD ribbon sample `r` to the procedure
+    ///
+    /// Must be called periodically at `RIBBON_SAMPLE_RATE_HZ`.
+    pub fn poll(&mut self, main_ribbon_sample: f32, mod_ribbon_sample: f32) {
+        self.main_ribbon.poll(main_ribbon_sample);
+        self.mod_ribbon.poll(mod_ribbon_sample);
+    }
+
+    /// `rcp.update()` moves on to the next step when the ribbon for the current step is released
+    ///
+    /// Must be called periodically at `OUTPUT_UPDATE_RATE_HZ`.
+    pub fn update(&mut self) {
+        let main_released = self.main_ribbon.finger_just_released();
+        let mod_released = self.mod_ribbon.finger_just_released();
+
+        let (ribbon, released) = match STEPS.get(self.step) {
+            Some((WhichRibbon::Main, _)) => (&self.main_ribbon, main_released),
+            Some((WhichRibbon::Mod, _)) => (&self.mod_ribbon, mod_released),
+            None => return,
+        };
+        if released {
+            // the value is held from just before the finger lifted
+            self.found[self.step] = ribbon.value();
+            self.step += 1;
+        }
+    }
+
+    /// `rcp.output()` is the ribbon to tap and the position to tap it at for the current step, or `None` if the
+    /// procedure is done
+    pub fn output(&self) -> Option<(WhichRibbon, f32)> {
+        STEPS.get(self.step).copied()
+    }
+
+    /// `rcp.finger_is_pressing()` is true iff either ribbon is pressed
+    pub fn finger_is_pressing(&self) -> bool {
+        self.main_ribbon.finger_is_pressing() || self.mod_ribbon.finger_is_pressing()
+    }
+
+    /// `rcp.result()` is the calibration found by the procedure, or `None` if it is not done yet or the taps were out of
+    /// order
+    pub fn result(&self) -> Option<RibbonCalibration> {
+        if self.step < NUM_STEPS {
+            return None;
+        }
+        Some(RibbonCalibration {
+            main_ribbon: RibbonMap::from_readings(&self.found[..NUM_MAIN_STEPS])?,
+            mod_ribbon: RibbonMap::from_readings(&self.found[NUM_MAIN_STEPS..])?,
+        })
+    }
+}
+
+impl Default for RibbonCalibrationProcedure {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// The most points a ribbon map can have
+pub const MAX_MAP_POINTS: usize = 8;
+
+const NUM_MAIN_STEPS: usize = 5;
+const NUM_STEPS: usize = 8;
+
+// the ribbon and position of each step, in order
+const STEPS: [(WhichRibbon, f32); NUM_STEPS] = [
+    (WhichRibbon::Main, 0.0),
+    (WhichRibbon::Main, 0.25),
+    (WhichRibbon::Main, 0.5),
+    (WhichRibbon::Main, 0.75),
+    (WhichRibbon::Main, 1.0),
+    (WhichRibbon::Mod, 0.0),
+    (WhichRibbon::Mod, 0.5),
+    (WhichRibbon::Mod, 1.0),
+];
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// `tap(rcp, w, v)` taps ribbon `w` at raw value `v` during ribbon calibration procedure `rcp`
+    fn tap(procedure: &mut RibbonCalibrationProcedure, which: WhichRibbon, val: f32) {
+        for sample in [val, 1.0] {
+            for _ in 0..100 {
+                match which {
+                    WhichRibbon::Main => procedure.poll(sample, 1.0),
+                    WhichRibbon::Mod => procedure.poll(1.0, sample),
+                }
+            }
+            procedure.update();
+        }
+    }
+
+    #[test]
+    fn new_map_changes_nothing() {
+        let map = RibbonMap::new();
+        for r in [0.0, 0.1, 0.5, 0.77, 1.0] {
+            assert_eq!(map.position(r), r);
+        }
+    }
+
+    #[test]
+    fn map_goes_through_every_point() {
+        let map = RibbonMap::from_readings(&[0.1, 0.2, 0.4, 0.5, 0.9]).unwrap();
+        for (i, &r) in map.readings().iter().enumerate() {
+            assert!((map.position(r) - i as f32 / 4.0).abs() < 1E-6);
+        }
+        assert!((map.position(0.3) - 0.375).abs() < 1E-6);
+    }
+
+    #[test]
+    fn readings_off_the_ends_are_clamped() {
+        let map = RibbonMap::from_readings(&[0.1, 0.2, 0.9]).unwrap();
+        assert_eq!(map.position(0.0), 0.0);
+        assert_eq!(map.position(1.0), 1.0);
+        assert_eq!(map.position(0.95), 1.0);
+        assert_eq!(map.position(0.05), 0.0);
+    }
+
+    #[test]
+    fn bad_readings_are_not_a_map() {
+        assert_eq!(RibbonMap::from_readings(&[0.5]), None);
+        assert_eq!(RibbonMap::from_readings(&[0.1, 0.3, 0.2]), None);
+        assert_eq!(RibbonMap::from_readings(&[0.1, 0.1]), None);
+        assert_eq!(RibbonMap::from_readings(&[0.1, f32::NAN]), None);
+        assert_eq!(RibbonMap::from_readings(&[0.0; MAX_MAP_POINTS + 1]), None);
+    }
+
+    #[test]
+    fn procedure_steps_through_both_ribbons() {
+        let mut procedure = RibbonCalibrationProcedure::new();
+        assert_eq!(procedure.output(), Some((WhichRibbon::Main, 0.0)));
+
+        // tapping the wrong ribbon does nothing
+        tap(&mut procedure, WhichRibbon::Mod, 0.1);
+        assert_eq!(procedure.output(), Some((WhichRibbon::Main, 0.0)));
+
+        for raw in [0.05, 0.2, 0.35, 0.5, 0.6] {
+            tap(&mut procedure, WhichRibbon::Main, raw);
+        }
+        assert_eq!(procedure.output(), Some((WhichRibbon::Mod, 0.0)));
+        assert_eq!(procedure.result(), None);
+
+        for raw in [0.05, 0.25, 0.45] {
+            tap(&mut procedure, WhichRibbon::Mod, raw);
+        }
+        assert_eq!(procedure.output(), None);
+
+        let cal = procedure.result().unwrap();
+        assert_eq!(cal.main_ribbon.readings().len(), 5);
+        assert_eq!(cal.mod_ribbon.readings().len(), 3);
+        assert!(cal.main_ribbon.readings()[0] < cal.main_ribbon.readings()[1]);
+    }
+
+    #[test]
+    fn out_of_order_taps_have_no_result() {
+        let mut procedure = RibbonCalibrationProcedure::new();
+        for raw in [0.05, 0.35, 0.2, 0.5, 0.6] {
+            tap(&mut procedure, WhichRibbon::Main, raw);
+        }
+        for raw in [0.05, 0.25, 0.45] {
+            tap(&mut procedure, WhichRibbon::Mod, raw);
+        }
+        assert_eq!(procedure.output(), None);
+        assert_eq!(procedure.result(), None);
+    }
+}
@@ -0,0 +1,78 @@
+//! # Registered Parameter Numbers
+//!
+//! MIDI receivers expose some of their settings as Registered Parameter Numbers (RPNs). An RPN is set with a sequence
+//! of control change messages which select the parameter, write its value with the data entry controllers, and then
+//! deselect the parameter again so that stray data entry messages can't change it by accident.
+
+use midi_convert::midi_types::MidiMessage;
+
+/// The RPN which sets the pitch bend range of the receiver, the value MSB is semitones and the LSB is cents
+pub const RPN_PITCH_BEND_SENSITIVITY: u16 = 0x0000;
+
+/// The MPE configuration message, sent on the manager channel of a zone, the value MSB is the number of member channels
+pub const RPN_MPE_CONFIGURATION: u16 = 0x0006;
+
+/// The number of MIDI messages needed to set an RPN
+pub const NUM_MESSAGES_PER_RPN: usize = 6;
+
+/// `rpn_messages(ch, p, msb, lsb)` is the sequence of MIDI messages which sets RPN `p` on channel `ch`
+///
+/// # Arguments
+///
+/// * `channel` - the MIDI channel to set the parameter on, in `[0..15]`
+///
+/// * `param` - the 14 bit registered parameter number
+///
+/// * `value_msb` - the most significant 7 bits of the value, sent with data entry MSB
+///
+/// * `value_lsb` - the least significant 7 bits of the value, sent with data entry LSB
+pub fn rpn_messages(
+    channel: u8,
+    param: u16,
+    value_msb: u8,
+    value_lsb: u8,
+) -> [MidiMessage; NUM_MESSAGES_PER_RPN] {
+    let cc = |control: u8, value: u8| {
+        MidiMessage::ControlChange(channel.into(), control.into(), (value & 0x7F).into())
+    };
+
+    [
+        cc(CC_RPN_MSB, (param >> 7) as u8),
+        cc(CC_RPN_LSB, param as u8),
+        cc(CC_DATA_ENTRY_MSB, value_msb),
+        cc(CC_DATA_ENTRY_LSB, value_lsb),
+        // select the null parameter so later data entry messages are ignored
+        cc(CC_RPN_MSB, 0x7F),
+        cc(CC_RPN_LSB, 0x7F),
+    ]
+}
+
+const CC_DATA_ENTRY_MSB: u8 = 6;
+const CC_DATA_ENTRY_LSB: u8 = 38;
+const CC_RPN_LSB: u8 = 100;
+const CC_RPN_MSB: u8 = 101;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use midi_convert::MidiRenderSlice;
+
+    #[test]
+    fn pitch_bend_sensitivity_renders_as_the_standard_sequence() {
+        let mut bytes = [0_u8; NUM_MESSAGES_PER_RPN * 3];
+        rpn_messages(3, RPN_PITCH_BEND_SENSITIVITY, 12, 0)
+            .iter()
+            .enumerate()
+            .for_each(|(i, m)| {
+                m.render_slice(&mut bytes[i * 3..]);
+            });
+
+        assert_eq!(
+            bytes,
+            [
+                0xB3, 101, 0, 0xB3, 100, 0, 0xB3, 6, 12, 0xB3, 38, 0, 0xB3, 101, 127, 0xB3, 100,
+                127
+            ]
+        );
+    }
+}
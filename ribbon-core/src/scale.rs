@@ -0,0 +1,157 @@
+//! # Scales
+//!
+//! Musical scales for the quantizers. A scale is a set of allowed pitch classes relative to a root note, and the
+//! quantizers only ever snap to notes which are in the scale.
+//!
+//! Roots are pitch classes in `[0..11]` where 0 is C, counted the same way as the MIDI note numbers sent by the ribbon
+//! controller so that the `RIBBON CV` and MIDI outputs always agree on which notes are in the scale.
+
+use crate::midi_generator::LOWEST_MIDI_NOTE;
+
+use synth_utils::quantizer::{Note, Quantizer};
+
+/// The selectable scales are represented here
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Scale {
+    Chromatic,
+    Major,
+    NaturalMinor,
+    HarmonicMinor,
+    MelodicMinor,
+    Dorian,
+    Phrygian,
+    Lydian,
+    Mixolydian,
+    Locrian,
+    MajorPentatonic,
+    MinorPentatonic,
+    Blues,
+    WholeTone,
+    /// A user defined scale, bit `n` set means the note `n` semitones above the root is allowed
+    User(u16),
+}
+
+impl Scale {
+    /// `s.intervals()` is the scale as a bitfield, bit `n` is set iff the note `n` semitones above the root is allowed
+    ///
+    /// A user scale with no notes allowed is treated as chromatic, a quantizer needs at least one note to snap to.
+    pub fn intervals(&self) -> u16 {
+        let intervals = match self {
+            Scale::Chromatic => 0b1111_1111_1111,
+            Scale::Major => 0b1010_1011_0101,
+            Scale::NaturalMinor => 0b0101_1010_1101,
+            Scale::HarmonicMinor => 0b1001_1010_1101,
+            Scale::MelodicMinor => 0b1010_1010_1101,
+            Scale::Dorian => 0b0110_1010_1101,
+            Scale::Phrygian => 0b0101_1010_1011,
+            Scale::Lydian => 0b1010_1101_0101,
+            Scale::Mixolydian => 0b0110_1011_0101,
+            Scale::Locrian => 0b0101_0110_1011,
+            Scale::MajorPentatonic => 0b0010_1001_0101,
+            Scale::MinorPentatonic => 0b0100_1010_1001,
+            Scale::Blues => 0b0100_1110_1001,
+            Scale::WholeTone => 0b0101_0101_0101,
+            Scale::User(intervals) => intervals & ALL_NOTES,
+        };
+
+        if intervals == 0 {
+            ALL_NOTES
+        } else {
+            intervals
+        }
+    }
+
+    /// `s.contains(r, n)` is true iff MIDI note `n` is in scale `s` with root `r`
+    pub fn contains(&self, root: u8, midi_note: u8) -> bool {
+        let degree = (midi_note + NUM_NOTES - root % NUM_NOTES) % NUM_NOTES;
+        self.intervals() & (1 << degree) != 0
+    }
+
+    /// `s.apply_to(q, r)` restricts quantizer `q` to the notes of scale `s` with root `r`
+    ///
+    /// The quantizer counts notes from zero volts, which is played as MIDI note `LOWEST_MIDI_NOTE`.
+    pub fn apply_to(&self, quantizer: &mut Quantizer, root: u8) {
+        for n in 0..NUM_NOTES {
+            let note = [Note::new(n)];
+            if self.contains(root, n + LOWEST_MIDI_NOTE) {
+                quantizer.allow(&note);
+            } else {
+                quantizer.forbid(&note);
+            }
+        }
+    }
+}
+
+/// The largest root note, roots are pitch classes where 0 is C and 11 is B
+pub const MAX_ROOT: u8 = NUM_NOTES - 1;
+
+const NUM_NOTES: u8 = 12;
+const ALL_NOTES: u16 = 0b1111_1111_1111;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use synth_utils::quantizer::SEMITONE_WIDTH;
+
+    const C: u8 = 0;
+    const D: u8 = 2;
+    const MIDDLE_C: u8 = 60;
+
+    fn notes_of(scale: Scale, root: u8) -> std::vec::Vec<u8> {
+        (MIDDLE_C..MIDDLE_C + 12)
+            .filter(|&n| scale.contains(root, n))
+            .map(|n| n - MIDDLE_C)
+            .collect()
+    }
+
+    #[test]
+    fn scales_have_the_right_notes() {
+        assert_eq!(notes_of(Scale::Major, C), [0, 2, 4, 5, 7, 9, 11]);
+        assert_eq!(notes_of(Scale::NaturalMinor, C), [0, 2, 3, 5, 7, 8, 10]);
+        assert_eq!(notes_of(Scale::HarmonicMinor, C), [0, 2, 3, 5, 7, 8, 11]);
+        assert_eq!(notes_of(Scale::MelodicMinor, C), [0, 2, 3, 5, 7, 9, 11]);
+        assert_eq!(notes_of(Scale::MajorPentatonic, C), [0, 2, 4, 7, 9]);
+        assert_eq!(notes_of(Scale::MinorPentatonic, C), [0, 3, 5, 7, 10]);
+        assert_eq!(notes_of(Scale::Blues, C), [0, 3, 5, 6, 7, 10]);
+        assert_eq!(notes_of(Scale::WholeTone, C), [0, 2, 4, 6, 8, 10]);
+        assert_eq!(notes_of(Scale::Chromatic, C).len(), 12);
+    }
+
+    #[test]
+    fn modes_are_the_major_scale_from_another_degree() {
+        // the white keys, starting from each degree of C major
+        for (mode, root) in [
+            (Scale::Dorian, 2),
+            (Scale::Phrygian, 4),
+            (Scale::Lydian, 5),
+            (Scale::Mixolydian, 7),
+            (Scale::NaturalMinor, 9),
+            (Scale::Locrian, 11),
+        ] {
+            assert_eq!(notes_of(mode, root), notes_of(Scale::Major, C));
+        }
+    }
+
+    #[test]
+    fn root_transposes_the_scale() {
+        assert_eq!(notes_of(Scale::Major, D), [1, 2, 4, 6, 7, 9, 11]);
+    }
+
+    #[test]
+    fn user_scale_is_a_bitmask_and_empty_means_chromatic() {
+        assert_eq!(notes_of(Scale::User(0b1001_0001), C), [0, 4, 7]);
+        assert_eq!(notes_of(Scale::User(0), C).len(), 12);
+    }
+
+    #[test]
+    fn quantizer_snaps_to_the_scale() {
+        let mut q = Quantizer::new();
+        Scale::Major.apply_to(&mut q, C);
+
+        // every note the quantizer produces across the whole range is in the scale
+        for i in 0..(3 * 12 * 4) {
+            let note = q.convert(i as f32 * SEMITONE_WIDTH / 4.0).note_num;
+            assert!(Scale::Major.contains(C, note + LOWEST_MIDI_NOTE));
+        }
+    }
+}
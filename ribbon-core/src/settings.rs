@@ -0,0 +1,991 @@
+//! # Settings
+//!
+//! The user settings are kept in internal flash so they survive a power cycle.
+//!
+//! Erasing wears the flash out, so the settings are kept in a log. Each save appends a new record after the previous
+//! ones, and loading scans the log and takes the newest record which is intact.
+//!
+//! The log takes up one of two flash pages. Once its page is full the log moves to the other page, starting with the
+//! settings being saved, and the full page is erased only after the new page has been written and read back. So there
+//! is always a page holding the settings, even if the power goes out part way through a save. Each page starts with a
+//! header holding a generation which counts up with each move, so if the power went out before the full page was
+//! erased the newer page is used.
+//!
+//! ```text
+//! page:   | magic (2) | generation (2) | inverted generation (2) | padding (2) | record | record | ...
+//! record: | magic (2) | version (1) | payload length (2) | payload | CRC-32 (4) | padding to a whole flash word |
+//! ```
+//!
+//! The CRC covers the header and the payload, so a record which was only partly written when the power went out is
+//! skipped. Records from a different settings version are skipped too. If no record can be used the defaults are used
+//! instead.
+//!
+//! The payload is a list of fields, one or more bytes each, in a fixed order. New fields are only ever added at the end
+//! so that records saved before the field existed still load, with the default value for the missing field.
+
+use crate::{
+    board::{
+        FlashError, SettingsFlash, FLASH_ERASED, FLASH_WORD_LEN, SETTINGS_NUM_PAGES,
+        SETTINGS_PAGE_LEN,
+    },
+    dac_calibration::{ChannelCalibration, DacCalibration},
+    midi_generator::{MidiMode, TransitionPolicy, DEFAULT_PITCH_BEND_RANGE},
+    pitch_engine::{PitchEngine, RibbonSpan},
+    ribbon_calibration::{RibbonCalibration, RibbonMap, MAX_MAP_POINTS},
+    scale::Scale,
+    transpose::Transpose,
+    tuning::{TuningTable, MAX_TUNING_TABLE_BYTES},
+    velocity::VelocityCurve,
+};
+
+use core::ops::Range;
+use heapless::Vec;
+
+/// The settings which are kept across power cycles are represented here
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Settings {
+    pub midi_mode: MidiMode,
+    pub transition_policy: TransitionPolicy,
+    pub velocity_curve: VelocityCurve,
+    pub pitch_bend_range: u8,
+    pub scale: Scale,
+    pub scale_root: u8,
+    pub transpose: Transpose,
+    pub span: RibbonSpan,
+    pub mts: bool,
+    pub dac_calibration: DacCalibration,
+    pub ribbon_calibration: RibbonCalibration,
+    pub tuning: Option<TuningTable>,
+}
+
+impl Settings {
+    /// `Settings::new()` is the default settings, the same as a new pitch engine
+    pub fn new() -> Self {
+        Self {
+            midi_mode: MidiMode::Standard,
+            transition_policy: TransitionPolicy::OverlappingLegato,
+            velocity_curve: VelocityCurve::Linear,
+            pitch_bend_range: DEFAULT_PITCH_BEND_RANGE,
+            scale: Scale::Chromatic,
+            scale_root: 0,
+            transpose: Transpose::default(),
+            span: RibbonSpan::default(),
+            mts: false,
+            dac_calibration: DacCalibration::new(),
+            ribbon_calibration: RibbonCalibration::new(),
+            tuning: None,
+        }
+    }
+
+    /// `Settings::of(pe, dc)` is the current settings of pitch engine `pe` with DAC calibration `dc`
+    pub fn of(engine: &PitchEngine, dac_calibration: DacCalibration) -> Self {
+        let (scale, scale_root) = engine.scale();
+        Self {
+            midi_mode: engine.midi_mode(),
+            transition_policy: engine.transition_policy(),
+            velocity_curve: engine.velocity_curve(),
+            pitch_bend_range: engine.pitch_bend_range(),
+            scale,
+            scale_root,
+            transpose: engine.transpose(),
+            span: engine.span(),
+            mts: engine.mts(),
+            dac_calibration,
+            ribbon_calibration: engine.ribbon_calibration(),
+            tuning: engine.tuning().copied(),
+        }
+    }
+
+    /// `s.apply_to(pe)` sets every setting of pitch engine `pe` to settings `s`, the DAC calibration is for the board
+    pub fn apply_to(&self, engine: &mut PitchEngine) {
+        engine.set_midi_mode(self.midi_mode);
+        engine.set_transition_policy(self.transition_policy);
+        engine.set_velocity_curve(self.velocity_curve);
+        engine.set_pitch_bend_range(self.pitch_bend_range);
+        engine.set_scale(self.scale, self.scale_root);
+        engine.set_transpose(self.transpose);
+        engine.set_span(self.span);
+        engine.set_mts(self.mts);
+        engine.set_ribbon_calibration(self.ribbon_calibration);
+        engine.set_tuning(self.tuning);
+    }
+
+    /// `s.to_bytes()` is settings `s` as a record payload
+    pub fn to_bytes(&self) -> Vec<u8, MAX_SETTINGS_LEN> {
+        let (curve, fixed_velocity) = match self.velocity_curve {
+            VelocityCurve::Linear => (0, 0),
+            VelocityCurve::Soft => (1, 0),
+            VelocityCurve::Hard => (2, 0),
+            VelocityCurve::Fixed(vel) => (3, vel),
+        };
+        let (scale, user_intervals) = match self.scale {
+            Scale::User(intervals) => (USER_SCALE, intervals),
+            named => (index_of(&NAMED_SCALES, named), 0),
+        };
+        let [intervals_lo, intervals_hi] = user_intervals.to_le_bytes();
+
+        let mut bytes = Vec::new();
+        bytes
+            .extend_from_slice(&[
+                index_of(&MIDI_MODES, self.midi_mode),
+                index_of(&TRANSITION_POLICIES, self.transition_policy),
+                curve,
+                fixed_velocity,
+                self.pitch_bend_range,
+                scale,
+                intervals_lo,
+                intervals_hi,
+                self.scale_root,
+                self.transpose.semitones() as u8,
+                self.transpose.octaves() as u8,
+                index_of(&SPANS, self.span),
+                self.mts as u8,
+            ])
+            .ok();
+        let cal = &self.dac_calibration;
+        [cal.a.gain, cal.a.offset, cal.b.gain, cal.b.offset]
+            .iter()
+            .for_each(|f| {
+                bytes.extend_from_slice(&f.to_le_bytes()).ok();
+            });
+        // each ribbon map is its number of readings followed by the readings
+        for map in [
+            &self.ribbon_calibration.main_ribbon,
+            &self.ribbon_calibration.mod_ribbon,
+        ] {
+            bytes.push(map.readings().len() as u8).ok();
+            map.readings().iter().for_each(|f| {
+                bytes.extend_from_slice(&f.to_le_bytes()).ok();
+            });
+        }
+        match &self.tuning {
+            Some(tuning) => bytes.extend_from_slice(&tuning.to_bytes()).ok(),
+            None => bytes.push(NO_TUNING).ok(),
+        };
+        bytes
+    }
+
+    /// `Settings::from_bytes(bs)` is the settings in record payload `bs`, see `to_bytes`
+    ///
+    /// Fields which are missing from the end of the payload or hold values which are not understood get their default.
+    pub fn from_bytes(bytes: &[u8]) -> Self {
+        let defaults = Self::new();
+        let byte = |i: usize| bytes.get(i).copied();
+
+        let velocity_curve = match (byte(2), byte(3)) {
+            (Some(0), _) => VelocityCurve::Linear,
+            (Some(1), _) => VelocityCurve::Soft,
+            (Some(2), _) => VelocityCurve::Hard,
+            (Some(3), Some(vel)) => VelocityCurve::Fixed(vel),
+            _ => defaults.velocity_curve,
+        };
+        let scale = match (byte(5), byte(6), byte(7)) {
+            (Some(USER_SCALE), Some(lo), Some(hi)) => Scale::User(u16::from_le_bytes([lo, hi])),
+            _ => pick(byte(5), &NAMED_SCALES, defaults.scale),
+        };
+        let transpose = match (byte(9), byte(10)) {
+            (Some(semitones), Some(octaves)) => Transpose::new(semitones as i8, octaves as i8),
+            _ => defaults.transpose,
+        };
+
+        let main_map = ribbon_map(bytes.get(MAIN_MAP_AT..));
+        let mod_map_at = MAIN_MAP_AT + 1 + main_map.map_or(0, |m| m.readings().len() * 4);
+        let mod_map = ribbon_map(bytes.get(mod_map_at..));
+        let tuning_at = field_ranges(bytes)[NUM_FIELDS - 1].clone();
+        let tuning = bytes
+            .get(tuning_at)
+            .and_then(|table| TuningTable::from_bytes(table).ok());
+
+        Self {
+            midi_mode: pick(byte(0), &MIDI_MODES, defaults.midi_mode),
+            transition_policy: pick(byte(1), &TRANSITION_POLICIES, defaults.transition_policy),
+            velocity_curve,
+            pitch_bend_range: byte(4).unwrap_or(defaults.pitch_bend_range),
+            scale,
+            scale_root: byte(8).unwrap_or(defaults.scale_root),
+            transpose,
+            span: pick(byte(11), &SPANS, defaults.span),
+            mts: byte(12).map_or(defaults.mts, |b| b == 1),
+            dac_calibration: DacCalibration {
+                a: channel_calibration(bytes.get(13..21)).unwrap_or(defaults.dac_calibration.a),
+                b: channel_calibration(bytes.get(21..29)).unwrap_or(defaults.dac_calibration.b),
+            },
+            ribbon_calibration: RibbonCalibration {
+                main_ribbon: main_map.unwrap_or(defaults.ribbon_calibration.main_ribbon),
+                // the MOD ribbon map can't be trusted if the main ribbon map before it is broken
+                mod_ribbon: mod_map
+                    .filter(|_| main_map.is_some())
+                    .unwrap_or(defaults.ribbon_calibration.mod_ribbon),
+            },
+            tuning,
+        }
+    }
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// `field_ranges(p)` is where each field of record payload `p` is, in the order they are stored, see `Settings::to_bytes`
+///
+/// Each ribbon map is as long as its number of readings says, and the tuning as long as its table says, so where the
+/// MOD ribbon map and the tuning are depends on the payload.
+pub fn field_ranges(payload: &[u8]) -> [Range<usize>; NUM_FIELDS] {
+    let map_at = |at: usize| at..at + 1 + payload.get(at).map_or(0, |&len| len as usize * 4);
+    let main_map = map_at(MAIN_MAP_AT);
+    let mod_map = map_at(main_map.end);
+    let tuning = match payload.get(mod_map.end..) {
+        Some(table @ [first, ..]) if *first != NO_TUNING => {
+            mod_map.end..mod_map.end + TuningTable::len_in(table)
+        }
+        _ => mod_map.end..mod_map.end + 1,
+    };
+    [
+        0..1,
+        1..2,
+        2..4,
+        4..5,
+        5..8,
+        8..9,
+        9..11,
+        11..12,
+        12..13,
+        13..21,
+        21..29,
+        main_map,
+        mod_map,
+        tuning,
+    ]
+}
+
+/// `load(f)` is the newest intact settings saved in flash `f`, if there are any
+pub fn load<F: SettingsFlash>(flash: &mut F) -> Option<Settings> {
+    scan(flash).newest
+}
+
+/// `save(f, s)` saves settings `s` in flash `f`, doing nothing if they are already the newest settings saved
+///
+/// The settings are added to the end of the log. When there is no room left the log moves to the other page, and the
+/// full page is only erased once the settings have been written to the other page and read back.
+pub fn save<F: SettingsFlash>(flash: &mut F, settings: &Settings) -> Result<(), FlashError> {
+    let log = scan(flash);
+    if log.newest == Some(*settings) {
+        return Ok(());
+    }
+
+    let record = record(settings);
+    let free = log
+        .active
+        .as_ref()
+        .and_then(|page| Some((page.index, page.free?)));
+    match free {
+        Some((page, offset)) if offset + record.len() <= SETTINGS_PAGE_LEN => {
+            // if the write fails the page is no good as it is, start over on the other page
+            flash
+                .settings_write(page, offset, &record)
+                .or_else(|_| move_log(flash, log.active, &record))
+        }
+        _ => move_log(flash, log.active, &record),
+    }
+}
+
+/// `move_log(f, a, r)` starts a new log in flash `f` with record `r`, on the page after active page `a` if there is one
+///
+/// The new page only becomes the active page once its header is written, and the old page is only erased after that.
+/// Losing power at any point leaves either the old settings or the new ones in flash.
+fn move_log<F: SettingsFlash>(
+    flash: &mut F,
+    active: Option<Page>,
+    record: &[u8],
+) -> Result<(), FlashError> {
+    let (index, generation) = match active {
+        Some(ref page) => (
+            (page.index + 1) % SETTINGS_NUM_PAGES,
+            page.generation.wrapping_add(1),
+        ),
+        None => (0, 0),
+    };
+
+    flash.settings_erase(index)?;
+    write_and_verify(flash, index, PAGE_HEADER_LEN, record)?;
+    write_and_verify(flash, index, 0, &page_header(generation))?;
+
+    match active {
+        Some(page) => flash.settings_erase(page.index),
+        None => Ok(()),
+    }
+}
+
+/// `write_and_verify(f, p, o, bs)` programs bytes `bs` into page `p` of flash `f` at offset `o`, and reads them back
+fn write_and_verify<F: SettingsFlash>(
+    flash: &mut F,
+    page: usize,
+    offset: usize,
+    bytes: &[u8],
+) -> Result<(), FlashError> {
+    flash.settings_write(page, offset, bytes)?;
+
+    let mut written = [0_u8; MAX_RECORD_LEN];
+    let written = &mut written[..bytes.len()];
+    flash.settings_read(page, offset, written);
+    if written == bytes {
+        Ok(())
+    } else {
+        Err(FlashError::Failed)
+    }
+}
+
+/// The result of scanning the settings log is represented here
+struct Log {
+    // the newest intact settings, if any
+    newest: Option<Settings>,
+    // the page the log is on, if any page has a header
+    active: Option<Page>,
+}
+
+/// A settings page with a header is represented here
+struct Page {
+    index: usize,
+    // counts up each time the log moves to another page, so the newer page wins if both have a header
+    generation: u16,
+    // the offset where the next record can be written, if the rest of the page can be trusted
+    free: Option<usize>,
+}
+
+/// `scan(f)` is the settings log in flash `f`
+///
+/// Both pages only have a header if the power went out between the log moving and the old page being erased, and then
+/// the settings on the old page are only used if the new page has none.
+fn scan<F: SettingsFlash>(flash: &mut F) -> Log {
+    let mut pages: Vec<Page, SETTINGS_NUM_PAGES> = (0..SETTINGS_NUM_PAGES)
+        .filter_map(|index| {
+            let mut header = [0_u8; PAGE_HEADER_LEN];
+            flash.settings_read(index, 0, &mut header);
+            let generation = generation(&header)?;
+            Some(Page {
+                index,
+                generation,
+                free: None,
+            })
+        })
+        .collect();
+    // newest first, the generation wraps around so only the difference between them counts
+    if let [a, b] = &pages[..] {
+        if (a.generation.wrapping_sub(b.generation) as i16) < 0 {
+            pages.swap(0, 1);
+        }
+    }
+
+    let mut newest = None;
+    for page in pages.iter_mut().rev() {
+        let (settings, free) = scan_page(flash, page.index);
+        page.free = free;
+        newest = settings.or(newest);
+    }
+
+    Log {
+        newest,
+        active: pages.into_iter().next(),
+    }
+}
+
+/// `scan_page(f, p)` is the newest intact settings on page `p` of flash `f`, and the offset where the next record can
+/// be written if the rest of the page can be trusted
+fn scan_page<F: SettingsFlash>(flash: &mut F, page: usize) -> (Option<Settings>, Option<usize>) {
+    let mut newest = None;
+    let mut offset = PAGE_HEADER_LEN;
+
+    while offset + HEADER_LEN <= SETTINGS_PAGE_LEN {
+        let mut header = [0_u8; HEADER_LEN];
+        flash.settings_read(page, offset, &mut header);
+
+        let len = u16::from_le_bytes([header[3], header[4]]) as usize;
+        let payload_len = match header {
+            h if h.iter().all(|&b| b == FLASH_ERASED) => return (newest, Some(offset)),
+            [MAGIC_0, MAGIC_1, ..] if len <= MAX_SETTINGS_LEN => len,
+            // the rest of the page is garbage, there is no telling where the next record starts
+            _ => break,
+        };
+
+        let record_len = record_len(payload_len);
+        if SETTINGS_PAGE_LEN < offset + record_len {
+            break;
+        }
+
+        let mut record = [0_u8; MAX_RECORD_LEN];
+        let record = &mut record[..record_len];
+        flash.settings_read(page, offset, record);
+
+        let crc_at = HEADER_LEN + payload_len;
+        let crc = u32::from_le_bytes([
+            record[crc_at],
+            record[crc_at + 1],
+            record[crc_at + 2],
+            record[crc_at + 3],
+        ]);
+        if crc == crc32(&record[..crc_at]) && header[2] == SETTINGS_VERSION {
+            newest = Some(Settings::from_bytes(&record[HEADER_LEN..crc_at]));
+        }
+
+        offset += record_len;
+    }
+
+    (newest, None)
+}
+
+/// `page_header(g)` is the header of a page of the log with generation `g`, one flash word long
+fn page_header(generation: u16) -> [u8; PAGE_HEADER_LEN] {
+    let [lo, hi] = generation.to_le_bytes();
+    let [not_lo, not_hi] = (!generation).to_le_bytes();
+    let mut header = [FLASH_ERASED; PAGE_HEADER_LEN];
+    header[..6].copy_from_slice(&[PAGE_MAGIC_0, PAGE_MAGIC_1, lo, hi, not_lo, not_hi]);
+    header
+}
+
+/// `generation(h)` is the generation in page header `h`, if it is a page header which was completely written
+fn generation(header: &[u8; PAGE_HEADER_LEN]) -> Option<u16> {
+    match header {
+        [PAGE_MAGIC_0, PAGE_MAGIC_1, lo, hi, not_lo, not_hi, ..] => {
+            let generation = u16::from_le_bytes([*lo, *hi]);
+            Some(generation).filter(|g| !g == u16::from_le_bytes([*not_lo, *not_hi]))
+        }
+        _ => None,
+    }
+}
+
+/// `record(s)` is settings `s` as a complete record, ready to be written to flash
+fn record(settings: &Settings) -> Vec<u8, MAX_RECORD_LEN> {
+    let payload = settings.to_bytes();
+
+    let mut record = Vec::new();
+    let [len_lo, len_hi] = (payload.len() as u16).to_le_bytes();
+    record
+        .extend_from_slice(&[MAGIC_0, MAGIC_1, SETTINGS_VERSION, len_lo, len_hi])
+        .ok();
+    record.extend_from_slice(&payload).ok();
+    let crc = crc32(&record);
+    record.extend_from_slice(&crc.to_le_bytes()).ok();
+    record.resize(record_len(payload.len()), FLASH_ERASED).ok();
+    record
+}
+
+/// `record_len(n)` is the length of a record with a payload of `n` bytes, padded to a whole number of flash words
+fn record_len(payload_len: usize) -> usize {
+    let unpadded = HEADER_LEN + payload_len + CRC_LEN;
+    unpadded.div_ceil(FLASH_WORD_LEN) * FLASH_WORD_LEN
+}
+
+/// `crc32(bs)` is the CRC-32 checksum of bytes `bs`, the same one used by zip and ethernet
+fn crc32(bytes: &[u8]) -> u32 {
+    let mut crc = 0xFFFF_FFFF_u32;
+    for &b in bytes {
+        crc ^= b as u32;
+        for _ in 0..8 {
+            let mask = 0_u32.wrapping_sub(crc & 1);
+            crc = (crc >> 1) ^ (0xEDB8_8320 & mask);
+        }
+    }
+    !crc
+}
+
+/// `channel_calibration(bs)` is the DAC channel calibration stored as gain then offset in bytes `bs`, if it is there and
+/// believable
+fn channel_calibration(bytes: Option<&[u8]>) -> Option<ChannelCalibration> {
+    let f32_at = |bs: &[u8], i: usize| f32::from_le_bytes([bs[i], bs[i + 1], bs[i + 2], bs[i + 3]]);
+    bytes
+        .map(|bs| ChannelCalibration {
+            gain: f32_at(bs, 0),
+            offset: f32_at(bs, 4),
+        })
+        .filter(|cal| cal.is_plausible())
+}
+
+/// `ribbon_map(bs)` is the ribbon map stored as a number of readings followed by the readings at the start of bytes
+/// `bs`, if it is there and makes sense
+fn ribbon_map(bytes: Option<&[u8]>) -> Option<RibbonMap> {
+    let bytes = bytes?;
+    let len = *bytes.first()? as usize;
+    if MAX_MAP_POINTS < len {
+        return None;
+    }
+
+    let mut readings = [0.0_f32; MAX_MAP_POINTS];
+    for (i, reading) in readings[..len].iter_mut().enumerate() {
+        let at = 1 + i * 4;
+        let b = bytes.get(at..at + 4)?;
+        *reading = f32::from_le_bytes([b[0], b[1], b[2], b[3]]);
+    }
+    RibbonMap::from_readings(&readings[..len])
+}
+
+/// `pick(b, cs, d)` is the choice in `cs` at position `b`, or default `d` if there is no such byte or choice
+fn pick<T: Copy>(byte: Option<u8>, choices: &[T], default: T) -> T {
+    byte.and_then(|b| choices.get(b as usize).copied())
+        .unwrap_or(default)
+}
+
+/// `index_of(cs, c)` is the position of `c` in choices `cs` as a byte
+fn index_of<T: PartialEq>(choices: &[T], choice: T) -> u8 {
+    choices.iter().position(|c| *c == choice).unwrap_or(0) as u8
+}
+
+/// Fake flash pages in RAM, for running the settings on a computer
+pub struct RamFlash {
+    bytes: [u8; SETTINGS_PAGE_LEN * SETTINGS_NUM_PAGES],
+    erase_count: u32,
+}
+
+impl RamFlash {
+    /// `RamFlash::new()` is new erased fake flash pages
+    pub fn new() -> Self {
+        Self {
+            bytes: [FLASH_ERASED; SETTINGS_PAGE_LEN * SETTINGS_NUM_PAGES],
+            erase_count: 0,
+        }
+    }
+
+    /// `rf.erase_count()` is the number of pages of fake flash `rf` which have been erased
+    pub fn erase_count(&self) -> u32 {
+        self.erase_count
+    }
+
+    /// `rf.bytes_mut()` is the raw bytes of every page of fake flash `rf` one after the other, for simulating corruption or keeping them between runs
+    pub fn bytes_mut(&mut self) -> &mut [u8] {
+        &mut self.bytes
+    }
+}
+
+impl Default for RamFlash {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl SettingsFlash for RamFlash {
+    fn settings_read(&mut self, page: usize, offset: usize, bytes: &mut [u8]) {
+        let start = page * SETTINGS_PAGE_LEN + offset;
+        bytes.copy_from_slice(&self.bytes[start..start + bytes.len()]);
+    }
+
+    fn settings_erase(&mut self, page: usize) -> Result<(), FlashError> {
+        self.bytes
+            .chunks_exact_mut(SETTINGS_PAGE_LEN)
+            .nth(page)
+            .ok_or(FlashError::OutOfBounds)?
+            .fill(FLASH_ERASED);
+        self.erase_count += 1;
+        Ok(())
+    }
+
+    fn settings_write(
+        &mut self,
+        page: usize,
+        offset: usize,
+        bytes: &[u8],
+    ) -> Result<(), FlashError> {
+        if !offset.is_multiple_of(FLASH_WORD_LEN) || !bytes.len().is_multiple_of(FLASH_WORD_LEN) {
+            return Err(FlashError::Misaligned);
+        }
+        let target = self
+            .bytes
+            .chunks_exact_mut(SETTINGS_PAGE_LEN)
+            .nth(page)
+            .and_then(|page| page.get_mut(offset..offset + bytes.len()))
+            .ok_or(FlashError::OutOfBounds)?;
+        if target.iter().any(|&b| b != FLASH_ERASED) {
+            return Err(FlashError::NotErased);
+        }
+        target.copy_from_slice(bytes);
+        Ok(())
+    }
+}
+
+/// The largest settings payload in bytes, with both ribbon maps and the tuning as long as they can be
+pub const MAX_SETTINGS_LEN: usize =
+    MAIN_MAP_AT + 2 * (1 + MAX_MAP_POINTS * 4) + MAX_TUNING_TABLE_BYTES;
+
+/// The version of the settings layout, records with any other version are ignored
+pub const SETTINGS_VERSION: u8 = 2;
+
+/// The number of fields in a record payload
+pub const NUM_FIELDS: usize = 14;
+
+const MAGIC_0: u8 = b'R';
+const MAGIC_1: u8 = b'S';
+
+const HEADER_LEN: usize = 5;
+const CRC_LEN: usize = 4;
+
+// each page of the log starts with a header holding its generation, and the generation inverted as a check
+const PAGE_MAGIC_0: u8 = b'R';
+const PAGE_MAGIC_1: u8 = b'L';
+const PAGE_HEADER_LEN: usize = FLASH_WORD_LEN;
+
+// where the main ribbon map starts in a record payload, after the fixed length fields
+const MAIN_MAP_AT: usize = 29;
+// the tuning field when there is no tuning, a tuning table starts with its version which is never 0
+const NO_TUNING: u8 = 0;
+const MAX_RECORD_LEN: usize = HEADER_LEN + MAX_SETTINGS_LEN + CRC_LEN;
+
+// the choices for each enumerated setting, in the order they are stored
+const MIDI_MODES: [MidiMode; 3] = [MidiMode::Standard, MidiMode::Mpe, MidiMode::PitchBendOnly];
+const TRANSITION_POLICIES: [TransitionPolicy; 3] = [
+    TransitionPolicy::OverlappingLegato,
+    TransitionPolicy::NoteOffFirst,
+    TransitionPolicy::SingleNote,
+];
+const SPANS: [RibbonSpan; 5] = [
+    RibbonSpan::Semitones12,
+    RibbonSpan::Semitones24,
+    RibbonSpan::Semitones32,
+    RibbonSpan::Semitones48,
+    RibbonSpan::Semitones60,
+];
+const NAMED_SCALES: [Scale; 14] = [
+    Scale::Chromatic,
+    Scale::Major,
+    Scale::NaturalMinor,
+    Scale::HarmonicMinor,
+    Scale::MelodicMinor,
+    Scale::Dorian,
+    Scale::Phrygian,
+    Scale::Lydian,
+    Scale::Mixolydian,
+    Scale::Locrian,
+    Scale::MajorPentatonic,
+    Scale::MinorPentatonic,
+    Scale::Blues,
+    Scale::WholeTone,
+];
+const USER_SCALE: u8 = NAMED_SCALES.len() as u8;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn custom() -> Settings {
+        Settings {
+            midi_mode: MidiMode::Mpe,
+            transition_policy: TransitionPolicy::SingleNote,
+            velocity_curve: VelocityCurve::Fixed(100),
+            pitch_bend_range: 12,
+            scale: Scale::User(0b1001_0001),
+            scale_root: 7,
+            transpose: Transpose::new(-5, 2),
+            span: RibbonSpan::Semitones48,
+            mts: true,
+            dac_calibration: DacCalibration {
+                a: ChannelCalibration::from_points(1.01, 4.02),
+                b: ChannelCalibration::from_points(0.99, 3.97),
+            },
+            ribbon_calibration: RibbonCalibration {
+                main_ribbon: RibbonMap::from_readings(&[0.02, 0.26, 0.5, 0.73, 0.98]).unwrap(),
+                mod_ribbon: RibbonMap::from_readings(&[0.01, 0.45, 0.99]).unwrap(),
+            },
+            tuning: Some(TuningTable::new(12.0, 60.25, &[0.0, 3.5, 7.0]).unwrap()),
+        }
+    }
+
+    #[test]
+    fn settings_round_trip_through_bytes() {
+        assert_eq!(Settings::from_bytes(&custom().to_bytes()), custom());
+
+        let named = Settings {
+            scale: Scale::Dorian,
+            ..custom()
+        };
+        assert_eq!(Settings::from_bytes(&named.to_bytes()), named);
+    }
+
+    #[test]
+    fn settings_round_trip_through_the_pitch_engine() {
+        assert_eq!(
+            Settings::of(&PitchEngine::new(), DacCalibration::new()),
+            Settings::new()
+        );
+
+        let mut engine = PitchEngine::new();
+        custom().apply_to(&mut engine);
+        assert_eq!(Settings::of(&engine, custom().dac_calibration), custom());
+    }
+
+    #[test]
+    fn field_ranges_cover_the_whole_payload_in_order() {
+        let bytes = custom().to_bytes();
+        let ranges = field_ranges(&bytes);
+
+        assert_eq!(ranges[0].start, 0);
+        assert!(ranges.windows(2).all(|r| r[0].end == r[1].start));
+        assert_eq!(ranges[NUM_FIELDS - 1].end, bytes.len());
+        // 5 readings in the main ribbon map and 3 in the MOD ribbon map
+        assert_eq!(ranges[11].len(), 1 + 5 * 4);
+        assert_eq!(ranges[12].len(), 1 + 3 * 4);
+        assert_eq!(
+            &bytes[ranges[13].clone()],
+            &custom().tuning.unwrap().to_bytes()[..]
+        );
+
+        let untuned = Settings {
+            tuning: None,
+            ..custom()
+        };
+        assert_eq!(field_ranges(&untuned.to_bytes())[13].len(), 1);
+    }
+
+    #[test]
+    fn missing_fields_get_their_defaults() {
+        let bytes = custom().to_bytes();
+        let short = Settings::from_bytes(&bytes[..5]);
+        assert_eq!(short.pitch_bend_range, 12);
+        assert_eq!(short.scale, Settings::new().scale);
+        assert_eq!(short.span, Settings::new().span);
+        assert_eq!(Settings::from_bytes(&[]), Settings::new());
+    }
+
+    #[test]
+    fn broken_ribbon_maps_are_not_loaded() {
+        let mut bytes = custom().to_bytes();
+        // swap two of the readings of the main ribbon map so they no longer increase
+        let first_reading = 30;
+        bytes[first_reading..first_reading + 8].rotate_left(4);
+
+        let loaded = Settings::from_bytes(&bytes);
+        assert_eq!(loaded.ribbon_calibration, RibbonCalibration::new());
+        assert_eq!(loaded.dac_calibration, custom().dac_calibration);
+    }
+
+    #[test]
+    fn unbelievable_dac_calibration_is_not_loaded() {
+        let broken = Settings {
+            dac_calibration: DacCalibration {
+                a: ChannelCalibration {
+                    gain: f32::NAN,
+                    offset: 0.0,
+                },
+                b: ChannelCalibration::from_points(0.0, 5.0),
+            },
+            ..custom()
+        };
+        assert_eq!(
+            Settings::from_bytes(&broken.to_bytes()).dac_calibration,
+            DacCalibration::new()
+        );
+    }
+
+    #[test]
+    fn blank_flash_has_no_settings() {
+        assert_eq!(load(&mut RamFlash::new()), None);
+    }
+
+    #[test]
+    fn saved_settings_load_again() {
+        let mut flash = RamFlash::new();
+        save(&mut flash, &custom()).unwrap();
+        assert_eq!(load(&mut flash), Some(custom()));
+    }
+
+    #[test]
+    fn newest_settings_win() {
+        let mut flash = RamFlash::new();
+        for range in 1..=10 {
+            let settings = Settings {
+                pitch_bend_range: range,
+                ..custom()
+            };
+            save(&mut flash, &settings).unwrap();
+        }
+        assert_eq!(load(&mut flash).unwrap().pitch_bend_range, 10);
+        // only to start the log
+        assert_eq!(flash.erase_count(), 1);
+    }
+
+    #[test]
+    fn pages_are_only_erased_when_full() {
+        let mut flash = RamFlash::new();
+        for i in 0..records_per_page() * 3 {
+            let settings = Settings {
+                pitch_bend_range: (i % 2) as u8 + 1,
+                ..custom()
+            };
+            save(&mut flash, &settings).unwrap();
+            assert_eq!(load(&mut flash), Some(settings));
+        }
+        // once to start the log, then each of the two moves erases the page moved to and the full page
+        assert_eq!(flash.erase_count(), 1 + 2 * 2);
+    }
+
+    #[test]
+    fn saving_unchanged_settings_writes_nothing() {
+        let mut flash = RamFlash::new();
+        save(&mut flash, &custom()).unwrap();
+        save(&mut flash, &custom()).unwrap();
+
+        let mut second_record = [0_u8; HEADER_LEN];
+        let second = PAGE_HEADER_LEN + record_len(custom().to_bytes().len());
+        flash.settings_read(0, second, &mut second_record);
+        assert_eq!(second_record, [FLASH_ERASED; HEADER_LEN]);
+    }
+
+    #[test]
+    fn corrupt_record_falls_back_to_the_one_before() {
+        let mut flash = RamFlash::new();
+        let older = Settings {
+            pitch_bend_range: 5,
+            ..custom()
+        };
+        save(&mut flash, &older).unwrap();
+        save(&mut flash, &custom()).unwrap();
+
+        // flip a bit in the payload of the newest record
+        let second = PAGE_HEADER_LEN + record_len(custom().to_bytes().len());
+        flash.bytes_mut()[second + HEADER_LEN] ^= 0x01;
+        assert_eq!(load(&mut flash), Some(older));
+
+        // and the next save goes after the corrupt record
+        save(&mut flash, &custom()).unwrap();
+        assert_eq!(load(&mut flash), Some(custom()));
+    }
+
+    #[test]
+    fn garbage_page_has_no_settings_and_is_erased_by_the_next_save() {
+        let mut flash = RamFlash::new();
+        flash.bytes_mut()[..8].copy_from_slice(&[1, 2, 3, 4, 5, 6, 7, 8]);
+        assert_eq!(load(&mut flash), None);
+
+        save(&mut flash, &custom()).unwrap();
+        assert_eq!(flash.erase_count(), 1);
+        assert_eq!(load(&mut flash), Some(custom()));
+    }
+
+    #[test]
+    fn other_versions_are_ignored() {
+        let mut flash = RamFlash::new();
+        save(&mut flash, &custom()).unwrap();
+
+        // rewrite the record as if it came from another version, with a matching CRC
+        let mut old = record(&custom());
+        old[2] = SETTINGS_VERSION + 1;
+        let crc_at = HEADER_LEN + custom().to_bytes().len();
+        let crc = crc32(&old[..crc_at]).to_le_bytes();
+        old[crc_at..crc_at + CRC_LEN].copy_from_slice(&crc);
+        flash.settings_erase(0).unwrap();
+        flash.settings_write(0, 0, &page_header(0)).unwrap();
+        flash.settings_write(0, PAGE_HEADER_LEN, &old).unwrap();
+
+        assert_eq!(load(&mut flash), None);
+    }
+
+    /// `records_per_page()` is the number of records of the custom settings which fit on a page
+    fn records_per_page() -> usize {
+        (SETTINGS_PAGE_LEN - PAGE_HEADER_LEN) / record_len(custom().to_bytes().len())
+    }
+
+    /// `full_log(f, o)` fills the first page of blank flash `f` with the log, the last settings being `o`
+    fn full_log(flash: &mut RamFlash, older: &Settings) {
+        for i in 0..records_per_page() - 1 {
+            let settings = Settings {
+                pitch_bend_range: i as u8,
+                ..custom()
+            };
+            save(flash, &settings).unwrap();
+        }
+        save(flash, older).unwrap();
+    }
+
+    #[test]
+    fn losing_power_while_the_log_moves_keeps_the_previous_settings() {
+        let older = Settings {
+            pitch_bend_range: 99,
+            ..custom()
+        };
+
+        // the log moves to the second page on the save after the first page fills up
+        let mut flash = RamFlash::new();
+        full_log(&mut flash, &older);
+        let before_move = flash.bytes_mut().to_vec();
+        save(&mut flash, &custom()).unwrap();
+        let after_move = flash.bytes_mut().to_vec();
+        assert_eq!(load(&mut flash), Some(custom()));
+
+        // the power goes out after the second page is erased, or part way through writing the new record or the page
+        // header, which is only programmed after the record
+        let second_page = SETTINGS_PAGE_LEN;
+        let record_len = record_len(custom().to_bytes().len());
+        for written in [0, FLASH_WORD_LEN, record_len] {
+            let mut flash = RamFlash::new();
+            flash.bytes_mut().copy_from_slice(&before_move);
+            let first_record = second_page + PAGE_HEADER_LEN;
+            let partly_written = first_record..first_record + written;
+            flash.bytes_mut()[partly_written.clone()].copy_from_slice(&after_move[partly_written]);
+            assert_eq!(load(&mut flash), Some(older));
+
+            // and the next save starts the move over
+            save(&mut flash, &custom()).unwrap();
+            assert_eq!(load(&mut flash), Some(custom()));
+        }
+
+        // the power goes out after the second page is complete but before the first page is erased
+        let mut flash = RamFlash::new();
+        flash.bytes_mut().copy_from_slice(&before_move);
+        flash.bytes_mut()[second_page..].copy_from_slice(&after_move[second_page..]);
+        assert_eq!(load(&mut flash), Some(custom()));
+
+        // and the next save goes after the new settings on the second page
+        let newer = Settings {
+            pitch_bend_range: 3,
+            ..custom()
+        };
+        save(&mut flash, &newer).unwrap();
+        assert_eq!(load(&mut flash), Some(newer));
+        let mut second_record = [0_u8; HEADER_LEN];
+        flash.settings_read(1, PAGE_HEADER_LEN + record_len, &mut second_record);
+        assert_eq!(&second_record[..2], &[MAGIC_0, MAGIC_1]);
+    }
+
+    #[test]
+    fn the_log_keeps_moving_between_the_pages() {
+        let mut flash = RamFlash::new();
+        for i in 0..records_per_page() * 4 {
+            let settings = Settings {
+                pitch_bend_range: (i % 2) as u8 + 1,
+                ..custom()
+            };
+            save(&mut flash, &settings).unwrap();
+
+            let page = (i / records_per_page()) % 2;
+            assert_eq!(scan(&mut flash).active.map(|p| p.index), Some(page));
+            // the page the log moved from is blank again
+            let mut other = [0_u8; PAGE_HEADER_LEN];
+            flash.settings_read(1 - page, 0, &mut other);
+            assert_eq!(other, [FLASH_ERASED; PAGE_HEADER_LEN]);
+        }
+    }
+
+    #[test]
+    fn the_newer_generation_wins_when_it_wraps_around() {
+        assert_eq!(generation(&page_header(u16::MAX)), Some(u16::MAX));
+        assert_eq!(generation(&[FLASH_ERASED; PAGE_HEADER_LEN]), None);
+
+        let older = Settings {
+            pitch_bend_range: 7,
+            ..custom()
+        };
+        let mut flash = RamFlash::new();
+        flash.settings_write(0, 0, &page_header(0)).unwrap();
+        flash
+            .settings_write(0, PAGE_HEADER_LEN, &record(&custom()))
+            .unwrap();
+        flash.settings_write(1, 0, &page_header(u16::MAX)).unwrap();
+        flash
+            .settings_write(1, PAGE_HEADER_LEN, &record(&older))
+            .unwrap();
+        assert_eq!(load(&mut flash), Some(custom()));
+    }
+
+    #[test]
+    fn crc32_matches_the_standard_check_value() {
+        assert_eq!(crc32(b"123456789"), 0xCBF4_3926);
+    }
+}
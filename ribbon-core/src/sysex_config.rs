@@ -0,0 +1,598 @@
+//! # SysEx configuration
+//!
+//! Every setting can be read and written at runtime with SysEx messages, so that the settings which can't be reached
+//! from the panel can still be changed, e.g. with the `ribbon-sysex` host crate. Requests are received on the MIDI input
+//! and answered on the MIDI output.
+//!
+//! ```text
+//! F0 7D 02 <device> <command> <data> <checksum> F7
+//! ```
+//!
+//! The manufacturer ID 7D is for non-commercial use, and 02 marks a configuration message, 01 is the calibration report,
+//! see `auto_calibration`. The device is the position of the `MIDI CH` switch, 0 for channel 1 up to 15 for channel 16,
+//! or `ALL_DEVICES` for every ribbon controller in the MIDI chain. The checksum makes the low 7 bits of the sum of every
+//! byte from the device to the checksum 0.
+//!
+//! The requests are:
+//!
+//! * `01 <param>` get, answered with the value of the parameter
+//!
+//! * `02 <param> <value>` set, answered with the new value of the parameter, which may have been clamped to its range
+//!
+//! * `03` dump, answered with the value of every parameter in order, one message each
+//!
+//! * `04` reset, sets every parameter apart from the calibrations back to its default, answered with `14`
+//!
+//! The answers are:
+//!
+//! * `11 <param> <value>` the value of a parameter
+//!
+//! * `14` the reset is done
+//!
+//! * `7F <error>` the request can't be carried out, see `ConfigError`
+//!
+//! The parameters are numbered from 0 in the order of `Param`. Each value is the bytes of the parameter's field in the
+//! saved settings, see `settings`, and as SysEx data only has 7 bits each byte of the value is sent as two data bytes,
+//! the high 4 bits then the low 4 bits.
+
+use crate::{
+    settings::{field_ranges, Settings, MAX_SETTINGS_LEN, NUM_FIELDS},
+    tuning::MAX_TUNING_TABLE_BYTES,
+};
+
+use heapless::Vec;
+
+/// The settings which can be configured are represented here, in the order they are numbered
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Param {
+    /// The MIDI mode: 0 standard, 1 MPE, 2 pitch bend only
+    MidiMode,
+    /// How one note hands over to the next: 0 overlapping legato, 1 note-off first, 2 single note
+    TransitionPolicy,
+    /// The velocity curve: 0 linear, 1 soft, 2 hard, 3 fixed, then the fixed velocity or 0
+    VelocityCurve,
+    /// The pitch bend range of the receiving instrument in semitones
+    PitchBendRange,
+    /// The scale: its number, then the notes of a user scale as a 12 bit mask, little endian, or 0
+    Scale,
+    /// The root note of the scale, 0 for C up to 11 for B
+    ScaleRoot,
+    /// The transpose: semitones, then octaves, both signed
+    Transpose,
+    /// The ribbon span: 0 12 semitones, 1 24, 2 32, 3 48, 4 60
+    Span,
+    /// MIDI Tuning Standard retuning: 0 off, 1 on
+    Mts,
+    /// The `RIBBON CV` DAC calibration: gain then offset, both little endian `f32`
+    DacCalibrationA,
+    /// The `MOD CV` DAC calibration: gain then offset, both little endian `f32`
+    DacCalibrationB,
+    /// The main ribbon map: the number of readings, then the readings as little endian `f32`
+    MainRibbonMap,
+    /// The MOD ribbon map: the number of readings, then the readings as little endian `f32`
+    ModRibbonMap,
+    /// The microtonal tuning: a tuning table as written by `ribbon-scala`, see `TuningTable::to_bytes`, or 0 for none
+    Tuning,
+}
+
+impl Param {
+    /// Every parameter, in the order they are numbered
+    pub const ALL: [Param; NUM_PARAMS] = [
+        Param::MidiMode,
+        Param::TransitionPolicy,
+        Param::VelocityCurve,
+        Param::PitchBendRange,
+        Param::Scale,
+        Param::ScaleRoot,
+        Param::Transpose,
+        Param::Span,
+        Param::Mts,
+        Param::DacCalibrationA,
+        Param::DacCalibrationB,
+        Param::MainRibbonMap,
+        Param::ModRibbonMap,
+        Param::Tuning,
+    ];
+
+    /// `Param::from_id(id)` is the parameter numbered `id`, if there is one
+    pub fn from_id(id: u8) -> Option<Self> {
+        Self::ALL.get(id as usize).copied()
+    }
+
+    /// `p.id()` is the number of parameter `p`
+    pub fn id(self) -> u8 {
+        self as u8
+    }
+}
+
+/// The value of a parameter, as the bytes of its field in the saved settings
+pub type Value = Vec<u8, MAX_VALUE_LEN>;
+
+/// A whole configuration SysEx message
+pub type Sysex = Vec<u8, MAX_CONFIG_SYSEX_LEN>;
+
+/// `value_of(s, p)` is the value of parameter `p` in settings `s`
+pub fn value_of(settings: &Settings, param: Param) -> Value {
+    let payload = settings.to_bytes();
+    let field = field_ranges(&payload)[param as usize].clone();
+    Vec::from_slice(&payload[field]).unwrap_or_default()
+}
+
+/// `with_value(s, p, v)` is settings `s` with parameter `p` set to value `v`, or `BadValue` if `v` is not a value of `p`
+///
+/// Values which would not be saved and loaded again unchanged are not values of the parameter, e.g. an unknown scale
+/// number, a DAC calibration which is not plausible, or a ribbon map which doesn't increase.
+pub fn with_value(
+    settings: &Settings,
+    param: Param,
+    value: &[u8],
+) -> Result<Settings, ConfigError> {
+    let payload = settings.to_bytes();
+    let field = field_ranges(&payload)[param as usize].clone();
+
+    let mut changed: Vec<u8, MAX_SETTINGS_LEN> = Vec::new();
+    changed
+        .extend_from_slice(&payload[..field.start])
+        .and_then(|_| changed.extend_from_slice(value))
+        .and_then(|_| changed.extend_from_slice(&payload[field.end..]))
+        .map_err(|_| ConfigError::BadValue)?;
+
+    // the value must be exactly one field long, or the fields after it would move
+    if field_ranges(&changed)[param as usize].len() != value.len() {
+        return Err(ConfigError::BadValue);
+    }
+
+    let new = Settings::from_bytes(&changed);
+    if new.to_bytes() == changed {
+        Ok(new)
+    } else {
+        Err(ConfigError::BadValue)
+    }
+}
+
+/// `request_target(bs)` is the device which a SysEx message starting with the bytes `bs` is a configuration request for,
+/// or `None` if it is not one
+///
+/// Only needs the first `REQUEST_HEADER_LEN` bytes of the message, so a message can be told apart while it is still
+/// arriving.
+pub fn request_target(start: &[u8]) -> Option<u8> {
+    match *start {
+        [SYSEX_START, NON_COMMERCIAL_ID, CONFIG_ID, device, command, ..]
+            if command < REPLY_FIRST =>
+        {
+            Some(device)
+        }
+        _ => None,
+    }
+}
+
+/// `addresses(t, d)` is true iff a message for target device `t` is for device `d`
+pub fn addresses(target: u8, device: u8) -> bool {
+    target == device || target == ALL_DEVICES
+}
+
+/// The requests which can be sent to a ribbon controller are represented here
+#[derive(Clone, Debug, PartialEq)]
+// there is no heap to box a value in, and only a few are ever kept at once
+#[allow(clippy::large_enum_variant)]
+pub enum Command {
+    Get(Param),
+    Set(Param, Value),
+    Dump,
+    Reset,
+}
+
+impl Command {
+    /// `c.sysex(d)` is command `c` as a SysEx message for device `d`
+    pub fn sysex(&self, device: u8) -> Sysex {
+        match self {
+            Command::Get(param) => sysex(device, GET, &[param.id()], &[]),
+            Command::Set(param, value) => sysex(device, SET, &[param.id()], value),
+            Command::Dump => sysex(device, DUMP, &[], &[]),
+            Command::Reset => sysex(device, RESET, &[], &[]),
+        }
+    }
+
+    /// `Command::parse(bs)` is the device which the SysEx message `bs` is for and the command in it, or `None` if it is
+    /// not a configuration request
+    ///
+    /// A request which can't be carried out is the error to answer it with instead of the command.
+    pub fn parse(sysex: &[u8]) -> Option<(u8, Result<Command, ConfigError>)> {
+        let frame = Frame::of(sysex)?;
+        // answers from other ribbon controllers in the chain are not requests
+        if REPLY_FIRST <= frame.command {
+            return None;
+        }
+        Some((frame.device, frame.checked().and_then(Self::of_frame)))
+    }
+
+    /// `Command::of_frame(f)` is the command in request frame `f`
+    fn of_frame(frame: Frame) -> Result<Command, ConfigError> {
+        match (frame.command, frame.data) {
+            (GET, [param]) => Ok(Command::Get(param_of(*param)?)),
+            (SET, [param, nibbles @ ..]) => Ok(Command::Set(param_of(*param)?, unpack(nibbles)?)),
+            (DUMP, []) => Ok(Command::Dump),
+            (RESET, []) => Ok(Command::Reset),
+            (GET | SET | DUMP | RESET, _) => Err(ConfigError::Malformed),
+            _ => Err(ConfigError::UnknownCommand),
+        }
+    }
+}
+
+/// The answers a ribbon controller sends back are represented here
+#[derive(Clone, Debug, PartialEq)]
+// there is no heap to box a value in, and only a few are ever kept at once
+#[allow(clippy::large_enum_variant)]
+pub enum Reply {
+    Value(Param, Value),
+    ResetDone,
+    Error(ConfigError),
+}
+
+impl Reply {
+    /// `r.sysex(d)` is reply `r` as a SysEx message from device `d`
+    pub fn sysex(&self, device: u8) -> Sysex {
+        match self {
+            Reply::Value(param, value) => sysex(device, VALUE, &[param.id()], value),
+            Reply::ResetDone => sysex(device, RESET_DONE, &[], &[]),
+            Reply::Error(error) => sysex(device, ERROR, &[*error as u8], &[]),
+        }
+    }
+
+    /// `Reply::parse(bs)` is the device which sent the SysEx message `bs` and the reply in it, or `None` if it is not a
+    /// configuration reply
+    ///
+    /// A reply which is damaged or not understood is the error saying why instead of the reply.
+    pub fn parse(sysex: &[u8]) -> Option<(u8, Result<Reply, ConfigError>)> {
+        let frame = Frame::of(sysex)?;
+        if frame.command < REPLY_FIRST {
+            return None;
+        }
+        Some((frame.device, frame.checked().and_then(Self::of_frame)))
+    }
+
+    /// `Reply::of_frame(f)` is the reply in reply frame `f`
+    fn of_frame(frame: Frame) -> Result<Reply, ConfigError> {
+        match (frame.command, frame.data) {
+            (VALUE, [param, nibbles @ ..]) => Ok(Reply::Value(param_of(*param)?, unpack(nibbles)?)),
+            (RESET_DONE, []) => Ok(Reply::ResetDone),
+            (ERROR, [code]) => ConfigError::from_code(*code)
+                .map(Reply::Error)
+                .ok_or(ConfigError::Malformed),
+            (VALUE | RESET_DONE | ERROR, _) => Err(ConfigError::Malformed),
+            _ => Err(ConfigError::UnknownCommand),
+        }
+    }
+}
+
+/// The reasons a request can't be carried out are represented here, numbered as they are sent
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ConfigError {
+    /// The checksum doesn't match, the message was damaged on the way
+    BadChecksum = 1,
+    /// The command is not one of the requests
+    UnknownCommand = 2,
+    /// There is no parameter with that number
+    UnknownParam = 3,
+    /// The value is not a value of the parameter
+    BadValue = 4,
+    /// The data is the wrong length or has bytes which are out of range for the command
+    Malformed = 5,
+}
+
+impl ConfigError {
+    /// `ConfigError::from_code(c)` is the error numbered `c`, if there is one
+    pub fn from_code(code: u8) -> Option<Self> {
+        [
+            ConfigError::BadChecksum,
+            ConfigError::UnknownCommand,
+            ConfigError::UnknownParam,
+            ConfigError::BadValue,
+            ConfigError::Malformed,
+        ]
+        .iter()
+        .copied()
+        .find(|&e| e as u8 == code)
+    }
+}
+
+/// The parts of a configuration SysEx message are represented here
+struct Frame<'a> {
+    device: u8,
+    command: u8,
+    data: &'a [u8],
+    // true iff the checksum matches
+    intact: bool,
+}
+
+impl<'a> Frame<'a> {
+    /// `Frame::of(bs)` is the parts of SysEx message `bs`, or `None` if it is not a configuration message
+    fn of(sysex: &'a [u8]) -> Option<Self> {
+        match sysex {
+            [SYSEX_START, NON_COMMERCIAL_ID, CONFIG_ID, covered @ .., _, SYSEX_END]
+                if 2 <= covered.len() =>
+            {
+                Some(Self {
+                    device: covered[0],
+                    command: covered[1],
+                    data: &covered[2..],
+                    intact: checksum(&sysex[3..sysex.len() - 1]) == 0,
+                })
+            }
+            _ => None,
+        }
+    }
+
+    /// `f.checked()` is frame `f` if its checksum matches, otherwise `BadChecksum`
+    fn checked(self) -> Result<Self, ConfigError> {
+        if self.intact {
+            Ok(self)
+        } else {
+            Err(ConfigError::BadChecksum)
+        }
+    }
+}
+
+/// `sysex(d, c, h, v)` is the message with command `c` for or from device `d`, with the data bytes `h` followed by the
+/// value `v` split into 4 bit halves
+fn sysex(device: u8, command: u8, head: &[u8], value: &[u8]) -> Sysex {
+    let mut msg = Sysex::new();
+    msg.extend_from_slice(&[SYSEX_START, NON_COMMERCIAL_ID, CONFIG_ID, device, command])
+        .ok();
+    msg.extend_from_slice(head).ok();
+    value.iter().for_each(|&b| {
+        msg.extend_from_slice(&[b >> 4, b & 0x0F]).ok();
+    });
+    let sum = checksum(&msg[3..]);
+    msg.push((0x80 - sum) & 0x7F).ok();
+    msg.push(SYSEX_END).ok();
+    msg
+}
+
+/// `checksum(bs)` is the low 7 bits of the sum of bytes `bs`
+fn checksum(bytes: &[u8]) -> u8 {
+    bytes.iter().fold(0_u8, |sum, &b| sum.wrapping_add(b)) & 0x7F
+}
+
+/// `param_of(b)` is the parameter numbered by data byte `b`
+fn param_of(byte: u8) -> Result<Param, ConfigError> {
+    Param::from_id(byte).ok_or(ConfigError::UnknownParam)
+}
+
+/// `unpack(ns)` is the value sent as the 4 bit halves `ns`, high half first
+fn unpack(nibbles: &[u8]) -> Result<Value, ConfigError> {
+    if !nibbles.len().is_multiple_of(2) || nibbles.iter().any(|&n| 0x0F < n) {
+        return Err(ConfigError::Malformed);
+    }
+    let mut value = Value::new();
+    for pair in nibbles.chunks(2) {
+        value
+            .push(pair[0] << 4 | pair[1])
+            .map_err(|_| ConfigError::Malformed)?;
+    }
+    Ok(value)
+}
+
+/// The number of parameters
+pub const NUM_PARAMS: usize = NUM_FIELDS;
+
+/// The longest value of any parameter, a tuning table with every degree
+pub const MAX_VALUE_LEN: usize = MAX_TUNING_TABLE_BYTES;
+
+/// The longest configuration SysEx message, a value with the start and end, the header, the parameter, and the checksum
+pub const MAX_CONFIG_SYSEX_LEN: usize = 8 + MAX_VALUE_LEN * 2;
+
+/// The number of bytes at the start of a SysEx message which tell whether it is a configuration request
+pub const REQUEST_HEADER_LEN: usize = 5;
+
+/// The device which addresses every ribbon controller in the MIDI chain
+pub const ALL_DEVICES: u8 = 0x7F;
+
+const SYSEX_START: u8 = 0xF0;
+const SYSEX_END: u8 = 0xF7;
+const NON_COMMERCIAL_ID: u8 = 0x7D;
+const CONFIG_ID: u8 = 0x02;
+
+// the requests
+const GET: u8 = 0x01;
+const SET: u8 = 0x02;
+const DUMP: u8 = 0x03;
+const RESET: u8 = 0x04;
+
+// the replies, every command from here on is a reply
+const REPLY_FIRST: u8 = 0x10;
+const VALUE: u8 = 0x11;
+const RESET_DONE: u8 = 0x14;
+const ERROR: u8 = 0x7F;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        auto_calibration::CalibrationReport,
+        dac_calibration::{ChannelCalibration, DacCalibration},
+        midi_generator::MidiMode,
+        midi_parser::{MidiParser, Received},
+        mts::NoteTuning,
+        ribbon_calibration::{RibbonCalibration, RibbonMap},
+        scale::Scale,
+        transpose::Transpose,
+        tuning::TuningTable,
+        velocity::VelocityCurve,
+    };
+
+    fn custom() -> Settings {
+        Settings {
+            midi_mode: MidiMode::PitchBendOnly,
+            velocity_curve: VelocityCurve::Fixed(90),
+            pitch_bend_range: 24,
+            scale: Scale::User(0b1010_1101_0101),
+            scale_root: 2,
+            transpose: Transpose::new(-7, -1),
+            mts: true,
+            dac_calibration: DacCalibration {
+                a: ChannelCalibration::from_points(1.01, 4.02),
+                b: ChannelCalibration::from_points(0.99, 3.97),
+            },
+            ribbon_calibration: RibbonCalibration {
+                main_ribbon: RibbonMap::from_readings(&[0.02, 0.26, 0.5, 0.73, 0.98]).unwrap(),
+                mod_ribbon: RibbonMap::from_readings(&[0.01, 0.45, 0.99]).unwrap(),
+            },
+            tuning: Some(TuningTable::new(12.0, 60.0, &[0.0, 3.5, 7.0]).unwrap()),
+            ..Settings::new()
+        }
+    }
+
+    fn value(bytes: &[u8]) -> Value {
+        Vec::from_slice(bytes).unwrap()
+    }
+
+    #[test]
+    fn get_request_is_laid_out_as_documented() {
+        // 3 + 1 + 3 + 0x79 is 0x80
+        assert_eq!(
+            Command::Get(Param::PitchBendRange).sysex(3),
+            [0xF0, 0x7D, 0x02, 3, 0x01, 3, 0x79, 0xF7]
+        );
+        assert_eq!(
+            Reply::Value(Param::Mts, value(&[0xA5])).sysex(ALL_DEVICES)[3..9],
+            [0x7F, 0x11, 8, 0x0A, 0x05, 0x59]
+        );
+    }
+
+    #[test]
+    fn commands_and_replies_read_back_the_same() {
+        let commands = [
+            Command::Get(Param::ModRibbonMap),
+            Command::Set(Param::Transpose, value(&[0xF9, 0x02])),
+            Command::Dump,
+            Command::Reset,
+        ];
+        for command in commands {
+            assert_eq!(Command::parse(&command.sysex(5)), Some((5, Ok(command))));
+        }
+
+        let replies = [
+            Reply::Value(
+                Param::MainRibbonMap,
+                value_of(&custom(), Param::MainRibbonMap),
+            ),
+            Reply::ResetDone,
+            Reply::Error(ConfigError::BadValue),
+        ];
+        for reply in replies {
+            let sysex = reply.sysex(15);
+            assert!(sysex[1..sysex.len() - 1].iter().all(|&b| b < 0x80));
+            assert_eq!(Reply::parse(&sysex), Some((15, Ok(reply))));
+        }
+    }
+
+    #[test]
+    fn other_messages_are_not_requests_or_replies() {
+        let report = CalibrationReport {
+            accepted: true,
+            calibration: ChannelCalibration::new(),
+            max_error_cents: 1.0,
+        };
+        assert_eq!(Command::parse(&report.sysex()), None);
+        assert_eq!(Reply::parse(&NoteTuning::new(60, 60.0).sysex()), None);
+
+        // requests and replies are told apart
+        assert_eq!(Command::parse(&Reply::ResetDone.sysex(0)), None);
+        assert_eq!(Reply::parse(&Command::Dump.sysex(0)), None);
+    }
+
+    #[test]
+    fn requests_are_told_apart_by_their_start() {
+        let start = |sysex: &[u8]| request_target(&sysex[..REQUEST_HEADER_LEN]);
+        assert_eq!(start(&Command::Get(Param::Span).sysex(9)), Some(9));
+        assert_eq!(start(&Command::Reset.sysex(ALL_DEVICES)), Some(ALL_DEVICES));
+        assert_eq!(start(&Reply::ResetDone.sysex(9)), None);
+        assert_eq!(start(&NoteTuning::new(60, 60.0).sysex()), None);
+        assert_eq!(request_target(&[0xF0, 0x7D, 0x02]), None);
+    }
+
+    #[test]
+    fn damaged_requests_are_the_error_to_answer_with() {
+        let mut damaged = Command::Get(Param::Span).sysex(2);
+        damaged[5] = Param::Mts.id();
+        assert_eq!(
+            Command::parse(&damaged),
+            Some((2, Err(ConfigError::BadChecksum)))
+        );
+
+        let unknown_param = sysex(2, GET, &[NUM_PARAMS as u8], &[]);
+        assert_eq!(
+            Command::parse(&unknown_param),
+            Some((2, Err(ConfigError::UnknownParam)))
+        );
+        let unknown_command = sysex(2, 0x0F, &[], &[]);
+        assert_eq!(
+            Command::parse(&unknown_command),
+            Some((2, Err(ConfigError::UnknownCommand)))
+        );
+        let extra_data = sysex(2, DUMP, &[0], &[]);
+        assert_eq!(
+            Command::parse(&extra_data),
+            Some((2, Err(ConfigError::Malformed)))
+        );
+        let odd_nibbles = sysex(2, SET, &[Param::Mts.id(), 0x01], &[]);
+        assert_eq!(
+            Command::parse(&odd_nibbles),
+            Some((2, Err(ConfigError::Malformed)))
+        );
+    }
+
+    #[test]
+    fn every_param_can_be_set_to_its_value_in_other_settings() {
+        for param in Param::ALL {
+            let new = with_value(&Settings::new(), param, &value_of(&custom(), param)).unwrap();
+            assert_eq!(value_of(&new, param), value_of(&custom(), param));
+        }
+
+        let mut settings = Settings::new();
+        for param in Param::ALL {
+            settings = with_value(&settings, param, &value_of(&custom(), param)).unwrap();
+        }
+        assert_eq!(settings, custom());
+    }
+
+    #[test]
+    fn values_which_do_not_load_back_the_same_are_rejected() {
+        let settings = custom();
+        let bad_values: [(Param, &[u8]); 8] = [
+            (Param::Mts, &[2]),
+            (Param::MidiMode, &[0, 0]),
+            (Param::Span, &[]),
+            (Param::Scale, &[40, 0, 0]),
+            (Param::DacCalibrationA, &[0; 8]),
+            // 2 readings which don't increase
+            (Param::ModRibbonMap, &[2, 0, 0, 0, 0, 0, 0, 0, 0]),
+            // a table with no degrees
+            (Param::Tuning, &[1, 0, 0, 0, 0x40, 0x41, 0, 0, 0x70, 0x42]),
+            // a table which is cut short
+            (Param::Tuning, &[1, 1, 0, 0, 0x40, 0x41]),
+        ];
+        for (param, bytes) in bad_values {
+            assert_eq!(
+                with_value(&settings, param, bytes),
+                Err(ConfigError::BadValue),
+                "{:?}",
+                param
+            );
+        }
+    }
+
+    #[test]
+    fn the_longest_message_can_be_received() {
+        let longest = Command::Set(Param::Tuning, value(&[0; MAX_VALUE_LEN])).sysex(0);
+        assert_eq!(longest.len(), MAX_CONFIG_SYSEX_LEN);
+
+        let mut parser = MidiParser::new();
+        let received = longest.iter().filter_map(|&b| match parser.parse(b) {
+            Some(Received::SysEx(sysex)) => Some(sysex.to_vec()),
+            _ => None,
+        });
+        assert_eq!(received.last(), Some(longest.to_vec()));
+    }
+}
@@ -0,0 +1,113 @@
+//! # Transpose
+//!
+//! Shifts everything the ribbon plays, both the `RIBBON CV` and MIDI outputs, by a number of semitones and octaves.
+//!
+//! The transpose can be changed from the panel without a computer. While the MOD ribbon is held at its very top, taps
+//! on the main ribbon don't play notes, instead the main ribbon is split into four zones:
+//!
+//! ```text
+//!  bottom                                                                  top
+//! | octave down      | semitone down    | semitone up      | octave up        |
+//! ```
+//!
+//! The transpose is saved with the other settings, so it survives a power cycle, see `settings`.
+
+use synth_utils::quantizer::SEMITONE_WIDTH;
+
+/// A transpose is represented here
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Transpose {
+    semitones: i8,
+    octaves: i8,
+}
+
+impl Transpose {
+    /// `Transpose::new(s, o)` is a transpose of `s` semitones and `o` octaves
+    ///
+    /// # Arguments
+    ///
+    /// * `semitones` - the semitone part of the transpose, clamped to `[-MAX_TRANSPOSE..MAX_TRANSPOSE]`
+    ///
+    /// * `octaves` - the octave shift, clamped to `[-MAX_OCTAVE_SHIFT..MAX_OCTAVE_SHIFT]`
+    pub fn new(semitones: i8, octaves: i8) -> Self {
+        Self {
+            semitones: semitones.clamp(-MAX_TRANSPOSE, MAX_TRANSPOSE),
+            octaves: octaves.clamp(-MAX_OCTAVE_SHIFT, MAX_OCTAVE_SHIFT),
+        }
+    }
+
+    /// `t.semitones()` is the semitone part of transpose `t`
+    pub fn semitones(&self) -> i8 {
+        self.semitones
+    }
+
+    /// `t.octaves()` is the octave shift of transpose `t`
+    pub fn octaves(&self) -> i8 {
+        self.octaves
+    }
+
+    /// `t.total_semitones()` is the full shift of transpose `t` in semitones, including the octave shift
+    pub fn total_semitones(&self) -> i8 {
+        self.semitones + self.octaves * SEMITONES_PER_OCTAVE
+    }
+
+    /// `t.volts()` is the full shift of transpose `t` in volts at 1volt/octave
+    pub fn volts(&self) -> f32 {
+        self.total_semitones() as f32 * SEMITONE_WIDTH
+    }
+
+    /// `t.tapped(p)` is transpose `t` changed by a tap on the main ribbon at position `p` in `[0.0, 1.0]`, see the
+    /// module docs for the zones
+    pub fn tapped(&self, position: f32) -> Self {
+        let (semitones, octaves) = match (position * 4.0_f32) as u8 {
+            0 => (0, -1),
+            1 => (-1, 0),
+            2 => (1, 0),
+            _ => (0, 1),
+        };
+        Self::new(self.semitones + semitones, self.octaves + octaves)
+    }
+}
+
+/// The largest transpose in semitones, up or down, not counting the octave shift
+pub const MAX_TRANSPOSE: i8 = 48;
+
+/// The largest octave shift, up or down
+pub const MAX_OCTAVE_SHIFT: i8 = 4;
+
+/// The MOD ribbon must be held above this value for taps on the main ribbon to change the transpose
+pub const TRANSPOSE_EDIT_MOD_THRESHOLD: f32 = 0.95_f32;
+
+const SEMITONES_PER_OCTAVE: i8 = 12;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn octaves_add_twelve_semitones() {
+        let t = Transpose::new(-3, 2);
+        assert_eq!(t.total_semitones(), 21);
+        assert!((t.volts() - 1.75).abs() < 1E-6);
+    }
+
+    #[test]
+    fn transpose_is_clamped() {
+        assert_eq!(Transpose::new(100, 0).semitones(), MAX_TRANSPOSE);
+        assert_eq!(Transpose::new(-100, -9).octaves(), -MAX_OCTAVE_SHIFT);
+        assert_eq!(
+            Transpose::new(0, MAX_OCTAVE_SHIFT).tapped(1.0),
+            Transpose::new(0, MAX_OCTAVE_SHIFT)
+        );
+    }
+
+    #[test]
+    fn taps_change_the_transpose_by_zone() {
+        let t = Transpose::default();
+        assert_eq!(t.tapped(0.1), Transpose::new(0, -1));
+        assert_eq!(t.tapped(0.3), Transpose::new(-1, 0));
+        assert_eq!(t.tapped(0.6), Transpose::new(1, 0));
+        assert_eq!(t.tapped(0.9), Transpose::new(0, 1));
+        assert_eq!(t.tapped(1.0), Transpose::new(0, 1));
+    }
+}
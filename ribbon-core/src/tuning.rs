@@ -0,0 +1,281 @@
+//! # Tuning tables
+//!
+//! Microtonal tunings for the quantizer, as a compact table of pitches which repeats every period.
+//!
+//! Tunings are described on a host computer with Scala scale (`.scl`) and keyboard mapping (`.kbm`) files, which are
+//! compiled into a tuning table by the `ribbon-scala` tool. The table is a handful of bytes, see
+//! `TuningTable::to_bytes` and `TuningTable::from_bytes`, which is sent to the ribbon controller as the tuning setting
+//! and saved with the other settings.
+//!
+//! Pitches in the table are absolute, measured in fractional MIDI note numbers (69.0 is A440, 69.5 is a quarter tone
+//! above it), so the MIDI output can play each degree exactly as a note plus pitch bend.
+
+use heapless::Vec;
+
+/// A microtonal tuning is represented here
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct TuningTable {
+    // the interval the degrees repeat at in semitones, 12.0 for scales which repeat at the octave
+    period: f32,
+    // the pitch of the first degree in fractional MIDI note numbers
+    root_pitch: f32,
+    // the degrees of the scale in semitones above the root, ascending and in [0.0, period), only the first `len` count
+    degrees: [f32; MAX_TUNING_DEGREES],
+    len: usize,
+}
+
+/// The reasons a tuning table can't be built are represented here
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TuningError {
+    /// There are no degrees in the tuning
+    NoDegrees,
+    /// There are more than `MAX_TUNING_DEGREES` degrees
+    TooManyDegrees,
+    /// The period is not a positive number of semitones, or a degree is outside of the period
+    BadInterval,
+    /// The bytes are not a tuning table
+    BadBytes,
+}
+
+impl TuningTable {
+    /// `TuningTable::new(p, r, ds)` is a tuning with period `p` and degrees `ds` in semitones above root pitch `r`
+    ///
+    /// # Arguments
+    ///
+    /// * `period` - the interval the degrees repeat at, in semitones
+    ///
+    /// * `root_pitch` - the pitch of the root in fractional MIDI note numbers
+    ///
+    /// * `degrees` - the degrees of the scale in semitones above the root, in `[0.0, period)`, in any order
+    pub fn new(period: f32, root_pitch: f32, degrees: &[f32]) -> Result<Self, TuningError> {
+        if degrees.is_empty() {
+            return Err(TuningError::NoDegrees);
+        }
+        if period.is_nan() || period <= 0.0_f32 || !root_pitch.is_finite() {
+            return Err(TuningError::BadInterval);
+        }
+        if degrees.iter().any(|&d| !(0.0_f32 <= d && d < period)) {
+            return Err(TuningError::BadInterval);
+        }
+
+        if MAX_TUNING_DEGREES < degrees.len() {
+            return Err(TuningError::TooManyDegrees);
+        }
+
+        let mut table = Self {
+            period,
+            root_pitch,
+            degrees: [0.0_f32; MAX_TUNING_DEGREES],
+            len: degrees.len(),
+        };
+        let sorted = &mut table.degrees[..degrees.len()];
+        sorted.copy_from_slice(degrees);
+        sorted.sort_unstable_by(|a, b| a.partial_cmp(b).unwrap_or(core::cmp::Ordering::Equal));
+        Ok(table)
+    }
+
+    /// `t.nearest(p)` is the pitch in tuning `t` nearest to pitch `p`, both in fractional MIDI note numbers
+    pub fn nearest(&self, pitch: f32) -> f32 {
+        let above_root = pitch - self.root_pitch;
+        let period_start = floor(above_root / self.period) * self.period;
+        let within_period = above_root - period_start;
+
+        // the nearest degree might be in the period below or above
+        let mut nearest = self.degrees[0];
+        for &d in self.degrees() {
+            for candidate in [d - self.period, d, d + self.period] {
+                if (candidate - within_period).abs() < (nearest - within_period).abs() {
+                    nearest = candidate;
+                }
+            }
+        }
+
+        self.root_pitch + period_start + nearest
+    }
+
+    /// `t.period()` is the interval in semitones that the degrees of tuning `t` repeat at
+    pub fn period(&self) -> f32 {
+        self.period
+    }
+
+    /// `t.root_pitch()` is the pitch of the root of tuning `t` in fractional MIDI note numbers
+    pub fn root_pitch(&self) -> f32 {
+        self.root_pitch
+    }
+
+    /// `t.degrees()` is the degrees of tuning `t` in ascending order, in semitones above the root
+    pub fn degrees(&self) -> &[f32] {
+        &self.degrees[..self.len]
+    }
+
+    /// `t.to_bytes()` is tuning `t` as a compact table of bytes
+    ///
+    /// The table is a version byte, the number of degrees, then the period, the root pitch, and each degree as little
+    /// endian `f32`s.
+    pub fn to_bytes(&self) -> Vec<u8, MAX_TUNING_TABLE_BYTES> {
+        let mut bytes = Vec::new();
+        bytes.push(TUNING_TABLE_VERSION).ok();
+        bytes.push(self.len as u8).ok();
+        bytes.extend_from_slice(&self.period.to_le_bytes()).ok();
+        bytes.extend_from_slice(&self.root_pitch.to_le_bytes()).ok();
+        for d in self.degrees() {
+            bytes.extend_from_slice(&d.to_le_bytes()).ok();
+        }
+        bytes
+    }
+
+    /// `TuningTable::len_in(bs)` is the number of bytes of the table at the start of bytes `bs`, going by its header
+    ///
+    /// Says nothing about whether the table is any good, that is up to `from_bytes`.
+    pub fn len_in(bytes: &[u8]) -> usize {
+        HEADER_LEN
+            + bytes
+                .get(1)
+                .map_or(0, |&num_degrees| num_degrees as usize * 4)
+    }
+
+    /// `TuningTable::from_bytes(bs)` is the tuning stored in table `bs`, see `to_bytes`
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, TuningError> {
+        let f32_at = |i: usize| {
+            bytes
+                .get(i..i + 4)
+                .map(|b| f32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+        };
+
+        match bytes {
+            [TUNING_TABLE_VERSION, num_degrees, ..]
+                if bytes.len() == HEADER_LEN + *num_degrees as usize * 4 =>
+            {
+                let mut degrees = Vec::<f32, MAX_TUNING_DEGREES>::new();
+                for i in 0..*num_degrees as usize {
+                    let d = f32_at(HEADER_LEN + i * 4).ok_or(TuningError::BadBytes)?;
+                    degrees.push(d).map_err(|_| TuningError::TooManyDegrees)?;
+                }
+                let period = f32_at(2).ok_or(TuningError::BadBytes)?;
+                let root_pitch = f32_at(6).ok_or(TuningError::BadBytes)?;
+                Self::new(period, root_pitch, &degrees)
+            }
+            _ => Err(TuningError::BadBytes),
+        }
+    }
+}
+
+/// The largest number of degrees in a tuning table
+pub const MAX_TUNING_DEGREES: usize = 64;
+
+/// The largest number of bytes in a tuning table
+pub const MAX_TUNING_TABLE_BYTES: usize = HEADER_LEN + MAX_TUNING_DEGREES * 4;
+
+const TUNING_TABLE_VERSION: u8 = 1;
+
+// version, number of degrees, period, and root pitch
+const HEADER_LEN: usize = 1 + 1 + 4 + 4;
+
+/// `floor(x)` is the largest whole number no greater than `x`, `f32::floor` needs `std`
+fn floor(x: f32) -> f32 {
+    let truncated = x as i32 as f32;
+    if x < truncated {
+        truncated - 1.0_f32
+    } else {
+        truncated
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn equal_temperament(num_steps: usize) -> TuningTable {
+        let degrees: std::vec::Vec<f32> = (0..num_steps)
+            .map(|i| i as f32 * 12.0 / num_steps as f32)
+            .collect();
+        TuningTable::new(12.0, 60.0, &degrees).unwrap()
+    }
+
+    #[test]
+    fn twelve_tone_equal_temperament_snaps_to_semitones() {
+        let t = equal_temperament(12);
+        assert_eq!(t.nearest(60.0), 60.0);
+        assert_eq!(t.nearest(61.4), 61.0);
+        assert_eq!(t.nearest(61.6), 62.0);
+        assert_eq!(t.nearest(47.2), 47.0);
+        assert_eq!(t.nearest(11.9), 12.0);
+    }
+
+    #[test]
+    fn quarter_tones_snap_between_semitones() {
+        let t = equal_temperament(24);
+        assert_eq!(t.nearest(69.4), 69.5);
+        assert_eq!(t.nearest(69.1), 69.0);
+    }
+
+    #[test]
+    fn nearest_degree_may_be_in_the_next_period() {
+        // only the root, a fifth, and nothing else
+        let t = TuningTable::new(12.0, 60.0, &[0.0, 7.0]).unwrap();
+        assert_eq!(t.nearest(66.0), 67.0);
+        assert_eq!(t.nearest(70.0), 72.0);
+        assert_eq!(t.nearest(58.0), 60.0);
+        assert_eq!(t.nearest(56.0), 55.0);
+    }
+
+    #[test]
+    fn degrees_need_not_include_the_root() {
+        let t = TuningTable::new(12.0, 60.0, &[4.0]).unwrap();
+        assert_eq!(t.nearest(60.5), 64.0);
+        assert_eq!(t.nearest(57.5), 52.0);
+    }
+
+    #[test]
+    fn non_octave_period_repeats_at_the_period() {
+        // Bohlen-Pierce, 13 equal steps of a tritave
+        let tritave = 19.019_55;
+        let degrees: std::vec::Vec<f32> = (0..13).map(|i| i as f32 * tritave / 13.0).collect();
+        let t = TuningTable::new(tritave, 60.0, &degrees).unwrap();
+        assert!((t.nearest(60.0 + tritave + 0.1) - (60.0 + tritave)).abs() < 1E-4);
+    }
+
+    #[test]
+    fn degrees_are_sorted() {
+        let t = TuningTable::new(12.0, 60.0, &[7.0, 0.0, 4.0]).unwrap();
+        assert_eq!(t.degrees(), [0.0, 4.0, 7.0]);
+    }
+
+    #[test]
+    fn bad_tunings_are_errors() {
+        assert_eq!(
+            TuningTable::new(12.0, 60.0, &[]),
+            Err(TuningError::NoDegrees)
+        );
+        assert_eq!(
+            TuningTable::new(0.0, 60.0, &[0.0]),
+            Err(TuningError::BadInterval)
+        );
+        assert_eq!(
+            TuningTable::new(12.0, 60.0, &[12.0]),
+            Err(TuningError::BadInterval)
+        );
+        assert_eq!(
+            TuningTable::new(12.0, 60.0, &[0.0; MAX_TUNING_DEGREES + 1]),
+            Err(TuningError::TooManyDegrees)
+        );
+    }
+
+    #[test]
+    fn bytes_round_trip() {
+        let t = TuningTable::new(12.0, 61.5, &[0.0, 1.5, 3.86, 7.02]).unwrap();
+        let bytes = t.to_bytes();
+        assert_eq!(bytes.len(), HEADER_LEN + 4 * 4);
+        assert_eq!(TuningTable::from_bytes(&bytes), Ok(t));
+    }
+
+    #[test]
+    fn truncated_bytes_are_an_error() {
+        let bytes = equal_temperament(12).to_bytes();
+        assert_eq!(
+            TuningTable::from_bytes(&bytes[..bytes.len() - 1]),
+            Err(TuningError::BadBytes)
+        );
+        assert_eq!(TuningTable::from_bytes(&[]), Err(TuningError::BadBytes));
+    }
+}
@@ -1,4 +1,7 @@
-use crate::board::{AdcPin, Board, Switch3wayState};
+use crate::{
+    board::{AdcInputs, AdcPin, PanelSwitches, Switch3wayState},
+    pitch_engine::PitchMode,
+};
 
 /// The user interface is represented here (i.e. the front panel pots and switches that the user interacts with)
 pub struct UiState {
@@ -7,14 +10,6 @@ pub struct UiState {
     glide_time: f32,
 }
 
-/// There are three modes for the ribbon pitch information
-#[derive(Clone, Copy)]
-pub enum PitchMode {
-    HardQuantize,
-    Assist,
-    Smooth,
-}
-
 impl UiState {
     /// `UiState::new()` is a new UI state initialized to default values.
     pub fn new() -> Self {
@@ -30,7 +25,7 @@ impl UiState {
     /// It is required to periodically call this function to updat the state of the UI controls. Since these controls
     /// are manually adjusted by the user, they don't need to be updated very fast, just fast enough that they don't
     /// feel sluggish to the user.
-    pub fn update(&mut self, board: &mut Board) {
+    pub fn update<B: AdcInputs + PanelSwitches>(&mut self, board: &mut B) {
         self.pitch_mode = match board.read_mode_switch() {
             Switch3wayState::Up => PitchMode::HardQuantize,
             Switch3wayState::Middle => PitchMode::Assist,
@@ -43,6 +38,11 @@ impl UiState {
         self.glide_time = bend_glide_ctl(self.glide_level);
     }
 
+    /// `ui.glide_knob()` is the position of the front panel glide control knob in `[0.0, 1.0]`, without any bending
+    pub fn glide_knob(&self) -> f32 {
+        self.glide_level
+    }
+
     /// `ui.glide_time()` is the current value of the front panel glide control knob as a time
     pub fn glide_time(&self) -> f32 {
         self.glide_time
@@ -54,6 +54,12 @@ impl UiState {
     }
 }
 
+impl Default for UiState {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 /// `bend_glide_ctl(v)` is value `v` scaled for a more natural feeling glide control
 ///
 /// The physical glide control is a linear potentiometer, but it feels better for the user if the taper of the control
@@ -65,3 +71,38 @@ impl UiState {
 fn bend_glide_ctl(val: f32) -> f32 {
     val * val * 3.
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock_board::MockBoard;
+
+    #[test]
+    fn mode_switch_positions_map_to_pitch_modes() {
+        let mut board = MockBoard::new();
+        let mut ui = UiState::new();
+
+        board.mode_switch = Switch3wayState::Up;
+        ui.update(&mut board);
+        assert_eq!(ui.pitch_mode(), PitchMode::HardQuantize);
+
+        board.mode_switch = Switch3wayState::Middle;
+        ui.update(&mut board);
+        assert_eq!(ui.pitch_mode(), PitchMode::Assist);
+
+        board.mode_switch = Switch3wayState::Down;
+        ui.update(&mut board);
+        assert_eq!(ui.pitch_mode(), PitchMode::Smooth);
+    }
+
+    #[test]
+    fn glide_knob_is_read_from_pa0_and_bent() {
+        let mut board = MockBoard::new();
+        let mut ui = UiState::new();
+
+        board.set_adc(AdcPin::PA0, 0.5);
+        ui.update(&mut board);
+        assert_eq!(ui.glide_time(), bend_glide_ctl(0.5));
+        assert!(ui.glide_time() < bend_glide_ctl(1.0));
+    }
+}
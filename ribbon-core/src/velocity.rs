@@ -0,0 +1,225 @@
+//! # Velocity
+//!
+//! The ribbon has no pressure sensor, but the way the finger lands still leaves a trace in the raw ribbon signal. The
+//! wiper is pulled up to the top of the range while nothing touches the ribbon. When a finger lands hard and fast the
+//! signal drops to the finger position within a sample or two, a slow and gentle landing drags the signal down over
+//! many samples as the contact builds up.
+//!
+//! Lifting the finger works the other way around, a quick flick off the ribbon lets the signal snap back up to the top
+//! of the range while a slow lift lets it creep up.
+//!
+//! The velocity detector keeps a short history of raw main ribbon samples. When the ribbon controller registers a new
+//! press the steepest drop in the history is converted to a MIDI note velocity via a velocity curve, and when it
+//! registers a release the steepest rise is converted to a release velocity via the same curve.
+
+/// The curves for converting the landing speed of the finger to a note velocity are represented here
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum VelocityCurve {
+    /// Velocity is proportional to the landing speed
+    Linear,
+    /// Gentle presses are boosted, it is easy to play loud notes
+    Soft,
+    /// Gentle presses are reduced, it takes a firm press to play loud notes
+    Hard,
+    /// Every note has the same velocity, in `[1..127]`
+    Fixed(u8),
+}
+
+/// The velocity detector is represented here
+pub struct VelocityDetector {
+    // ring buffer of the most recent raw main ribbon samples
+    history: [f32; HISTORY_LEN],
+    // the index the next sample will be written to, which is also the oldest sample in the history
+    next: usize,
+
+    curve: VelocityCurve,
+}
+
+impl VelocityDetector {
+    /// `VelocityDetector::new()` is a new velocity detector with a linear curve which has only seen the ribbon released
+    pub fn new() -> Self {
+        Self {
+            history: [1.0_f32; HISTORY_LEN],
+            next: 0,
+            curve: VelocityCurve::Linear,
+        }
+    }
+
+    /// `vd.poll(s)` feeds the raw main ribbon sample `s` in `[0.0, 1.0]` to the detector.
+    ///
+    /// Must be called with every sample given to the main ribbon controller.
+    pub fn poll(&mut self, raw_sample: f32) {
+        self.history[self.next] = raw_sample;
+        self.next = (self.next + 1) % HISTORY_LEN;
+    }
+
+    /// `vd.set_curve(c)` sets the velocity curve to `c`
+    pub fn set_curve(&mut self, curve: VelocityCurve) {
+        self.curve = curve;
+    }
+
+    /// `vd.curve()` is the velocity curve
+    pub fn curve(&self) -> VelocityCurve {
+        self.curve
+    }
+
+    /// `vd.velocity()` is the MIDI note velocity in `[1..127]` of the most recent press in the sample history.
+    ///
+    /// Meant to be read when the ribbon controller registers a new press.
+    pub fn velocity(&self) -> u8 {
+        self.apply_curve(self.steepest_change(LANDING))
+    }
+
+    /// `vd.release_velocity()` is the MIDI release velocity in `[1..127]` of the most recent release in the sample
+    /// history.
+    ///
+    /// Meant to be read when the ribbon controller registers a release.
+    pub fn release_velocity(&self) -> u8 {
+        self.apply_curve(self.steepest_change(LIFTING))
+    }
+
+    /// `vd.apply_curve(c)` is the velocity for a change `c` of the raw signal across the window
+    fn apply_curve(&self, change: f32) -> u8 {
+        let speed = (change / FULL_VELOCITY_CHANGE).clamp(0.0_f32, 1.0_f32);
+
+        let shaped = match self.curve {
+            VelocityCurve::Linear => speed,
+            VelocityCurve::Soft => 1.0_f32 - (1.0_f32 - speed) * (1.0_f32 - speed),
+            VelocityCurve::Hard => speed * speed,
+            VelocityCurve::Fixed(vel) => return vel.clamp(MIN_VELOCITY, MAX_VELOCITY),
+        };
+
+        // velocity zero would be a note-off
+        let vel = (shaped * MAX_VELOCITY as f32 + 0.5_f32) as u8;
+        vel.clamp(MIN_VELOCITY, MAX_VELOCITY)
+    }
+
+    /// `vd.steepest_change(d)` is the largest change of the raw signal in direction `d` across any `WINDOW_LEN`
+    /// consecutive samples, where `d` is `LANDING` for falling or `LIFTING` for rising
+    fn steepest_change(&self, direction: f32) -> f32 {
+        let sample =
+            |age_from_oldest: usize| self.history[(self.next + age_from_oldest) % HISTORY_LEN];
+
+        (0..HISTORY_LEN - WINDOW_LEN)
+            .map(|i| (sample(i) - sample(i + WINDOW_LEN)) * direction)
+            .fold(0.0_f32, f32::max)
+    }
+}
+
+impl Default for VelocityDetector {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// The smallest note velocity, zero is reserved for note-off
+pub const MIN_VELOCITY: u8 = 1;
+
+/// The largest note velocity
+pub const MAX_VELOCITY: u8 = 127;
+
+// The ribbon controller needs about 20 samples to register a press, the history reaches back far enough to see the
+// finger land
+const HISTORY_LEN: usize = 32;
+
+// the number of samples the landing and lifting speeds are measured over, about 4ms at the ribbon sample rate
+const WINDOW_LEN: usize = 4;
+
+// a change this large across the window is full velocity, about half of the raw signal range
+const FULL_VELOCITY_CHANGE: f32 = 0.5_f32;
+
+// the raw signal falls when the finger lands and rises when it lifts
+const LANDING: f32 = 1.0_f32;
+const LIFTING: f32 = -1.0_f32;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // press down to `target` from released, dropping by `step` each sample
+    fn land(detector: &mut VelocityDetector, target: f32, step: f32) {
+        let mut sample = 1.0_f32;
+        while target < sample {
+            sample = (sample - step).max(target);
+            detector.poll(sample);
+        }
+        for _ in 0..20 {
+            detector.poll(target);
+        }
+    }
+
+    fn velocity_of(curve: VelocityCurve, step: f32) -> u8 {
+        let mut detector = VelocityDetector::new();
+        detector.set_curve(curve);
+        land(&mut detector, 0.3, step);
+        detector.velocity()
+    }
+
+    #[test]
+    fn fast_landing_is_louder_than_slow_landing() {
+        let fast = velocity_of(VelocityCurve::Linear, 0.7);
+        let medium = velocity_of(VelocityCurve::Linear, 0.05);
+        let slow = velocity_of(VelocityCurve::Linear, 0.02);
+
+        assert_eq!(fast, MAX_VELOCITY);
+        assert!(slow < medium && medium < fast);
+    }
+
+    #[test]
+    fn linear_velocity_is_proportional_to_landing_speed() {
+        // 0.025 per sample is 0.1 over the window, a fifth of full velocity
+        assert_eq!(velocity_of(VelocityCurve::Linear, 0.025), 25);
+    }
+
+    #[test]
+    fn soft_curve_is_louder_and_hard_curve_is_quieter_than_linear() {
+        let linear = velocity_of(VelocityCurve::Linear, 0.05);
+        assert!(linear < velocity_of(VelocityCurve::Soft, 0.05));
+        assert!(velocity_of(VelocityCurve::Hard, 0.05) < linear);
+    }
+
+    #[test]
+    fn fixed_curve_ignores_landing_speed() {
+        assert_eq!(velocity_of(VelocityCurve::Fixed(90), 0.7), 90);
+        assert_eq!(velocity_of(VelocityCurve::Fixed(90), 0.01), 90);
+        assert_eq!(velocity_of(VelocityCurve::Fixed(0), 0.7), MIN_VELOCITY);
+    }
+
+    // lift off from `from` back to released, rising by `step` each sample
+    fn lift(detector: &mut VelocityDetector, from: f32, step: f32) {
+        let mut sample = from;
+        while sample < 1.0_f32 {
+            sample = (sample + step).min(1.0_f32);
+            detector.poll(sample);
+        }
+    }
+
+    #[test]
+    fn quick_lift_has_a_higher_release_velocity_than_slow_lift() {
+        let release_velocity_of = |step: f32| {
+            let mut detector = VelocityDetector::new();
+            land(&mut detector, 0.3, 0.7);
+            lift(&mut detector, 0.3, step);
+            detector.release_velocity()
+        };
+
+        assert_eq!(release_velocity_of(0.7), MAX_VELOCITY);
+        assert!(release_velocity_of(0.02) < release_velocity_of(0.05));
+    }
+
+    #[test]
+    fn landing_does_not_count_as_lifting() {
+        let mut detector = VelocityDetector::new();
+        land(&mut detector, 0.3, 0.7);
+        assert_eq!(detector.release_velocity(), MIN_VELOCITY);
+    }
+
+    #[test]
+    fn velocity_is_never_zero() {
+        let mut detector = VelocityDetector::new();
+        for _ in 0..HISTORY_LEN {
+            detector.poll(0.3);
+        }
+        assert_eq!(detector.velocity(), MIN_VELOCITY);
+    }
+}
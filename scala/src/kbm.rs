@@ -0,0 +1,171 @@
+//! # Scala keyboard mapping files
+//!
+//! A `.kbm` file says which scale degree each key plays and which key is tuned to a reference frequency. After any
+//! `!` comment lines it is a list of numbers, one per line:
+//!
+//! ```text
+//! ! size of the map, 0 for a linear mapping where consecutive keys play consecutive degrees
+//! 12
+//! ! first and last MIDI notes to retune
+//! 0
+//! 127
+//! ! the middle note, which plays degree 0
+//! 60
+//! ! the reference note and its frequency in Hz
+//! 69
+//! 440.0
+//! ! the scale degree of the formal octave, the keys repeat the map every formal octave
+//! 12
+//! ! the map, one scale degree per key starting from the middle note, `x` for keys which play nothing
+//! 0
+//! x
+//! 2
+//! ...
+//! ```
+//!
+//! The ribbon has no keys, so the first and last notes are read but have no effect.
+
+use crate::{scl::first_word, ParseError};
+
+/// A Scala keyboard mapping is represented here
+#[derive(Clone, Debug, PartialEq)]
+pub struct Kbm {
+    /// The first MIDI note to retune
+    pub first_note: u8,
+    /// The last MIDI note to retune
+    pub last_note: u8,
+    /// The MIDI note which plays degree 0 of the scale
+    pub middle_note: u8,
+    /// The MIDI note which is tuned to the reference frequency
+    pub reference_note: u8,
+    /// The frequency of the reference note in Hz
+    pub reference_freq: f64,
+    /// The scale degree the map repeats at
+    pub octave_degree: usize,
+    /// The scale degree played by each key from the middle note up, `None` for unmapped keys, empty for a linear
+    /// mapping
+    pub map: Vec<Option<usize>>,
+}
+
+impl Kbm {
+    /// `Kbm::linear()` is the mapping used when there is no `.kbm` file, each key plays the next scale degree with
+    /// middle C on degree 0 and A4 at 440Hz
+    pub fn linear() -> Self {
+        Self {
+            first_note: 0,
+            last_note: 127,
+            middle_note: 60,
+            reference_note: 69,
+            reference_freq: 440.0,
+            octave_degree: 0,
+            map: Vec::new(),
+        }
+    }
+}
+
+impl Default for Kbm {
+    fn default() -> Self {
+        Self::linear()
+    }
+}
+
+/// `parse_kbm(t)` is the `.kbm` file text `t` parsed into a keyboard mapping, or the first error found
+pub fn parse_kbm(text: &str) -> Result<Kbm, ParseError> {
+    let mut lines = text
+        .lines()
+        .enumerate()
+        .map(|(i, l)| (i + 1, first_word(l.trim())))
+        .filter(|(_, l)| !l.is_empty() && !l.starts_with('!'));
+
+    let mut next = |what: &str| {
+        lines
+            .next()
+            .ok_or_else(|| ParseError::new(0, format!("missing the {}", what)))
+    };
+    let number = |(line, text): (usize, &str), what: &str| {
+        text.parse::<usize>()
+            .map_err(|_| ParseError::new(line, format!("bad {} `{}`", what, text)))
+    };
+    let note = |(line, text): (usize, &str), what: &str| match text.parse::<u8>() {
+        Ok(note) if note <= 127 => Ok(note),
+        _ => Err(ParseError::new(line, format!("bad {} `{}`", what, text))),
+    };
+
+    let map_size = number(next("map size")?, "map size")?;
+    let first_note = note(next("first note")?, "first note")?;
+    let last_note = note(next("last note")?, "last note")?;
+    let middle_note = note(next("middle note")?, "middle note")?;
+    let reference_note = note(next("reference note")?, "reference note")?;
+
+    let (line, text) = next("reference frequency")?;
+    let reference_freq = match text.parse::<f64>() {
+        Ok(freq) if freq > 0.0 => freq,
+        _ => return Err(ParseError::new(line, format!("bad frequency `{}`", text))),
+    };
+
+    let (line, text) = next("formal octave")?;
+    let octave_degree = number((line, text), "formal octave")?;
+    if map_size != 0 && octave_degree == 0 {
+        return Err(ParseError::new(line, "the formal octave must not be 0"));
+    }
+
+    let mut map = Vec::new();
+    for (line, text) in lines.take(map_size) {
+        map.push(match text {
+            "x" | "X" => None,
+            _ => Some(number((line, text), "scale degree")?),
+        });
+    }
+    // keys missing from the end of the map are unmapped
+    map.resize(map_size, None);
+
+    Ok(Kbm {
+        first_note,
+        last_note,
+        middle_note,
+        reference_note,
+        reference_freq,
+        octave_degree,
+        map,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const WHITE_KEYS: &str =
+        "! white keys only\n12\n0\n127\n60\n69\n432.0\n7\n0\nx\n1\nx\n2\n3\nx\n4\nx\n5\nx\n6\n";
+
+    #[test]
+    fn mapping_is_parsed() {
+        let kbm = parse_kbm(WHITE_KEYS).unwrap();
+        assert_eq!(kbm.middle_note, 60);
+        assert_eq!(kbm.reference_note, 69);
+        assert_eq!(kbm.reference_freq, 432.0);
+        assert_eq!(kbm.octave_degree, 7);
+        assert_eq!(kbm.map.len(), 12);
+        assert_eq!(kbm.map[..3], [Some(0), None, Some(1)]);
+    }
+
+    #[test]
+    fn short_map_is_padded_with_unmapped_keys() {
+        let kbm = parse_kbm("3\n0\n127\n60\n69\n440.0\n3\n0\n1\n").unwrap();
+        assert_eq!(kbm.map, [Some(0), Some(1), None]);
+    }
+
+    #[test]
+    fn bad_mappings_are_errors() {
+        assert_eq!(
+            parse_kbm("0\n0\n127\n60\n69\n").unwrap_err().msg,
+            "missing the reference frequency"
+        );
+        assert_eq!(parse_kbm("0\n0\n200\n").unwrap_err().line, 3);
+        assert_eq!(
+            parse_kbm("1\n0\n127\n60\n69\n440.0\n1\ny\n")
+                .unwrap_err()
+                .line,
+            8
+        );
+    }
+}
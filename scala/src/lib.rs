@@ -0,0 +1,168 @@
+//! # Scala tuning compiler
+//!
+//! Compiles Scala scale (`.scl`) and keyboard mapping (`.kbm`) files into the compact tuning tables used by the
+//! ribbon controller quantizers, see `ribbon_core::tuning`.
+//!
+//! The ribbon is continuous rather than a row of keys, so the keyboard mapping only decides which degrees of the scale
+//! can be played, which degree the pattern repeats at, and which pitch the scale is anchored to. Without a `.kbm` file
+//! every degree is played, the scale repeats at its last pitch, and degree 0 is middle C with A4 at 440Hz.
+
+pub mod kbm;
+pub mod scl;
+
+use kbm::Kbm;
+use scl::Scl;
+
+use ribbon_core::tuning::TuningTable;
+
+use std::fmt;
+
+/// An error found while reading or compiling a Scala file is represented here
+#[derive(Debug, PartialEq)]
+pub struct ParseError {
+    /// The 1-based line number where the error was found, 0 if the error is not on any one line
+    pub line: usize,
+    /// A description of the problem
+    pub msg: String,
+}
+
+impl ParseError {
+    /// `ParseError::new(l, m)` is an error with message `m` found on line `l`
+    pub fn new<S: Into<String>>(line: usize, msg: S) -> Self {
+        Self {
+            line,
+            msg: msg.into(),
+        }
+    }
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "line {}: {}", self.line, self.msg)
+    }
+}
+
+/// `compile(s, k)` is scale `s` with keyboard mapping `k` compiled into a tuning table
+pub fn compile(scl: &Scl, kbm: &Kbm) -> Result<TuningTable, ParseError> {
+    // a linear mapping plays every degree and repeats at the period of the scale
+    let (map, octave_degree) = if kbm.map.is_empty() {
+        let every_degree: Vec<Option<usize>> = (0..scl.pitches.len()).map(Some).collect();
+        (every_degree, scl.pitches.len())
+    } else {
+        (kbm.map.clone(), kbm.octave_degree)
+    };
+
+    let period = scl.cents(octave_degree as i64);
+    if period <= 0.0 {
+        return Err(ParseError::new(
+            0,
+            "the formal octave must be above the unison",
+        ));
+    }
+
+    // the pitch of key `k` keys above the middle note, in cents above degree 0
+    let key_cents = |key: i64| {
+        let cycle_len = map.len() as i64;
+        map[key.rem_euclid(cycle_len) as usize]
+            .map(|d| key.div_euclid(cycle_len) as f64 * period + scl.cents(d as i64))
+    };
+
+    let reference_cents = key_cents(kbm.reference_note as i64 - kbm.middle_note as i64)
+        .ok_or_else(|| ParseError::new(0, "the reference note is not mapped"))?;
+    let reference_pitch = 69.0 + 12.0 * (kbm.reference_freq / 440.0).log2();
+    let root_pitch = reference_pitch - reference_cents / 100.0;
+
+    let period = period / 100.0;
+    let mut degrees: Vec<f32> = Vec::new();
+    for cents in map.iter().flatten().map(|&d| scl.cents(d as i64)) {
+        let degree = (cents / 100.0).rem_euclid(period) as f32;
+        // rounding to f32 can land a degree just below the period on the period itself, which is the root
+        let degree = if degree < period as f32 { degree } else { 0.0 };
+        if !degrees
+            .iter()
+            .any(|d| (d - degree).abs() < DUPLICATE_DEGREE)
+        {
+            degrees.push(degree);
+        }
+    }
+
+    TuningTable::new(period as f32, root_pitch as f32, &degrees)
+        .map_err(|e| ParseError::new(0, format!("can't build the tuning table: {:?}", e)))
+}
+
+// degrees closer together than this are the same degree, a hundredth of a cent
+const DUPLICATE_DEGREE: f32 = 1E-4;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use kbm::parse_kbm;
+    use scl::parse_scl;
+
+    fn equal_temperament(num_steps: usize) -> Scl {
+        Scl {
+            description: String::new(),
+            pitches: (1..=num_steps)
+                .map(|i| i as f64 * 1200.0 / num_steps as f64)
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn twelve_tone_equal_temperament_is_the_midi_notes() {
+        let t = compile(&equal_temperament(12), &Kbm::linear()).unwrap();
+        assert_eq!(t.period(), 12.0);
+        assert!((t.root_pitch() - 60.0).abs() < 1E-4);
+        assert_eq!(t.degrees().len(), 12);
+        assert!((t.nearest(64.3) - 64.0).abs() < 1E-4);
+    }
+
+    #[test]
+    fn reference_frequency_detunes_the_scale() {
+        let mut kbm = Kbm::linear();
+        kbm.reference_freq = 432.0;
+        let t = compile(&equal_temperament(12), &kbm).unwrap();
+
+        // A4 at 432Hz is about 31.8 cents flat
+        assert!((t.nearest(69.0) - 68.682).abs() < 1E-3);
+    }
+
+    #[test]
+    fn quarter_tone_file_compiles() {
+        let scl = parse_scl(include_str!("../tunings/24edo.scl")).unwrap();
+        let t = compile(&scl, &Kbm::linear()).unwrap();
+        assert_eq!(t.degrees().len(), 24);
+        assert!((t.nearest(69.4) - 69.5).abs() < 1E-4);
+    }
+
+    #[test]
+    fn just_major_third_is_flat_of_equal_temperament() {
+        let scl = parse_scl(include_str!("../tunings/just_major.scl")).unwrap();
+        let t = compile(&scl, &Kbm::linear()).unwrap();
+
+        // the 5/4 major third is about 13.7 cents flat of the equal tempered one
+        assert!((t.degrees()[2] - 3.8631).abs() < 1E-3);
+    }
+
+    #[test]
+    fn unmapped_keys_drop_their_degrees() {
+        // play only degrees 0, 4, and 7 of 12 tone equal temperament, C major triads
+        let kbm =
+            parse_kbm("12\n0\n127\n60\n60\n261.6256\n12\n0\nx\nx\nx\n4\nx\nx\n7\nx\nx\nx\nx\n")
+                .unwrap();
+        let t = compile(&equal_temperament(12), &kbm).unwrap();
+
+        assert_eq!(t.degrees().len(), 3);
+        assert!((t.nearest(66.0) - 67.0).abs() < 1E-3);
+        assert!((t.nearest(71.0) - 72.0).abs() < 1E-3);
+    }
+
+    #[test]
+    fn reference_note_must_be_mapped() {
+        let kbm = parse_kbm("2\n0\n127\n60\n61\n440.0\n12\n0\nx\n").unwrap();
+        assert_eq!(
+            compile(&equal_temperament(12), &kbm).unwrap_err().msg,
+            "the reference note is not mapped"
+        );
+    }
+}
@@ -0,0 +1,42 @@
+//! # Scala tuning compiler
+//!
+//! Compiles a Scala scale file, and optionally a keyboard mapping file, into a tuning table for the ribbon controller.
+//!
+//! ```text
+//! ribbon-scala <scale.scl> [mapping.kbm] <table.bin>
+//! ```
+
+use ribbon_scala::{compile, kbm, scl};
+
+use std::{env, fs, process};
+
+fn main() {
+    let args: Vec<String> = env::args().collect();
+    if !(3..=4).contains(&args.len()) {
+        eprintln!("usage: {} <scale.scl> [mapping.kbm] <table.bin>", args[0]);
+        process::exit(2);
+    }
+
+    let scl_path = &args[1];
+    let text = fs::read_to_string(scl_path).unwrap_or_else(|e| fail(scl_path, e));
+    let scl = scl::parse_scl(&text).unwrap_or_else(|e| fail(scl_path, e));
+
+    let kbm = match args.get(3).map(|_| &args[2]) {
+        Some(kbm_path) => {
+            let text = fs::read_to_string(kbm_path).unwrap_or_else(|e| fail(kbm_path, e));
+            kbm::parse_kbm(&text).unwrap_or_else(|e| fail(kbm_path, e))
+        }
+        None => kbm::Kbm::linear(),
+    };
+
+    let table = compile(&scl, &kbm).unwrap_or_else(|e| fail(scl_path, e));
+
+    let out_path = args.last().unwrap();
+    fs::write(out_path, table.to_bytes()).unwrap_or_else(|e| fail(out_path, e));
+}
+
+/// `fail(p, e)` reports error `e` with file path `p` and exits
+fn fail<E: std::fmt::Display>(path: &str, err: E) -> ! {
+    eprintln!("{}: {}", path, err);
+    process::exit(1);
+}
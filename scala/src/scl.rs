@@ -0,0 +1,168 @@
+//! # Scala scale files
+//!
+//! A `.scl` file lists the pitches of a scale above its unison, one per line. Pitches containing a `.` are in cents,
+//! anything else is a ratio such as `3/2` or a whole number such as `2`. The last pitch is the interval the scale
+//! repeats at, usually the octave.
+//!
+//! ```text
+//! ! just_major.scl
+//! !
+//! 5-limit just intonation major scale
+//!  7
+//! !
+//!  9/8
+//!  5/4
+//!  ...
+//!  2/1
+//! ```
+//!
+//! Lines starting with `!` are comments. The first other line is a description, which may be empty, followed by the
+//! number of pitches and then the pitches. Anything after the first word of a line is ignored.
+
+use crate::ParseError;
+
+/// A Scala scale is represented here
+#[derive(Clone, Debug, PartialEq)]
+pub struct Scl {
+    /// The description of the scale
+    pub description: String,
+    /// The pitches of the scale above the unison in cents, the last is the period
+    pub pitches: Vec<f64>,
+}
+
+impl Scl {
+    /// `scl.period()` is the interval in cents that scale `scl` repeats at
+    pub fn period(&self) -> f64 {
+        *self.pitches.last().unwrap_or(&0.0)
+    }
+
+    /// `scl.cents(d)` is degree `d` of scale `scl` in cents above the unison, degree 0 is the unison and degrees
+    /// outside of `[0, len)` continue into the periods above and below
+    pub fn cents(&self, degree: i64) -> f64 {
+        let len = self.pitches.len() as i64;
+        let periods = degree.div_euclid(len);
+        let degree = degree.rem_euclid(len) as usize;
+        let within_period = if degree == 0 {
+            0.0
+        } else {
+            self.pitches[degree - 1]
+        };
+        periods as f64 * self.period() + within_period
+    }
+}
+
+/// `parse_scl(t)` is the `.scl` file text `t` parsed into a scale, or the first error found
+pub fn parse_scl(text: &str) -> Result<Scl, ParseError> {
+    let mut lines = text
+        .lines()
+        .enumerate()
+        .map(|(i, l)| (i + 1, l.trim()))
+        .filter(|(_, l)| !l.starts_with('!'));
+
+    let description = match lines.next() {
+        Some((_, description)) => description.to_string(),
+        None => return Err(ParseError::new(0, "the scale is empty")),
+    };
+
+    // the description is the only line which may be blank
+    let mut lines = lines.filter(|(_, l)| !l.is_empty());
+
+    let count = match lines.next() {
+        Some((line, text)) => match first_word(text).parse::<usize>() {
+            Ok(count) if count > 0 => count,
+            _ => return Err(ParseError::new(line, format!("bad count `{}`", text))),
+        },
+        None => return Err(ParseError::new(0, "the scale has no count")),
+    };
+
+    let mut pitches = Vec::new();
+    for (line, text) in lines {
+        pitches.push(parse_pitch(first_word(text)).map_err(|msg| ParseError::new(line, msg))?);
+    }
+
+    if pitches.len() != count {
+        return Err(ParseError::new(
+            0,
+            format!("expected {} pitches but found {}", count, pitches.len()),
+        ));
+    }
+    if pitches[count - 1] <= 0.0 {
+        return Err(ParseError::new(
+            0,
+            "the last pitch must be above the unison",
+        ));
+    }
+
+    Ok(Scl {
+        description,
+        pitches,
+    })
+}
+
+/// `parse_pitch(t)` is the pitch `t` in cents, `t` is either cents with a decimal point or a ratio
+fn parse_pitch(text: &str) -> Result<f64, String> {
+    let bad_pitch = || format!("bad pitch `{}`", text);
+
+    if text.contains('.') {
+        return text.parse().map_err(|_| bad_pitch());
+    }
+
+    let (num, den) = match text.split_once('/') {
+        Some((num, den)) => (num, den),
+        None => (text, "1"),
+    };
+    let num: u64 = num.parse().map_err(|_| bad_pitch())?;
+    let den: u64 = den.parse().map_err(|_| bad_pitch())?;
+    if num == 0 || den == 0 {
+        return Err(bad_pitch());
+    }
+
+    Ok(1200.0 * (num as f64 / den as f64).log2())
+}
+
+/// `first_word(t)` is the first whitespace separated word of `t`
+pub(crate) fn first_word(text: &str) -> &str {
+    text.split_whitespace().next().unwrap_or("")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pitches_may_be_cents_or_ratios() {
+        let scl = parse_scl("! test\nmixed\n 4\n 100.0\n 3/2\n 700.0 a fifth\n 2\n").unwrap();
+        assert_eq!(scl.description, "mixed");
+        assert_eq!(scl.pitches[0], 100.0);
+        assert!((scl.pitches[1] - 701.955).abs() < 1E-3);
+        assert_eq!(scl.pitches[2], 700.0);
+        assert_eq!(scl.pitches[3], 1200.0);
+    }
+
+    #[test]
+    fn description_may_be_blank() {
+        let scl = parse_scl("!\n\n 1\n 2/1\n").unwrap();
+        assert_eq!(scl.description, "");
+        assert_eq!(scl.pitches, [1200.0]);
+    }
+
+    #[test]
+    fn degrees_continue_past_the_period() {
+        let scl = parse_scl("fifths\n 2\n 700.0\n 1200.0\n").unwrap();
+        assert_eq!(scl.cents(0), 0.0);
+        assert_eq!(scl.cents(1), 700.0);
+        assert_eq!(scl.cents(3), 1900.0);
+        assert_eq!(scl.cents(-1), -500.0);
+    }
+
+    #[test]
+    fn bad_scales_are_errors() {
+        assert_eq!(
+            parse_scl("short\n 3\n 100.0\n 2/1\n").unwrap_err().msg,
+            "expected 3 pitches but found 2"
+        );
+        assert_eq!(parse_scl("bad\n 1\n 3/0\n").unwrap_err().line, 3);
+        assert_eq!(parse_scl("bad\n two\n").unwrap_err().line, 2);
+        assert!(parse_scl("! only comments\n").is_err());
+    }
+}
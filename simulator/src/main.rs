@@ -0,0 +1,45 @@
+//! # Ribbon controller simulator
+//!
+//! Replays a recorded trace of board inputs through the firmware logic on a host computer, and writes out the
+//! resulting analog outputs and MIDI log, and optionally the MIDI output as a Standard MIDI File.
+//!
+//! ```text
+//! ribbon-simulator <trace.csv> <outputs.csv> <midi.log> [session.mid]
+//! ```
+
+mod report;
+mod sim_board;
+mod simulation;
+mod smf;
+mod trace;
+
+use std::{env, fs, process};
+
+fn main() {
+    let args: Vec<String> = env::args().collect();
+    if !(4..=5).contains(&args.len()) {
+        eprintln!(
+            "usage: {} <trace.csv> <outputs.csv> <midi.log> [session.mid]",
+            args[0]
+        );
+        process::exit(2);
+    }
+
+    let text = fs::read_to_string(&args[1]).unwrap_or_else(|e| fail(&args[1], e));
+    let trace = trace::parse_trace(&text).unwrap_or_else(|e| fail(&args[1], e));
+
+    let result = simulation::run(&trace);
+
+    fs::write(&args[2], report::outputs_csv(&result.outputs)).unwrap_or_else(|e| fail(&args[2], e));
+    fs::write(&args[3], report::midi_log(&result.midi)).unwrap_or_else(|e| fail(&args[3], e));
+
+    if let Some(path) = args.get(4) {
+        fs::write(path, result.to_smf()).unwrap_or_else(|e| fail(path, e));
+    }
+}
+
+/// `fail(p, e)` reports error `e` with file path `p` and exits
+fn fail<E: std::fmt::Display>(path: &str, err: E) -> ! {
+    eprintln!("{}: {}", path, err);
+    process::exit(1);
+}
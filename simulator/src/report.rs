@@ -0,0 +1,107 @@
+//! # Simulation reports
+//!
+//! Text renderings of a simulation result, suitable for writing to files and diffing against earlier runs.
+
+use midi_convert::midi_types::MidiMessage;
+
+use crate::simulation::{MidiEvent, OutputSample};
+
+/// The header of the analog outputs CSV
+pub const OUTPUTS_HEADER: &str = "time_ms,ribbon_cv,mod_cv,gate";
+
+/// `outputs_csv(os)` is the analog output samples `os` rendered as CSV
+pub fn outputs_csv(outputs: &[OutputSample]) -> String {
+    let mut csv = String::from(OUTPUTS_HEADER) + "\n";
+    for o in outputs {
+        csv += &format!(
+            "{},{:.4},{:.4},{}\n",
+            us_to_ms(o.time_us),
+            o.ribbon_cv,
+            o.mod_cv,
+            o.gate as u8
+        );
+    }
+    csv
+}
+
+/// `midi_log(es)` is the MIDI events `es` rendered as a human readable log, one message per line
+pub fn midi_log(events: &[MidiEvent]) -> String {
+    events
+        .iter()
+        .map(|e| format!("{} {}\n", us_to_ms(e.time_us), describe(&e.msg)))
+        .collect()
+}
+
+/// `describe(m)` is a short human readable description of MIDI message `m`, channels are numbered from 1
+pub fn describe(msg: &MidiMessage) -> String {
+    match *msg {
+        MidiMessage::NoteOn(ch, note, vel) => format!(
+            "ch {:>2} note-on  {:>3} vel {:>3}",
+            u8::from(ch) + 1,
+            u8::from(note),
+            u8::from(vel)
+        ),
+        MidiMessage::NoteOff(ch, note, vel) => format!(
+            "ch {:>2} note-off {:>3} vel {:>3}",
+            u8::from(ch) + 1,
+            u8::from(note),
+            u8::from(vel)
+        ),
+        MidiMessage::PitchBendChange(ch, bend) => format!(
+            "ch {:>2} pitch-bend {:+}",
+            u8::from(ch) + 1,
+            i16::from(bend)
+        ),
+        MidiMessage::ControlChange(ch, cc, val) => format!(
+            "ch {:>2} cc {:>3} = {:>3}",
+            u8::from(ch) + 1,
+            u8::from(cc),
+            u8::from(val)
+        ),
+        other => format!("{:?}", other),
+    }
+}
+
+/// `us_to_ms(t)` is time `t` in microseconds formatted as milliseconds
+fn us_to_ms(time_us: u64) -> String {
+    format!("{}.{:03}", time_us / 1_000, time_us % 1_000)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn outputs_are_one_row_per_sample() {
+        let csv = outputs_csv(&[OutputSample {
+            time_us: 3_333,
+            ribbon_cv: 1.5,
+            mod_cv: 0.25,
+            gate: true,
+        }]);
+        assert_eq!(
+            csv,
+            "time_ms,ribbon_cv,mod_cv,gate\n3.333,1.5000,0.2500,1\n"
+        );
+    }
+
+    #[test]
+    fn midi_is_logged_with_one_based_channels() {
+        let log = midi_log(&[
+            MidiEvent {
+                time_us: 10_000,
+                tick: 3,
+                msg: MidiMessage::NoteOn(0.into(), 60.into(), 127.into()),
+            },
+            MidiEvent {
+                time_us: 13_333,
+                tick: 4,
+                msg: MidiMessage::PitchBendChange(15.into(), (-100_i16).into()),
+            },
+        ]);
+        assert_eq!(
+            log,
+            "10.000 ch  1 note-on   60 vel 127\n13.333 ch 16 pitch-bend -100\n"
+        );
+    }
+}
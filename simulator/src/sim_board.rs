@@ -0,0 +1,168 @@
+//! # Simulated board
+//!
+//! A stand-in for the physical board which is driven by a recorded trace. The simulation sets the inputs and expires
+//! the timers, and the board keeps the latest value of each output just like the real jacks would. The settings flash
+//! pages start out blank, so every simulation runs with the default settings.
+
+use ribbon_core::{
+    board::{
+        adc_fs_to_normalized_fl, dac8162_words, AdcInputs, AdcPin, Dac8162Channel, DacOutputs,
+        FlashError, GateOutput, PanelSwitches, PeriodicTimers, SerialInput, SerialOutput,
+        SettingsFlash, Switch3wayState, DAC8162_MAX_COUNT, DAC8162_MAX_VOUT, NUM_ADC_PINS,
+    },
+    dac_calibration::DacCalibration,
+    settings::RamFlash,
+};
+
+use std::cell::Cell;
+
+use crate::trace::TraceRow;
+
+/// The simulated board is represented here
+pub struct SimBoard {
+    adc: [u16; NUM_ADC_PINS],
+    mode_switch: Switch3wayState,
+    midi_ch: u8,
+
+    dac_calibration: DacCalibration,
+
+    /// The voltage at the `RIBBON CV` jack
+    pub ribbon_cv: f32,
+    /// The voltage at the `MOD CV` jack
+    pub mod_cv: f32,
+    /// The state of the `GATE` jack
+    pub gate: bool,
+    /// Bytes written to the serial port which have not been collected yet
+    pub serial_bytes: Vec<u8>,
+
+    flash: RamFlash,
+
+    tim2_timeout: Cell<bool>,
+    tim6_timeout: Cell<bool>,
+    tim15_timeout: Cell<bool>,
+}
+
+impl SimBoard {
+    /// `SimBoard::new(r)` is a new simulated board with its inputs set from trace row `r`
+    pub fn new(row: &TraceRow) -> Self {
+        Self {
+            adc: row.adc,
+            mode_switch: row.mode_switch,
+            midi_ch: row.midi_ch,
+            dac_calibration: DacCalibration::new(),
+            ribbon_cv: 0.0_f32,
+            mod_cv: 0.0_f32,
+            gate: false,
+            serial_bytes: Vec::new(),
+            flash: RamFlash::new(),
+            tim2_timeout: Cell::new(false),
+            tim6_timeout: Cell::new(false),
+            tim15_timeout: Cell::new(false),
+        }
+    }
+
+    /// `sb.set_inputs(r)` sets the board inputs from trace row `r`
+    pub fn set_inputs(&mut self, row: &TraceRow) {
+        self.adc = row.adc;
+        self.mode_switch = row.mode_switch;
+        self.midi_ch = row.midi_ch;
+    }
+
+    /// `sb.expire_timers(t2, t6, t15)` makes the next timeout check of each timer whose flag is set return true
+    pub fn expire_timers(&mut self, tim2: bool, tim6: bool, tim15: bool) {
+        self.tim2_timeout.set(self.tim2_timeout.get() || tim2);
+        self.tim6_timeout.set(self.tim6_timeout.get() || tim6);
+        self.tim15_timeout.set(self.tim15_timeout.get() || tim15);
+    }
+}
+
+impl AdcInputs for SimBoard {
+    fn read_adc(&mut self, pin: AdcPin) -> f32 {
+        adc_fs_to_normalized_fl(self.adc[pin as usize])
+    }
+}
+
+impl DacOutputs for SimBoard {
+    fn dac8162_set_vout(&mut self, v_out: f32, channel: Dac8162Channel) {
+        // go through the same conversion as the real DAC so the resolution and clamping match the hardware
+        let words = dac8162_words(self.dac_calibration.apply(v_out, channel), channel);
+        let counts = (((words[1] as u16) << 8) | words[2] as u16) >> 2;
+        let v_out = counts as f32 * DAC8162_MAX_VOUT / DAC8162_MAX_COUNT as f32;
+
+        match channel {
+            Dac8162Channel::A => self.ribbon_cv = v_out,
+            Dac8162Channel::B => self.mod_cv = v_out,
+        }
+    }
+
+    fn set_dac_calibration(&mut self, calibration: DacCalibration) {
+        self.dac_calibration = calibration;
+    }
+}
+
+impl GateOutput for SimBoard {
+    fn set_gate(&mut self, val: bool) {
+        self.gate = val;
+    }
+}
+
+impl PanelSwitches for SimBoard {
+    fn read_mode_switch(&self) -> Switch3wayState {
+        self.mode_switch
+    }
+
+    fn read_midi_ch_switch(&self) -> u8 {
+        self.midi_ch
+    }
+}
+
+impl SerialOutput for SimBoard {
+    fn serial_tx_free(&self) -> usize {
+        // the bytes are collected after every update, there is always room
+        usize::MAX
+    }
+
+    fn serial_write_all(&mut self, bytes: &[u8]) {
+        self.serial_bytes.extend_from_slice(bytes);
+    }
+}
+
+impl SerialInput for SimBoard {
+    fn serial_read(&mut self, _bytes: &mut [u8]) -> usize {
+        // nothing is plugged into the MIDI input of a simulated board
+        0
+    }
+}
+
+impl PeriodicTimers for SimBoard {
+    fn get_tim2_timeout(&self) -> bool {
+        self.tim2_timeout.replace(false)
+    }
+
+    fn get_tim6_timeout(&self) -> bool {
+        self.tim6_timeout.replace(false)
+    }
+
+    fn get_tim15_timeout(&self) -> bool {
+        self.tim15_timeout.replace(false)
+    }
+}
+
+impl SettingsFlash for SimBoard {
+    fn settings_read(&mut self, page: usize, offset: usize, bytes: &mut [u8]) {
+        self.flash.settings_read(page, offset, bytes);
+    }
+
+    fn settings_erase(&mut self, page: usize) -> Result<(), FlashError> {
+        self.flash.settings_erase(page)
+    }
+
+    fn settings_write(
+        &mut self,
+        page: usize,
+        offset: usize,
+        bytes: &[u8],
+    ) -> Result<(), FlashError> {
+        self.flash.settings_write(page, offset, bytes)
+    }
+}
@@ -0,0 +1,243 @@
+//! # Simulation
+//!
+//! Replays a recorded trace through the same application logic that runs on the firmware. Simulated time starts at
+//! zero when the firmware would enter its main loop, and each periodic timer expires at exactly its nominal rate.
+
+use midi_convert::{midi_types::MidiMessage, MidiByteStreamParser};
+use ribbon_core::{app::App, OUTPUT_UPDATE_RATE_HZ, RIBBON_SAMPLE_RATE_HZ, UI_UPDATE_RATE_HZ};
+
+use crate::{sim_board::SimBoard, smf::SmfWriter, trace::TraceRow};
+
+/// The state of the analog outputs after an output update is represented here
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct OutputSample {
+    /// The time of the output update in microseconds
+    pub time_us: u64,
+    /// The voltage at the `RIBBON CV` jack
+    pub ribbon_cv: f32,
+    /// The voltage at the `MOD CV` jack
+    pub mod_cv: f32,
+    /// The state of the `GATE` jack
+    pub gate: bool,
+}
+
+/// A MIDI message sent by the simulated firmware is represented here
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct MidiEvent {
+    /// The time the message was sent in microseconds
+    pub time_us: u64,
+    /// The output update the message was sent during, counting from 1
+    pub tick: u32,
+    /// The decoded message
+    pub msg: MidiMessage,
+}
+
+/// Everything produced by a simulation run is represented here
+pub struct SimResult {
+    /// The analog outputs after every output update
+    pub outputs: Vec<OutputSample>,
+    /// Every MIDI message sent, in order
+    pub midi: Vec<MidiEvent>,
+    /// The raw bytes sent via the MIDI serial port, in order, each paired with the output update it was sent during
+    pub midi_bytes: Vec<(u32, u8)>,
+}
+
+impl SimResult {
+    /// `sr.to_smf()` is the MIDI output of the simulation as a Standard MIDI File
+    pub fn to_smf(&self) -> Vec<u8> {
+        let mut smf = SmfWriter::new();
+        self.midi_bytes
+            .iter()
+            .for_each(|&(tick, byte)| smf.record(tick, &[byte]));
+        smf.to_bytes()
+    }
+}
+
+/// A timer which expires periodically in simulated time is represented here
+struct SimTimer {
+    rate_hz: u64,
+    num_expiries: u64,
+}
+
+impl SimTimer {
+    fn new(rate_hz: u32) -> Self {
+        Self {
+            rate_hz: rate_hz as u64,
+            num_expiries: 0,
+        }
+    }
+
+    /// `t.next_us()` is the time of the next expiry of the timer in microseconds
+    fn next_us(&self) -> u64 {
+        ((self.num_expiries + 1) * 1_000_000) / self.rate_hz
+    }
+
+    /// `t.expire_if_due(now)` is true iff the timer expires at time `now`, in which case the timer moves on
+    fn expire_if_due(&mut self, now_us: u64) -> bool {
+        if self.next_us() == now_us {
+            self.num_expiries += 1;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+/// `run(t)` is the result of replaying trace `t` from time zero until the time of its last row
+///
+/// # Requires
+///
+/// * `trace` is not empty and its rows are in time order
+pub fn run(trace: &[TraceRow]) -> SimResult {
+    let end_us = trace[trace.len() - 1].time_us;
+
+    let mut board = SimBoard::new(&trace[0]);
+    let mut app = App::new();
+    app.init(&mut board);
+
+    let mut tim2 = SimTimer::new(RIBBON_SAMPLE_RATE_HZ);
+    let mut tim6 = SimTimer::new(UI_UPDATE_RATE_HZ);
+    let mut tim15 = SimTimer::new(OUTPUT_UPDATE_RATE_HZ);
+
+    let mut parser = MidiByteStreamParser::new();
+    let mut result = SimResult {
+        outputs: Vec::new(),
+        midi: Vec::new(),
+        midi_bytes: Vec::new(),
+    };
+
+    let mut next_row = 0;
+    loop {
+        let now_us = tim2.next_us().min(tim6.next_us()).min(tim15.next_us());
+        if end_us < now_us {
+            break;
+        }
+
+        // sample-and-hold the most recent row of the trace
+        while next_row < trace.len() && trace[next_row].time_us <= now_us {
+            board.set_inputs(&trace[next_row]);
+            next_row += 1;
+        }
+
+        let outputs_updated = tim15.expire_if_due(now_us);
+        board.expire_timers(
+            tim2.expire_if_due(now_us),
+            tim6.expire_if_due(now_us),
+            outputs_updated,
+        );
+        app.service(&mut board);
+
+        if outputs_updated {
+            result.outputs.push(OutputSample {
+                time_us: now_us,
+                ribbon_cv: board.ribbon_cv,
+                mod_cv: board.mod_cv,
+                gate: board.gate,
+            });
+        }
+
+        // MIDI is only ever sent by the output update, so the output timer count is the tick it was sent on
+        let tick = tim15.num_expiries as u32;
+        for byte in board.serial_bytes.drain(..) {
+            result.midi_bytes.push((tick, byte));
+            if let Some(msg) = parser.parse(byte) {
+                result.midi.push(MidiEvent {
+                    time_us: now_us,
+                    tick,
+                    msg,
+                });
+            }
+        }
+    }
+
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ribbon_core::board::Switch3wayState;
+
+    const RELEASED: u16 = 0xFFF0;
+
+    fn row(time_ms: u64, main_ribbon: u16) -> TraceRow {
+        TraceRow {
+            time_us: time_ms * 1_000,
+            adc: [0, main_ribbon, RELEASED],
+            mode_switch: Switch3wayState::Up,
+            midi_ch: 2,
+        }
+    }
+
+    #[test]
+    fn outputs_are_updated_at_the_output_rate() {
+        let result = run(&[row(0, RELEASED), row(1_000, RELEASED)]);
+        assert_eq!(result.outputs.len(), OUTPUT_UPDATE_RATE_HZ as usize);
+        assert_eq!(result.outputs.last().unwrap().time_us, 1_000_000);
+    }
+
+    #[test]
+    fn midi_ticks_count_output_updates() {
+        let result = run(&[row(0, RELEASED), row(100, 20_000), row(300, RELEASED)]);
+        for e in &result.midi {
+            assert_eq!(
+                e.time_us,
+                (e.tick as u64 * 1_000_000) / OUTPUT_UPDATE_RATE_HZ as u64
+            );
+        }
+    }
+
+    #[test]
+    fn pressing_the_ribbon_plays_a_note() {
+        let result = run(&[
+            row(0, RELEASED),
+            row(100, 20_000),
+            row(300, RELEASED),
+            row(400, RELEASED),
+        ]);
+
+        let gate_rose = result.outputs.iter().find(|o| o.gate).unwrap();
+        let gate_fell = result
+            .outputs
+            .iter()
+            .find(|o| gate_rose.time_us < o.time_us && !o.gate)
+            .unwrap();
+        assert!(100_000 < gate_rose.time_us && gate_rose.time_us < 150_000);
+        assert!(300_000 <= gate_fell.time_us && gate_fell.time_us < 310_000);
+
+        let note_ons: Vec<_> = result
+            .midi
+            .iter()
+            .filter(|e| matches!(e.msg, MidiMessage::NoteOn(..)))
+            .collect();
+        assert_eq!(note_ons.len(), 1);
+        assert_eq!(note_ons[0].time_us, gate_rose.time_us);
+        assert!(result.midi.iter().any(|e| matches!(
+            e.msg,
+            MidiMessage::NoteOff(ch, note, _) if u8::from(ch) == 2 && note == note_number(note_ons[0])
+        )));
+    }
+
+    #[test]
+    fn example_trace_matches_its_golden_midi_file() {
+        // set UPDATE_GOLDEN=1 to regenerate the golden file after an intentional change to the MIDI output
+        let golden_path = concat!(
+            env!("CARGO_MANIFEST_DIR"),
+            "/traces/press_slide_release.mid"
+        );
+        let trace = crate::trace::parse_trace(include_str!("../traces/press_slide_release.csv"));
+        let smf = run(&trace.unwrap()).to_smf();
+
+        if std::env::var_os("UPDATE_GOLDEN").is_some() {
+            std::fs::write(golden_path, &smf).unwrap();
+        }
+        assert!(smf == std::fs::read(golden_path).unwrap());
+    }
+
+    fn note_number(event: &MidiEvent) -> midi_convert::midi_types::Note {
+        match event.msg {
+            MidiMessage::NoteOn(_, note, _) => note,
+            _ => unreachable!(),
+        }
+    }
+}
@@ -0,0 +1,257 @@
+//! # Standard MIDI File export
+//!
+//! Captures the byte stream sent via the MIDI serial port as a type-0 Standard MIDI File.
+//!
+//! One file tick is one output update (one TIM15 period), the file is written at 120 BPM with the division chosen so
+//! that the ticks line up exactly with real time. The file can be opened in a DAW, and since the same session always
+//! produces the same bytes it can be kept as a golden file for tests.
+//!
+//! Channel messages are written with their full status byte even if the wire stream used running status, and SysEx
+//! messages are written as SysEx events. Realtime and system common messages have no place in a Standard MIDI File
+//! and are dropped.
+
+use ribbon_core::OUTPUT_UPDATE_RATE_HZ;
+
+/// The tempo of the file in microseconds per quarter note, 120 BPM
+const TEMPO_US_PER_QUARTER: u32 = 500_000;
+
+/// The number of file ticks per quarter note, chosen so that one tick is one output update
+const TICKS_PER_QUARTER: u16 =
+    (OUTPUT_UPDATE_RATE_HZ * (TEMPO_US_PER_QUARTER / 1_000) / 1_000) as u16;
+
+/// A Standard MIDI File being built from a MIDI byte stream is represented here
+pub struct SmfWriter {
+    // the encoded track events, without the track header
+    track: Vec<u8>,
+    // the tick of the last event written to the track
+    last_tick: u32,
+
+    // the message being parsed from the byte stream
+    running_status: Option<u8>,
+    data: Vec<u8>,
+    in_sysex: bool,
+}
+
+impl SmfWriter {
+    /// `SmfWriter::new()` is a new writer with an empty track
+    pub fn new() -> Self {
+        let mut track = Vec::new();
+        // set the tempo at the very start of the track
+        push_vlq(&mut track, 0);
+        track.extend_from_slice(&[0xFF, 0x51, 0x03]);
+        track.extend_from_slice(&TEMPO_US_PER_QUARTER.to_be_bytes()[1..]);
+
+        Self {
+            track,
+            last_tick: 0,
+            running_status: None,
+            data: Vec::new(),
+            in_sysex: false,
+        }
+    }
+
+    /// `w.record(t, bs)` records the bytes `bs` sent via the MIDI serial port during output update `t`
+    ///
+    /// # Requires
+    ///
+    /// * `tick` is not less than the tick of any earlier call
+    pub fn record(&mut self, tick: u32, bytes: &[u8]) {
+        bytes.iter().for_each(|&b| self.record_byte(tick, b));
+    }
+
+    /// `w.to_bytes()` is the complete Standard MIDI File
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut track = self.track.clone();
+        // end of track
+        push_vlq(&mut track, 0);
+        track.extend_from_slice(&[0xFF, 0x2F, 0x00]);
+
+        let mut file = Vec::new();
+        file.extend_from_slice(b"MThd");
+        file.extend_from_slice(&6_u32.to_be_bytes());
+        file.extend_from_slice(&0_u16.to_be_bytes()); // type 0, a single track
+        file.extend_from_slice(&1_u16.to_be_bytes());
+        file.extend_from_slice(&TICKS_PER_QUARTER.to_be_bytes());
+
+        file.extend_from_slice(b"MTrk");
+        file.extend_from_slice(&(track.len() as u32).to_be_bytes());
+        file.extend_from_slice(&track);
+
+        file
+    }
+
+    /// `w.record_byte(t, b)` parses byte `b` of the stream, writing an event when a message is complete
+    fn record_byte(&mut self, tick: u32, byte: u8) {
+        match byte {
+            // realtime messages may appear anywhere, even inside of other messages
+            0xF8..=0xFF => (),
+            0xF0 => {
+                self.running_status = None;
+                self.in_sysex = true;
+                self.data.clear();
+            }
+            0xF7 => {
+                if self.in_sysex {
+                    self.in_sysex = false;
+                    self.write_delta(tick);
+                    self.track.push(0xF0);
+                    push_vlq(&mut self.track, self.data.len() as u32 + 1);
+                    self.track.extend_from_slice(&self.data);
+                    self.track.push(0xF7);
+                }
+                self.data.clear();
+            }
+            // system common messages cancel running status and are not stored
+            0xF1..=0xF6 => {
+                self.running_status = None;
+                self.in_sysex = false;
+                self.data.clear();
+            }
+            0x80..=0xEF => {
+                self.running_status = Some(byte);
+                self.in_sysex = false;
+                self.data.clear();
+            }
+            _ => {
+                self.data.push(byte);
+                if self.in_sysex {
+                    return;
+                }
+                if let Some(status) = self.running_status {
+                    if self.data.len() == num_data_bytes(status) {
+                        self.write_delta(tick);
+                        self.track.push(status);
+                        self.track.extend_from_slice(&self.data);
+                        self.data.clear();
+                    }
+                } else {
+                    // stray data byte with no status to go with it
+                    self.data.clear();
+                }
+            }
+        }
+    }
+
+    /// `w.write_delta(t)` writes the delta time from the last event to tick `t`
+    fn write_delta(&mut self, tick: u32) {
+        push_vlq(&mut self.track, tick.saturating_sub(self.last_tick));
+        self.last_tick = self.last_tick.max(tick);
+    }
+}
+
+impl Default for SmfWriter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// `num_data_bytes(s)` is the number of data bytes which follow channel status byte `s`
+fn num_data_bytes(status: u8) -> usize {
+    match status & 0xF0 {
+        0xC0 | 0xD0 => 1,
+        _ => 2,
+    }
+}
+
+/// `push_vlq(bs, v)` appends value `v` to `bs` as a variable length quantity
+fn push_vlq(bytes: &mut Vec<u8>, val: u32) {
+    let mut groups = [0_u8; 5];
+    let mut n = 0;
+    let mut val = val;
+    loop {
+        groups[n] = (val & 0x7F) as u8;
+        n += 1;
+        val >>= 7;
+        if val == 0 {
+            break;
+        }
+    }
+    for i in (0..n).rev() {
+        let continuation = if i == 0 { 0 } else { 0x80 };
+        bytes.push(groups[i] | continuation);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // every track starts with the tempo and ends with the end of track marker
+    const TEMPO_EVENT: [u8; 7] = [0x00, 0xFF, 0x51, 0x03, 0x07, 0xA1, 0x20];
+    const END_OF_TRACK: [u8; 4] = [0x00, 0xFF, 0x2F, 0x00];
+
+    fn track_events(file: &[u8]) -> &[u8] {
+        &file[22 + TEMPO_EVENT.len()..file.len() - END_OF_TRACK.len()]
+    }
+
+    #[test]
+    fn one_tick_is_one_output_update() {
+        assert_eq!(
+            TEMPO_US_PER_QUARTER as f32 / TICKS_PER_QUARTER as f32,
+            1E6 / OUTPUT_UPDATE_RATE_HZ as f32
+        );
+    }
+
+    #[test]
+    fn empty_file_has_headers_tempo_and_end_of_track() {
+        let file = SmfWriter::new().to_bytes();
+        assert_eq!(&file[..4], b"MThd");
+        assert_eq!(&file[8..14], [0, 0, 0, 1, 0, 150]);
+        assert_eq!(&file[14..18], b"MTrk");
+        assert_eq!(&file[18..22], [0, 0, 0, 11]);
+        assert_eq!(&file[22..29], TEMPO_EVENT);
+        assert_eq!(&file[29..], END_OF_TRACK);
+    }
+
+    #[test]
+    fn delta_times_are_ticks_since_the_last_event() {
+        let mut w = SmfWriter::new();
+        w.record(10, &[0x90, 60, 127]);
+        w.record(10, &[0x80, 60, 0]);
+        w.record(300, &[0xE0, 0, 64]);
+
+        assert_eq!(
+            track_events(&w.to_bytes()),
+            [10, 0x90, 60, 127, 0, 0x80, 60, 0, 0x82, 0x22, 0xE0, 0, 64]
+        );
+    }
+
+    #[test]
+    fn running_status_is_expanded() {
+        let mut w = SmfWriter::new();
+        w.record(0, &[0xB3, 1, 10, 1, 11]);
+        w.record(1, &[12, 13]);
+
+        assert_eq!(
+            track_events(&w.to_bytes()),
+            [0, 0xB3, 1, 10, 0, 0xB3, 1, 11, 1, 0xB3, 12, 13]
+        );
+    }
+
+    #[test]
+    fn sysex_is_length_prefixed_and_realtime_is_dropped() {
+        let mut w = SmfWriter::new();
+        w.record(2, &[0xF0, 0x7D, 0xF8, 0x01, 0xF7, 0xC0, 0xFE, 5]);
+
+        assert_eq!(
+            track_events(&w.to_bytes()),
+            [2, 0xF0, 3, 0x7D, 0x01, 0xF7, 0, 0xC0, 5]
+        );
+    }
+
+    #[test]
+    fn vlq_matches_the_spec_examples() {
+        for (val, expected) in [
+            (0x00, &[0x00][..]),
+            (0x7F, &[0x7F][..]),
+            (0x80, &[0x81, 0x00][..]),
+            (0x2000, &[0xC0, 0x00][..]),
+            (0x1F_FFFF, &[0xFF, 0xFF, 0x7F][..]),
+            (0x0FFF_FFFF, &[0xFF, 0xFF, 0xFF, 0x7F][..]),
+        ] {
+            let mut bytes = Vec::new();
+            push_vlq(&mut bytes, val);
+            assert_eq!(bytes, expected);
+        }
+    }
+}
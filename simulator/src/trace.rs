@@ -0,0 +1,215 @@
+//! # Recorded input traces
+//!
+//! A trace is a CSV file of timestamped board inputs. Each row holds the raw ADC readings of the three analog inputs
+//! and the positions of the panel switches from its timestamp up until the timestamp of the next row, so rows only
+//! need to be written when something changes.
+//!
+//! ```text
+//! time_ms,pa0,pa1,pa2,mode,midi_ch
+//! 0,0,65520,65520,middle,0
+//! 100.5,0,20000,65520,middle,0
+//! ```
+//!
+//! * `time_ms` - the time of the row in milliseconds, may be fractional, must not decrease from row to row
+//!
+//! * `pa0`, `pa1`, `pa2` - the raw 16 bit ADC readings for the glide knob, main ribbon, and MOD ribbon
+//!
+//! * `mode` - the position of the 3-way mode switch, one of `up`, `middle`, or `down`
+//!
+//! * `midi_ch` - the value of the rotary MIDI channel switch in `[0..15]`
+//!
+//! Blank lines and lines starting with `#` are ignored.
+
+use ribbon_core::board::{Switch3wayState, NUM_ADC_PINS};
+
+use std::fmt;
+
+/// A single row of a recorded trace is represented here
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct TraceRow {
+    /// The time of the row in microseconds
+    pub time_us: u64,
+    /// The raw ADC readings, indexed by `AdcPin`
+    pub adc: [u16; NUM_ADC_PINS],
+    /// The position of the mode switch
+    pub mode_switch: Switch3wayState,
+    /// The value of the MIDI channel switch
+    pub midi_ch: u8,
+}
+
+/// An error found while parsing a trace is represented here
+#[derive(Debug, PartialEq)]
+pub struct TraceError {
+    /// The 1-based line number where the error was found
+    pub line: usize,
+    /// A description of the problem
+    pub msg: String,
+}
+
+impl fmt::Display for TraceError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "line {}: {}", self.line, self.msg)
+    }
+}
+
+/// The header which must be the first non-comment line of a trace
+pub const TRACE_HEADER: &str = "time_ms,pa0,pa1,pa2,mode,midi_ch";
+
+/// `parse_trace(t)` is the trace text `t` parsed into rows, or the first error found
+pub fn parse_trace(text: &str) -> Result<Vec<TraceRow>, TraceError> {
+    let mut lines = text
+        .lines()
+        .enumerate()
+        .map(|(i, l)| (i + 1, l.trim()))
+        .filter(|(_, l)| !l.is_empty() && !l.starts_with('#'));
+
+    match lines.next() {
+        Some((_, header)) if header.replace(' ', "") == TRACE_HEADER => (),
+        Some((line, _)) => {
+            return Err(TraceError {
+                line,
+                msg: format!("expected the header `{}`", TRACE_HEADER),
+            })
+        }
+        None => {
+            return Err(TraceError {
+                line: 0,
+                msg: "the trace is empty".into(),
+            })
+        }
+    }
+
+    let mut rows: Vec<TraceRow> = Vec::new();
+    for (line, text) in lines {
+        let row = parse_row(text).map_err(|msg| TraceError { line, msg })?;
+
+        if let Some(last) = rows.last() {
+            if row.time_us < last.time_us {
+                return Err(TraceError {
+                    line,
+                    msg: "time must not decrease".into(),
+                });
+            }
+        }
+        rows.push(row);
+    }
+
+    if rows.is_empty() {
+        return Err(TraceError {
+            line: 0,
+            msg: "the trace has no rows".into(),
+        });
+    }
+
+    Ok(rows)
+}
+
+/// `parse_row(t)` is the single line of text `t` parsed into a trace row
+fn parse_row(text: &str) -> Result<TraceRow, String> {
+    let fields: Vec<&str> = text.split(',').map(str::trim).collect();
+    if fields.len() != 6 {
+        return Err(format!("expected 6 fields but found {}", fields.len()));
+    }
+
+    let time_ms: f64 = fields[0]
+        .parse()
+        .map_err(|_| format!("bad time `{}`", fields[0]))?;
+    if !(0.0..).contains(&time_ms) {
+        return Err(format!("bad time `{}`", fields[0]));
+    }
+
+    let mut adc = [0_u16; NUM_ADC_PINS];
+    for (val, field) in adc.iter_mut().zip(&fields[1..4]) {
+        *val = field
+            .parse()
+            .map_err(|_| format!("bad ADC reading `{}`", field))?;
+    }
+
+    let mode_switch = match fields[4] {
+        "up" => Switch3wayState::Up,
+        "middle" => Switch3wayState::Middle,
+        "down" => Switch3wayState::Down,
+        other => return Err(format!("bad mode switch position `{}`", other)),
+    };
+
+    let midi_ch = match fields[5].parse() {
+        Ok(ch) if ch <= 15 => ch,
+        _ => return Err(format!("bad MIDI channel `{}`", fields[5])),
+    };
+
+    Ok(TraceRow {
+        time_us: (time_ms * 1_000.0).round() as u64,
+        adc,
+        mode_switch,
+        midi_ch,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rows_are_parsed() {
+        let rows = parse_trace(
+            "# a comment\n\
+             time_ms,pa0,pa1,pa2,mode,midi_ch\n\
+             0,1,2,3,up,0\n\
+             \n\
+             12.5, 4, 5, 6, down, 15\n",
+        )
+        .unwrap();
+
+        assert_eq!(
+            rows,
+            [
+                TraceRow {
+                    time_us: 0,
+                    adc: [1, 2, 3],
+                    mode_switch: Switch3wayState::Up,
+                    midi_ch: 0,
+                },
+                TraceRow {
+                    time_us: 12_500,
+                    adc: [4, 5, 6],
+                    mode_switch: Switch3wayState::Down,
+                    midi_ch: 15,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn missing_header_is_an_error() {
+        assert_eq!(parse_trace("0,1,2,3,up,0").unwrap_err().line, 1);
+    }
+
+    #[test]
+    fn bad_fields_report_their_line() {
+        let header = TRACE_HEADER.to_string() + "\n";
+        assert_eq!(
+            parse_trace(&(header.clone() + "0,1,2,3,sideways,0"))
+                .unwrap_err()
+                .line,
+            2
+        );
+        assert_eq!(
+            parse_trace(&(header.clone() + "0,1,2,3,up,16"))
+                .unwrap_err()
+                .line,
+            2
+        );
+        assert_eq!(
+            parse_trace(&(header.clone() + "0,1,2,70000,up,0"))
+                .unwrap_err()
+                .line,
+            2
+        );
+        assert_eq!(
+            parse_trace(&(header + "5,1,2,3,up,0\n4,1,2,3,up,0"))
+                .unwrap_err()
+                .line,
+            3
+        );
+    }
+}
@@ -0,0 +1,287 @@
+//! # Ribbon controller SysEx configuration, host side
+//!
+//! Reads and writes the settings of a ribbon controller from a computer, with the SysEx configuration protocol described
+//! in `ribbon_core::sysex_config`. The messages themselves are built and read by `ribbon_core`, so the host and the
+//! firmware can't disagree about them, and this crate adds the host's half of the conversation: send a request, wait
+//! for the answer, and check that it answers the request.
+//!
+//! The messages travel over a `Transport`, e.g. a `MidiPort`, or the `Loopback` which runs the real application on a
+//! mock board for tests. Settings can be kept in preset files, see `preset`.
+
+pub mod loopback;
+pub mod midi_port;
+pub mod preset;
+
+pub use loopback::Loopback;
+pub use midi_port::MidiPort;
+pub use ribbon_core::{
+    settings::Settings,
+    sysex_config::{Command, ConfigError, Param, Reply, Value, ALL_DEVICES, NUM_PARAMS},
+};
+
+use ribbon_core::sysex_config;
+
+use std::{fmt, io};
+
+/// A way of sending SysEx messages to ribbon controllers and receiving their answers is represented here
+pub trait Transport {
+    /// `t.send(bs)` sends the whole SysEx message `bs`
+    fn send(&mut self, sysex: &[u8]) -> io::Result<()>;
+
+    /// `t.receive()` is the next whole SysEx message received, or `None` if nothing arrives for a while
+    fn receive(&mut self) -> io::Result<Option<Vec<u8>>>;
+}
+
+/// An error while configuring a ribbon controller is represented here
+#[derive(Debug)]
+pub enum Error {
+    /// The transport failed
+    Io(io::Error),
+    /// The ribbon controller can't carry out the request
+    Refused(ConfigError),
+    /// The answer was damaged on the way or is not understood
+    BadAnswer(ConfigError),
+    /// The answer is not an answer to the request
+    WrongAnswer(Box<Reply>),
+    /// Nothing answered, the device may be wrong or the ribbon controller may be calibrating
+    NoAnswer,
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Io(err) => write!(f, "{}", err),
+            Error::Refused(err) => {
+                write!(f, "the ribbon controller refused the request: {:?}", err)
+            }
+            Error::BadAnswer(err) => write!(f, "the answer can't be read: {:?}", err),
+            Error::WrongAnswer(reply) => {
+                write!(f, "the answer doesn't match the request: {:?}", reply)
+            }
+            Error::NoAnswer => write!(f, "no answer from the ribbon controller"),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl From<io::Error> for Error {
+    fn from(err: io::Error) -> Self {
+        Error::Io(err)
+    }
+}
+
+/// A connection to one ribbon controller is represented here
+pub struct Client<T> {
+    transport: T,
+    device: u8,
+}
+
+impl<T: Transport> Client<T> {
+    /// `Client::new(t, d)` is a connection to device `d` over transport `t`
+    ///
+    /// The device is the position of the controller's `MIDI CH` switch, 0 for channel 1 up to 15 for channel 16. With
+    /// `ALL_DEVICES` the first answer is taken, so it is only useful with one ribbon controller in the MIDI chain.
+    pub fn new(transport: T, device: u8) -> Self {
+        Self { transport, device }
+    }
+
+    /// `c.get(p)` is the value of parameter `p`
+    pub fn get(&mut self, param: Param) -> Result<Value, Error> {
+        self.request(&Command::Get(param))?;
+        self.value_of(param)
+    }
+
+    /// `c.set(p, v)` sets parameter `p` to value `v`, and is the value it was set to, which may have been clamped
+    pub fn set(&mut self, param: Param, value: &[u8]) -> Result<Value, Error> {
+        let value = Value::from_slice(value).map_err(|_| Error::Refused(ConfigError::BadValue))?;
+        self.request(&Command::Set(param, value))?;
+        self.value_of(param)
+    }
+
+    /// `c.dump()` is every setting
+    pub fn dump(&mut self) -> Result<Settings, Error> {
+        self.request(&Command::Dump)?;
+        let mut settings = Settings::new();
+        for param in Param::ALL {
+            let value = self.value_of(param)?;
+            settings =
+                sysex_config::with_value(&settings, param, &value).map_err(Error::BadAnswer)?;
+        }
+        Ok(settings)
+    }
+
+    /// `c.write(s)` sets every setting to settings `s`, and is the settings as they were set, which may have been
+    /// clamped
+    pub fn write(&mut self, settings: &Settings) -> Result<Settings, Error> {
+        self.write_params(settings, &Param::ALL)
+    }
+
+    /// `c.write_params(s, ps)` sets each of parameters `ps` to its value in settings `s`, and is settings `s` with those
+    /// parameters as they were set, which may have been clamped
+    pub fn write_params(
+        &mut self,
+        settings: &Settings,
+        params: &[Param],
+    ) -> Result<Settings, Error> {
+        let mut written = *settings;
+        for &param in params {
+            let value = self.set(param, &sysex_config::value_of(settings, param))?;
+            written =
+                sysex_config::with_value(&written, param, &value).map_err(Error::BadAnswer)?;
+        }
+        Ok(written)
+    }
+
+    /// `c.reset()` sets every setting apart from the calibrations back to its default
+    pub fn reset(&mut self) -> Result<(), Error> {
+        self.request(&Command::Reset)?;
+        match self.answer()? {
+            Reply::ResetDone => Ok(()),
+            reply => Err(Error::WrongAnswer(Box::new(reply))),
+        }
+    }
+
+    /// `c.into_transport()` is the transport of connection `c`
+    pub fn into_transport(self) -> T {
+        self.transport
+    }
+
+    /// `c.request(r)` sends request `r`
+    fn request(&mut self, command: &Command) -> Result<(), Error> {
+        self.transport.send(&command.sysex(self.device))?;
+        Ok(())
+    }
+
+    /// `c.value_of(p)` is the value of parameter `p` in the next answer
+    fn value_of(&mut self, param: Param) -> Result<Value, Error> {
+        match self.answer()? {
+            Reply::Value(p, value) if p == param => Ok(value),
+            reply => Err(Error::WrongAnswer(Box::new(reply))),
+        }
+    }
+
+    /// `c.answer()` is the next answer from the device, skipping any other messages
+    fn answer(&mut self) -> Result<Reply, Error> {
+        loop {
+            let sysex = self.transport.receive()?.ok_or(Error::NoAnswer)?;
+            match Reply::parse(&sysex) {
+                Some((from, reply)) if sysex_config::addresses(self.device, from) => {
+                    return match reply {
+                        Ok(Reply::Error(err)) => Err(Error::Refused(err)),
+                        Ok(reply) => Ok(reply),
+                        Err(err) => Err(Error::BadAnswer(err)),
+                    };
+                }
+                // e.g. forwarded from further up the MIDI chain
+                _ => continue,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ribbon_core::{
+        dac_calibration::ChannelCalibration, scale::Scale, transpose::Transpose,
+        tuning::TuningTable,
+    };
+
+    const DEVICE: u8 = 5;
+
+    fn client() -> Client<Loopback> {
+        Client::new(Loopback::new(DEVICE), DEVICE)
+    }
+
+    #[test]
+    fn values_which_are_set_can_be_got_again() {
+        let mut client = client();
+
+        assert_eq!(client.set(Param::ScaleRoot, &[4]).unwrap(), [4]);
+        assert_eq!(client.get(Param::ScaleRoot).unwrap(), [4]);
+        assert!(matches!(
+            client.set(Param::Span, &[9]),
+            Err(Error::Refused(ConfigError::BadValue))
+        ));
+    }
+
+    #[test]
+    fn written_settings_are_dumped_and_saved() {
+        let mut client = client();
+        let mut settings = client.dump().unwrap();
+        assert_eq!(settings, Settings::new());
+
+        settings.pitch_bend_range = 7;
+        settings.mts = true;
+        settings.scale_root = 9;
+        assert_eq!(client.write(&settings).unwrap(), settings);
+        assert_eq!(client.dump().unwrap(), settings);
+
+        let mut loopback = client.into_transport();
+        assert_eq!(loopback.saved_settings(), Some(settings));
+    }
+
+    #[test]
+    fn reset_goes_back_to_the_defaults() {
+        let mut client = client();
+        client.set(Param::Mts, &[1]).unwrap();
+        client.reset().unwrap();
+        assert_eq!(client.dump().unwrap(), Settings::new());
+    }
+
+    #[test]
+    fn presets_can_be_copied_between_ribbon_controllers() {
+        let mut first = client();
+        let played = Settings {
+            scale: Scale::Dorian,
+            transpose: Transpose::new(-2, 1),
+            tuning: Some(TuningTable::new(12.0, 60.5, &[0.0, 2.0, 4.0, 7.0, 9.0]).unwrap()),
+            ..Settings::new()
+        };
+        first.write(&played).unwrap();
+        let text = preset::to_toml(&first.dump().unwrap());
+
+        // without the calibrations, so the second ribbon controller keeps its own
+        let text: String = text
+            .lines()
+            .filter(|line| !line.contains("calibration") && !line.contains("ribbon-map"))
+            .map(|line| format!("{}\n", line))
+            .collect();
+
+        let mut second = client();
+        let mut calibrated = Settings::new();
+        calibrated.dac_calibration.a = ChannelCalibration::from_points(1.01, 4.02);
+        second.write(&calibrated).unwrap();
+
+        let (settings, params) = preset::from_toml(&text, &Settings::new()).unwrap();
+        assert_eq!(params.len(), NUM_PARAMS - 4);
+        second.write_params(&settings, &params).unwrap();
+        assert_eq!(
+            second.dump().unwrap(),
+            Settings {
+                dac_calibration: calibrated.dac_calibration,
+                ..played
+            }
+        );
+    }
+
+    #[test]
+    fn a_loopback_remembers_its_settings_in_its_flash() {
+        let mut client = client();
+        client.set(Param::PitchBendRange, &[24]).unwrap();
+        let flash = client.into_transport().flash().to_vec();
+
+        let mut client = Client::new(Loopback::from_flash(DEVICE, &flash), DEVICE);
+        assert_eq!(client.get(Param::PitchBendRange).unwrap(), [24]);
+    }
+
+    #[test]
+    fn other_devices_do_not_answer() {
+        let mut client = Client::new(Loopback::new(DEVICE), DEVICE + 1);
+        assert!(matches!(client.get(Param::Mts), Err(Error::NoAnswer)));
+
+        let mut anyone = Client::new(client.into_transport(), ALL_DEVICES);
+        assert_eq!(anyone.get(Param::Mts).unwrap(), [0]);
+    }
+}
@@ -0,0 +1,112 @@
+//! # Loopback
+//!
+//! A stand-in for a ribbon controller on the other end of a MIDI cable, running the real application on a mock board.
+//! Whatever is sent to it arrives on the mock board's MIDI input, and the SysEx messages the application sends back are
+//! received. Time only passes while waiting for an answer.
+
+use crate::Transport;
+
+use ribbon_core::{
+    app::App,
+    midi_parser::{MidiParser, Received},
+    mock_board::MockBoard,
+    settings::{self, Settings},
+    OUTPUT_UPDATE_RATE_HZ, UI_UPDATE_RATE_HZ,
+};
+
+use std::{collections::VecDeque, io};
+
+/// A ribbon controller running on a mock board is represented here
+pub struct Loopback {
+    app: App,
+    board: MockBoard,
+    // reads the MIDI output of the board
+    parser: MidiParser,
+    // SysEx messages sent by the application which have not been received yet
+    received: VecDeque<Vec<u8>>,
+    // the number of output updates so far, the UI is updated every few of them
+    num_updates: u32,
+}
+
+impl Loopback {
+    /// `Loopback::new(d)` is a freshly powered up ribbon controller with its `MIDI CH` switch set to device `d`, and
+    /// nothing saved in flash
+    pub fn new(device: u8) -> Self {
+        Self::from_flash(device, &[])
+    }
+
+    /// `Loopback::from_flash(d, bs)` is a freshly powered up ribbon controller with its `MIDI CH` switch set to device
+    /// `d`, and the bytes `bs` at the start of its settings flash pages, e.g. as kept by `flash` on an earlier run
+    pub fn from_flash(device: u8, flash: &[u8]) -> Self {
+        let mut board = MockBoard::new();
+        board.midi_ch_switch = device;
+        let page = board.flash.bytes_mut();
+        let len = flash.len().min(page.len());
+        page[..len].copy_from_slice(&flash[..len]);
+        let mut app = App::new();
+        app.init(&mut board);
+
+        Self {
+            app,
+            board,
+            parser: MidiParser::new(),
+            received: VecDeque::new(),
+            num_updates: 0,
+        }
+    }
+
+    /// `lb.saved_settings()` is the settings saved in the flash of ribbon controller `lb`, if there are any
+    pub fn saved_settings(&mut self) -> Option<Settings> {
+        settings::load(&mut self.board)
+    }
+
+    /// `lb.flash()` is every settings flash page of ribbon controller `lb`, once it has had time to save any changed
+    /// settings
+    pub fn flash(&mut self) -> &[u8] {
+        // changed settings are saved by the next UI update
+        for _ in 0..UPDATES_PER_UI_UPDATE {
+            self.update();
+        }
+        self.board.flash.bytes_mut()
+    }
+
+    /// `lb.update()` runs one output update of the application, and the UI update if one is due
+    fn update(&mut self) {
+        if self.num_updates.is_multiple_of(UPDATES_PER_UI_UPDATE) {
+            self.board.expire_tim6();
+        }
+        self.board.expire_tim15();
+        self.app.service(&mut self.board);
+        self.num_updates = self.num_updates.wrapping_add(1);
+
+        for &byte in &self.board.serial_bytes {
+            if let Some(Received::SysEx(sysex)) = self.parser.parse(byte) {
+                self.received.push_back(sysex.to_vec());
+            }
+        }
+        self.board.clear_outputs();
+    }
+}
+
+impl Transport for Loopback {
+    fn send(&mut self, sysex: &[u8]) -> io::Result<()> {
+        self.board.serial_rx.extend(sysex);
+        Ok(())
+    }
+
+    fn receive(&mut self) -> io::Result<Option<Vec<u8>>> {
+        for _ in 0..MAX_UPDATES_PER_RECEIVE {
+            if let Some(sysex) = self.received.pop_front() {
+                return Ok(Some(sysex));
+            }
+            self.update();
+        }
+        Ok(self.received.pop_front())
+    }
+}
+
+/// The number of output updates for each UI update
+const UPDATES_PER_UI_UPDATE: u32 = OUTPUT_UPDATE_RATE_HZ / UI_UPDATE_RATE_HZ;
+
+/// The longest time to wait for a message in output updates, one second
+const MAX_UPDATES_PER_RECEIVE: u32 = OUTPUT_UPDATE_RATE_HZ;
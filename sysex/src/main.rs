@@ -0,0 +1,198 @@
+//! # Ribbon controller configuration
+//!
+//! Reads and changes the settings of a ribbon controller over MIDI, and keeps them in preset files.
+//!
+//! ```text
+//! ribbon-sysex <port> <channel> get <setting>
+//! ribbon-sysex <port> <channel> set <setting> <value>
+//! ribbon-sysex <port> <channel> dump [preset.toml]
+//! ribbon-sysex <port> <channel> load <preset.toml>
+//! ribbon-sysex <port> <channel> reset
+//! ```
+//!
+//! The port is a MIDI device such as `/dev/snd/midiC1D0`, or `loopback` for a stand-in ribbon controller which runs
+//! the application on a mock board. With `loopback:<flash.bin>` the stand-in keeps its settings flash in a file, so it
+//! remembers its settings from one run to the next.
+//!
+//! The channel is the position of the ribbon controller's `MIDI CH` switch, 1 to 16, or `all` for whichever ribbon
+//! controller answers first.
+//!
+//! Settings and their values are named as in preset files, see `ribbon_sysex::preset`, and the tuning can also be set to
+//! a `.bin` tuning table written by `ribbon-scala`. `get` and `set` print the setting as it is on the ribbon controller,
+//! `dump` prints every setting unless a preset file is given, and `load` only changes the settings in the preset file.
+
+use ribbon_sysex::{
+    preset::{self, Item},
+    Client, Loopback, MidiPort, Param, Settings, Transport, ALL_DEVICES,
+};
+
+use ribbon_core::{sysex_config, tuning::TuningTable};
+
+use std::{env, fmt, fs, io, process};
+
+fn main() {
+    let args: Vec<String> = env::args().collect();
+    let usage = || -> ! {
+        eprintln!(
+            "usage: {} <port> <channel> get <setting> | set <setting> <value> | dump [preset.toml] | load <preset.toml> | reset",
+            args[0]
+        );
+        process::exit(2);
+    };
+    if args.len() < 4 {
+        usage();
+    }
+
+    let device = match args[2].as_str() {
+        "all" => ALL_DEVICES,
+        channel => match channel.parse::<u8>() {
+            Ok(channel @ 1..=16) => channel - 1,
+            _ => usage(),
+        },
+    };
+    let param =
+        |name: &String| preset::param_named(name).unwrap_or_else(|| fail(name, "unknown setting"));
+
+    let port_name = &args[1];
+    let port = Port::open(port_name, device).unwrap_or_else(|e| fail(port_name, e));
+    let mut client = Client::new(port, device);
+
+    match (args[3].as_str(), &args[4..]) {
+        ("get", [name]) => {
+            let param = param(name);
+            let value = client.get(param).unwrap_or_else(|e| fail(port_name, e));
+            print_setting(param, &value);
+        }
+        ("set", [name, value]) => {
+            let param = param(name);
+            let item = match param {
+                // a tuning can be given as the table written by `ribbon-scala`
+                Param::Tuning if value.ends_with(".bin") => tuning_item(value),
+                // a bare word is taken as a string, so names don't need quoting in the shell
+                _ => preset::parse_item(value).unwrap_or_else(|_| Item::Str(value.clone())),
+            };
+            let settings =
+                preset::with_item(&Settings::new(), param, &item).unwrap_or_else(|e| fail(name, e));
+            let value = client
+                .set(param, &sysex_config::value_of(&settings, param))
+                .unwrap_or_else(|e| fail(port_name, e));
+            print_setting(param, &value);
+        }
+        ("dump", []) => {
+            let settings = client.dump().unwrap_or_else(|e| fail(port_name, e));
+            print!("{}", preset::to_toml(&settings));
+        }
+        ("dump", [path]) => {
+            let settings = client.dump().unwrap_or_else(|e| fail(port_name, e));
+            fs::write(path, preset::to_toml(&settings)).unwrap_or_else(|e| fail(path, e));
+        }
+        ("load", [path]) => {
+            let text = fs::read_to_string(path).unwrap_or_else(|e| fail(path, e));
+            let (settings, params) =
+                preset::from_toml(&text, &Settings::new()).unwrap_or_else(|e| fail(path, e));
+            let written = client
+                .write_params(&settings, &params)
+                .unwrap_or_else(|e| fail(port_name, e));
+            for param in params {
+                let item = preset::item_of(&written, param);
+                if item != preset::item_of(&settings, param) {
+                    eprintln!(
+                        "{}: {} was set to {}",
+                        path,
+                        preset::param_name(param),
+                        item
+                    );
+                }
+            }
+        }
+        ("reset", []) => client.reset().unwrap_or_else(|e| fail(port_name, e)),
+        _ => usage(),
+    }
+
+    client
+        .into_transport()
+        .close()
+        .unwrap_or_else(|e| fail(port_name, e));
+}
+
+/// `print_setting(p, v)` prints parameter `p` with value `v` the way it is written in preset files
+fn print_setting(param: Param, value: &[u8]) {
+    let settings = sysex_config::with_value(&Settings::new(), param, value)
+        .unwrap_or_else(|e| fail(preset::param_name(param), format!("{:?}", e)));
+    println!(
+        "{} = {}",
+        preset::param_name(param),
+        preset::item_of(&settings, param)
+    );
+}
+
+/// `tuning_item(p)` is the tuning table in the file at path `p` as a preset value
+fn tuning_item(path: &str) -> Item {
+    let bytes = fs::read(path).unwrap_or_else(|e| fail(path, e));
+    let tuning = TuningTable::from_bytes(&bytes).unwrap_or_else(|e| fail(path, format!("{:?}", e)));
+    let settings = Settings {
+        tuning: Some(tuning),
+        ..Settings::new()
+    };
+    preset::item_of(&settings, Param::Tuning)
+}
+
+/// `fail(w, e)` reports error `e` with what it happened to `w` and exits
+fn fail<E: fmt::Display>(what: &str, err: E) -> ! {
+    eprintln!("{}: {}", what, err);
+    process::exit(1);
+}
+
+/// A port a ribbon controller can be reached through is represented here
+enum Port {
+    Midi(Box<MidiPort>),
+    /// A stand-in, and the file its flash is kept in if there is one
+    Loopback(Box<Loopback>, Option<String>),
+}
+
+impl Port {
+    /// `Port::open(n, d)` is the port named `n`, with a stand-in answering as device `d` if it is a loopback
+    fn open(name: &str, device: u8) -> io::Result<Self> {
+        // the stand-in has to answer as some device
+        let device = if device == ALL_DEVICES { 0 } else { device };
+        match name.strip_prefix("loopback") {
+            Some("") => Ok(Port::Loopback(Box::new(Loopback::new(device)), None)),
+            Some(path) if path.starts_with(':') => {
+                let path = &path[1..];
+                let flash = match fs::read(path) {
+                    Err(e) if e.kind() == io::ErrorKind::NotFound => Vec::new(),
+                    read => read?,
+                };
+                Ok(Port::Loopback(
+                    Box::new(Loopback::from_flash(device, &flash)),
+                    Some(path.to_string()),
+                ))
+            }
+            _ => MidiPort::open(name).map(|port| Port::Midi(Box::new(port))),
+        }
+    }
+
+    /// `p.close()` closes port `p`, keeping the flash of a stand-in if it is kept in a file
+    fn close(self) -> io::Result<()> {
+        match self {
+            Port::Loopback(mut loopback, Some(path)) => fs::write(path, loopback.flash()),
+            _ => Ok(()),
+        }
+    }
+}
+
+impl Transport for Port {
+    fn send(&mut self, sysex: &[u8]) -> io::Result<()> {
+        match self {
+            Port::Midi(port) => port.send(sysex),
+            Port::Loopback(loopback, _) => loopback.send(sysex),
+        }
+    }
+
+    fn receive(&mut self) -> io::Result<Option<Vec<u8>>> {
+        match self {
+            Port::Midi(port) => port.receive(),
+            Port::Loopback(loopback, _) => loopback.receive(),
+        }
+    }
+}
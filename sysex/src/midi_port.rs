@@ -0,0 +1,103 @@
+//! # MIDI port
+//!
+//! Talks to a ribbon controller through a device file which carries MIDI bytes as they are, such as an ALSA raw MIDI
+//! device like `/dev/snd/midiC1D0` on Linux, or a serial port which has already been set up for MIDI.
+//!
+//! Reads block, so they happen on a thread of their own which hands the bytes over as they arrive. That way waiting for
+//! an answer can give up after a while, even if nothing is received at all.
+
+use crate::Transport;
+
+use ribbon_core::midi_parser::{MidiParser, Received};
+
+use std::{
+    collections::VecDeque,
+    fs::{File, OpenOptions},
+    io::{self, Read, Write},
+    path::Path,
+    sync::mpsc::{self, Receiver, RecvTimeoutError},
+    thread,
+    time::{Duration, Instant},
+};
+
+/// An open MIDI device is represented here
+pub struct MidiPort {
+    output: File,
+    // chunks of bytes read from the device by the reading thread
+    input: Receiver<io::Result<Vec<u8>>>,
+    parser: MidiParser,
+    // SysEx messages received which have not been handed out yet
+    received: VecDeque<Vec<u8>>,
+}
+
+impl MidiPort {
+    /// `MidiPort::open(p)` is the MIDI device at path `p`, opened for reading and writing
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        let output = OpenOptions::new().write(true).open(&path)?;
+        let mut device = File::open(&path)?;
+
+        let (sender, input) = mpsc::channel();
+        thread::spawn(move || {
+            let mut chunk = [0; CHUNK_LEN];
+            loop {
+                let read = device.read(&mut chunk).and_then(|len| match len {
+                    0 => Err(io::Error::new(
+                        io::ErrorKind::UnexpectedEof,
+                        "the MIDI device was closed",
+                    )),
+                    len => Ok(chunk[..len].to_vec()),
+                });
+                let failed = read.is_err();
+                // stop once nobody is listening any more, or the device can't be read
+                if sender.send(read).is_err() || failed {
+                    return;
+                }
+            }
+        });
+
+        Ok(Self {
+            output,
+            input,
+            parser: MidiParser::new(),
+            received: VecDeque::new(),
+        })
+    }
+}
+
+impl Transport for MidiPort {
+    fn send(&mut self, sysex: &[u8]) -> io::Result<()> {
+        self.output.write_all(sysex)?;
+        self.output.flush()
+    }
+
+    fn receive(&mut self) -> io::Result<Option<Vec<u8>>> {
+        // other MIDI traffic, e.g. the ribbon being played, doesn't count as an answer
+        let deadline = Instant::now() + ANSWER_TIMEOUT;
+        loop {
+            if let Some(sysex) = self.received.pop_front() {
+                return Ok(Some(sysex));
+            }
+
+            let wait = deadline.saturating_duration_since(Instant::now());
+            let chunk = match self.input.recv_timeout(wait) {
+                Ok(chunk) => chunk?,
+                Err(RecvTimeoutError::Timeout) => return Ok(None),
+                Err(RecvTimeoutError::Disconnected) => return Err(io::ErrorKind::BrokenPipe.into()),
+            };
+            for &byte in &chunk {
+                if let Some(Received::SysEx(sysex)) = self.parser.parse(byte) {
+                    self.received.push_back(sysex.to_vec());
+                }
+            }
+        }
+    }
+}
+
+/// The number of bytes read from the device at a time
+const CHUNK_LEN: usize = 256;
+
+/// The longest time to wait for a SysEx message to be received
+///
+/// A dump is answered one parameter at a time while the MIDI output is idle, so a busy ribbon controller can take a
+/// moment between answers.
+const ANSWER_TIMEOUT: Duration = Duration::from_secs(1);
@@ -0,0 +1,507 @@
+//! # Preset files
+//!
+//! A preset file holds settings of a ribbon controller in a small subset of TOML, one `name = value` line per parameter.
+//! Parameters which are left out are not changed when the preset is loaded, so a preset can hold as few settings as
+//! needed, e.g. leave out the calibrations to keep each ribbon controller's own.
+//!
+//! ```text
+//! # a ribbon controller preset
+//! midi-mode = "mpe"
+//! transition-policy = "overlapping-legato"
+//! velocity-curve = "soft"             # or a whole number for a fixed velocity
+//! pitch-bend-range = 48
+//! scale = "dorian"                    # or the notes of a user scale, e.g. [0, 3, 5, 7, 10]
+//! scale-root = 2                      # 0 for C up to 11 for B
+//! transpose = [-5, 1]                 # semitones and octaves
+//! span = 24                           # semitones
+//! mts = false
+//! dac-calibration-a = [1.002, -0.004] # gain and offset
+//! dac-calibration-b = [0.998, 0.003]
+//! main-ribbon-map = [0.02, 0.26, 0.5, 0.73, 0.98]
+//! mod-ribbon-map = [0.01, 0.5, 0.99]
+//! tuning = [12.0, 60.0, 0.0, 3.5, 7.0] # period and root pitch then the degrees, see `TuningTable`, or "none"
+//! ```
+//!
+//! Values are TOML strings, whole numbers, decimal numbers, booleans, or arrays of them on one line. Comments start with
+//! `#`. Tables and the rest of TOML are not needed, so they are not understood.
+
+use ribbon_core::{
+    dac_calibration::ChannelCalibration,
+    midi_generator::{MidiMode, TransitionPolicy},
+    pitch_engine::RibbonSpan,
+    ribbon_calibration::RibbonMap,
+    scale::Scale,
+    settings::Settings,
+    sysex_config::{self, Param},
+    transpose::Transpose,
+    tuning::TuningTable,
+    velocity::VelocityCurve,
+};
+
+use std::{convert::TryFrom, fmt};
+
+/// An error found while reading a preset is represented here
+#[derive(Debug, PartialEq)]
+pub struct ParseError {
+    /// The 1-based line number where the error was found, 0 if the error is not on any one line
+    pub line: usize,
+    /// A description of the problem
+    pub msg: String,
+}
+
+impl ParseError {
+    /// `ParseError::new(l, m)` is an error with message `m` found on line `l`
+    pub fn new<S: Into<String>>(line: usize, msg: S) -> Self {
+        Self {
+            line,
+            msg: msg.into(),
+        }
+    }
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "line {}: {}", self.line, self.msg)
+    }
+}
+
+/// A value in a preset file is represented here
+#[derive(Clone, Debug, PartialEq)]
+pub enum Item {
+    Bool(bool),
+    Int(i64),
+    Float(f32),
+    Str(String),
+    Array(Vec<Item>),
+}
+
+impl fmt::Display for Item {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Item::Bool(b) => write!(f, "{}", b),
+            Item::Int(i) => write!(f, "{}", i),
+            // the shortest form which reads back as the same number, always with a `.` or an exponent
+            Item::Float(x) => write!(f, "{:?}", x),
+            Item::Str(s) => write!(f, "\"{}\"", s),
+            Item::Array(items) => {
+                let items: Vec<String> = items.iter().map(|i| i.to_string()).collect();
+                write!(f, "[{}]", items.join(", "))
+            }
+        }
+    }
+}
+
+/// `parse_item(t)` is the value written as text `t`
+pub fn parse_item(text: &str) -> Result<Item, String> {
+    let text = text.trim();
+    if let Some(inner) = text.strip_prefix('[').and_then(|t| t.strip_suffix(']')) {
+        if inner.trim().is_empty() {
+            return Ok(Item::Array(Vec::new()));
+        }
+        // nested arrays and strings with commas are not needed
+        return inner
+            .split(',')
+            .map(parse_item)
+            .collect::<Result<_, _>>()
+            .map(Item::Array);
+    }
+    if let Some(inner) = text.strip_prefix('"').and_then(|t| t.strip_suffix('"')) {
+        if inner.contains(['"', '\\']) {
+            return Err(format!("escapes are not understood: {}", text));
+        }
+        return Ok(Item::Str(inner.to_string()));
+    }
+    match text {
+        "true" => Ok(Item::Bool(true)),
+        "false" => Ok(Item::Bool(false)),
+        _ if text.contains(['.', 'e', 'E']) => text
+            .parse()
+            .map(Item::Float)
+            .map_err(|_| format!("not a number: {}", text)),
+        _ => text
+            .parse()
+            .map(Item::Int)
+            .map_err(|_| format!("not a value: {}", text)),
+    }
+}
+
+/// `param_name(p)` is the name of parameter `p` in preset files
+pub fn param_name(param: Param) -> &'static str {
+    PARAM_NAMES[param as usize]
+}
+
+/// `param_named(n)` is the parameter with name `n` in preset files, if there is one
+pub fn param_named(name: &str) -> Option<Param> {
+    PARAM_NAMES
+        .iter()
+        .position(|&n| n == name)
+        .map(|i| Param::ALL[i])
+}
+
+/// `item_of(s, p)` is the value of parameter `p` in settings `s` as a preset value
+pub fn item_of(settings: &Settings, param: Param) -> Item {
+    let floats = |xs: &[f32]| Item::Array(xs.iter().map(|&x| Item::Float(x)).collect());
+    let calibration = |c: ChannelCalibration| floats(&[c.gain, c.offset]);
+
+    match param {
+        Param::MidiMode => Item::Str(name_of(&MIDI_MODES, settings.midi_mode).into()),
+        Param::TransitionPolicy => {
+            Item::Str(name_of(&TRANSITION_POLICIES, settings.transition_policy).into())
+        }
+        Param::VelocityCurve => match settings.velocity_curve {
+            VelocityCurve::Fixed(vel) => Item::Int(vel as i64),
+            curve => Item::Str(name_of(&VELOCITY_CURVES, curve).into()),
+        },
+        Param::PitchBendRange => Item::Int(settings.pitch_bend_range as i64),
+        Param::Scale => match settings.scale {
+            Scale::User(intervals) => Item::Array(
+                (0..12)
+                    .filter(|n| intervals & (1 << n) != 0)
+                    .map(Item::Int)
+                    .collect(),
+            ),
+            named => Item::Str(name_of(&SCALES, named).into()),
+        },
+        Param::ScaleRoot => Item::Int(settings.scale_root as i64),
+        Param::Transpose => Item::Array(vec![
+            Item::Int(settings.transpose.semitones() as i64),
+            Item::Int(settings.transpose.octaves() as i64),
+        ]),
+        Param::Span => Item::Int(settings.span.semitones() as i64),
+        Param::Mts => Item::Bool(settings.mts),
+        Param::DacCalibrationA => calibration(settings.dac_calibration.a),
+        Param::DacCalibrationB => calibration(settings.dac_calibration.b),
+        Param::MainRibbonMap => floats(settings.ribbon_calibration.main_ribbon.readings()),
+        Param::ModRibbonMap => floats(settings.ribbon_calibration.mod_ribbon.readings()),
+        Param::Tuning => match &settings.tuning {
+            Some(tuning) => floats(
+                &[tuning.period(), tuning.root_pitch()]
+                    .iter()
+                    .chain(tuning.degrees())
+                    .copied()
+                    .collect::<Vec<f32>>(),
+            ),
+            None => Item::Str(NO_TUNING.into()),
+        },
+    }
+}
+
+/// `with_item(s, p, i)` is settings `s` with parameter `p` set to preset value `i`, or why it can't be
+///
+/// Values which the ribbon controller would not accept are not accepted here either, see `sysex_config::with_value`.
+pub fn with_item(settings: &Settings, param: Param, item: &Item) -> Result<Settings, String> {
+    let bad = || format!("{} can't be {}", param_name(param), item);
+    let int = |item: &Item| match item {
+        Item::Int(i) => Some(*i),
+        _ => None,
+    };
+    let byte = |item: &Item| int(item).and_then(|i| u8::try_from(i).ok());
+    let signed = |item: &Item| int(item).and_then(|i| i8::try_from(i).ok());
+    let float = |item: &Item| match item {
+        Item::Float(x) => Some(*x),
+        Item::Int(i) => Some(*i as f32),
+        _ => None,
+    };
+    let floats = |item: &Item| match item {
+        Item::Array(items) => items.iter().map(float).collect::<Option<Vec<f32>>>(),
+        _ => None,
+    };
+    let named = |item: &Item| match item {
+        Item::Str(s) => Some(s.clone()),
+        _ => None,
+    };
+    let calibration = |item: &Item| match floats(item).as_deref() {
+        Some(&[gain, offset]) => Some(ChannelCalibration { gain, offset }),
+        _ => None,
+    };
+    let map = |item: &Item| floats(item).and_then(|readings| RibbonMap::from_readings(&readings));
+
+    let mut changed = *settings;
+    let ok = match param {
+        Param::MidiMode => named(item)
+            .and_then(|n| choice_named(&MIDI_MODES, &n))
+            .map(|m| changed.midi_mode = m),
+        Param::TransitionPolicy => named(item)
+            .and_then(|n| choice_named(&TRANSITION_POLICIES, &n))
+            .map(|p| changed.transition_policy = p),
+        Param::VelocityCurve => match item {
+            Item::Int(_) => byte(item).map(VelocityCurve::Fixed),
+            _ => named(item).and_then(|n| choice_named(&VELOCITY_CURVES, &n)),
+        }
+        .map(|c| changed.velocity_curve = c),
+        Param::PitchBendRange => byte(item).map(|r| changed.pitch_bend_range = r),
+        Param::Scale => match item {
+            Item::Array(notes) => notes
+                .iter()
+                .map(|n| byte(n).filter(|&n| n < 12))
+                .try_fold(0_u16, |intervals, n| n.map(|n| intervals | 1 << n))
+                .map(Scale::User),
+            _ => named(item).and_then(|n| choice_named(&SCALES, &n)),
+        }
+        .map(|s| changed.scale = s),
+        Param::ScaleRoot => byte(item).map(|r| changed.scale_root = r),
+        Param::Transpose => match item {
+            Item::Array(parts) => match parts.as_slice() {
+                [semitones, octaves] => signed(semitones)
+                    .zip(signed(octaves))
+                    .map(|(s, o)| (s, o, Transpose::new(s, o)))
+                    // `Transpose::new` clamps, so check nothing was
+                    .filter(|&(s, o, t)| t.semitones() == s && t.octaves() == o)
+                    .map(|(_, _, t)| changed.transpose = t),
+                _ => None,
+            },
+            _ => None,
+        },
+        Param::Span => SPANS
+            .iter()
+            .find(|s| Some(s.semitones() as i64) == int(item))
+            .map(|&s| changed.span = s),
+        Param::Mts => match item {
+            Item::Bool(b) => {
+                changed.mts = *b;
+                Some(())
+            }
+            _ => None,
+        },
+        Param::DacCalibrationA => calibration(item).map(|c| changed.dac_calibration.a = c),
+        Param::DacCalibrationB => calibration(item).map(|c| changed.dac_calibration.b = c),
+        Param::MainRibbonMap => map(item).map(|m| changed.ribbon_calibration.main_ribbon = m),
+        Param::ModRibbonMap => map(item).map(|m| changed.ribbon_calibration.mod_ribbon = m),
+        Param::Tuning => match item {
+            Item::Str(s) if s == NO_TUNING => Some(None),
+            _ => match floats(item).as_deref() {
+                Some([period, root_pitch, degrees @ ..]) => {
+                    TuningTable::new(*period, *root_pitch, degrees)
+                        .ok()
+                        .map(Some)
+                }
+                _ => None,
+            },
+        }
+        .map(|t| changed.tuning = t),
+    };
+    ok.ok_or_else(bad)?;
+
+    // e.g. a transpose which is out of range, or a calibration which is not plausible
+    sysex_config::with_value(settings, param, &sysex_config::value_of(&changed, param))
+        .map_err(|_| bad())
+}
+
+/// `to_toml(s)` is settings `s` as a preset file
+pub fn to_toml(settings: &Settings) -> String {
+    let mut text = String::from("# ribbon controller preset\n");
+    for param in Param::ALL {
+        text += &format!("{} = {}\n", param_name(param), item_of(settings, param));
+    }
+    text
+}
+
+/// `from_toml(t, s)` is settings `s` changed by the preset file `t`, and the parameters it changes in order
+pub fn from_toml(text: &str, settings: &Settings) -> Result<(Settings, Vec<Param>), ParseError> {
+    let mut settings = *settings;
+    let mut params: Vec<Param> = Vec::new();
+
+    for (i, line) in text.lines().enumerate() {
+        let line_num = i + 1;
+        // a `#` in a string would be cut off too, but no value needs one
+        let line = line.split('#').next().unwrap_or("").trim();
+        if line.is_empty() {
+            continue;
+        }
+
+        let (name, value) = line.split_once('=').ok_or_else(|| {
+            ParseError::new(line_num, format!("expected `name = value`: {}", line))
+        })?;
+        let name = name.trim();
+        let param = param_named(name)
+            .ok_or_else(|| ParseError::new(line_num, format!("unknown setting: {}", name)))?;
+        if params.contains(&param) {
+            return Err(ParseError::new(line_num, format!("{} is set twice", name)));
+        }
+
+        let item = parse_item(value).map_err(|msg| ParseError::new(line_num, msg))?;
+        settings =
+            with_item(&settings, param, &item).map_err(|msg| ParseError::new(line_num, msg))?;
+        params.push(param);
+    }
+
+    Ok((settings, params))
+}
+
+/// `name_of(cs, c)` is the name of choice `c` in the named choices `cs`
+fn name_of<T: PartialEq>(choices: &[(&'static str, T)], choice: T) -> &'static str {
+    choices
+        .iter()
+        .find(|(_, c)| *c == choice)
+        .map_or("", |(name, _)| name)
+}
+
+/// `choice_named(cs, n)` is the choice with name `n` in the named choices `cs`, if there is one
+fn choice_named<T: Copy>(choices: &[(&'static str, T)], name: &str) -> Option<T> {
+    choices.iter().find(|(n, _)| *n == name).map(|&(_, c)| c)
+}
+
+/// The names of the parameters, in the order they are numbered
+const PARAM_NAMES: [&str; sysex_config::NUM_PARAMS] = [
+    "midi-mode",
+    "transition-policy",
+    "velocity-curve",
+    "pitch-bend-range",
+    "scale",
+    "scale-root",
+    "transpose",
+    "span",
+    "mts",
+    "dac-calibration-a",
+    "dac-calibration-b",
+    "main-ribbon-map",
+    "mod-ribbon-map",
+    "tuning",
+];
+
+/// The value of the tuning when there is none, the scale is used instead
+const NO_TUNING: &str = "none";
+
+const MIDI_MODES: [(&str, MidiMode); 3] = [
+    ("standard", MidiMode::Standard),
+    ("mpe", MidiMode::Mpe),
+    ("pitch-bend-only", MidiMode::PitchBendOnly),
+];
+
+const TRANSITION_POLICIES: [(&str, TransitionPolicy); 3] = [
+    ("overlapping-legato", TransitionPolicy::OverlappingLegato),
+    ("note-off-first", TransitionPolicy::NoteOffFirst),
+    ("single-note", TransitionPolicy::SingleNote),
+];
+
+const VELOCITY_CURVES: [(&str, VelocityCurve); 3] = [
+    ("linear", VelocityCurve::Linear),
+    ("soft", VelocityCurve::Soft),
+    ("hard", VelocityCurve::Hard),
+];
+
+const SCALES: [(&str, Scale); 14] = [
+    ("chromatic", Scale::Chromatic),
+    ("major", Scale::Major),
+    ("natural-minor", Scale::NaturalMinor),
+    ("harmonic-minor", Scale::HarmonicMinor),
+    ("melodic-minor", Scale::MelodicMinor),
+    ("dorian", Scale::Dorian),
+    ("phrygian", Scale::Phrygian),
+    ("lydian", Scale::Lydian),
+    ("mixolydian", Scale::Mixolydian),
+    ("locrian", Scale::Locrian),
+    ("major-pentatonic", Scale::MajorPentatonic),
+    ("minor-pentatonic", Scale::MinorPentatonic),
+    ("blues", Scale::Blues),
+    ("whole-tone", Scale::WholeTone),
+];
+
+const SPANS: [RibbonSpan; 5] = [
+    RibbonSpan::Semitones12,
+    RibbonSpan::Semitones24,
+    RibbonSpan::Semitones32,
+    RibbonSpan::Semitones48,
+    RibbonSpan::Semitones60,
+];
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ribbon_core::{dac_calibration::DacCalibration, ribbon_calibration::RibbonCalibration};
+
+    fn custom() -> Settings {
+        Settings {
+            midi_mode: MidiMode::Mpe,
+            transition_policy: TransitionPolicy::SingleNote,
+            velocity_curve: VelocityCurve::Fixed(100),
+            pitch_bend_range: 12,
+            scale: Scale::User(0b1001_0001),
+            scale_root: 7,
+            transpose: Transpose::new(-5, 2),
+            span: RibbonSpan::Semitones48,
+            mts: true,
+            dac_calibration: DacCalibration {
+                a: ChannelCalibration::from_points(1.01, 4.02),
+                b: ChannelCalibration::from_points(0.99, 3.97),
+            },
+            ribbon_calibration: RibbonCalibration {
+                main_ribbon: RibbonMap::from_readings(&[0.02, 0.26, 0.5, 0.73, 0.98]).unwrap(),
+                mod_ribbon: RibbonMap::from_readings(&[0.01, 0.45, 0.99]).unwrap(),
+            },
+            tuning: Some(TuningTable::new(12.0, 60.25, &[0.0, 3.5, 7.0]).unwrap()),
+        }
+    }
+
+    #[test]
+    fn presets_read_back_the_same() {
+        for settings in [Settings::new(), custom()] {
+            let (read, params) = from_toml(&to_toml(&settings), &Settings::new()).unwrap();
+            assert_eq!(read, settings);
+            assert_eq!(params, Param::ALL);
+        }
+    }
+
+    #[test]
+    fn presets_are_written_as_documented() {
+        let text = to_toml(&custom());
+        let lines: Vec<&str> = text.lines().collect();
+        assert_eq!(lines[1], "midi-mode = \"mpe\"");
+        assert_eq!(lines[3], "velocity-curve = 100");
+        assert_eq!(lines[5], "scale = [0, 4, 7]");
+        assert_eq!(lines[7], "transpose = [-5, 2]");
+        assert_eq!(lines[8], "span = 48");
+        assert_eq!(lines[13], "mod-ribbon-map = [0.01, 0.45, 0.99]");
+        assert_eq!(lines[14], "tuning = [12.0, 60.25, 0.0, 3.5, 7.0]");
+        assert_eq!(lines.len(), 1 + Param::ALL.len());
+
+        let untuned = Settings {
+            tuning: None,
+            ..custom()
+        };
+        assert!(to_toml(&untuned).ends_with("tuning = \"none\"\n"));
+    }
+
+    #[test]
+    fn settings_left_out_are_not_changed() {
+        let text = "
+            # a few settings
+            scale = \"dorian\"   # the rest stay as they are
+            pitch-bend-range = 2
+        ";
+        let (read, params) = from_toml(text, &custom()).unwrap();
+        assert_eq!(params, [Param::Scale, Param::PitchBendRange]);
+        assert_eq!(
+            read,
+            Settings {
+                scale: Scale::Dorian,
+                pitch_bend_range: 2,
+                ..custom()
+            }
+        );
+    }
+
+    #[test]
+    fn mistakes_are_reported_with_their_line() {
+        let error = |text: &str| from_toml(text, &Settings::new()).unwrap_err().line;
+        assert_eq!(error("mts = true\nmts = false"), 2);
+        assert_eq!(error("\n\nvolume = 11"), 3);
+        assert_eq!(error("span"), 1);
+        assert_eq!(error("span = 25"), 1);
+        assert_eq!(error("transpose = [99, 0]"), 1);
+        assert_eq!(error("scale = [0, 12]"), 1);
+        assert_eq!(error("midi-mode = mpe"), 1);
+        assert_eq!(error("dac-calibration-a = [0.0, 0.0]"), 1);
+        assert_eq!(error("main-ribbon-map = [0.5, 0.2]"), 1);
+        assert_eq!(error("tuning = [12.0]"), 1);
+        assert_eq!(error("tuning = [12.0, 60.0, 12.5]"), 1);
+        assert_eq!(error("tuning = \"just\""), 1);
+    }
+
+    #[test]
+    fn every_param_has_a_name() {
+        for param in Param::ALL {
+            assert_eq!(param_named(param_name(param)), Some(param));
+        }
+    }
+}